@@ -1,20 +1,30 @@
-use serde::Serialize;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
 use std::time::Duration;
 
 pub const TIMESTAMP_FORMAT: &str = "%Y%m%d-%H%M%S";
+// names stills, several stills are taken within a second
+pub const STILL_TIMESTAMP_FORMAT: &str = "%Y%m%d-%H%M%S-%3f";
 #[derive(Default)]
 pub struct ChunkInfo {
     pub chunk: String,
     pub timestamp: String,
     pub duration: Duration,
+    pub program_date_time: DateTime<Local>,
 }
 
 impl ChunkInfo {
-    pub fn new(chunk: String, timestamp: String, duration: Duration) -> ChunkInfo {
+    pub fn new(
+        chunk: String,
+        timestamp: String,
+        duration: Duration,
+        program_date_time: DateTime<Local>,
+    ) -> ChunkInfo {
         ChunkInfo {
             chunk,
             timestamp,
             duration,
+            program_date_time,
         }
     }
 }
@@ -39,7 +49,311 @@ pub struct RecordingInfo {
     pub prefix: String,
 }
 
-#[derive(Default, Serialize)]
+// Optional conditions that end a recording automatically
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RecordingLimits {
+    pub max_duration_sec: Option<u64>,
+    pub max_bytes: Option<u64>,
+    pub stop_at: Option<DateTime<Local>>,
+}
+
+// Body of a start recording request
+// name: an optional name of the session (e.g. the schedule that started it)
+// profile: the recording profile (pipeline) to use, the default recording pipeline if empty
+// analyzers: the registered frame analyzers to run on this recording only
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RecordingRequest {
+    pub name: Option<String>,
+    pub profile: Option<String>,
+    #[serde(flatten)]
+    pub limits: RecordingLimits,
+    pub analyzers: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StopReason {
+    Manual,
+    MaxDuration,
+    MaxBytes,
+    StopAt,
+    EndOfStream,
+    Error,
+}
+
+// Body of a protect request: the footage around now is kept in an incident playlist
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProtectRequest {
+    pub name: Option<String>,
+    pub before_sec: u64,
+    pub after_sec: u64,
+}
+
+impl Default for ProtectRequest {
+    fn default() -> Self {
+        ProtectRequest {
+            name: None,
+            before_sec: 120,
+            after_sec: 120,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct IncidentInfo {
+    pub id: String,
+    pub name: Option<String>,
+    pub from: DateTime<Local>,
+    pub until: DateTime<Local>,
+    pub playlist: String,
+}
+
+// Written as {timestamp}-session.json next to the playlist
+#[derive(Default, Clone, Serialize)]
+pub struct SessionManifest {
+    pub prefix: String,
+    pub name: Option<String>,
+    pub profile: Option<String>,
+    pub playlist: String,
+    pub started_at: DateTime<Local>,
+    pub ended_at: Option<DateTime<Local>>,
+    pub stop_reason: Option<StopReason>,
+    pub limits: RecordingLimits,
+    // the segments are deleted by the retention window (loop recording)
+    pub loop_recording: bool,
+    pub segments: u64,
+    pub bytes: u64,
+    pub duration_sec: f64,
+    pub incidents: Vec<IncidentInfo>,
+    pub motion_intervals: Vec<MotionInterval>,
+    pub artifacts: Vec<Artifact>,
+    // the cover image and the animated preview of the session
+    pub poster: Option<String>,
+    pub preview: Option<String>,
+    pub quality_issues: Vec<QualityIssue>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QualityIssueKind {
+    Black,
+    Frozen,
+    Overexposed,
+}
+
+impl std::fmt::Display for QualityIssueKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QualityIssueKind::Black => write!(f, "black"),
+            QualityIssueKind::Frozen => write!(f, "frozen"),
+            QualityIssueKind::Overexposed => write!(f, "overexposed"),
+        }
+    }
+}
+
+// A period with a bad signal in a recording session
+#[derive(Debug, Clone, Serialize)]
+pub struct QualityIssue {
+    pub kind: QualityIssueKind,
+    pub from: DateTime<Local>,
+    pub until: DateTime<Local>,
+}
+
+// The frame queue of the analyzers, served by GET /metrics
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct FrameMetrics {
+    pub queue_capacity: usize,
+    // the frames waiting for the analyzers
+    pub queue_depth: usize,
+    pub frames_processed: u64,
+    // the frames dropped because the queue was full
+    pub frames_dropped: u64,
+}
+
+// A file written by a frame analyzer during a session
+#[derive(Debug, Clone, Serialize)]
+pub struct Artifact {
+    pub analyzer: String,
+    pub kind: String,
+    pub file: String,
+    pub timestamp: DateTime<Local>,
+}
+
+// A scene of the storyboard, written to {prefix}-storyboard.json
+#[derive(Debug, Clone, Serialize)]
+pub struct SceneInfo {
+    pub scene: usize,
+    // the playlist time of the scene
+    pub start_sec: f64,
+    pub end_sec: f64,
+    // the wall-clock time of the first frame
+    pub timestamp: DateTime<Local>,
+    // the histogram distance to the previous scene
+    pub score: f64,
+    // the thumbnail of the first frame
+    pub image: String,
+}
+
+// An object found by the detection analyzer, written to {prefix}-detections.json
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Detection {
+    pub label: String,
+    pub class_id: i32,
+    pub confidence: f32,
+    // the box in relative coordinates (0.0 - 1.0)
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+    // the wall-clock time of the frame
+    pub timestamp: DateTime<Local>,
+    // the session the frame was recorded in
+    pub prefix: String,
+}
+
+// The detections of a recording session
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DetectionReport {
+    pub prefix: String,
+    pub model: String,
+    pub started_at: DateTime<Local>,
+    pub detections: Vec<Detection>,
+}
+
+// A period with motion in a recording session
+#[derive(Debug, Clone, Serialize)]
+pub struct MotionInterval {
+    pub from: DateTime<Local>,
+    pub until: DateTime<Local>,
+    pub peak_score: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    MotionStarted,
+    MotionEnded,
+    // reported by a frame analyzer, see detail
+    Analyzer,
+    // a bad signal (black, frozen or overexposed frames, see detail)
+    QualityAlarmStarted,
+    QualityAlarmEnded,
+}
+
+// Something detected while the source is running, served by GET /events
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    pub timestamp: DateTime<Local>,
+    pub kind: EventKind,
+    pub score: Option<f64>,
+    // the recording session the event belongs to
+    pub prefix: Option<String>,
+    // the component reporting the event (motion, the name of an analyzer)
+    pub source: Option<String>,
+    pub detail: Option<String>,
+}
+
+// The image format of a still
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StillFormat {
+    #[default]
+    #[serde(alias = "jpg")]
+    Jpeg,
+    Png,
+    Webp,
+    // lossless (LZW)
+    #[serde(alias = "tif")]
+    Tiff,
+}
+
+impl StillFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            StillFormat::Jpeg => "jpg",
+            StillFormat::Png => "png",
+            StillFormat::Webp => "webp",
+            StillFormat::Tiff => "tiff",
+        }
+    }
+
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            StillFormat::Jpeg => "image/jpeg",
+            StillFormat::Png => "image/png",
+            StillFormat::Webp => "image/webp",
+            StillFormat::Tiff => "image/tiff",
+        }
+    }
+
+    // The format reported in StillInfo
+    pub fn name(&self) -> &'static str {
+        match self {
+            StillFormat::Jpeg => "JPEG",
+            StillFormat::Png => "PNG",
+            StillFormat::Webp => "WebP",
+            StillFormat::Tiff => "TIFF",
+        }
+    }
+}
+
+// A rectangle of the frame, relative coordinates (0 - 1)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CropRect {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+// How a still is encoded
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StillEncoding {
+    pub format: StillFormat,
+    // 1 - 100, JPEG and WebP
+    pub quality: Option<u8>,
+    // the size of the still, the aspect ratio is kept if only one is given
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    // applied before the still is scaled
+    pub crop: Option<CropRect>,
+}
+
+impl StillEncoding {
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(quality) = self.quality {
+            if !(1..=100).contains(&quality) {
+                return Err(format!("Quality {quality} not in 1 - 100"));
+            }
+        }
+        if self.width == Some(0) || self.height == Some(0) {
+            return Err("Width and height must be positive".to_string());
+        }
+        if let Some(crop) = self.crop.as_ref() {
+            let inside = |start: f64, size: f64| start >= 0.0 && size > 0.0 && start + size <= 1.0;
+            if !inside(crop.x, crop.width) || !inside(crop.y, crop.height) {
+                return Err("The crop rectangle must be inside the frame (0 - 1)".to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+// Body of a still request
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StillRequest {
+    // embedded in the still (Exif image description, JPEG only)
+    pub note: Option<String>,
+    #[serde(flatten)]
+    pub encoding: StillEncoding,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
 pub struct StillInfo {
     pub device: String,
     pub width: u32,
@@ -47,3 +361,92 @@ pub struct StillInfo {
     pub format: String,
     pub still_file: String,
 }
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StillJobKind {
+    // count stills, one per frame
+    #[default]
+    Burst,
+    // a still every interval_sec
+    Interval,
+}
+
+// Body of a still job request
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct StillJobRequest {
+    pub name: Option<String>,
+    pub kind: StillJobKind,
+    // the number of stills, required for a burst
+    pub count: Option<u32>,
+    pub interval_sec: f64,
+    // an interval job ends after duration_sec (or count stills), else when it is stopped
+    pub duration_sec: Option<u64>,
+    // how the stills are taken
+    #[serde(flatten)]
+    pub still: StillRequest,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StillJobState {
+    Running,
+    Finished,
+    Stopped,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct JobStill {
+    pub index: u32,
+    pub captured_at: DateTime<Local>,
+    #[serde(flatten)]
+    pub info: StillInfo,
+}
+
+// A burst or interval job, the first and the last line of its manifest {id}-stills.jsonl
+#[derive(Debug, Clone, Serialize)]
+pub struct StillJob {
+    pub id: String,
+    pub name: Option<String>,
+    pub kind: StillJobKind,
+    pub state: StillJobState,
+    pub started_at: DateTime<Local>,
+    pub finished_at: Option<DateTime<Local>>,
+    pub count: Option<u32>,
+    pub interval_sec: f64,
+    pub duration_sec: Option<u64>,
+    pub manifest: String,
+    // the stills taken and the stills that failed
+    pub captured: u32,
+    pub failed: u32,
+}
+
+// Body of a timelapse request, the configured interval and frame rate are used if not set
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TimelapseRequest {
+    pub name: Option<String>,
+    // the source is sampled every interval_sec
+    pub interval_sec: Option<f64>,
+    // the frame rate of the video
+    pub fps: Option<u32>,
+}
+
+// A timelapse video, written to {prefix}-timelapse.json when it is stopped
+#[derive(Debug, Clone, Serialize)]
+pub struct TimelapseInfo {
+    pub prefix: String,
+    pub name: Option<String>,
+    pub video_file: String,
+    pub interval_sec: f64,
+    pub fps: u32,
+    pub started_at: DateTime<Local>,
+    pub finished_at: Option<DateTime<Local>>,
+    // the samples in the video
+    pub frames: u32,
+    // the thumbnail files (in the output dir)
+    pub thumbnails: Vec<String>,
+    // the encoder error that ended the sampling
+    pub error: Option<String>,
+}
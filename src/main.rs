@@ -1,22 +1,50 @@
 mod dtos;
+mod motion;
 mod recorder;
+mod scheduler;
 mod utils;
 
-use crate::dtos::messages::{RecordingInfo, StillInfo, TIMESTAMP_FORMAT};
+use crate::dtos::messages::{
+    Detection, Event, FrameMetrics, IncidentInfo, ProtectRequest, RecordingInfo, RecordingRequest,
+    StillInfo, StillJob, StillJobRequest, StillRequest, TimelapseInfo, TimelapseRequest,
+    STILL_TIMESTAMP_FORMAT,
+};
+use crate::motion::trigger::{MotionTrigger, MotionTriggerBuilder};
+use crate::recorder::clock::WallClock;
+use crate::recorder::common::PipelineError;
+use crate::recorder::detection::{DetectionAnalyzer, DetectionConfig, DetectionQuery};
+use crate::recorder::events::EventLog;
+use crate::recorder::heatmap::HeatmapAnalyzer;
+use crate::recorder::poster::PosterConfig;
+use crate::recorder::privacy::PrivacyMask;
+use crate::recorder::quality::{QualityAnalyzer, QualityConfig};
+use crate::recorder::retention::RetentionPolicy;
+use crate::recorder::storyboard::{StoryboardAnalyzer, DEFAULT_SCENE_THRESHOLD};
+use crate::recorder::thumbnailer::ThumbnailConfig;
 use crate::recorder::videocontroller::{VideoController, VideoControllerImpl};
+use crate::scheduler::schedule::Schedule;
+use crate::scheduler::scheduler::Scheduler;
+use crate::scheduler::stilljobs::StillJobs;
 use crate::utils::config::RecordingConfig;
 use crate::ApiError::StillError;
-use crate::ApiResponse::{Still, VideoRecording, VideoSource};
+use crate::ApiResponse::{
+    Detections, Events, Image, Incident, Metrics, ScheduleEntry, Schedules, Still, StillJobEntry,
+    StillJobList, Timelapse, VideoRecording, VideoSource,
+};
 use axum::{
-    extract::State,
-    http::StatusCode,
+    body::Bytes,
+    extract::{Path, Query, State},
+    http::{header, StatusCode},
     response::{IntoResponse, Response},
-    routing::{get, post},
+    routing::{delete, get, post},
     Json, Router,
 };
-use chrono::Local;
+use chrono::{DateTime, Local};
 use env_logger::Env;
+use gstreamer_app::gst;
 use log::{error, info};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
 use std::io::Write;
 use std::sync::{Arc, Mutex};
 
@@ -24,6 +52,17 @@ enum ApiResponse {
     Still(StillInfo),
     VideoRecording(RecordingInfo),
     VideoSource,
+    Schedules(Vec<Schedule>),
+    ScheduleEntry(Schedule),
+    Incident(IncidentInfo),
+    Events(Vec<Event>),
+    Metrics(FrameMetrics),
+    Detections(Vec<Detection>),
+    StillJobList(Vec<StillJob>),
+    StillJobEntry(StillJob),
+    Timelapse(TimelapseInfo),
+    // the content type and the encoded image
+    Image(&'static str, Vec<u8>),
 }
 impl IntoResponse for ApiResponse {
     fn into_response(self) -> Response {
@@ -31,6 +70,21 @@ impl IntoResponse for ApiResponse {
             Self::Still(still) => (StatusCode::OK, Json(still)).into_response(),
             Self::VideoRecording(info) => (StatusCode::OK, Json(info)).into_response(),
             Self::VideoSource => (StatusCode::OK).into_response(),
+            Self::Schedules(schedules) => (StatusCode::OK, Json(schedules)).into_response(),
+            Self::ScheduleEntry(schedule) => (StatusCode::OK, Json(schedule)).into_response(),
+            Self::Incident(incident) => (StatusCode::OK, Json(incident)).into_response(),
+            Self::Events(events) => (StatusCode::OK, Json(events)).into_response(),
+            Self::Metrics(metrics) => (StatusCode::OK, Json(metrics)).into_response(),
+            Self::Detections(detections) => (StatusCode::OK, Json(detections)).into_response(),
+            Self::StillJobList(jobs) => (StatusCode::OK, Json(jobs)).into_response(),
+            Self::StillJobEntry(job) => (StatusCode::OK, Json(job)).into_response(),
+            Self::Timelapse(info) => (StatusCode::OK, Json(info)).into_response(),
+            Self::Image(content_type, image) => (
+                StatusCode::OK,
+                [(header::CONTENT_TYPE, content_type)],
+                image,
+            )
+                .into_response(),
         }
     }
 }
@@ -39,6 +93,11 @@ enum ApiError {
     StillError,
     RecordingError,
     SourceError,
+    ScheduleError(String),
+    BadRequest(String),
+    NotFound,
+    Conflict(String),
+    DetectionError,
 }
 
 impl IntoResponse for ApiError {
@@ -57,72 +116,317 @@ impl IntoResponse for ApiError {
             Self::SourceError => {
                 (StatusCode::INTERNAL_SERVER_ERROR, Json("Error in source")).into_response()
             }
+            Self::ScheduleError(e) => (StatusCode::BAD_REQUEST, Json(e)).into_response(),
+            Self::BadRequest(e) => (StatusCode::BAD_REQUEST, Json(e)).into_response(),
+            Self::NotFound => (StatusCode::NOT_FOUND, Json("Not found")).into_response(),
+            Self::Conflict(e) => (StatusCode::CONFLICT, Json(e)).into_response(),
+            Self::DetectionError => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json("Error searching detections"),
+            )
+                .into_response(),
         }
     }
 }
 
+// Parses the optional JSON body of a request, without a body the request has its defaults
+fn json_body<T: DeserializeOwned + Default>(body: &Bytes) -> Result<T, ApiError> {
+    if body.iter().all(u8::is_ascii_whitespace) {
+        return Ok(T::default());
+    }
+    serde_json::from_slice(body).map_err(|e| ApiError::BadRequest(e.to_string()))
+}
+
 async fn root() -> Json<&'static str> {
     Json("Hello, World!")
 }
 
-async fn start(State(state): State<Arc<Mutex<AppState>>>) -> Result<ApiResponse, ApiError> {
+async fn start(State(state): State<Arc<AppState>>) -> Result<ApiResponse, ApiError> {
     info!("Starting recording");
     state
+        .controller
         .lock()
         .unwrap()
-        .controller
         .start("video10")
         .map_or_else(|_| Err(ApiError::SourceError), |_| Ok(VideoSource))
 }
 
 async fn start_recording(
-    State(state): State<Arc<Mutex<AppState>>>,
+    State(state): State<Arc<AppState>>,
+    body: Bytes,
 ) -> Result<ApiResponse, ApiError> {
     info!("Starting recording");
+    let request: RecordingRequest = json_body(&body)?;
     state
+        .controller
         .lock()
         .unwrap()
-        .controller
-        .start_recording()
+        .start_recording(request)
         .map_or_else(|_| Err(ApiError::RecordingError), |r| Ok(VideoRecording(r)))
 }
 
-async fn stop(State(state): State<Arc<Mutex<AppState>>>) -> Result<ApiResponse, ApiError> {
+async fn stop(State(state): State<Arc<AppState>>) -> Result<ApiResponse, ApiError> {
     info!("Stopping recording");
     state
+        .controller
         .lock()
         .unwrap()
-        .controller
         .stop("video10")
         .map_or_else(|_| Err(ApiError::RecordingError), |_| Ok(VideoSource))
 }
 
-async fn stop_recording(
-    State(state): State<Arc<Mutex<AppState>>>,
-) -> Result<ApiResponse, ApiError> {
+async fn stop_recording(State(state): State<Arc<AppState>>) -> Result<ApiResponse, ApiError> {
     info!("Stopping recording");
     state
+        .controller
         .lock()
         .unwrap()
-        .controller
         .stop_recording()
         .map_or_else(|_| Err(ApiError::RecordingError), |_| Ok(VideoSource))
 }
 
-async fn take_still(State(state): State<Arc<Mutex<AppState>>>) -> Result<ApiResponse, ApiError> {
-    info!("Stopping recording");
+async fn pause_recording(State(state): State<Arc<AppState>>) -> Result<ApiResponse, ApiError> {
+    info!("Pausing recording");
+    state
+        .controller
+        .lock()
+        .unwrap()
+        .pause_recording()
+        .map_or_else(|_| Err(ApiError::RecordingError), |_| Ok(VideoSource))
+}
+
+async fn resume_recording(State(state): State<Arc<AppState>>) -> Result<ApiResponse, ApiError> {
+    info!("Resuming recording");
+    state
+        .controller
+        .lock()
+        .unwrap()
+        .resume_recording()
+        .map_or_else(|_| Err(ApiError::RecordingError), |_| Ok(VideoSource))
+}
+
+async fn protect_recording(
+    State(state): State<Arc<AppState>>,
+    body: Bytes,
+) -> Result<ApiResponse, ApiError> {
+    info!("Protecting recording");
+    let request: ProtectRequest = json_body(&body)?;
+    state
+        .controller
+        .lock()
+        .unwrap()
+        .protect_recording(request)
+        .map_or_else(|_| Err(ApiError::RecordingError), |i| Ok(Incident(i)))
+}
+
+#[derive(Deserialize)]
+struct StillQuery {
+    // the response is the image instead of the still info
+    #[serde(default)]
+    inline: bool,
+    // keeps the still in the output dir, an inline still is encoded in memory if false
+    persist: Option<bool>,
+}
+
+async fn take_still(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<StillQuery>,
+    body: Bytes,
+) -> Result<ApiResponse, ApiError> {
+    info!("Taking still");
+    let request: StillRequest = json_body(&body)?;
+    let persist = query.persist.unwrap_or(true);
+    let device = state.controller.lock().unwrap().device();
+    let device = device.ok_or(StillError)?;
+    still(&state, &device, &request, query.inline, persist)
+}
+
+async fn snapshot(
+    State(state): State<Arc<AppState>>,
+    Path(device): Path<String>,
+    Query(query): Query<StillQuery>,
+) -> Result<ApiResponse, ApiError> {
+    info!("Taking snapshot of {}", device);
+    if state.controller.lock().unwrap().device().as_ref() != Some(&device) {
+        return Err(ApiError::NotFound);
+    }
+    let persist = query.persist.unwrap_or(false);
+    still(&state, &device, &StillRequest::default(), true, persist)
+}
+
+fn still(
+    state: &AppState,
+    device: &str,
+    request: &StillRequest,
+    inline: bool,
+    persist: bool,
+) -> Result<ApiResponse, ApiError> {
+    request.encoding.validate().map_err(ApiError::BadRequest)?;
+    if !inline && !persist {
+        return Err(ApiError::BadRequest(
+            "A still that is not persisted must be inline".to_string(),
+        ));
+    }
+    if !persist {
+        let image = state
+            .controller
+            .lock()
+            .unwrap()
+            .encode_still(device, request)
+            .map_err(|_| StillError)?;
+        return Ok(Image(request.encoding.format.mime_type(), image));
+    }
     // a string holding the current time
-    let time = Local::now().format(TIMESTAMP_FORMAT).to_string();
+    let time = Local::now().format(STILL_TIMESTAMP_FORMAT).to_string();
     let still_info = state
+        .controller
+        .lock()
+        .unwrap()
+        .take_still(device, time.as_str(), request)
+        .map_err(|_| StillError)?;
+    if !inline {
+        return Ok(Still(still_info));
+    }
+    let image = std::fs::read(&still_info.still_file).map_err(|e| {
+        error!("Unable to read {}: {}", still_info.still_file, e);
+        StillError
+    })?;
+    Ok(Image(request.encoding.format.mime_type(), image))
+}
+async fn list_still_jobs(State(state): State<Arc<AppState>>) -> Result<ApiResponse, ApiError> {
+    Ok(StillJobList(state.still_jobs.lock().unwrap().list()))
+}
+
+async fn start_still_job(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<StillJobRequest>,
+) -> Result<ApiResponse, ApiError> {
+    info!("Starting still job");
+    state
+        .still_jobs
+        .lock()
+        .unwrap()
+        .start(request)
+        .map_or_else(|e| Err(ApiError::BadRequest(e)), |j| Ok(StillJobEntry(j)))
+}
+
+async fn stop_still_job(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<ApiResponse, ApiError> {
+    info!("Stopping still job {}", id);
+    state
+        .still_jobs
+        .lock()
+        .unwrap()
+        .stop(&id)
+        .map_or_else(|| Err(ApiError::NotFound), |j| Ok(StillJobEntry(j)))
+}
+
+async fn start_timelapse(
+    State(state): State<Arc<AppState>>,
+    body: Bytes,
+) -> Result<ApiResponse, ApiError> {
+    info!("Starting timelapse");
+    let request: TimelapseRequest = json_body(&body)?;
+    state
+        .controller
         .lock()
         .unwrap()
+        .start_timelapse(request)
+        .map(Timelapse)
+        .map_err(|e| match e {
+            PipelineError::AlreadyStarted => ApiError::Conflict("Timelapse running".to_string()),
+            _ => ApiError::RecordingError,
+        })
+}
+
+async fn stop_timelapse(State(state): State<Arc<AppState>>) -> Result<ApiResponse, ApiError> {
+    info!("Stopping timelapse");
+    state
         .controller
-        .take_still("video0", time.as_str())
-        .map_or_else(|_| Err(StillError), |still| Ok(Still(still)));
-    still_info
+        .lock()
+        .unwrap()
+        .stop_timelapse()
+        .map(Timelapse)
+        .map_err(|e| match e {
+            PipelineError::NotRunning => ApiError::Conflict("No timelapse running".to_string()),
+            _ => ApiError::RecordingError,
+        })
 }
+
+async fn list_schedules(State(state): State<Arc<AppState>>) -> Result<ApiResponse, ApiError> {
+    Ok(Schedules(state.scheduler.lock().unwrap().list()))
+}
+
+async fn create_schedule(
+    State(state): State<Arc<AppState>>,
+    Json(schedule): Json<Schedule>,
+) -> Result<ApiResponse, ApiError> {
+    info!("Creating schedule {}", schedule.name);
+    state.scheduler.lock().unwrap().add(schedule).map_or_else(
+        |e| Err(ApiError::ScheduleError(e)),
+        |s| Ok(ScheduleEntry(s)),
+    )
+}
+
+async fn delete_schedule(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<ApiResponse, ApiError> {
+    info!("Deleting schedule {}", id);
+    state
+        .scheduler
+        .lock()
+        .unwrap()
+        .remove(&id)
+        .map_or_else(|| Err(ApiError::NotFound), |s| Ok(ScheduleEntry(s)))
+}
+
+#[derive(Deserialize)]
+struct EventQuery {
+    since: Option<DateTime<Local>>,
+}
+
+async fn list_events(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<EventQuery>,
+) -> Result<ApiResponse, ApiError> {
+    Ok(Events(state.events.lock().unwrap().list(query.since)))
+}
+
+async fn metrics(State(state): State<Arc<AppState>>) -> Result<ApiResponse, ApiError> {
+    Ok(Metrics(state.controller.lock().unwrap().frame_metrics()))
+}
+
+async fn search_detections(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<DetectionQuery>,
+) -> Result<ApiResponse, ApiError> {
+    // the reports are read from disk, off the async runtime
+    let output_dir = state.output_dir.clone();
+    tokio::task::spawn_blocking(move || recorder::detection::search(&output_dir, &query))
+        .await
+        .map_err(|e| {
+            error!("Detection search failed: {}", e);
+            ApiError::DetectionError
+        })?
+        .map_or_else(
+            |e| {
+                error!("Detection search failed: {}", e);
+                Err(ApiError::DetectionError)
+            },
+            |detections| Ok(Detections(detections)),
+        )
+}
+
 struct AppState {
-    controller: crate::recorder::videocontroller::VideoControllerImpl,
+    controller: Arc<Mutex<VideoControllerImpl>>,
+    scheduler: Arc<Mutex<Scheduler>>,
+    still_jobs: Arc<Mutex<StillJobs>>,
+    events: Arc<Mutex<EventLog>>,
+    // the directory of the recordings and their reports
+    output_dir: String,
 }
 
 #[tokio::main]
@@ -155,38 +459,181 @@ async fn main() {
         } else {
             conf.preview_pipeline
         };
-        let shared_state = Arc::new(Mutex::new(AppState {
-            controller: VideoControllerImpl::new(
-                recorder::videosource::VideoSourceBuilder::new()
-                    .with_fd_dir("/tmp")
-                    .with_pipeline(conf.source_pipeline.as_str())
-                    .build(),
-                recorder::videorecorder::VideoRecorderBuilder::new()
-                    .with_pipeline(conf.recording_pipeline.to_string())
-                    .with_chunks_sec(conf.chunk_size)
-                    .with_output_dir(conf.output_dir.to_string())
-                    .with_socket_path("/tmp/video10.sock".to_string())
-                    .with_on_chunk(|chunk| {
-                        info!(
-                            "Chunk: {}, timestamp: {}, duration: {}",
-                            chunk.chunk,
-                            chunk.timestamp,
-                            chunk.duration.as_secs()
-                        );
-                    })
-                    .build(),
-                recorder::stillrecorder::StillRecorderBuilder::new()
-                    .with_output_dir(conf.output_dir.as_str())
-                    .with_socket_path("/tmp/video10.sock")
-                    .with_device("video10")
-                    .with_pipeline_str(conf.still_pipeline.as_str())
-                    .build(),
-                recorder::preview::PreviewBuilder::new()
+        let retention = conf.loop_recording.then(|| RetentionPolicy {
+            max_age: conf
+                .retention_hours
+                .map(|h| std::time::Duration::from_secs_f64(h * 3600.0)),
+            max_bytes: conf.retention_gb.map(|gb| (gb * 1_000_000_000.0) as u64),
+        });
+        info!("Retention: {:?}", retention);
+        let source_pipeline = if conf.branch_mode {
+            conf.source_branch_pipeline.as_str()
+        } else {
+            conf.source_pipeline.as_str()
+        };
+        let events = Arc::new(Mutex::new(EventLog::new()));
+        gst::init().unwrap();
+        // recordings tagged with an unsynchronized clock are worse than no recordings
+        let clock = WallClock::new(&conf.clock_source, conf.ntp_server.as_str(), conf.ntp_port)
+            .expect("Unable to set up the recording clock");
+        let mut recorder_builder = recorder::videorecorder::VideoRecorderBuilder::new()
+            .with_pipeline(conf.recording_pipeline.to_string())
+            .with_chunks_sec(conf.chunk_size)
+            .with_output_dir(conf.output_dir.to_string())
+            .with_socket_path("/tmp/video10.sock".to_string())
+            .with_clock(clock)
+            .with_retention(retention)
+            .with_events(events.clone())
+            .with_frame_queue_size(conf.frame_queue_size)
+            .with_thumbnails(ThumbnailConfig {
+                width: conf.thumbnail_width,
+                height: conf.thumbnail_height,
+                sprite_width: conf.sprite_width,
+                sprite_height: conf.sprite_height,
+                frames_per_tile: conf.thumbnails_per_tile,
+            })
+            .with_poster(PosterConfig {
+                position: conf.poster_position,
+                preview_frames: conf.preview_frames,
+            })
+            .with_analyzer_factory("quality", || {
+                Box::new(QualityAnalyzer::new(QualityConfig::default()))
+            })
+            .with_analyzer_factory("black_frame", || {
+                Box::new(QualityAnalyzer::black_frame(QualityConfig::default()))
+            })
+            .with_analyzer_factory("storyboard", || {
+                Box::new(StoryboardAnalyzer::new(DEFAULT_SCENE_THRESHOLD))
+            })
+            .with_analyzer_factory("heatmap", || Box::new(HeatmapAnalyzer::new()))
+            .with_on_chunk(|chunk| {
+                info!(
+                    "Chunk: {}, timestamp: {}, duration: {}, program-date-time: {}",
+                    chunk.chunk,
+                    chunk.timestamp,
+                    chunk.duration.as_secs(),
+                    chunk.program_date_time.to_rfc3339()
+                );
+            });
+        if conf.quality_alarms {
+            info!(
+                "Quality alarms after {}s, frozen after {}s",
+                conf.quality_alarm_sec, conf.frozen_sec
+            );
+            recorder_builder =
+                recorder_builder.with_analyzer(QualityAnalyzer::new(QualityConfig {
+                    alarm_sec: conf.quality_alarm_sec,
+                    frozen_sec: conf.frozen_sec,
+                    frozen_threshold: conf.frozen_threshold,
+                }));
+        }
+        if conf.storyboard {
+            info!("Storyboard, scene threshold: {}", conf.scene_threshold);
+            recorder_builder =
+                recorder_builder.with_analyzer(StoryboardAnalyzer::new(conf.scene_threshold));
+        }
+        if conf.heatmap {
+            info!("Motion heatmap");
+            recorder_builder = recorder_builder.with_analyzer(HeatmapAnalyzer::new());
+        }
+        if !conf.detection_model.is_empty() {
+            let detection = DetectionAnalyzer::new(DetectionConfig {
+                model: conf.detection_model.to_string(),
+                labels: conf.detection_labels.to_string(),
+                input_size: conf.detection_input_size,
+                confidence: conf.detection_confidence,
+                fps: conf.detection_fps,
+            });
+            match detection {
+                Ok(detection) => recorder_builder = recorder_builder.with_analyzer(detection),
+                Err(e) => error!("Object detection disabled: {}", e),
+            }
+        }
+        let face_model = conf
+            .privacy_faces
+            .then_some(conf.privacy_face_model.as_str());
+        let privacy = PrivacyMask::new(conf.privacy_regions.clone(), face_model)
+            .expect("Unable to set up the privacy masks");
+        let mut controller = VideoControllerImpl::new(
+            recorder::videosource::VideoSourceBuilder::new()
+                .with_fd_dir("/tmp")
+                .with_pipeline(source_pipeline)
+                .with_privacy(Arc::new(privacy))
+                .build(),
+            recorder_builder.build(),
+            recorder::stillrecorder::StillRecorderBuilder::new()
+                .with_output_dir(conf.output_dir.as_str())
+                .with_socket_path("/tmp/video10.sock")
+                .with_device("video10")
+                .with_pipeline_str(conf.still_pipeline.as_str())
+                .with_branch_str(conf.still_branch.as_str())
+                .with_live_pipeline_str(conf.still_live_pipeline.as_str())
+                .with_live_branch_str(conf.still_live_branch.as_str())
+                .build(),
+            recorder::preview::PreviewBuilder::new()
+                .with_socket_path("/tmp/video10.sock")
+                .with_pipeline_str(preview_pipeline.as_str())
+                .build(),
+        )
+        .with_profiles(conf.profiles.clone())
+        .with_timelapse(
+            recorder::timelapse::TimelapseBuilder::new()
+                .with_socket_path("/tmp/video10.sock")
+                .with_output_dir(conf.output_dir.as_str())
+                .with_pipeline_str(conf.timelapse_pipeline.as_str())
+                .with_branch_str(conf.timelapse_branch.as_str())
+                .with_encoding_pipeline_str(conf.timelapse_encoding_pipeline.as_str())
+                .with_interval_sec(conf.timelapse_interval_sec)
+                .with_fps(conf.timelapse_fps)
+                .with_thumbnail_every(conf.timelapse_thumbnail_every)
+                .build(),
+        );
+        if conf.branch_mode {
+            info!("Branch mode, tee: {}", conf.source_tee);
+            controller =
+                controller.with_branches(conf.source_tee.as_str(), conf.recording_branch.as_str());
+        }
+        if conf.preroll_sec > 0 {
+            info!("Pre-roll: {}s", conf.preroll_sec);
+            controller = controller.with_preroll(
+                recorder::preroll::PrerollBuilder::new()
                     .with_socket_path("/tmp/video10.sock")
-                    .with_pipeline_str(preview_pipeline.as_str())
+                    .with_pipeline_str(conf.preroll_pipeline.as_str())
+                    .with_recording_pipeline_str(conf.preroll_recording_pipeline.as_str())
+                    .with_duration_sec(conf.preroll_sec)
                     .build(),
-            ),
-        }));
+            );
+        }
+        let controller = Arc::new(Mutex::new(controller));
+        let scheduler = Arc::new(Mutex::new(Scheduler::new(
+            conf.schedule_file.as_str(),
+            controller.clone(),
+            conf.missed_schedule_policy.clone(),
+        )));
+        Scheduler::run(scheduler.clone());
+        let still_jobs = Arc::new(Mutex::new(StillJobs::new(
+            controller.clone(),
+            conf.output_dir.as_str(),
+        )));
+        if conf.motion_detection {
+            info!("Motion detection, sensitivity: {}", conf.motion_sensitivity);
+            let trigger = MotionTriggerBuilder::new()
+                .with_pipeline_str(conf.motion_pipeline.as_str())
+                .with_branch_str(conf.motion_branch.as_str())
+                .with_socket_path("/tmp/video10.sock")
+                .with_sensitivity(conf.motion_sensitivity)
+                .with_regions(conf.motion_regions.clone())
+                .with_cooldown_sec(conf.motion_cooldown_sec)
+                .build(controller.clone(), events.clone());
+            MotionTrigger::run(Arc::new(Mutex::new(trigger)));
+        }
+        let shared_state = Arc::new(AppState {
+            controller,
+            scheduler,
+            still_jobs,
+            events,
+            output_dir: conf.output_dir.to_string(),
+        });
 
         // build our application with a route
         let app = Router::new()
@@ -194,9 +641,22 @@ async fn main() {
             .route("/", get(root))
             .route("/start", post(start))
             .route("/still", post(take_still))
+            .route("/devices/:id/snapshot.jpg", get(snapshot))
+            .route("/still/jobs", get(list_still_jobs).post(start_still_job))
+            .route("/still/jobs/:id/stop", post(stop_still_job))
+            .route("/timelapse/start", post(start_timelapse))
+            .route("/timelapse/stop", post(stop_timelapse))
             .route("/recording/start", post(start_recording))
             .route("/recording/stop", post(stop_recording))
+            .route("/recording/pause", post(pause_recording))
+            .route("/recording/resume", post(resume_recording))
+            .route("/recording/protect", post(protect_recording))
             .route("/stop", post(stop))
+            .route("/schedules", get(list_schedules).post(create_schedule))
+            .route("/schedules/:id", delete(delete_schedule))
+            .route("/events", get(list_events))
+            .route("/metrics", get(metrics))
+            .route("/detections", get(search_detections))
             .with_state(shared_state);
 
         // run our app with hyper, listening globally on port 3000
@@ -0,0 +1,160 @@
+use crate::utils::config::MotionRegion;
+use opencv::core::{self, Mat, Rect, Scalar, CV_8UC1};
+use opencv::prelude::*;
+use opencv::{imgproc, video};
+
+// the number of frames the background model is built from
+const HISTORY: i32 = 100;
+// frames analyzed before motion is reported (the background model is still learning)
+const WARMUP_FRAMES: u32 = 25;
+// the background subtractor marks shadows with 127, everything above is foreground
+const FOREGROUND_THRESHOLD: f64 = 200.0;
+// the share of moving pixels needed for motion at sensitivity 0.0
+const MAX_MOTION_RATIO: f64 = 0.05;
+
+// Detects motion with a background subtractor (MOG2) on grayscale frames
+pub struct MotionDetector {
+    subtractor: core::Ptr<video::BackgroundSubtractorMOG2>,
+    regions: Vec<MotionRegion>,
+    mask: Option<Mat>,
+    threshold: f64,
+    frames: u32,
+}
+
+impl MotionDetector {
+    // sensitivity: 0.0 (only large changes) to 1.0 (any changed pixel)
+    // regions: the regions of the frame that are analyzed (or excluded)
+    pub fn new(sensitivity: f64, regions: Vec<MotionRegion>) -> opencv::Result<MotionDetector> {
+        Ok(MotionDetector {
+            subtractor: video::create_background_subtractor_mog2(HISTORY, 16.0, true)?,
+            regions,
+            mask: None,
+            threshold: (1.0 - sensitivity.clamp(0.0, 1.0)) * MAX_MOTION_RATIO,
+            frames: 0,
+        })
+    }
+
+    // Feeds a frame into the background model
+    // returns: the share of the analyzed pixels in motion (0.0 - 1.0)
+    pub fn score(&mut self, frame: &Mat) -> opencv::Result<f64> {
+        self.update_mask(frame.rows(), frame.cols())?;
+        let mut foreground = Mat::default();
+        self.subtractor.apply(frame, &mut foreground, -1.0)?;
+        self.frames += 1;
+        if self.frames <= WARMUP_FRAMES {
+            return Ok(0.0);
+        }
+        let mut moving = Mat::default();
+        imgproc::threshold(
+            &foreground,
+            &mut moving,
+            FOREGROUND_THRESHOLD,
+            255.0,
+            imgproc::THRESH_BINARY,
+        )?;
+        let mask = self.mask.as_ref().unwrap();
+        let area = core::count_non_zero(mask)?;
+        if area == 0 {
+            return Ok(0.0);
+        }
+        let mut masked = Mat::default();
+        core::bitwise_and(&moving, &moving, &mut masked, mask)?;
+        Ok(core::count_non_zero(&masked)? as f64 / area as f64)
+    }
+
+    pub fn is_motion(&self, score: f64) -> bool {
+        score > self.threshold
+    }
+
+    fn update_mask(&mut self, rows: i32, cols: i32) -> opencv::Result<()> {
+        if let Some(mask) = self.mask.as_ref() {
+            if mask.rows() == rows && mask.cols() == cols {
+                return Ok(());
+            }
+        }
+        self.mask = Some(create_mask(&self.regions, rows, cols)?);
+        Ok(())
+    }
+}
+
+// The analyzed pixels: the include regions (the whole frame without include regions)
+// minus the exclude regions
+pub fn create_mask(regions: &[MotionRegion], rows: i32, cols: i32) -> opencv::Result<Mat> {
+    let includes = regions.iter().any(|r| !r.exclude);
+    let background = if includes { 0.0 } else { 255.0 };
+    let mut mask = Mat::new_rows_cols_with_default(rows, cols, CV_8UC1, Scalar::all(background))?;
+    for region in regions.iter().filter(|r| !r.exclude) {
+        fill(&mut mask, region, 255.0)?;
+    }
+    for region in regions.iter().filter(|r| r.exclude) {
+        fill(&mut mask, region, 0.0)?;
+    }
+    Ok(mask)
+}
+
+fn fill(mask: &mut Mat, region: &MotionRegion, value: f64) -> opencv::Result<()> {
+    let cols = mask.cols() as f64;
+    let rows = mask.rows() as f64;
+    let rect = Rect::new(
+        (region.x * cols).round() as i32,
+        (region.y * rows).round() as i32,
+        (region.width * cols).round() as i32,
+        (region.height * rows).round() as i32,
+    );
+    imgproc::rectangle(
+        mask,
+        rect,
+        Scalar::all(value),
+        imgproc::FILLED,
+        imgproc::LINE_8,
+        0,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(x: f64, width: f64, exclude: bool) -> MotionRegion {
+        MotionRegion {
+            x,
+            y: 0.0,
+            width,
+            height: 1.0,
+            exclude,
+        }
+    }
+
+    #[test]
+    fn test_create_mask() {
+        let mask = create_mask(&[], 10, 10).unwrap();
+        assert_eq!(core::count_non_zero(&mask).unwrap(), 100);
+        let mask = create_mask(&[region(0.0, 0.5, true)], 10, 10).unwrap();
+        assert_eq!(core::count_non_zero(&mask).unwrap(), 50);
+        let regions = [region(0.0, 0.5, false), region(0.0, 0.2, true)];
+        let mask = create_mask(&regions, 10, 10).unwrap();
+        assert_eq!(core::count_non_zero(&mask).unwrap(), 30);
+    }
+
+    #[test]
+    fn test_score() {
+        let mut detector = MotionDetector::new(0.5, vec![]).unwrap();
+        let still = Mat::new_rows_cols_with_default(120, 160, CV_8UC1, Scalar::all(0.0)).unwrap();
+        for _ in 0..WARMUP_FRAMES + 10 {
+            let score = detector.score(&still).unwrap();
+            assert_eq!(detector.is_motion(score), false);
+        }
+        let mut moving = still.clone();
+        imgproc::rectangle(
+            &mut moving,
+            Rect::new(40, 40, 60, 60),
+            Scalar::all(255.0),
+            imgproc::FILLED,
+            imgproc::LINE_8,
+            0,
+        )
+        .unwrap();
+        let score = detector.score(&moving).unwrap();
+        assert_eq!(detector.is_motion(score), true);
+    }
+}
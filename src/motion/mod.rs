@@ -0,0 +1,2 @@
+pub mod detector;
+pub mod trigger;
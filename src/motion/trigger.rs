@@ -0,0 +1,431 @@
+use crate::dtos::messages::{Event, EventKind, MotionInterval, RecordingRequest};
+use crate::motion::detector::MotionDetector;
+use crate::recorder::branch::DynamicBranch;
+use crate::recorder::common::PipelineError;
+use crate::recorder::events::EventLog;
+use crate::recorder::framehandler::FrameFormat;
+use crate::recorder::videocontroller::VideoController;
+use crate::utils::config::MotionRegion;
+use chrono::{DateTime, Duration, Local};
+use gst::prelude::*;
+use gstreamer_app::{gst, AppSink};
+use log::{debug, error, info};
+use opencv::core::Mat;
+use opencv::prelude::*;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+const TICK: std::time::Duration = std::time::Duration::from_millis(200);
+// the analysis pipeline is restarted after this delay (e.g. while the source is stopped)
+const RESTART_SEC: i64 = 5;
+const VIDEO_SOURCE: &str = "video-source";
+const FRAME_SINK: &str = "frame-sink";
+// the name of the recordings started on motion
+const MOTION_RECORDING: &str = "motion";
+
+// The motion seen by the detector since the last motion period ended
+#[derive(Default)]
+struct MotionState {
+    first: Option<DateTime<Local>>,
+    last: Option<DateTime<Local>>,
+    peak_score: f64,
+}
+
+impl MotionState {
+    fn add(&mut self, now: DateTime<Local>, score: f64) {
+        self.first.get_or_insert(now);
+        self.last = Some(now);
+        self.peak_score = self.peak_score.max(score);
+    }
+}
+
+// The frames analyzed for motion: a pipeline of its own or a branch of the source
+enum Analysis {
+    Pipeline(gst::Pipeline),
+    Branch(DynamicBranch),
+}
+
+impl Analysis {
+    fn stop(self) {
+        match self {
+            Analysis::Pipeline(pipeline) => {
+                let _ = pipeline.set_state(gst::State::Null);
+            }
+            Analysis::Branch(branch) => branch.release(),
+        }
+    }
+}
+
+// A running motion period
+struct Episode {
+    started_at: DateTime<Local>,
+    recording_started: bool,
+    prefix: Option<String>,
+}
+
+// Analyzes a low-rate copy of the source and starts a recording on motion.
+// In branch mode the copy is a branch of the source, otherwise a pipeline reading the socket.
+// The recording is stopped once there was no motion for the cooldown, recordings started
+// otherwise (api, schedules) keep running; the motion periods are added to the session.
+pub struct MotionTrigger {
+    pipeline_str: String,
+    branch_str: String,
+    socket_path: String,
+    sensitivity: f64,
+    regions: Vec<MotionRegion>,
+    cooldown: Duration,
+    controller: Arc<Mutex<dyn VideoController>>,
+    events: Arc<Mutex<EventLog>>,
+    analysis: Option<Analysis>,
+    launched_at: Option<DateTime<Local>>,
+    state: Arc<Mutex<MotionState>>,
+    episode: Option<Episode>,
+}
+
+impl MotionTrigger {
+    // Runs the trigger in a background thread
+    pub fn run(trigger: Arc<Mutex<MotionTrigger>>) {
+        thread::spawn(move || loop {
+            trigger.lock().unwrap().tick(Local::now());
+            thread::sleep(TICK);
+        });
+    }
+
+    // Starts and stops recordings according to the detected motion
+    // now: the current time
+    pub fn tick(&mut self, now: DateTime<Local>) {
+        self.watch_pipeline(now);
+        let (first, last, peak_score) = {
+            let state = self.state.lock().unwrap();
+            (state.first, state.last, state.peak_score)
+        };
+        match (self.episode.is_some(), first, last) {
+            (false, Some(first), _) => self.start_episode(first, peak_score),
+            (true, _, Some(last)) if now - last >= self.cooldown => {
+                self.end_episode(last, peak_score)
+            }
+            _ => (),
+        }
+    }
+
+    fn start_episode(&mut self, started_at: DateTime<Local>, score: f64) {
+        info!("Motion detected at {}", started_at);
+        let mut controller = self.controller.lock().unwrap();
+        let (recording_started, prefix) = if controller.is_recording() {
+            (false, None)
+        } else {
+            let request = RecordingRequest {
+                name: Some(MOTION_RECORDING.to_string()),
+                ..Default::default()
+            };
+            match controller.start_recording(request) {
+                Ok(info) => (true, Some(info.prefix)),
+                Err(e) => {
+                    error!("Unable to start motion recording: {:?}", e);
+                    (false, None)
+                }
+            }
+        };
+        drop(controller);
+        self.events.lock().unwrap().push(Event {
+            timestamp: started_at,
+            kind: EventKind::MotionStarted,
+            score: Some(score),
+            prefix: prefix.clone(),
+            source: Some(MOTION_RECORDING.to_string()),
+            detail: None,
+        });
+        self.episode = Some(Episode {
+            started_at,
+            recording_started,
+            prefix,
+        });
+    }
+
+    fn end_episode(&mut self, until: DateTime<Local>, peak_score: f64) {
+        let Some(episode) = self.episode.take() else {
+            return;
+        };
+        *self.state.lock().unwrap() = MotionState::default();
+        info!("Motion ended at {}", until);
+        let controller = self.controller.lock().unwrap();
+        let interval = MotionInterval {
+            from: episode.started_at,
+            until,
+            peak_score,
+        };
+        if let Err(e) = controller.add_motion_interval(interval) {
+            debug!("Motion interval not recorded: {:?}", e);
+        }
+        // the motion recording may have been stopped and another one started meanwhile
+        if episode.recording_started && controller.session_prefix() == episode.prefix {
+            if let Err(e) = controller.stop_recording() {
+                error!("Unable to stop motion recording: {:?}", e);
+            }
+        } else if episode.recording_started {
+            info!("Motion recording {:?} already ended", episode.prefix);
+        }
+        drop(controller);
+        self.events.lock().unwrap().push(Event {
+            timestamp: until,
+            kind: EventKind::MotionEnded,
+            score: Some(peak_score),
+            prefix: episode.prefix,
+            source: Some(MOTION_RECORDING.to_string()),
+            detail: None,
+        });
+    }
+
+    // (Re)starts the analysis if it is not running
+    fn watch_pipeline(&mut self, now: DateTime<Local>) {
+        match self.analysis.as_ref() {
+            Some(Analysis::Pipeline(pipeline)) => {
+                let bus = pipeline.bus().expect("Pipeline without bus");
+                while let Some(msg) = bus.pop() {
+                    if let gst::MessageView::Error(err) = msg.view() {
+                        debug!("Motion pipeline error: {}", err.error());
+                        let _ = pipeline.set_state(gst::State::Null);
+                    }
+                }
+                if pipeline.current_state() == gst::State::Playing {
+                    return;
+                }
+            }
+            Some(Analysis::Branch(branch)) if branch.is_running() => return,
+            _ => (),
+        }
+        if let Some(launched_at) = self.launched_at {
+            if now - launched_at < Duration::seconds(RESTART_SEC) {
+                return;
+            }
+        }
+        self.launched_at = Some(now);
+        if let Some(analysis) = self.analysis.take() {
+            analysis.stop();
+        }
+        let branch = self
+            .controller
+            .lock()
+            .unwrap()
+            .attach_branch(&self.branch_str);
+        let analysis = match branch {
+            Ok(Some(branch)) => self.launch_branch(branch).map(Analysis::Branch),
+            Ok(None) => self.launch_pipeline().map(Analysis::Pipeline),
+            Err(e) => Err(e),
+        };
+        match analysis {
+            Ok(analysis) => self.analysis = Some(analysis),
+            Err(e) => debug!("Motion detection not started: {:?}", e),
+        }
+    }
+
+    fn launch_branch(&self, branch: DynamicBranch) -> Result<DynamicBranch, PipelineError> {
+        let started = self
+            .connect_frame_sink(branch.bin())
+            .and_then(|_| branch.start());
+        if let Err(e) = started {
+            branch.release();
+            return Err(e);
+        }
+        info!("Motion detection started on the source branch");
+        Ok(branch)
+    }
+
+    fn launch_pipeline(&self) -> Result<gst::Pipeline, PipelineError> {
+        let pipeline = gst::parse::launch(self.pipeline_str.as_str())
+            .map_err(|e| {
+                error!("{e}");
+                PipelineError::ParseError
+            })?
+            .downcast::<gst::Pipeline>()
+            .map_err(|_| PipelineError::ParseError)?;
+        if let Some(source) = pipeline.by_name(VIDEO_SOURCE) {
+            if source.has_property("socket-path", None) {
+                source.set_property("socket-path", &self.socket_path);
+            }
+        }
+        self.connect_frame_sink(pipeline.upcast_ref())?;
+        pipeline.set_state(gst::State::Playing).map_err(|e| {
+            debug!("{e}");
+            PipelineError::EncodingError
+        })?;
+        info!("Motion detection started");
+        Ok(pipeline)
+    }
+
+    // Runs the detector on the samples of the frame sink of the bin
+    fn connect_frame_sink(&self, bin: &gst::Bin) -> Result<(), PipelineError> {
+        let frame_sink = bin
+            .by_name(FRAME_SINK)
+            .and_then(|s| s.downcast::<AppSink>().ok())
+            .ok_or(PipelineError::ParseError)?;
+        let mut detector =
+            MotionDetector::new(self.sensitivity, self.regions.clone()).map_err(|e| {
+                error!("{e}");
+                PipelineError::ParseError
+            })?;
+        let state = self.state.clone();
+        frame_sink.set_callbacks(
+            gstreamer_app::AppSinkCallbacks::builder()
+                .new_sample(move |app_sink| {
+                    let sample = app_sink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
+                    match motion_score(&sample, &mut detector) {
+                        Ok(score) if detector.is_motion(score) => {
+                            state.lock().unwrap().add(Local::now(), score)
+                        }
+                        Ok(_) => (),
+                        Err(e) => error!("Motion analysis failed: {e}"),
+                    }
+                    Ok(gst::FlowSuccess::Ok)
+                })
+                .build(),
+        );
+        Ok(())
+    }
+}
+
+// Runs the detector on a grayscale (GRAY8) sample
+fn motion_score(sample: &gst::Sample, detector: &mut MotionDetector) -> Result<f64, String> {
+    let caps = sample.caps().ok_or("Sample without caps")?;
+    let format = FrameFormat::from_caps(caps)?;
+    if format.format != "GRAY8" {
+        return Err(format!("Unsupported frame format: {}", format.format));
+    }
+    let buffer = sample.buffer().ok_or("Sample without buffer")?;
+    // the rows without the padding of the buffer
+    let mut data = format.read(buffer)?;
+    let frame = unsafe {
+        Mat::new_rows_cols_with_data_unsafe_def(
+            format.height,
+            format.width,
+            u8::opencv_type(),
+            data.as_mut_ptr().cast(),
+        )
+    }
+    .map_err(|e| e.to_string())?;
+    detector.score(&frame).map_err(|e| e.to_string())
+}
+
+pub struct MotionTriggerBuilder {
+    pipeline_str: String,
+    branch_str: String,
+    socket_path: String,
+    sensitivity: f64,
+    regions: Vec<MotionRegion>,
+    cooldown_sec: u32,
+}
+
+impl MotionTriggerBuilder {
+    pub fn new() -> MotionTriggerBuilder {
+        gst::init().unwrap();
+        MotionTriggerBuilder {
+            pipeline_str: "unixfdsrc name=video-source \
+                ! queue leaky=2 \
+                ! videoconvert \
+                ! videorate \
+                ! videoscale \
+                ! video/x-raw, format=GRAY8, width=320, height=240, framerate=5/1 \
+                ! appsink name=frame-sink sync=false max-buffers=1 drop=true"
+                .to_string(),
+            branch_str: "queue leaky=2 max-size-buffers=1 \
+                ! videoconvert \
+                ! videorate \
+                ! videoscale \
+                ! video/x-raw, format=GRAY8, width=320, height=240, framerate=5/1 \
+                ! appsink name=frame-sink sync=false max-buffers=1 drop=true"
+                .to_string(),
+            socket_path: "/tmp/video0.sock".to_string(),
+            sensitivity: 0.5,
+            regions: Vec::new(),
+            cooldown_sec: 10,
+        }
+    }
+
+    pub fn with_pipeline_str(mut self, pipeline_str: &str) -> MotionTriggerBuilder {
+        self.pipeline_str = pipeline_str.to_string();
+        self
+    }
+
+    // The analysis as a branch of the source, used instead of the pipeline in branch mode
+    pub fn with_branch_str(mut self, branch_str: &str) -> MotionTriggerBuilder {
+        self.branch_str = branch_str.to_string();
+        self
+    }
+
+    pub fn with_socket_path(mut self, socket_path: &str) -> MotionTriggerBuilder {
+        self.socket_path = socket_path.to_string();
+        self
+    }
+
+    pub fn with_sensitivity(mut self, sensitivity: f64) -> MotionTriggerBuilder {
+        self.sensitivity = sensitivity;
+        self
+    }
+
+    pub fn with_regions(mut self, regions: Vec<MotionRegion>) -> MotionTriggerBuilder {
+        self.regions = regions;
+        self
+    }
+
+    // The time without motion before a motion recording is stopped
+    pub fn with_cooldown_sec(mut self, cooldown_sec: u32) -> MotionTriggerBuilder {
+        self.cooldown_sec = cooldown_sec;
+        self
+    }
+
+    pub fn build(
+        self,
+        controller: Arc<Mutex<dyn VideoController>>,
+        events: Arc<Mutex<EventLog>>,
+    ) -> MotionTrigger {
+        MotionTrigger {
+            pipeline_str: self.pipeline_str,
+            branch_str: self.branch_str,
+            socket_path: self.socket_path,
+            sensitivity: self.sensitivity,
+            regions: self.regions,
+            cooldown: Duration::seconds(self.cooldown_sec as i64),
+            controller,
+            events,
+            analysis: None,
+            launched_at: None,
+            state: Arc::new(Mutex::new(MotionState::default())),
+            episode: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_motion_state() {
+        let now = Local::now();
+        let mut state = MotionState::default();
+        state.add(now, 0.1);
+        state.add(now + Duration::seconds(1), 0.3);
+        state.add(now + Duration::seconds(2), 0.2);
+        assert_eq!(state.first, Some(now));
+        assert_eq!(state.last, Some(now + Duration::seconds(2)));
+        assert_eq!(state.peak_score, 0.3);
+    }
+
+    #[test]
+    fn test_motion_score() {
+        gst::init().unwrap();
+        // the rows of a 6 pixels wide frame are padded to 8 bytes
+        let caps = gst::Caps::builder("video/x-raw")
+            .field("format", "GRAY8")
+            .field("width", 6)
+            .field("height", 4)
+            .build();
+        let mut detector = MotionDetector::new(0.5, Vec::new()).unwrap();
+        let buffer = gst::Buffer::from_mut_slice(vec![0u8; 8 * 4]);
+        let sample = gst::Sample::builder().caps(&caps).buffer(&buffer).build();
+        assert!(motion_score(&sample, &mut detector).is_ok());
+        let short = gst::Buffer::from_mut_slice(vec![0u8; 6 * 4]);
+        let sample = gst::Sample::builder().caps(&caps).buffer(&short).build();
+        assert!(motion_score(&sample, &mut detector).is_err());
+    }
+}
@@ -0,0 +1,168 @@
+use crate::dtos::messages::{Artifact, Event, QualityIssue};
+use chrono::{DateTime, Local};
+use gstreamer_app::gst;
+use opencv::core::Mat;
+
+// A decoded frame of a recording
+pub struct Frame<'a> {
+    // the frame (BGR)
+    pub image: &'a Mat,
+    // the running time of the frame (comparable with the segment start)
+    pub pts: Option<gst::ClockTime>,
+    // the wall-clock time the frame was captured
+    pub timestamp: DateTime<Local>,
+}
+
+// A segment written to the playlist of the recording
+#[derive(Clone, Debug)]
+pub struct Segment {
+    // the index of the segment file
+    pub index: u32,
+    // the running time of the start of the segment
+    pub start: gst::ClockTime,
+    pub duration: gst::ClockTime,
+}
+
+// The recording session the analyzers run in
+#[derive(Clone, Debug)]
+pub struct AnalyzerSession {
+    pub output_dir: String,
+    // the prefix of the session files
+    pub prefix: String,
+    pub started_at: DateTime<Local>,
+}
+
+impl Default for AnalyzerSession {
+    fn default() -> Self {
+        AnalyzerSession {
+            output_dir: ".".to_string(),
+            prefix: String::new(),
+            started_at: Local::now(),
+        }
+    }
+}
+
+// Collects what the analyzers report, the events are published in the event log
+// and the artifacts and quality issues are added to the session manifest
+#[derive(Default)]
+pub struct AnalyzerOutput {
+    events: Vec<Event>,
+    artifacts: Vec<Artifact>,
+    quality_issues: Vec<QualityIssue>,
+}
+
+impl AnalyzerOutput {
+    pub fn emit(&mut self, event: Event) {
+        self.events.push(event);
+    }
+
+    pub fn add_artifact(&mut self, artifact: Artifact) {
+        self.artifacts.push(artifact);
+    }
+
+    pub fn add_quality_issue(&mut self, issue: QualityIssue) {
+        self.quality_issues.push(issue);
+    }
+
+    pub fn take_events(&mut self) -> Vec<Event> {
+        std::mem::take(&mut self.events)
+    }
+
+    pub fn take_artifacts(&mut self) -> Vec<Artifact> {
+        std::mem::take(&mut self.artifacts)
+    }
+
+    pub fn take_quality_issues(&mut self) -> Vec<QualityIssue> {
+        std::mem::take(&mut self.quality_issues)
+    }
+}
+
+// Receives the decoded frames of a recording.
+// Analyzers are registered on the recorder, either for every recording of the source
+// or as a named factory that is selected by the analyzers of a recording request.
+pub trait FrameAnalyzer: Send {
+    // Names the analyzer in the events and artifacts it reports
+    fn name(&self) -> String;
+
+    // A recording session starts
+    fn start(&mut self, _session: &AnalyzerSession) {}
+
+    // Analyzes a frame
+    fn analyze(&mut self, frame: &Frame, output: &mut AnalyzerOutput) -> Result<(), String>;
+
+    // A segment of the recording has been written
+    fn segment_finished(
+        &mut self,
+        _segment: &Segment,
+        _output: &mut AnalyzerOutput,
+    ) -> Result<(), String> {
+        Ok(())
+    }
+
+    // The recording session ends
+    fn finish(&mut self, _output: &mut AnalyzerOutput) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+// Creates an analyzer for a single recording
+pub type AnalyzerFactory = fn() -> Box<dyn FrameAnalyzer>;
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use opencv::core::{Scalar, CV_8UC3};
+    use std::fs::{create_dir_all, remove_dir_all};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static SESSIONS: AtomicUsize = AtomicUsize::new(0);
+
+    // A session in a temporary directory of its own, the directory is removed on drop
+    pub struct TestSession {
+        pub session: AnalyzerSession,
+    }
+
+    impl TestSession {
+        pub fn new(prefix: &str) -> TestSession {
+            let dir = std::env::temp_dir().join(format!(
+                "{}-{}-{}",
+                prefix,
+                std::process::id(),
+                SESSIONS.fetch_add(1, Ordering::SeqCst)
+            ));
+            create_dir_all(&dir).unwrap();
+            TestSession {
+                session: AnalyzerSession {
+                    output_dir: dir.to_string_lossy().to_string(),
+                    prefix: prefix.to_string(),
+                    started_at: Local::now(),
+                },
+            }
+        }
+
+        // The path of a file of the session directory
+        pub fn path(&self, file: &str) -> String {
+            format!("{}/{}", self.session.output_dir, file)
+        }
+    }
+
+    impl Drop for TestSession {
+        fn drop(&mut self) {
+            let _ = remove_dir_all(&self.session.output_dir);
+        }
+    }
+
+    // A BGR image of a single color
+    pub fn solid(width: i32, height: i32, color: Scalar) -> Mat {
+        Mat::new_rows_cols_with_default(height, width, CV_8UC3, color).unwrap()
+    }
+
+    // A frame of the image at the running time in milliseconds
+    pub fn frame_at(image: &Mat, ms: Option<u64>, timestamp: DateTime<Local>) -> Frame<'_> {
+        Frame {
+            image,
+            pts: ms.map(gst::ClockTime::from_mseconds),
+            timestamp,
+        }
+    }
+}
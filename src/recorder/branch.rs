@@ -0,0 +1,189 @@
+use crate::recorder::common::PipelineError;
+use gst::prelude::*;
+use gstreamer_app::gst;
+use log::{debug, error, info};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+// A bin attached to a tee of the running source pipeline.
+// The branch is linked while the source keeps running, so the first buffer of the branch
+// is the next frame of the source. On end the tee pad is unlinked in an idle probe and an
+// EOS is sent into the branch only; the branch is released after all its sinks are EOS.
+pub struct DynamicBranch {
+    pipeline: gst::Pipeline,
+    tee: gst::Element,
+    bin: gst::Bin,
+    tee_pad: Mutex<Option<gst::Pad>>,
+    sinks: usize,
+    eos_count: AtomicUsize,
+    ended: AtomicBool,
+}
+
+impl DynamicBranch {
+    // Parses the branch and adds it (still unlinked) to the pipeline, so it can be configured
+    pub fn attach(
+        pipeline: &gst::Pipeline,
+        tee_name: &str,
+        description: &str,
+    ) -> Result<DynamicBranch, PipelineError> {
+        let tee = pipeline.by_name(tee_name).ok_or_else(|| {
+            error!("Tee {} not found in the source pipeline", tee_name);
+            PipelineError::ParseError
+        })?;
+        let bin = gst::parse::bin_from_description(description, true).map_err(|e| {
+            error!("{e}");
+            PipelineError::ParseError
+        })?;
+        // the EOS of the sinks are forwarded, so the end of the branch can be detected
+        bin.set_property("message-forward", true);
+        let sinks = bin.iterate_sinks().into_iter().flatten().count();
+        pipeline.add(&bin).map_err(|e| {
+            error!("{e}");
+            PipelineError::ParseError
+        })?;
+        Ok(DynamicBranch {
+            pipeline: pipeline.clone(),
+            tee,
+            bin,
+            tee_pad: Mutex::new(None),
+            sinks,
+            eos_count: AtomicUsize::new(0),
+            ended: AtomicBool::new(false),
+        })
+    }
+
+    pub fn bin(&self) -> &gst::Bin {
+        &self.bin
+    }
+
+    // Brings the branch to the state of the source pipeline and links it to the tee
+    pub fn start(&self) -> Result<(), PipelineError> {
+        self.bin.sync_state_with_parent().map_err(|e| {
+            error!("{e}");
+            PipelineError::EncodingError
+        })?;
+        let tee_pad = self
+            .tee
+            .request_pad_simple("src_%u")
+            .ok_or(PipelineError::EncodingError)?;
+        let sink_pad = self
+            .bin
+            .static_pad("sink")
+            .ok_or(PipelineError::ParseError)?;
+        tee_pad.link(&sink_pad).map_err(|e| {
+            error!("{e}");
+            PipelineError::EncodingError
+        })?;
+        info!("Branch {} attached to {}", self.bin.name(), self.tee.name());
+        *self.tee_pad.lock().unwrap() = Some(tee_pad);
+        Ok(())
+    }
+
+    // Unlinks the branch from the tee and ends it with an EOS, the source keeps running
+    pub fn end(&self) -> Result<(), PipelineError> {
+        if self.ended.swap(true, Ordering::SeqCst) {
+            return Err(PipelineError::NotRunning);
+        }
+        let Some(tee_pad) = self.tee_pad.lock().unwrap().clone() else {
+            return Err(PipelineError::NotRunning);
+        };
+        let sink_pad = self
+            .bin
+            .static_pad("sink")
+            .ok_or(PipelineError::ParseError)?;
+        // the idle probe runs between two buffers, so no frame is cut in half
+        tee_pad.add_probe(gst::PadProbeType::IDLE, move |pad, _| {
+            if let Err(e) = pad.unlink(&sink_pad) {
+                error!("{e}");
+            }
+            if !sink_pad.send_event(gst::event::Eos::new()) {
+                error!("Failed to send eos event into branch");
+            }
+            gst::PadProbeReturn::Remove
+        });
+        Ok(())
+    }
+
+    pub fn is_ended(&self) -> bool {
+        self.ended.load(Ordering::SeqCst)
+    }
+
+    // True while the branch is not ended and the source pipeline is playing
+    pub fn is_running(&self) -> bool {
+        !self.is_ended() && self.pipeline.current_state() == gst::State::Playing
+    }
+
+    // True for the message completing the EOS of the branch (the last of its sinks)
+    pub fn is_eos(&self, msg: &gst::Message) -> bool {
+        let gst::MessageView::Element(_) = msg.view() else {
+            return false;
+        };
+        if msg.src() != Some(self.bin.upcast_ref::<gst::Object>()) {
+            return false;
+        }
+        let Some(forwarded) = msg
+            .structure()
+            .filter(|s| s.name() == "GstBinForwarded")
+            .and_then(|s| s.get::<gst::Message>("message").ok())
+        else {
+            return false;
+        };
+        if !matches!(forwarded.view(), gst::MessageView::Eos(..)) {
+            return false;
+        }
+        let count = self.eos_count.fetch_add(1, Ordering::SeqCst) + 1;
+        debug!(
+            "Branch {}: {}/{} sinks eos",
+            self.bin.name(),
+            count,
+            self.sinks
+        );
+        count >= self.sinks
+    }
+
+    // Removes the (ended) branch from the pipeline and releases the tee pad
+    pub fn release(&self) {
+        self.ended.store(true, Ordering::SeqCst);
+        if let Some(tee_pad) = self.tee_pad.lock().unwrap().take() {
+            if let Some(peer) = tee_pad.peer() {
+                let _ = tee_pad.unlink(&peer);
+            }
+            self.tee.release_request_pad(&tee_pad);
+        }
+        if let Err(e) = self.bin.set_state(gst::State::Null) {
+            error!("{e}");
+        }
+        if let Err(e) = self.pipeline.remove(&self.bin) {
+            error!("{e}");
+        }
+        info!("Branch {} released", self.bin.name());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_attach() {
+        gst::init().unwrap();
+        let pipeline = gst::parse::launch(
+            "videotestsrc is-live=true ! tee name=source-tee allow-not-linked=true",
+        )
+        .unwrap()
+        .downcast::<gst::Pipeline>()
+        .unwrap();
+        pipeline.set_state(gst::State::Playing).unwrap();
+        let res = DynamicBranch::attach(&pipeline, "unknown-tee", "queue ! fakesink");
+        assert_eq!(res.err(), Some(PipelineError::ParseError));
+        let branch = DynamicBranch::attach(&pipeline, "source-tee", "queue ! fakesink").unwrap();
+        assert_eq!(branch.sinks, 1);
+        assert_eq!(branch.start().is_ok(), true);
+        assert_eq!(branch.is_running(), true);
+        assert_eq!(branch.end().is_ok(), true);
+        assert_eq!(branch.is_running(), false);
+        assert_eq!(branch.end().err(), Some(PipelineError::NotRunning));
+        branch.release();
+        pipeline.set_state(gst::State::Null).unwrap();
+    }
+}
@@ -0,0 +1,83 @@
+use crate::recorder;
+use crate::utils::config::ClockSource;
+use gst::prelude::*;
+use gstreamer_app::gst;
+use log::{error, info, warn};
+use recorder::common::PipelineError;
+
+// offset between the NTP epoch (1900) and the unix epoch (1970)
+const NTP_UNIX_OFFSET_SEC: u64 = 2_208_988_800;
+const NTP_SYNC_TIMEOUT_SEC: u64 = 5;
+
+// A pipeline clock that runs in wall-clock time.
+// clock: the clock to be used by the recording pipeline
+// epoch_offset: nanoseconds to subtract from a clock time to get the unix time
+#[derive(Clone)]
+pub struct WallClock {
+    pub clock: gst::Clock,
+    pub epoch_offset: u64,
+}
+
+impl WallClock {
+    // Creates the wall clock for the configured clock source
+    // source: system (realtime system clock) or ntp (clock disciplined by an ntp server)
+    // ntp_server: the ntp server to synchronize with (only used for ntp)
+    // ntp_port: the port of the ntp server (only used for ntp)
+    pub fn new(
+        source: &ClockSource,
+        ntp_server: &str,
+        ntp_port: i32,
+    ) -> Result<WallClock, PipelineError> {
+        match source {
+            ClockSource::System => {
+                let clock = gst::glib::Object::builder::<gst::SystemClock>()
+                    .property("clock-type", gst::ClockType::Realtime)
+                    .build();
+                Ok(WallClock {
+                    clock: clock.upcast(),
+                    epoch_offset: 0,
+                })
+            }
+            ClockSource::Ntp => {
+                info!("Synchronizing with ntp server {}:{}", ntp_server, ntp_port);
+                let clock =
+                    gstreamer_net::NtpClock::new(None, ntp_server, ntp_port, gst::ClockTime::ZERO);
+                if let Err(e) =
+                    clock.wait_for_sync(gst::ClockTime::from_seconds(NTP_SYNC_TIMEOUT_SEC))
+                {
+                    error!("Unable to synchronize with ntp server: {e}");
+                    return Err(PipelineError::ClockError);
+                }
+                if !clock.is_synced() {
+                    warn!("Ntp clock not yet synchronized");
+                }
+                Ok(WallClock {
+                    clock: clock.upcast(),
+                    epoch_offset: NTP_UNIX_OFFSET_SEC * 1_000_000_000,
+                })
+            }
+        }
+    }
+
+    // Converts a running time of the pipeline into unix time (nanoseconds)
+    // base_time: the base time of the pipeline
+    // running_time: the running time (e.g. of an hls segment)
+    pub fn to_unix_nanos(&self, base_time: u64, running_time: u64) -> u64 {
+        (base_time + running_time).saturating_sub(self.epoch_offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_system_clock() {
+        let _ = gst::init();
+        let wall_clock = WallClock::new(&ClockSource::System, "", 0).unwrap();
+        let now = chrono::Local::now().timestamp_nanos_opt().unwrap() as u64;
+        let clock_time = wall_clock.clock.time().unwrap().nseconds();
+        let unix = wall_clock.to_unix_nanos(clock_time, 0);
+        assert!(unix.abs_diff(now) < 1_000_000_000);
+    }
+}
@@ -4,4 +4,9 @@ pub enum PipelineError {
     EncodingError,
     NotRunning,
     AlreadyStarted,
+    AlreadyPaused,
+    NotPaused,
+    ClockError,
+    StorageError,
+    StaleFrame,
 }
@@ -0,0 +1,575 @@
+use crate::dtos::messages::{Artifact, Detection, DetectionReport};
+use crate::recorder::analyzer::{AnalyzerOutput, AnalyzerSession, Frame, FrameAnalyzer, Segment};
+use chrono::{DateTime, Duration, Local};
+use log::{debug, error, info};
+use opencv::core::{self, Mat, Rect, Scalar, Size, Vector, CV_32F};
+use opencv::dnn;
+use opencv::prelude::*;
+use serde::Deserialize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread;
+
+const REPORT_SUFFIX: &str = "detections.json";
+// overlapping boxes of a class (intersection over union) are merged
+const NMS_THRESHOLD: f32 = 0.45;
+// the detections returned by a search without limit
+const DEFAULT_LIMIT: usize = 100;
+// the frames waiting for the model before new frames are dropped
+const DETECTION_QUEUE: usize = 2;
+// how long finish waits for the queued frames and the report
+const FINISH_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+#[derive(Clone, Debug)]
+pub struct DetectionConfig {
+    // the onnx model (YOLOv5 or YOLOv8 output layout)
+    pub model: String,
+    // the class names, one per line (e.g. coco.names), without the class ids are used
+    pub labels: String,
+    // the width and height of the model input
+    pub input_size: i32,
+    // the minimal confidence (0.0 - 1.0) of a detection
+    pub confidence: f32,
+    // the frames analyzed per second
+    pub fps: f64,
+}
+
+impl Default for DetectionConfig {
+    fn default() -> Self {
+        DetectionConfig {
+            model: String::new(),
+            labels: String::new(),
+            input_size: 640,
+            confidence: 0.5,
+            fps: 1.0,
+        }
+    }
+}
+
+// A box of the model output in relative coordinates
+#[derive(Clone, Debug)]
+struct Candidate {
+    class_id: i32,
+    confidence: f32,
+    x: f32,
+    y: f32,
+    width: f32,
+    height: f32,
+}
+
+// The work of the detection thread, processed in the order it was queued
+enum Job {
+    Start(AnalyzerSession),
+    Frame(Mat, DateTime<Local>),
+    // writes the report if there are new detections, done receives the result
+    Write(Option<Sender<Result<(), String>>>),
+}
+
+// Runs an object detection model (onnx) with OpenCV DNN on the CPU at a low frame rate.
+// The model runs on a thread of its own with a short queue, so a slow model drops frames of
+// the detection only and never holds up the other analyzers of the recording.
+// The detections of a session are written to {prefix}-detections.json, the file is
+// rewritten with every segment so the running session can be searched as well.
+pub struct DetectionAnalyzer {
+    fps: f64,
+    session: AnalyzerSession,
+    last: Option<DateTime<Local>>,
+    sender: Sender<Job>,
+    // the frames waiting for the model
+    queued: Arc<AtomicUsize>,
+}
+
+impl DetectionAnalyzer {
+    pub fn new(config: DetectionConfig) -> Result<DetectionAnalyzer, String> {
+        let mut net = dnn::read_net_from_onnx(&config.model).map_err(|e| e.to_string())?;
+        net.set_preferable_backend(dnn::DNN_BACKEND_OPENCV)
+            .map_err(|e| e.to_string())?;
+        net.set_preferable_target(dnn::DNN_TARGET_CPU)
+            .map_err(|e| e.to_string())?;
+        let labels = match config.labels.as_str() {
+            "" => Vec::new(),
+            file => std::fs::read_to_string(file)
+                .map_err(|e| format!("{file}: {e}"))?
+                .lines()
+                .map(|label| label.trim().to_string())
+                .collect(),
+        };
+        info!(
+            "Object detection with {}, {} labels",
+            config.model,
+            labels.len()
+        );
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let queued = Arc::new(AtomicUsize::new(0));
+        let fps = config.fps;
+        let detector = Detector {
+            config,
+            net,
+            labels,
+            session: AnalyzerSession::default(),
+            detections: Vec::new(),
+            written: 0,
+            queued: queued.clone(),
+        };
+        thread::spawn(move || detector.run(receiver));
+        Ok(DetectionAnalyzer {
+            fps,
+            session: AnalyzerSession::default(),
+            last: None,
+            sender,
+            queued,
+        })
+    }
+
+    fn file(&self) -> String {
+        format!("{}-{}", self.session.prefix, REPORT_SUFFIX)
+    }
+
+    fn send(&self, job: Job) -> Result<(), String> {
+        self.sender
+            .send(job)
+            .map_err(|_| "Detection thread stopped".to_string())
+    }
+}
+
+impl FrameAnalyzer for DetectionAnalyzer {
+    fn name(&self) -> String {
+        "detection".to_string()
+    }
+
+    fn start(&mut self, session: &AnalyzerSession) {
+        self.session = session.clone();
+        self.last = None;
+        if let Err(e) = self.send(Job::Start(session.clone())) {
+            error!("{e}");
+        }
+    }
+
+    fn analyze(&mut self, frame: &Frame, _output: &mut AnalyzerOutput) -> Result<(), String> {
+        let interval = Duration::milliseconds((1000.0 / self.fps.max(0.001)) as i64);
+        if self
+            .last
+            .is_some_and(|last| frame.timestamp - last < interval)
+        {
+            return Ok(());
+        }
+        self.last = Some(frame.timestamp);
+        if self.queued.load(Ordering::SeqCst) >= DETECTION_QUEUE {
+            debug!("Detection queue full, dropping frame");
+            return Ok(());
+        }
+        let image = frame.image.try_clone().map_err(|e| e.to_string())?;
+        self.queued.fetch_add(1, Ordering::SeqCst);
+        self.send(Job::Frame(image, frame.timestamp))
+    }
+
+    fn segment_finished(
+        &mut self,
+        _segment: &Segment,
+        _output: &mut AnalyzerOutput,
+    ) -> Result<(), String> {
+        self.send(Job::Write(None))
+    }
+
+    fn finish(&mut self, output: &mut AnalyzerOutput) -> Result<(), String> {
+        // the report is complete once the queued frames are detected
+        let (done, written) = mpsc::channel();
+        self.send(Job::Write(Some(done)))?;
+        written
+            .recv_timeout(FINISH_TIMEOUT)
+            .map_err(|_| "Detection did not finish in time".to_string())??;
+        output.add_artifact(Artifact {
+            analyzer: self.name(),
+            kind: "detections".to_string(),
+            file: self.file(),
+            timestamp: Local::now(),
+        });
+        Ok(())
+    }
+}
+
+// Runs the model on the detection thread
+struct Detector {
+    config: DetectionConfig,
+    net: dnn::Net,
+    labels: Vec<String>,
+    session: AnalyzerSession,
+    detections: Vec<Detection>,
+    // the detections in the report file
+    written: usize,
+    queued: Arc<AtomicUsize>,
+}
+
+impl Detector {
+    // Processes the queued jobs until the analyzer is dropped
+    fn run(mut self, receiver: Receiver<Job>) {
+        for job in receiver {
+            match job {
+                Job::Start(session) => {
+                    self.session = session;
+                    self.detections.clear();
+                    self.written = 0;
+                }
+                Job::Frame(image, timestamp) => {
+                    self.queued.fetch_sub(1, Ordering::SeqCst);
+                    if let Err(e) = self.analyze(&image, timestamp) {
+                        error!("Detection failed: {e}");
+                    }
+                }
+                Job::Write(done) => {
+                    // the report of a finished session is written even without detections
+                    let result = if done.is_some() || self.detections.len() != self.written {
+                        self.write()
+                    } else {
+                        Ok(())
+                    };
+                    match done {
+                        Some(done) => {
+                            let _ = done.send(result);
+                        }
+                        None => {
+                            if let Err(e) = result {
+                                error!("Detection report not written: {e}");
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn file(&self) -> String {
+        format!("{}-{}", self.session.prefix, REPORT_SUFFIX)
+    }
+
+    fn label(&self, class_id: i32) -> String {
+        self.labels
+            .get(class_id as usize)
+            .cloned()
+            .unwrap_or_else(|| format!("class_{class_id}"))
+    }
+
+    fn analyze(&mut self, image: &Mat, timestamp: DateTime<Local>) -> opencv::Result<()> {
+        for candidate in self.detect(image)? {
+            self.detections.push(Detection {
+                label: self.label(candidate.class_id),
+                class_id: candidate.class_id,
+                confidence: candidate.confidence,
+                x: candidate.x,
+                y: candidate.y,
+                width: candidate.width,
+                height: candidate.height,
+                timestamp,
+                prefix: self.session.prefix.clone(),
+            });
+        }
+        Ok(())
+    }
+
+    fn detect(&mut self, image: &Mat) -> opencv::Result<Vec<Candidate>> {
+        let size = self.config.input_size;
+        let blob = dnn::blob_from_image(
+            image,
+            1.0 / 255.0,
+            Size::new(size, size),
+            Scalar::default(),
+            true,
+            false,
+            CV_32F,
+        )?;
+        self.net.set_input(&blob, "", 1.0, Scalar::default())?;
+        let output = self.net.forward_single("")?;
+        parse_output(&output, size as f32, self.config.confidence)
+    }
+
+    // Rewrites the report, a reader never sees a partly written file
+    fn write(&mut self) -> Result<(), String> {
+        let report = DetectionReport {
+            prefix: self.session.prefix.clone(),
+            model: self.config.model.clone(),
+            started_at: self.session.started_at,
+            detections: self.detections.clone(),
+        };
+        let json = serde_json::to_string_pretty(&report).map_err(|e| e.to_string())?;
+        let location = format!("{}/{}", self.session.output_dir, self.file());
+        let temp = format!("{location}.tmp");
+        std::fs::write(&temp, json).map_err(|e| e.to_string())?;
+        std::fs::rename(&temp, &location).map_err(|e| e.to_string())?;
+        self.written = self.detections.len();
+        Ok(())
+    }
+}
+
+// Reads the boxes of a YOLO output, [1, 4 + classes, boxes] (YOLOv8) or
+// [1, boxes, 5 + classes] (YOLOv5, with objectness), and merges the overlapping boxes
+fn parse_output(
+    output: &Mat,
+    input_size: f32,
+    min_confidence: f32,
+) -> opencv::Result<Vec<Candidate>> {
+    let shape = output.mat_size();
+    if shape.len() != 3 {
+        return Err(opencv::Error::new(
+            core::StsBadArg,
+            format!("Unexpected model output: {:?}", &*shape),
+        ));
+    }
+    let yolov8 = shape[1] < shape[2];
+    let table = output.reshape(1, shape[1])?;
+    let boxes = if yolov8 {
+        let mut transposed = Mat::default();
+        core::transpose(&*table, &mut transposed)?;
+        transposed
+    } else {
+        table.try_clone()?
+    };
+    let scores_from = if yolov8 { 4 } else { 5 };
+    let mut candidates = Vec::new();
+    let mut rects = Vector::<Rect>::new();
+    let mut scores = Vector::<f32>::new();
+    for i in 0..boxes.rows() {
+        let row = boxes.at_row::<f32>(i)?;
+        let best = row[scores_from..]
+            .iter()
+            .copied()
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+        let Some((class_id, score)) = best else {
+            continue;
+        };
+        let confidence = if yolov8 { score } else { score * row[4] };
+        if confidence < min_confidence {
+            continue;
+        }
+        let (x, y, width, height) = (row[0] - row[2] / 2.0, row[1] - row[3] / 2.0, row[2], row[3]);
+        // the boxes of a class are moved apart from the other classes, so only boxes of the
+        // same class are merged
+        let shift = class_id as f32 * input_size * 2.0;
+        rects.push(Rect::new(
+            (x + shift) as i32,
+            y as i32,
+            width as i32,
+            height as i32,
+        ));
+        scores.push(confidence);
+        candidates.push(Candidate {
+            class_id: class_id as i32,
+            confidence,
+            x: (x / input_size).clamp(0.0, 1.0),
+            y: (y / input_size).clamp(0.0, 1.0),
+            width: (width / input_size).clamp(0.0, 1.0),
+            height: (height / input_size).clamp(0.0, 1.0),
+        });
+    }
+    let mut keep = Vector::<i32>::new();
+    dnn::nms_boxes(
+        &rects,
+        &scores,
+        min_confidence,
+        NMS_THRESHOLD,
+        &mut keep,
+        1.0,
+        0,
+    )?;
+    Ok(keep
+        .iter()
+        .map(|i| candidates[i as usize].clone())
+        .collect())
+}
+
+// Selects the detections of a search, all conditions are optional
+#[derive(Deserialize, Default, Debug)]
+pub struct DetectionQuery {
+    pub label: Option<String>,
+    pub min_confidence: Option<f32>,
+    pub since: Option<DateTime<Local>>,
+    pub until: Option<DateTime<Local>>,
+    // the session
+    pub prefix: Option<String>,
+    pub limit: Option<usize>,
+}
+
+impl DetectionQuery {
+    fn matches(&self, detection: &Detection) -> bool {
+        self.label
+            .as_ref()
+            .map_or(true, |label| label.eq_ignore_ascii_case(&detection.label))
+            && self
+                .min_confidence
+                .map_or(true, |confidence| detection.confidence >= confidence)
+            && self
+                .since
+                .map_or(true, |since| detection.timestamp >= since)
+            && self
+                .until
+                .map_or(true, |until| detection.timestamp <= until)
+    }
+}
+
+// Searches the session reports in the output directory
+// returns: the matching detections, the oldest first
+pub fn search(output_dir: &str, query: &DetectionQuery) -> Result<Vec<Detection>, String> {
+    let mut detections = Vec::new();
+    let entries = std::fs::read_dir(output_dir).map_err(|e| e.to_string())?;
+    for path in entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+    {
+        if !path.to_string_lossy().ends_with(REPORT_SUFFIX) {
+            continue;
+        }
+        let report = std::fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|json| {
+                serde_json::from_str::<DetectionReport>(&json).map_err(|e| e.to_string())
+            });
+        let report = match report {
+            Ok(report) => report,
+            Err(e) => {
+                debug!("Skipping {}: {}", path.display(), e);
+                continue;
+            }
+        };
+        if query
+            .prefix
+            .as_ref()
+            .is_some_and(|prefix| *prefix != report.prefix)
+        {
+            continue;
+        }
+        detections.extend(report.detections.into_iter().filter(|d| query.matches(d)));
+    }
+    detections.sort_by_key(|detection| detection.timestamp);
+    detections.truncate(query.limit.unwrap_or(DEFAULT_LIMIT));
+    Ok(detections)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(shape: &[i32], values: &[(i32, i32, f32)]) -> Mat {
+        let mut output = Mat::new_nd_with_default(shape, CV_32F, Scalar::all(0.0)).unwrap();
+        for (row, col, value) in values {
+            *output.at_3d_mut::<f32>(0, *row, *col).unwrap() = *value;
+        }
+        output
+    }
+
+    #[test]
+    fn test_parse_output() {
+        // YOLOv8: two overlapping boxes of class 1, a box of class 0 at the same place
+        let mut values = Vec::new();
+        for (col, (class, score)) in [(1, 0.9), (1, 0.6), (0, 0.7)].into_iter().enumerate() {
+            let col = col as i32;
+            values.extend([
+                (0, col, 320.0),
+                (1, col, 320.0),
+                (2, col, 64.0),
+                (3, col, 128.0),
+            ]);
+            values.push((4 + class, col, score));
+        }
+        let candidates = parse_output(&output(&[1, 6, 8], &values), 640.0, 0.5).unwrap();
+        assert_eq!(candidates.len(), 2);
+        assert_eq!(candidates[0].class_id, 1);
+        assert_eq!(candidates[0].confidence, 0.9);
+        assert_eq!(candidates[0].x, 0.45);
+        assert_eq!(candidates[0].height, 0.2);
+        assert_eq!(candidates[1].class_id, 0);
+        // YOLOv5: the confidence is the objectness times the class score
+        let values = [
+            (0, 0, 100.0),
+            (0, 1, 100.0),
+            (0, 2, 20.0),
+            (0, 3, 20.0),
+            (0, 4, 0.8),
+            (0, 6, 0.9),
+        ];
+        let candidates = parse_output(&output(&[1, 8, 7], &values), 640.0, 0.5).unwrap();
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].class_id, 1);
+        assert!((candidates[0].confidence - 0.72).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_missing_model() {
+        let config = DetectionConfig {
+            model: "/tmp/missing-model.onnx".to_string(),
+            ..Default::default()
+        };
+        assert!(DetectionAnalyzer::new(config).is_err());
+    }
+
+    #[test]
+    fn test_search() {
+        let dir = "/tmp/detection-search-test";
+        std::fs::create_dir_all(dir).unwrap();
+        let now = Local::now();
+        let detection = |label: &str, confidence: f32, sec: i64, prefix: &str| Detection {
+            label: label.to_string(),
+            class_id: 0,
+            confidence,
+            x: 0.1,
+            y: 0.1,
+            width: 0.2,
+            height: 0.2,
+            timestamp: now + Duration::seconds(sec),
+            prefix: prefix.to_string(),
+        };
+        for (prefix, detections) in [
+            (
+                "a",
+                vec![
+                    detection("person", 0.9, 10, "a"),
+                    detection("car", 0.9, 11, "a"),
+                ],
+            ),
+            (
+                "b",
+                vec![
+                    detection("person", 0.6, 5, "b"),
+                    detection("Person", 0.8, 20, "b"),
+                ],
+            ),
+        ] {
+            let report = DetectionReport {
+                prefix: prefix.to_string(),
+                model: "test.onnx".to_string(),
+                started_at: now,
+                detections,
+            };
+            std::fs::write(
+                format!("{dir}/{prefix}-{REPORT_SUFFIX}"),
+                serde_json::to_string(&report).unwrap(),
+            )
+            .unwrap();
+        }
+        let query = DetectionQuery {
+            label: Some("person".to_string()),
+            min_confidence: Some(0.7),
+            ..Default::default()
+        };
+        let found = search(dir, &query).unwrap();
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].prefix, "a");
+        assert_eq!(found[1].prefix, "b");
+        let query = DetectionQuery {
+            since: Some(now + Duration::seconds(6)),
+            until: Some(now + Duration::seconds(15)),
+            ..Default::default()
+        };
+        assert_eq!(search(dir, &query).unwrap().len(), 2);
+        let query = DetectionQuery {
+            prefix: Some("b".to_string()),
+            limit: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(
+            search(dir, &query).unwrap(),
+            vec![detection("person", 0.6, 5, "b")]
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
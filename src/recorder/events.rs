@@ -0,0 +1,63 @@
+use crate::dtos::messages::Event;
+use chrono::{DateTime, Local};
+use log::info;
+use std::collections::VecDeque;
+
+// the number of events kept in memory
+const MAX_EVENTS: usize = 1000;
+
+// The most recent events (motion, ...) of the recorder
+#[derive(Default)]
+pub struct EventLog {
+    events: VecDeque<Event>,
+}
+
+impl EventLog {
+    pub fn new() -> EventLog {
+        EventLog::default()
+    }
+
+    pub fn push(&mut self, event: Event) {
+        info!("Event: {:?}", event);
+        self.events.push_back(event);
+        while self.events.len() > MAX_EVENTS {
+            self.events.pop_front();
+        }
+    }
+
+    // since: only the events after this time
+    pub fn list(&self, since: Option<DateTime<Local>>) -> Vec<Event> {
+        self.events
+            .iter()
+            .filter(|e| since.map_or(true, |since| e.timestamp > since))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dtos::messages::EventKind;
+
+    #[test]
+    fn test_event_log() {
+        let now = Local::now();
+        let mut log = EventLog::new();
+        for i in 0..MAX_EVENTS + 10 {
+            log.push(Event {
+                timestamp: now + chrono::Duration::seconds(i as i64),
+                kind: EventKind::MotionStarted,
+                score: None,
+                prefix: None,
+                source: None,
+                detail: None,
+            });
+        }
+        assert_eq!(log.list(None).len(), MAX_EVENTS);
+        assert_eq!(
+            log.list(Some(now + chrono::Duration::seconds(1005))).len(),
+            4
+        );
+    }
+}
@@ -0,0 +1,229 @@
+use chrono::{DateTime, Local};
+use std::fs;
+use std::io;
+
+// TIFF field types
+const ASCII: u16 = 2;
+const LONG: u16 = 4;
+// IFD0 tags
+const DOCUMENT_NAME: u16 = 0x010D;
+const IMAGE_DESCRIPTION: u16 = 0x010E;
+const MODEL: u16 = 0x0110;
+const SOFTWARE: u16 = 0x0131;
+const DATE_TIME: u16 = 0x0132;
+const EXIF_IFD: u16 = 0x8769;
+// Exif IFD tags
+const DATE_TIME_ORIGINAL: u16 = 0x9003;
+const OFFSET_TIME_ORIGINAL: u16 = 0x9011;
+
+const EXIF_DATE_FORMAT: &str = "%Y:%m:%d %H:%M:%S";
+// keeps the segment below its 64 KiB limit
+const MAX_NOTE_CHARS: usize = 2000;
+
+// What is embedded in a still
+#[derive(Debug, Clone)]
+pub struct StillMetadata {
+    pub captured_at: DateTime<Local>,
+    // the session prefix if a recording is running
+    pub session: Option<String>,
+    // a note of the user
+    pub note: Option<String>,
+    // the device the still is taken from, the device of the still recorder without
+    pub device: Option<String>,
+}
+
+impl StillMetadata {
+    pub fn new() -> StillMetadata {
+        StillMetadata {
+            captured_at: Local::now(),
+            session: None,
+            note: None,
+            device: None,
+        }
+    }
+}
+
+// (tag, type, count, value)
+type Entry = (u16, u16, u32, Vec<u8>);
+
+fn ascii(tag: u16, value: &str) -> Entry {
+    let mut bytes = value.replace('\0', " ").into_bytes();
+    bytes.push(0);
+    (tag, ASCII, bytes.len() as u32, bytes)
+}
+
+// Writes an IFD (big endian) starting at offset of the TIFF data, values longer than
+// 4 bytes follow the IFD
+fn ifd(entries: &[Entry], offset: usize) -> Vec<u8> {
+    let mut data_offset = offset + 2 + entries.len() * 12 + 4;
+    let mut out = (entries.len() as u16).to_be_bytes().to_vec();
+    let mut data = Vec::new();
+    for (tag, typ, count, value) in entries {
+        out.extend_from_slice(&tag.to_be_bytes());
+        out.extend_from_slice(&typ.to_be_bytes());
+        out.extend_from_slice(&count.to_be_bytes());
+        if value.len() <= 4 {
+            let mut inline = value.clone();
+            inline.resize(4, 0);
+            out.extend_from_slice(&inline);
+        } else {
+            out.extend_from_slice(&(data_offset as u32).to_be_bytes());
+            data.extend_from_slice(value);
+            // values start on a word boundary
+            if value.len() % 2 == 1 {
+                data.push(0);
+            }
+            data_offset += value.len().div_ceil(2) * 2;
+        }
+    }
+    // no next IFD
+    out.extend_from_slice(&0u32.to_be_bytes());
+    out.extend_from_slice(&data);
+    out
+}
+
+// The APP1 (Exif) segment of a still taken by device
+pub fn segment(device: &str, metadata: &StillMetadata) -> Vec<u8> {
+    let captured_at = metadata.captured_at.format(EXIF_DATE_FORMAT).to_string();
+    let mut entries = Vec::new();
+    if let Some(session) = metadata.session.as_ref() {
+        entries.push(ascii(DOCUMENT_NAME, session));
+    }
+    if let Some(note) = metadata.note.as_ref() {
+        let note = note.chars().take(MAX_NOTE_CHARS).collect::<String>();
+        entries.push(ascii(IMAGE_DESCRIPTION, &note));
+    }
+    entries.push(ascii(MODEL, device));
+    entries.push(ascii(SOFTWARE, env!("CARGO_PKG_NAME")));
+    entries.push(ascii(DATE_TIME, &captured_at));
+    // the pointer does not change the size of IFD0, so it is written with the final offset
+    entries.push((EXIF_IFD, LONG, 1, vec![0; 4]));
+    let exif_offset = 8 + ifd(&entries, 8).len();
+    entries.last_mut().unwrap().3 = (exif_offset as u32).to_be_bytes().to_vec();
+    let exif_entries = [
+        ascii(DATE_TIME_ORIGINAL, &captured_at),
+        ascii(
+            OFFSET_TIME_ORIGINAL,
+            &metadata.captured_at.format("%:z").to_string(),
+        ),
+    ];
+
+    let mut tiff = b"MM\0\x2a".to_vec();
+    tiff.extend_from_slice(&8u32.to_be_bytes());
+    tiff.extend(ifd(&entries, 8));
+    tiff.extend(ifd(&exif_entries, exif_offset));
+
+    let mut app1 = vec![0xFF, 0xE1];
+    app1.extend_from_slice(&((tiff.len() + 8) as u16).to_be_bytes());
+    app1.extend_from_slice(b"Exif\0\0");
+    app1.extend(tiff);
+    app1
+}
+
+// Inserts the Exif segment into a JPEG, after the JFIF segment if there is one.
+// returns: false if the data is no JPEG
+pub fn insert(jpeg: &mut Vec<u8>, device: &str, metadata: &StillMetadata) -> bool {
+    if !jpeg.starts_with(&[0xFF, 0xD8]) {
+        return false;
+    }
+    let mut position = 2;
+    if jpeg.len() > 6 && jpeg[2..4] == [0xFF, 0xE0] {
+        position += 2 + u16::from_be_bytes([jpeg[4], jpeg[5]]) as usize;
+    }
+    let position = position.min(jpeg.len());
+    jpeg.splice(position..position, segment(device, metadata));
+    true
+}
+
+// Embeds the Exif segment in a JPEG file (see insert)
+// returns: false if the file is no JPEG
+pub fn embed(file: &str, device: &str, metadata: &StillMetadata) -> io::Result<bool> {
+    let mut jpeg = fs::read(file)?;
+    if !insert(&mut jpeg, device, metadata) {
+        return Ok(false);
+    }
+    let tmp = format!("{file}.tmp");
+    fs::write(&tmp, &jpeg)?;
+    fs::rename(&tmp, file)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opencv::core::{Mat, Scalar, Vector, CV_8UC3};
+    use opencv::imgcodecs;
+    use opencv::prelude::*;
+    use std::fs::remove_file;
+
+    // The ASCII value of a tag in an IFD of the TIFF data
+    fn read_ascii(tiff: &[u8], offset: usize, tag: u16) -> Option<String> {
+        let count = u16::from_be_bytes([tiff[offset], tiff[offset + 1]]) as usize;
+        (0..count).find_map(|i| {
+            let entry = &tiff[offset + 2 + i * 12..offset + 14 + i * 12];
+            if u16::from_be_bytes([entry[0], entry[1]]) != tag {
+                return None;
+            }
+            let len = u32::from_be_bytes(entry[4..8].try_into().unwrap()) as usize;
+            let value = if len <= 4 {
+                &entry[8..8 + len]
+            } else {
+                let start = u32::from_be_bytes(entry[8..12].try_into().unwrap()) as usize;
+                &tiff[start..start + len]
+            };
+            Some(String::from_utf8_lossy(&value[..len - 1]).to_string())
+        })
+    }
+
+    fn read_exif_pointer(tiff: &[u8]) -> usize {
+        let count = u16::from_be_bytes([tiff[8], tiff[9]]) as usize;
+        let entry = (0..count)
+            .map(|i| &tiff[10 + i * 12..22 + i * 12])
+            .find(|entry| u16::from_be_bytes([entry[0], entry[1]]) == EXIF_IFD)
+            .unwrap();
+        u32::from_be_bytes(entry[8..12].try_into().unwrap()) as usize
+    }
+
+    #[test]
+    fn test_embed() {
+        let file = "/tmp/exif-test.jpg";
+        let image = Mat::new_rows_cols_with_default(48, 64, CV_8UC3, Scalar::all(128.0)).unwrap();
+        imgcodecs::imwrite(file, &image, &Vector::new()).unwrap();
+        let metadata = StillMetadata {
+            session: Some("20250101-120000".to_string()),
+            note: Some("front door".to_string()),
+            ..StillMetadata::new()
+        };
+        assert!(embed(file, "video0", &metadata).unwrap());
+
+        let jpeg = fs::read(file).unwrap();
+        let start = jpeg.windows(6).position(|w| w == b"Exif\0\0").unwrap() + 6;
+        let tiff = &jpeg[start..];
+        assert_eq!(&tiff[..4], b"MM\0\x2a");
+        assert_eq!(read_ascii(tiff, 8, MODEL).unwrap(), "video0");
+        assert_eq!(
+            read_ascii(tiff, 8, IMAGE_DESCRIPTION).unwrap(),
+            "front door"
+        );
+        assert_eq!(
+            read_ascii(tiff, 8, DOCUMENT_NAME).unwrap(),
+            "20250101-120000"
+        );
+        let captured_at = metadata.captured_at.format(EXIF_DATE_FORMAT).to_string();
+        assert_eq!(read_ascii(tiff, 8, DATE_TIME).unwrap(), captured_at);
+        let exif_ifd = read_exif_pointer(tiff);
+        assert_eq!(
+            read_ascii(tiff, exif_ifd, DATE_TIME_ORIGINAL).unwrap(),
+            captured_at
+        );
+
+        // the image is still readable
+        let read = imgcodecs::imread(file, imgcodecs::IMREAD_COLOR).unwrap();
+        assert_eq!((read.cols(), read.rows()), (64, 48));
+        remove_file(file).unwrap();
+
+        fs::write(file, b"no jpeg").unwrap();
+        assert!(!embed(file, "video0", &metadata).unwrap());
+        remove_file(file).unwrap();
+    }
+}
@@ -1,226 +1,627 @@
-use crate::dtos;
-use chrono::{DateTime, Duration, Local, NaiveTime};
+use crate::dtos::messages::FrameMetrics;
+use crate::recorder::analyzer::{AnalyzerOutput, AnalyzerSession, Frame, FrameAnalyzer, Segment};
+use crate::recorder::events::EventLog;
+use crate::recorder::session::Session;
+use chrono::{DateTime, Local};
 use gstreamer::BufferRef;
 use gstreamer_app::gst;
-use opencv::boxed_ref::BoxedRef;
-use opencv::core::Vec3b;
+use gstreamer_video::{VideoFrameRef, VideoInfo};
+use log::{debug, error, info};
+use opencv::core::{Mat, CV_8UC1, CV_8UC2, CV_8UC3, CV_8UC4};
+use opencv::imgproc;
 use opencv::prelude::*;
-use opencv::{
-    core::{self, hconcat, Mat, Size, Vector},
-    imgcodecs, imgproc,
-};
-use std::fs::OpenOptions;
-use std::io::Write;
-use std::sync::mpsc::Receiver;
-
-const SPRITE_WIDTH: i32 = 4;
-const SPRITE_HEIGHT: i32 = 54;
-const SPRITE_COUNT: usize = 6;
-const WIDTH: i32 = 720;
-const HEIGHT: i32 = 480;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// how long finish waits for the analyzers to process the queued frames
+const FINISH_TIMEOUT: Duration = Duration::from_secs(10);
+
+// The layout of the raw frames of the frame sink, read from the negotiated caps
+#[derive(Clone, Debug, PartialEq)]
+pub struct FrameFormat {
+    pub width: i32,
+    pub height: i32,
+    // GRAY8, BGR, RGB, BGRx, BGRA, RGBx, RGBA, YUY2, UYVY, NV12 or I420
+    pub format: String,
+    // the default strides and offsets of the planes, a VideoMeta of the buffer overrides them
+    pub info: VideoInfo,
+}
+
+impl FrameFormat {
+    pub fn from_caps(caps: &gst::CapsRef) -> Result<FrameFormat, String> {
+        let info = VideoInfo::from_caps(caps).map_err(|e| e.to_string())?;
+        let format = info.format().to_str().to_string();
+        channels(&format).ok_or(format!("Unsupported frame format: {format}"))?;
+        Ok(FrameFormat {
+            width: info.width() as i32,
+            height: info.height() as i32,
+            format,
+            info,
+        })
+    }
+
+    // The bytes and rows of each plane of the frame data (without padding)
+    fn planes(&self) -> Vec<(usize, usize)> {
+        let format_info = self.info.format_info();
+        (0..format_info.n_planes())
+            .map(|plane| {
+                // the first component stored in the plane
+                let component = (0..format_info.n_components())
+                    .find(|&c| format_info.plane()[c as usize] == plane)
+                    .unwrap_or_default();
+                let width = format_info.scale_width(component as u8, self.info.width());
+                let height = format_info.scale_height(component as u8, self.info.height());
+                let pixel_stride = format_info.pixel_stride()[component as usize];
+                (width as usize * pixel_stride as usize, height as usize)
+            })
+            .collect()
+    }
+
+    // The rows of the frame data, the chroma planes of NV12 and I420 follow the luma rows
+    fn rows(&self) -> i32 {
+        match self.format.as_str() {
+            "NV12" | "I420" => self.height * 3 / 2,
+            _ => self.height,
+        }
+    }
+
+    // The bytes of a row (of the luma plane for NV12 and I420)
+    fn stride(&self) -> usize {
+        self.planes().first().map_or(0, |(bytes, _)| *bytes)
+    }
+
+    // The bytes of a frame
+    pub(crate) fn size(&self) -> usize {
+        self.planes().iter().map(|(bytes, rows)| bytes * rows).sum()
+    }
+
+    // Copies the planes of the buffer without their padding, with the strides and offsets of
+    // its VideoMeta or else of the caps
+    pub(crate) fn read(&self, buffer: &BufferRef) -> Result<Vec<u8>, String> {
+        let frame = VideoFrameRef::from_buffer_ref_readable(buffer, &self.info)
+            .map_err(|e| e.to_string())?;
+        let mut data = Vec::with_capacity(self.size());
+        for (plane, (bytes, rows)) in self.planes().into_iter().enumerate() {
+            let plane_data = frame.plane_data(plane as u32).map_err(|e| e.to_string())?;
+            let stride = frame.plane_stride()[plane] as usize;
+            for row in 0..rows {
+                let offset = row * stride;
+                let row_data = plane_data
+                    .get(offset..offset + bytes)
+                    .ok_or(format!("Unexpected frame size: {} bytes", plane_data.len()))?;
+                data.extend_from_slice(row_data);
+            }
+        }
+        Ok(data)
+    }
+}
+
+// The bytes of a pixel (of the luma plane for NV12 and I420)
+pub(crate) fn channels(format: &str) -> Option<usize> {
+    match format {
+        "GRAY8" | "NV12" | "I420" => Some(1),
+        "YUY2" | "UYVY" => Some(2),
+        "BGR" | "RGB" => Some(3),
+        "BGRx" | "BGRA" | "RGBx" | "RGBA" => Some(4),
+        _ => None,
+    }
+}
+
+// Wraps the frame data (see FrameFormat::read) in a Mat and converts it to BGR
+pub(crate) fn to_bgr(data: &mut [u8], format: &FrameFormat) -> Result<Mat, String> {
+    if data.len() < format.size() {
+        return Err(format!("Unexpected frame size: {} bytes", data.len()));
+    }
+    let (typ, conversion) = match format.format.as_str() {
+        "GRAY8" => (CV_8UC1, Some(imgproc::COLOR_GRAY2BGR)),
+        "BGR" => (CV_8UC3, None),
+        "RGB" => (CV_8UC3, Some(imgproc::COLOR_RGB2BGR)),
+        "BGRx" | "BGRA" => (CV_8UC4, Some(imgproc::COLOR_BGRA2BGR)),
+        "RGBx" | "RGBA" => (CV_8UC4, Some(imgproc::COLOR_RGBA2BGR)),
+        "YUY2" => (CV_8UC2, Some(imgproc::COLOR_YUV2BGR_YUY2)),
+        "UYVY" => (CV_8UC2, Some(imgproc::COLOR_YUV2BGR_UYVY)),
+        "NV12" => (CV_8UC1, Some(imgproc::COLOR_YUV2BGR_NV12)),
+        "I420" => (CV_8UC1, Some(imgproc::COLOR_YUV2BGR_I420)),
+        _ => return Err(format!("Unsupported frame format: {}", format.format)),
+    };
+    let mat = unsafe {
+        Mat::new_rows_cols_with_data_unsafe(
+            format.rows(),
+            format.width,
+            typ,
+            data.as_mut_ptr().cast(),
+            format.stride(),
+        )
+    }
+    .map_err(|e| e.to_string())?;
+    match conversion {
+        Some(code) => {
+            let mut bgr = Mat::default();
+            imgproc::cvt_color_def(&mat, &mut bgr, code).map_err(|e| e.to_string())?;
+            Ok(bgr)
+        }
+        // the data is copied, so the frame outlives the buffer
+        None => mat.try_clone().map_err(|e| e.to_string()),
+    }
+}
+
+// Hands the decoded frames of a recording to the registered analyzers.
+// The analyzers run on a worker thread, the streaming thread only copies the frame into a
+// bounded queue. If the queue is full the new frame is dropped (and counted in the metrics),
+// segment and session events are never dropped.
 pub trait FrameHandler {
-    fn handle_frame(&mut self, frame: &BufferRef) -> Result<(), gst::FlowError>;
-    fn collect_frames(&mut self) -> Result<(), gst::FlowError>;
-    fn reset(&mut self);
-    fn set_start_timestamp(&mut self, timestamp: DateTime<Local>);
+    // Starts a recording session
+    // analyzers: the analyzers of this recording only (in addition to the ones of the source)
+    fn start(&self, session: AnalyzerSession, analyzers: Vec<Box<dyn FrameAnalyzer>>);
+    // Queues a frame for the analyzers
+    // format: the layout of the frame (see FrameFormat::from_caps)
+    // timestamp: the wall-clock time the frame was captured
+    fn handle_frame(
+        &self,
+        frame: &BufferRef,
+        format: &FrameFormat,
+        timestamp: DateTime<Local>,
+    ) -> Result<(), gst::FlowError>;
+    // A segment has been added to the playlist
+    fn segment_finished(&self, segment: Segment) -> Result<(), gst::FlowError>;
+    // Ends the recording session once the queued frames are analyzed
+    fn finish(&self);
+    // The state of the frame queue
+    fn metrics(&self) -> FrameMetrics;
+}
+
+// The work of the analyzer thread, processed in the order it was queued
+enum Job {
+    Start(AnalyzerSession, Vec<Box<dyn FrameAnalyzer>>),
+    Frame {
+        data: Vec<u8>,
+        format: FrameFormat,
+        pts: Option<gst::ClockTime>,
+        timestamp: DateTime<Local>,
+    },
+    SegmentFinished(Segment),
+    Finish(Sender<()>),
+}
+
+// Shared between the streaming thread and the analyzer thread
+#[derive(Default)]
+struct QueueStats {
+    queued: AtomicUsize,
+    processed: AtomicU64,
+    dropped: AtomicU64,
 }
 
 pub struct FrameHandlerImpl {
-    pub frames: Vec<Mat>,
-    pub idx: usize,
-    pub file: Option<std::fs::File>,
-    pub output_path: String,
-    pub receiver: Receiver<String>,
-    start_timestamp: DateTime<Local>,
+    sender: Mutex<Sender<Job>>,
+    capacity: usize,
+    stats: Arc<QueueStats>,
+    running: AtomicBool,
 }
 
 impl FrameHandlerImpl {
-    pub fn new(output_path: String, receiver: Receiver<String>) -> FrameHandlerImpl {
+    // capacity: the number of frames waiting for the analyzers before new frames are dropped
+    // analyzers: the analyzers running on every recording of the source
+    // recording_session: receives the artifacts of the analyzers
+    // events: receives the events of the analyzers
+    pub fn new(
+        capacity: usize,
+        analyzers: Vec<Box<dyn FrameAnalyzer>>,
+        recording_session: Arc<Mutex<Session>>,
+        events: Option<Arc<Mutex<EventLog>>>,
+    ) -> FrameHandlerImpl {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let stats = Arc::new(QueueStats::default());
+        let worker = Worker {
+            analyzers,
+            recording_analyzers: Vec::new(),
+            session: AnalyzerSession::default(),
+            output: AnalyzerOutput::default(),
+            recording_session,
+            events,
+            stats: stats.clone(),
+        };
+        thread::spawn(move || worker.run(receiver));
         FrameHandlerImpl {
-            frames: Vec::new(),
-            idx: 0,
-            output_path: output_path.clone(),
-            file: None,
-            start_timestamp: Local::now(),
-            receiver,
+            sender: Mutex::new(sender),
+            capacity,
+            stats,
+            running: AtomicBool::new(false),
         }
     }
+
+    fn send(&self, job: Job) -> Result<(), gst::FlowError> {
+        self.sender.lock().unwrap().send(job).map_err(|_| {
+            error!("Frame analyzer thread stopped");
+            gst::FlowError::Error
+        })
+    }
 }
 
 impl FrameHandler for FrameHandlerImpl {
-    fn set_start_timestamp(&mut self, timestamp: DateTime<Local>) {
-        self.start_timestamp = timestamp;
-    }
-
-    fn handle_frame(&mut self, frame: &BufferRef) -> Result<(), gst::FlowError> {
-        let asw = self
-            .receiver
-            .recv_timeout(std::time::Duration::from_millis(50));
-        if asw.is_ok() {
-            println!("Received collect signal");
-            self.collect_frames()?;
-        }
-        let map = frame.map_readable().map_err(|_| gst::FlowError::Error)?;
-        let mut rgb = Vec::<u8>::new();
-        map.clone_into(rgb.as_mut());
-        let mat = unsafe {
-            Mat::new_rows_cols_with_data_unsafe_def(
-                HEIGHT,
-                WIDTH,
-                Vec3b::opencv_type(),
-                rgb.as_mut_ptr().cast(),
-            )
-        }
-        .unwrap();
-        self.frames.push(mat.clone());
+    fn start(&self, session: AnalyzerSession, analyzers: Vec<Box<dyn FrameAnalyzer>>) {
+        self.running.store(true, Ordering::SeqCst);
+        let _ = self.send(Job::Start(session, analyzers));
+    }
 
-        Ok(())
+    fn handle_frame(
+        &self,
+        frame: &BufferRef,
+        format: &FrameFormat,
+        timestamp: DateTime<Local>,
+    ) -> Result<(), gst::FlowError> {
+        if self.stats.queued.load(Ordering::SeqCst) >= self.capacity {
+            self.stats.dropped.fetch_add(1, Ordering::SeqCst);
+            debug!("Frame queue full, dropping frame");
+            return Ok(());
+        }
+        let data = format.read(frame).map_err(|e| {
+            error!("{e}");
+            gst::FlowError::Error
+        })?;
+        self.stats.queued.fetch_add(1, Ordering::SeqCst);
+        self.send(Job::Frame {
+            data,
+            format: format.clone(),
+            pts: frame.pts(),
+            timestamp,
+        })
     }
 
-    fn collect_frames(&mut self) -> Result<(), gst::FlowError> {
-        if self.frames.is_empty() {
+    fn segment_finished(&self, segment: Segment) -> Result<(), gst::FlowError> {
+        // the analyzers finished the session with the frames received so far
+        if !self.running.load(Ordering::SeqCst) {
             return Ok(());
         }
-        let sprite = concat_sprites(create_sprites(&self.frames).as_ref());
-        let timestamp = self
-            .start_timestamp
-            .format(dtos::messages::TIMESTAMP_FORMAT)
-            .to_string();
-        imgcodecs::imwrite(
-            format!(
-                "{}/{}-sprite_{:05}.jpg",
-                self.output_path, timestamp, self.idx
-            )
-            .as_str(),
-            &sprite,
-            &Vector::new(),
-        )
-        .unwrap();
-        let tooltips = concat_sprites(&self.frames);
-        imgcodecs::imwrite(
-            format!(
-                "{}/{}-tooltips_{:05}.jpg",
-                self.output_path, timestamp, self.idx
-            )
-            .as_str(),
-            &tooltips,
-            &Vector::new(),
-        )
-        .unwrap();
-        self.frames.clear();
-
-        // vtt file creation
-        if self.idx == 0 {
-            self.file = Some(
-                OpenOptions::new()
-                    .create(true)
-                    .write(true)
-                    .open(format!("{}/{}-thumbnails.vtt", self.output_path, timestamp))
-                    .unwrap(),
-            );
-            writeln!(self.file.as_mut().unwrap(), "WEBVTT").unwrap();
-            writeln!(self.file.as_mut().unwrap(), "").unwrap();
-        }
-        for i in 1..=SPRITE_COUNT {
-            let vtt_file = self.file.as_mut().unwrap();
-            writeln!(vtt_file, "{}", i + self.idx * 6).unwrap();
-            let from_sec = (self.idx + i - 1) + self.idx * 6;
-            let to_sec = (self.idx + i) + self.idx * 6;
-            writeln!(
-                vtt_file,
-                "{} --> {}",
-                format_seconds(from_sec),
-                format_seconds(to_sec)
-            )
-            .unwrap();
-            let x = (i - 1) * WIDTH as usize;
-            let y = 0;
-            let w = WIDTH as usize;
-            let h = HEIGHT as usize;
-            writeln!(
-                vtt_file,
-                "{}-tooltips_{:05}.jpg#xywh={},{},{},{}",
-                timestamp, self.idx, x, y, w, h
-            )
-            .unwrap();
-            writeln!(vtt_file, "").unwrap();
+        self.send(Job::SegmentFinished(segment))
+    }
+
+    fn finish(&self) {
+        if !self.running.swap(false, Ordering::SeqCst) {
+            return;
+        }
+        let (done, finished) = mpsc::channel();
+        if self.send(Job::Finish(done)).is_ok() && finished.recv_timeout(FINISH_TIMEOUT).is_err() {
+            error!("Frame analyzers did not finish in time");
         }
-        self.idx += 1;
-        Ok(())
     }
 
-    fn reset(&mut self) {
-        self.frames.clear();
-        self.idx = 0;
+    fn metrics(&self) -> FrameMetrics {
+        FrameMetrics {
+            queue_capacity: self.capacity,
+            queue_depth: self.stats.queued.load(Ordering::SeqCst),
+            frames_processed: self.stats.processed.load(Ordering::SeqCst),
+            frames_dropped: self.stats.dropped.load(Ordering::SeqCst),
+        }
     }
 }
-fn format_seconds(seconds: usize) -> String {
-    let duration = Duration::seconds(seconds as i64);
-    let time =
-        NaiveTime::from_num_seconds_from_midnight_opt(duration.num_seconds() as u32 % 86400, 0)
-            .unwrap();
-    format!("{}", time.format("%H:%M:%S%.3f"))
-}
-fn create_sprite(input: &Mat) -> Mat {
-    let new_height = SPRITE_HEIGHT;
-    let scale_factor = new_height as f64 / input.rows() as f64;
-    let new_width = (input.cols() as f64 * scale_factor).round() as i32;
-    let mut img = Mat::default();
-    imgproc::resize(
-        &input,
-        &mut img,
-        Size::new(new_width, new_height),
-        0.0,
-        0.0,
-        imgproc::INTER_LINEAR,
-    )
-    .unwrap();
-    let center = img.cols() / 2;
-    let roi = img
-        .roi(core::Rect::new(center - 2, 0, SPRITE_WIDTH, img.rows()))
-        .unwrap();
-    roi.clone_pointee()
+
+// Runs the analyzers on the analyzer thread
+struct Worker {
+    analyzers: Vec<Box<dyn FrameAnalyzer>>,
+    recording_analyzers: Vec<Box<dyn FrameAnalyzer>>,
+    session: AnalyzerSession,
+    output: AnalyzerOutput,
+    recording_session: Arc<Mutex<Session>>,
+    events: Option<Arc<Mutex<EventLog>>>,
+    stats: Arc<QueueStats>,
 }
 
-fn create_sprites(input: &Vec<Mat>) -> Vec<Mat> {
-    let mut mat_vec: Vec<Mat> = Vec::new();
-    for frame in input.iter() {
-        let sprite = create_sprite(frame);
-        mat_vec.push(sprite);
+impl Worker {
+    // Processes the queued jobs until the frame handler is dropped
+    fn run(mut self, receiver: Receiver<Job>) {
+        for job in receiver {
+            match job {
+                Job::Start(session, analyzers) => self.start(session, analyzers),
+                Job::Frame {
+                    mut data,
+                    format,
+                    pts,
+                    timestamp,
+                } => {
+                    self.stats.queued.fetch_sub(1, Ordering::SeqCst);
+                    match to_bgr(&mut data, &format) {
+                        Ok(image) => self.analyze(&image, pts, timestamp),
+                        Err(e) => error!("{e}"),
+                    }
+                    self.stats.processed.fetch_add(1, Ordering::SeqCst);
+                }
+                Job::SegmentFinished(segment) => self.segment_finished(&segment),
+                Job::Finish(done) => {
+                    self.finish();
+                    let _ = done.send(());
+                }
+            }
+        }
+    }
+
+    fn start(&mut self, session: AnalyzerSession, analyzers: Vec<Box<dyn FrameAnalyzer>>) {
+        self.recording_analyzers = analyzers;
+        self.call(|analyzer, _| {
+            analyzer.start(&session);
+            Ok(())
+        });
+        self.session = session;
+    }
+
+    fn analyze(&mut self, image: &Mat, pts: Option<gst::ClockTime>, timestamp: DateTime<Local>) {
+        let frame = Frame {
+            image,
+            pts,
+            timestamp,
+        };
+        self.call(|analyzer, output| analyzer.analyze(&frame, output));
+        self.publish();
+    }
+
+    fn segment_finished(&mut self, segment: &Segment) {
+        self.call(|analyzer, output| analyzer.segment_finished(segment, output));
+        self.publish();
+    }
+
+    fn finish(&mut self) {
+        self.call(|analyzer, output| analyzer.finish(output));
+        self.publish();
+        self.recording_analyzers.clear();
+    }
+
+    // Calls every analyzer, an analyzer that panics is dropped and the others keep running
+    fn call(
+        &mut self,
+        mut f: impl FnMut(&mut Box<dyn FrameAnalyzer>, &mut AnalyzerOutput) -> Result<(), String>,
+    ) {
+        let output = &mut self.output;
+        for analyzers in [&mut self.analyzers, &mut self.recording_analyzers] {
+            analyzers.retain_mut(|analyzer| {
+                match panic::catch_unwind(AssertUnwindSafe(|| f(analyzer, output))) {
+                    Ok(Ok(())) => true,
+                    Ok(Err(e)) => {
+                        error!("Analyzer {} failed: {e}", analyzer.name());
+                        true
+                    }
+                    Err(_) => {
+                        error!("Analyzer {} panicked and is dropped", analyzer.name());
+                        false
+                    }
+                }
+            });
+        }
+    }
+
+    // Moves the reported events into the event log, the artifacts and issues into the session
+    fn publish(&mut self) {
+        for mut event in self.output.take_events() {
+            event
+                .prefix
+                .get_or_insert_with(|| self.session.prefix.clone());
+            match self.events.as_ref() {
+                Some(events) => events.lock().unwrap().push(event),
+                None => info!("Event: {:?}", event),
+            }
+        }
+        let artifacts = self.output.take_artifacts();
+        let issues = self.output.take_quality_issues();
+        if !artifacts.is_empty() || !issues.is_empty() {
+            let mut session = self.recording_session.lock().unwrap();
+            for artifact in artifacts {
+                session.add_artifact(artifact);
+            }
+            for issue in issues {
+                session.add_quality_issue(issue);
+            }
+        }
     }
-    mat_vec
-}
-macro_rules! bref_from_mat {
-    ($val:expr) => {
-        $val.roi(core::Rect::new(0, 0, $val.cols(), $val.rows()))
-    };
-}
-fn concat_sprites(input: &Vec<Mat>) -> Mat {
-    let mut roi_vec = Vector::<BoxedRef<Mat>>::new();
-    for mat in input.iter() {
-        let ref_mat = bref_from_mat!(mat).unwrap();
-        roi_vec.push(ref_mat);
-    }
-    let diff = SPRITE_COUNT.checked_sub(input.len()).unwrap_or(0);
-    for _ in 0..diff {
-        let ref_mat = bref_from_mat!(input.last().unwrap()).unwrap();
-        roi_vec.push(ref_mat);
-    }
-    let mut result = core::Mat::default();
-    hconcat(&roi_vec, &mut result).unwrap();
-    result
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use opencv::core::CV_8UC3;
+    use crate::dtos::messages::{Artifact, RecordingRequest};
+    use gstreamer_video::{VideoFormat, VideoFrameFlags, VideoMeta};
+    use opencv::core::Vector;
+    use opencv::imgcodecs;
     use std::fs::remove_file;
 
+    struct CountingAnalyzer {
+        frames: usize,
+    }
+
+    impl FrameAnalyzer for CountingAnalyzer {
+        fn name(&self) -> String {
+            "counting".to_string()
+        }
+
+        fn analyze(&mut self, _frame: &Frame, _output: &mut AnalyzerOutput) -> Result<(), String> {
+            self.frames += 1;
+            Ok(())
+        }
+
+        fn finish(&mut self, output: &mut AnalyzerOutput) -> Result<(), String> {
+            output.add_artifact(Artifact {
+                analyzer: self.name(),
+                kind: "count".to_string(),
+                file: self.frames.to_string(),
+                timestamp: Local::now(),
+            });
+            Ok(())
+        }
+    }
+
+    struct PanickingAnalyzer;
+
+    impl FrameAnalyzer for PanickingAnalyzer {
+        fn name(&self) -> String {
+            "panicking".to_string()
+        }
+
+        fn analyze(&mut self, _frame: &Frame, _output: &mut AnalyzerOutput) -> Result<(), String> {
+            panic!("broken analyzer");
+        }
+    }
+
     #[test]
     fn test_opencv() {
         let img3: Mat = unsafe { Mat::new_nd(&[480, 640], CV_8UC3).unwrap() };
         imgcodecs::imwrite("sprite.png", &img3, &Vector::new()).unwrap();
         let _ = remove_file("sprite.png");
     }
+
+    fn bgr_format(width: i32, height: i32) -> FrameFormat {
+        let caps = gst::Caps::builder("video/x-raw")
+            .field("format", "BGR")
+            .field("width", width)
+            .field("height", height)
+            .build();
+        FrameFormat::from_caps(&caps).unwrap()
+    }
+
+    #[test]
+    fn test_frame_format() {
+        gst::init().unwrap();
+        let format = bgr_format(718, 480);
+        assert_eq!(format.info.stride()[0], 2156);
+        assert_eq!(format.size(), 718 * 3 * 480);
+        let buffer = gst::Buffer::from_mut_slice(vec![0u8; format.info.size()]);
+        let mut data = format.read(&buffer).unwrap();
+        assert_eq!(data.len(), format.size());
+        let image = to_bgr(&mut data, &format).unwrap();
+        assert_eq!((image.cols(), image.rows()), (718, 480));
+        assert!(to_bgr(&mut data[..100], &format).is_err());
+        let short = gst::Buffer::from_mut_slice(vec![0u8; 100]);
+        assert!(format.read(&short).is_err());
+        for (name, width, size) in [
+            ("YUY2", 640, 640 * 480 * 2),
+            ("NV12", 640, 640 * 480 * 3 / 2),
+            ("I420", 636, 636 * 480 * 3 / 2),
+        ] {
+            let caps = gst::Caps::builder("video/x-raw")
+                .field("format", name)
+                .field("width", width)
+                .field("height", 480)
+                .build();
+            let format = FrameFormat::from_caps(&caps).unwrap();
+            assert_eq!(format.size(), size);
+            let buffer = gst::Buffer::from_mut_slice(vec![128u8; format.info.size()]);
+            let mut data = format.read(&buffer).unwrap();
+            let image = to_bgr(&mut data, &format).unwrap();
+            assert_eq!((image.cols(), image.rows()), (width, 480));
+        }
+    }
+
+    #[test]
+    fn test_video_meta() {
+        gst::init().unwrap();
+        let format = bgr_format(4, 2);
+        // rows padded to 16 bytes, the second row marked with 1
+        let mut padded = vec![0u8; 32];
+        padded[16..28].fill(1);
+        let mut buffer = gst::Buffer::from_mut_slice(padded);
+        VideoMeta::add_full(
+            buffer.get_mut().unwrap(),
+            VideoFrameFlags::empty(),
+            VideoFormat::Bgr,
+            4,
+            2,
+            &[0],
+            &[16],
+        )
+        .unwrap();
+        let data = format.read(&buffer).unwrap();
+        assert_eq!(data.len(), 24);
+        assert_eq!(data[..12], [0u8; 12]);
+        assert_eq!(data[12..], [1u8; 12]);
+    }
+
+    #[test]
+    fn test_analyzers() {
+        gst::init().unwrap();
+        let session = Arc::new(Mutex::new(Session::new()));
+        session.lock().unwrap().start(
+            "/tmp",
+            "framehandler-test",
+            &Local::now(),
+            RecordingRequest::default(),
+            false,
+        );
+        let handler = FrameHandlerImpl::new(
+            8,
+            vec![Box::new(CountingAnalyzer { frames: 0 })],
+            session.clone(),
+            None,
+        );
+        handler.start(
+            AnalyzerSession::default(),
+            vec![Box::new(CountingAnalyzer { frames: 0 })],
+        );
+        let format = bgr_format(64, 48);
+        let buffer = gst::Buffer::from_mut_slice(vec![0u8; format.info.size()]);
+        handler
+            .handle_frame(&buffer, &format, Local::now())
+            .unwrap();
+        handler
+            .handle_frame(&buffer, &format, Local::now())
+            .unwrap();
+        handler.finish();
+        handler.finish();
+        let manifest = session.lock().unwrap().manifest().clone();
+        assert_eq!(manifest.artifacts.len(), 2);
+        assert_eq!(manifest.artifacts[0].file, "2");
+        let metrics = handler.metrics();
+        assert_eq!(metrics.queue_depth, 0);
+        assert_eq!(metrics.frames_processed, 2);
+        remove_file("/tmp/framehandler-test-session.json").unwrap();
+    }
+
+    #[test]
+    fn test_panicking_analyzer() {
+        gst::init().unwrap();
+        let session = Arc::new(Mutex::new(Session::new()));
+        session.lock().unwrap().start(
+            "/tmp",
+            "framehandler-panic-test",
+            &Local::now(),
+            RecordingRequest::default(),
+            false,
+        );
+        let handler = FrameHandlerImpl::new(8, Vec::new(), session.clone(), None);
+        let format = bgr_format(64, 48);
+        let buffer = gst::Buffer::from_mut_slice(vec![0u8; format.info.size()]);
+        // the worker survives the broken analyzer of the first recording
+        for analyzer in [
+            Box::new(PanickingAnalyzer) as Box<dyn FrameAnalyzer>,
+            Box::new(CountingAnalyzer { frames: 0 }),
+        ] {
+            handler.start(AnalyzerSession::default(), vec![analyzer]);
+            handler
+                .handle_frame(&buffer, &format, Local::now())
+                .unwrap();
+            handler.finish();
+        }
+        let manifest = session.lock().unwrap().manifest().clone();
+        assert_eq!(manifest.artifacts.len(), 1);
+        assert_eq!(manifest.artifacts[0].file, "1");
+        assert_eq!(handler.metrics().frames_processed, 2);
+        remove_file("/tmp/framehandler-panic-test-session.json").unwrap();
+    }
+
+    #[test]
+    fn test_full_queue() {
+        gst::init().unwrap();
+        let handler =
+            FrameHandlerImpl::new(0, Vec::new(), Arc::new(Mutex::new(Session::new())), None);
+        let format = bgr_format(64, 48);
+        let buffer = gst::Buffer::from_mut_slice(vec![0u8; format.info.size()]);
+        handler
+            .handle_frame(&buffer, &format, Local::now())
+            .unwrap();
+        handler
+            .handle_frame(&buffer, &format, Local::now())
+            .unwrap();
+        let metrics = handler.metrics();
+        assert_eq!(metrics.frames_dropped, 2);
+        assert_eq!(metrics.frames_processed, 0);
+    }
 }
@@ -0,0 +1,193 @@
+use crate::dtos::messages::Artifact;
+use crate::recorder::analyzer::{AnalyzerOutput, AnalyzerSession, Frame, FrameAnalyzer};
+use chrono::Local;
+use opencv::core::{self, Mat, Scalar, Size, Vector, CV_32FC1, CV_8U};
+use opencv::prelude::*;
+use opencv::{imgcodecs, imgproc};
+
+// the motion is accumulated at this width, the height keeps the aspect ratio
+const ANALYSIS_WIDTH: i32 = 320;
+// pixels changing more than this (0 - 255) between two frames are moving
+const DIFF_THRESHOLD: f64 = 25.0;
+// the opacity of the heat colors on the frame
+const OPACITY: f64 = 0.6;
+
+// Accumulates the moving pixels of a session and writes {prefix}-heatmap.jpg when the
+// session ends: the motion (blue: rare, red: frequent) on the quietest frame of the session
+pub struct HeatmapAnalyzer {
+    session: AnalyzerSession,
+    // the previous frame (small, gray and blurred)
+    previous: Option<Mat>,
+    // the number of frames each pixel moved in
+    heat: Option<Mat>,
+    // the frame with the least motion, the heat is drawn on it
+    background: Option<Mat>,
+    quietest: f64,
+}
+
+impl HeatmapAnalyzer {
+    pub fn new() -> HeatmapAnalyzer {
+        HeatmapAnalyzer {
+            session: AnalyzerSession::default(),
+            previous: None,
+            heat: None,
+            background: None,
+            quietest: f64::MAX,
+        }
+    }
+
+    fn file(&self) -> String {
+        format!("{}-heatmap.jpg", self.session.prefix)
+    }
+
+    fn accumulate(&mut self, image: &Mat) -> opencv::Result<()> {
+        let height = (image.rows() * ANALYSIS_WIDTH / image.cols().max(1)).max(1);
+        let mut small = Mat::default();
+        let size = Size::new(ANALYSIS_WIDTH, height);
+        imgproc::resize(image, &mut small, size, 0.0, 0.0, imgproc::INTER_AREA)?;
+        let mut gray = Mat::default();
+        imgproc::cvt_color_def(&small, &mut gray, imgproc::COLOR_BGR2GRAY)?;
+        let mut blurred = Mat::default();
+        imgproc::gaussian_blur_def(&gray, &mut blurred, Size::new(5, 5), 0.0)?;
+        let previous = self.previous.replace(blurred);
+        let current = self.previous.as_ref().unwrap();
+        let Some(previous) = previous.filter(|p| p.size().ok() == current.size().ok()) else {
+            if self.background.is_none() {
+                self.background = Some(image.try_clone()?);
+            }
+            return Ok(());
+        };
+        let mut diff = Mat::default();
+        core::absdiff(&previous, current, &mut diff)?;
+        let mut moving = Mat::default();
+        imgproc::threshold(
+            &diff,
+            &mut moving,
+            DIFF_THRESHOLD,
+            1.0,
+            imgproc::THRESH_BINARY,
+        )?;
+        let mut heat = match self.heat.take() {
+            Some(heat) if heat.size()? == moving.size()? => heat,
+            _ => Mat::new_rows_cols_with_default(
+                moving.rows(),
+                moving.cols(),
+                CV_32FC1,
+                Scalar::all(0.0),
+            )?,
+        };
+        imgproc::accumulate(&moving, &mut heat, &core::no_array())?;
+        self.heat = Some(heat);
+        let score = core::count_non_zero(&moving)? as f64 / moving.total() as f64;
+        if score < self.quietest {
+            self.quietest = score;
+            self.background = Some(image.try_clone()?);
+        }
+        Ok(())
+    }
+}
+
+impl FrameAnalyzer for HeatmapAnalyzer {
+    fn name(&self) -> String {
+        "heatmap".to_string()
+    }
+
+    fn start(&mut self, session: &AnalyzerSession) {
+        *self = HeatmapAnalyzer::new();
+        self.session = session.clone();
+    }
+
+    fn analyze(&mut self, frame: &Frame, _output: &mut AnalyzerOutput) -> Result<(), String> {
+        self.accumulate(frame.image).map_err(|e| e.to_string())
+    }
+
+    fn finish(&mut self, output: &mut AnalyzerOutput) -> Result<(), String> {
+        let (Some(heat), Some(background)) = (self.heat.as_ref(), self.background.as_ref()) else {
+            return Ok(());
+        };
+        let image = render(heat, background).map_err(|e| e.to_string())?;
+        let location = format!("{}/{}", self.session.output_dir, self.file());
+        imgcodecs::imwrite(&location, &image, &Vector::new()).map_err(|e| e.to_string())?;
+        output.add_artifact(Artifact {
+            analyzer: self.name(),
+            kind: "heatmap".to_string(),
+            file: self.file(),
+            timestamp: Local::now(),
+        });
+        Ok(())
+    }
+}
+
+// Colors the pixels of the frame that moved, the colors are scaled to the most moving pixel
+pub fn render(heat: &Mat, background: &Mat) -> opencv::Result<Mat> {
+    let mut max = 0.0;
+    core::min_max_loc(heat, None, Some(&mut max), None, None, &core::no_array())?;
+    let scale = if max > 0.0 { 255.0 / max } else { 0.0 };
+    let mut scaled = Mat::default();
+    heat.convert_to(&mut scaled, CV_8U, scale, 0.0)?;
+    let mut resized = Mat::default();
+    let size = background.size()?;
+    imgproc::resize(&scaled, &mut resized, size, 0.0, 0.0, imgproc::INTER_LINEAR)?;
+    let mut colored = Mat::default();
+    imgproc::apply_color_map(&resized, &mut colored, imgproc::COLORMAP_JET)?;
+    let mut blended = Mat::default();
+    core::add_weighted(
+        background,
+        1.0 - OPACITY,
+        &colored,
+        OPACITY,
+        0.0,
+        &mut blended,
+        -1,
+    )?;
+    let mut mask = Mat::default();
+    imgproc::threshold(&resized, &mut mask, 0.0, 255.0, imgproc::THRESH_BINARY)?;
+    let mut result = background.try_clone()?;
+    blended.copy_to_masked(&mut result, &mask)?;
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recorder::analyzer::tests::{frame_at, solid, TestSession};
+    use opencv::core::{Rect, Vec3b};
+    use std::path::Path;
+
+    #[test]
+    fn test_heatmap() {
+        let test = TestSession::new("heatmap-test");
+        let mut analyzer = HeatmapAnalyzer::new();
+        analyzer.start(&test.session);
+        let mut output = AnalyzerOutput::default();
+        // a white square moving up and down in the left half of the frame
+        for i in 0..10 {
+            let mut image = solid(320, 240, Scalar::all(0.0));
+            let square = Rect::new(40, 40 + (i % 2) * 100, 60, 60);
+            imgproc::rectangle(
+                &mut image,
+                square,
+                Scalar::all(255.0),
+                imgproc::FILLED,
+                8,
+                0,
+            )
+            .unwrap();
+            analyzer
+                .analyze(&frame_at(&image, None, Local::now()), &mut output)
+                .unwrap();
+        }
+        let heat = analyzer.heat.as_ref().unwrap();
+        assert_eq!(*heat.at_2d::<f32>(70, 70).unwrap(), 9.0);
+        assert_eq!(*heat.at_2d::<f32>(70, 250).unwrap(), 0.0);
+        let image = render(heat, analyzer.background.as_ref().unwrap()).unwrap();
+        // the moving square is colored, the still right half is not
+        let moved = image.at_2d::<Vec3b>(70, 70).unwrap();
+        assert!(moved[0] != moved[2], "{:?}", moved);
+        assert_eq!(*image.at_2d::<Vec3b>(70, 250).unwrap(), Vec3b::all(0));
+        analyzer.finish(&mut output).unwrap();
+        let artifacts = output.take_artifacts();
+        assert_eq!(artifacts[0].file, "heatmap-test-heatmap.jpg");
+        assert!(Path::new(&test.path("heatmap-test-heatmap.jpg")).exists());
+    }
+}
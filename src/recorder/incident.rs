@@ -0,0 +1,181 @@
+use crate::dtos::messages::IncidentInfo;
+use crate::recorder::playlist::{
+    DISCONTINUITY, EXTINF, MEDIA_SEQUENCE, PROGRAM_DATE_TIME, TARGET_DURATION,
+};
+use chrono::{DateTime, Local, SecondsFormat};
+use log::{debug, error, info};
+use std::fmt::Write;
+use std::path::Path;
+use std::time::Duration;
+
+struct IncidentSegment {
+    index: u32,
+    name: String,
+    duration: Duration,
+    program_date_time: DateTime<Local>,
+}
+
+// The footage around an incident, copied into {prefix}-incident-{id}/ with its own playlist
+pub struct Incident {
+    info: IncidentInfo,
+    directory: String,
+    segments: Vec<IncidentSegment>,
+}
+
+impl Incident {
+    // output_dir: the directory holding the session files
+    // prefix: the timestamp prefix of the session
+    // id: the id of the incident (unique within the session)
+    // name: an optional description of the incident
+    // from, until: the protected time window
+    pub fn new(
+        output_dir: &str,
+        prefix: &str,
+        id: &str,
+        name: Option<String>,
+        from: DateTime<Local>,
+        until: DateTime<Local>,
+    ) -> Result<Incident, std::io::Error> {
+        let directory = format!("{}/{}-incident-{}", output_dir, prefix, id);
+        // an existing incident is never overwritten
+        std::fs::create_dir(&directory)?;
+        info!("Incident {} protected from {} until {}", id, from, until);
+        Ok(Incident {
+            info: IncidentInfo {
+                id: id.to_string(),
+                name,
+                from,
+                until,
+                playlist: format!("{}/playlist.m3u8", directory),
+            },
+            directory,
+            segments: Vec::new(),
+        })
+    }
+
+    pub fn info(&self) -> &IncidentInfo {
+        &self.info
+    }
+
+    // Checks if a segment lies (partly) within the protected window
+    pub fn overlaps(&self, start: &DateTime<Local>, duration: Duration) -> bool {
+        *start < self.info.until && segment_end(start, duration) > self.info.from
+    }
+
+    // Checks if a segment ends after the protected window (no further segments are needed)
+    pub fn ends_before(&self, start: &DateTime<Local>, duration: Duration) -> bool {
+        segment_end(start, duration) >= self.info.until
+    }
+
+    // Copies a segment into the incident and updates the incident playlist
+    pub fn add_segment(
+        &mut self,
+        location: &str,
+        index: u32,
+        duration: Duration,
+        program_date_time: DateTime<Local>,
+    ) {
+        let Some(name) = Path::new(location)
+            .file_name()
+            .map(|f| f.to_string_lossy().to_string())
+        else {
+            return;
+        };
+        if self.segments.iter().any(|s| s.index == index) {
+            return;
+        }
+        copy(location, &format!("{}/{}", self.directory, name));
+        self.segments.push(IncidentSegment {
+            index,
+            name,
+            duration,
+            program_date_time,
+        });
+        self.segments.sort_by_key(|s| s.index);
+        self.write_playlist(false);
+    }
+
+    // Finishes the incident: copies the thumbnails and ends the playlist
+    // thumbnails: the thumbnail files (sprites, tooltips) of a segment index
+    pub fn finish(&self, thumbnails: impl Fn(u32) -> Vec<String>) {
+        for segment in self.segments.iter() {
+            for file in thumbnails(segment.index) {
+                if let Some(name) = Path::new(&file).file_name() {
+                    copy(
+                        &file,
+                        &format!("{}/{}", self.directory, name.to_string_lossy()),
+                    );
+                }
+            }
+        }
+        self.write_playlist(true);
+        info!(
+            "Incident {} finished with {} segments",
+            self.info.id,
+            self.segments.len()
+        );
+    }
+
+    fn write_playlist(&self, complete: bool) {
+        let target_duration = self
+            .segments
+            .iter()
+            .map(|s| s.duration.as_secs_f64().ceil() as u64)
+            .max()
+            .unwrap_or(1);
+        let mut playlist = String::new();
+        let _ = writeln!(playlist, "#EXTM3U");
+        let _ = writeln!(playlist, "#EXT-X-VERSION:3");
+        let _ = writeln!(
+            playlist,
+            "#EXT-X-PLAYLIST-TYPE:{}",
+            if complete { "VOD" } else { "EVENT" }
+        );
+        let _ = writeln!(playlist, "{}:{}", TARGET_DURATION, target_duration);
+        let _ = writeln!(playlist, "{}:0", MEDIA_SEQUENCE);
+        let mut previous: Option<u32> = None;
+        for segment in self.segments.iter() {
+            if previous.map(|p| p + 1 != segment.index).unwrap_or(false) {
+                let _ = writeln!(playlist, "{}", DISCONTINUITY);
+            }
+            let _ = writeln!(
+                playlist,
+                "{}:{}",
+                PROGRAM_DATE_TIME,
+                segment
+                    .program_date_time
+                    .to_rfc3339_opts(SecondsFormat::Millis, false)
+            );
+            let _ = writeln!(
+                playlist,
+                "{}:{:.3},",
+                EXTINF,
+                segment.duration.as_secs_f64()
+            );
+            let _ = writeln!(playlist, "{}", segment.name);
+            previous = Some(segment.index);
+        }
+        if complete {
+            let _ = writeln!(playlist, "#EXT-X-ENDLIST");
+        }
+        if let Err(e) = std::fs::write(&self.info.playlist, playlist) {
+            error!("Unable to write {}: {e}", self.info.playlist);
+        }
+    }
+}
+
+fn segment_end(start: &DateTime<Local>, duration: Duration) -> DateTime<Local> {
+    *start + chrono::Duration::from_std(duration).unwrap_or(chrono::Duration::zero())
+}
+
+// Hard links the file if possible (no extra space needed), copies it otherwise
+fn copy(from: &str, to: &str) {
+    if Path::new(to).exists() {
+        return;
+    }
+    if std::fs::hard_link(from, to).is_err() {
+        if let Err(e) = std::fs::copy(from, to) {
+            debug!("Unable to copy {} to {}: {e}", from, to);
+        }
+    }
+}
@@ -1,7 +1,25 @@
+pub mod analyzer;
+pub mod branch;
+pub mod clock;
 pub mod common;
-mod framehandler;
+pub mod detection;
+pub mod events;
+pub mod exif;
+pub mod framehandler;
+pub mod heatmap;
+mod incident;
+mod playlist;
+pub mod poster;
+pub mod preroll;
 pub mod preview;
+pub mod privacy;
+pub mod quality;
+pub mod retention;
+mod session;
 pub mod stillrecorder;
+pub mod storyboard;
+pub mod thumbnailer;
+pub mod timelapse;
 pub mod videocontroller;
 pub mod videorecorder;
 pub mod videosource;
@@ -0,0 +1,312 @@
+use chrono::{DateTime, Local, SecondsFormat};
+use log::error;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::Path;
+use std::time::Duration;
+
+pub(crate) const EXTINF: &str = "#EXTINF";
+pub(crate) const PROGRAM_DATE_TIME: &str = "#EXT-X-PROGRAM-DATE-TIME";
+pub(crate) const DISCONTINUITY: &str = "#EXT-X-DISCONTINUITY";
+const DISCONTINUITY_SEQUENCE: &str = "#EXT-X-DISCONTINUITY-SEQUENCE";
+pub(crate) const MEDIA_SEQUENCE: &str = "#EXT-X-MEDIA-SEQUENCE";
+pub(crate) const TARGET_DURATION: &str = "#EXT-X-TARGETDURATION";
+
+// The segments of a loop recording within the retention window
+#[derive(Default)]
+struct PlaylistWindow {
+    target_duration: u64,
+    segments: VecDeque<(String, Duration)>,
+    media_sequence: u64,
+}
+
+// Post-processes the playlist written by hlssink3.
+// hlssink3 rewrites the whole playlist on every new segment, so the
+// annotations are kept per session and re-applied after each segment.
+// In a loop recording the annotator writes the playlist itself, as a sliding window
+// over the segments kept by the retention manager.
+#[derive(Default)]
+pub struct PlaylistAnnotator {
+    playlist_location: String,
+    program_date_times: HashMap<String, DateTime<Local>>,
+    discontinuities: HashSet<String>,
+    removed: HashSet<String>,
+    removed_discontinuities: u64,
+    window: Option<PlaylistWindow>,
+}
+
+impl PlaylistAnnotator {
+    pub fn new() -> PlaylistAnnotator {
+        PlaylistAnnotator::default()
+    }
+
+    // Starts a new session
+    // playlist_location: the playlist written by hlssink3
+    pub fn reset(&mut self, playlist_location: &str) {
+        self.playlist_location = playlist_location.to_string();
+        self.program_date_times.clear();
+        self.discontinuities.clear();
+        self.removed.clear();
+        self.removed_discontinuities = 0;
+        self.window = None;
+    }
+
+    // Starts a new loop recording session, the playlist is written by the annotator
+    // playlist_location: the playlist of the session
+    // target_duration: the target duration of the segments (seconds)
+    pub fn reset_window(&mut self, playlist_location: &str, target_duration: u32) {
+        self.reset(playlist_location);
+        self.window = Some(PlaylistWindow {
+            target_duration: target_duration as u64,
+            ..Default::default()
+        });
+    }
+
+    // Registers a segment with its absolute capture time
+    // location: the location of the segment (as reported by hlssink3)
+    // duration: the duration of the segment
+    // program_date_time: the wall-clock time of the first frame of the segment
+    pub fn add_segment(
+        &mut self,
+        location: &str,
+        duration: Duration,
+        program_date_time: DateTime<Local>,
+    ) {
+        let name = segment_name(location);
+        if let Some(window) = self.window.as_mut() {
+            window.segments.push_back((name.clone(), duration));
+        }
+        self.program_date_times.insert(name, program_date_time);
+    }
+
+    // Marks a segment as the first one after a discontinuity (e.g. after a pause)
+    // location: the location of the segment (as reported by hlssink3)
+    pub fn add_discontinuity(&mut self, location: &str) {
+        self.discontinuities.insert(segment_name(location));
+    }
+
+    // Removes a (deleted) segment from the head of the playlist, the media sequence is advanced
+    // location: the location of the segment (as reported by hlssink3)
+    pub fn remove_segment(&mut self, location: &str) {
+        let name = segment_name(location);
+        self.program_date_times.remove(&name);
+        if self.discontinuities.remove(&name) {
+            self.removed_discontinuities += 1;
+        }
+        match self.window.as_mut() {
+            Some(window) => {
+                if let Some(idx) = window.segments.iter().position(|(n, _)| *n == name) {
+                    window.segments.remove(idx);
+                    window.media_sequence += 1;
+                }
+            }
+            None => {
+                self.removed.insert(name);
+            }
+        }
+    }
+
+    // Applies the annotations to the playlist on disk
+    pub fn apply(&mut self) -> Result<(), std::io::Error> {
+        if self.playlist_location.is_empty() {
+            return Ok(());
+        }
+        let annotated = match self.window.as_ref() {
+            Some(window) => self.render_window(window),
+            None => {
+                let playlist = std::fs::read_to_string(&self.playlist_location)?;
+                // removed segments no longer listed by hlssink3 don't need to be tracked anymore
+                let listed = playlist
+                    .lines()
+                    .filter(|line| !line.starts_with("#") && !line.is_empty())
+                    .map(segment_name)
+                    .collect::<HashSet<String>>();
+                self.removed.retain(|name| listed.contains(name));
+                self.annotate(&playlist)
+            }
+        };
+        let tmp = format!("{}.tmp", self.playlist_location);
+        std::fs::write(&tmp, annotated)?;
+        std::fs::rename(&tmp, &self.playlist_location).map_err(|e| {
+            error!("Unable to replace playlist {}: {e}", self.playlist_location);
+            e
+        })
+    }
+
+    // Inserts an EXT-X-DISCONTINUITY and an EXT-X-PROGRAM-DATE-TIME tag in front of the known segments
+    // and drops the removed segments. Tags written by a previous run are dropped, so the function is idempotent.
+    fn annotate(&self, playlist: &str) -> String {
+        let lines = playlist
+            .lines()
+            .filter(|line| {
+                !line.starts_with(PROGRAM_DATE_TIME)
+                    && !line.starts_with(DISCONTINUITY_SEQUENCE)
+                    && *line != DISCONTINUITY
+            })
+            .collect::<Vec<&str>>();
+        let mut result = Vec::<String>::with_capacity(lines.len() * 2);
+        let mut pending = Vec::<String>::new();
+        let mut dropped = 0;
+        for line in lines {
+            if line.starts_with(EXTINF) || (!pending.is_empty() && line.starts_with("#")) {
+                pending.push(line.to_string());
+                continue;
+            }
+            if !line.starts_with("#") && !line.is_empty() {
+                let name = segment_name(line);
+                if self.removed.contains(&name) {
+                    pending.clear();
+                    dropped += 1;
+                    continue;
+                }
+                if self.discontinuities.contains(&name) {
+                    result.push(DISCONTINUITY.to_string());
+                }
+                if let Some(pdt) = self.program_date_times.get(&name) {
+                    result.push(format!(
+                        "{}:{}",
+                        PROGRAM_DATE_TIME,
+                        pdt.to_rfc3339_opts(SecondsFormat::Millis, false)
+                    ));
+                }
+            }
+            result.append(&mut pending);
+            result.push(line.to_string());
+        }
+        result.append(&mut pending);
+        if let Some(idx) = result.iter().position(|l| l.starts_with(MEDIA_SEQUENCE)) {
+            let sequence = result[idx][MEDIA_SEQUENCE.len() + 1..]
+                .trim()
+                .parse::<u64>()
+                .unwrap_or_default();
+            result[idx] = format!("{}:{}", MEDIA_SEQUENCE, sequence + dropped);
+            if self.removed_discontinuities > 0 {
+                result.insert(
+                    idx + 1,
+                    format!(
+                        "{}:{}",
+                        DISCONTINUITY_SEQUENCE, self.removed_discontinuities
+                    ),
+                );
+            }
+        }
+        let mut annotated = result.join("\n");
+        annotated.push('\n');
+        annotated
+    }
+
+    // Writes the playlist of a loop recording (without an end, the recording goes on)
+    fn render_window(&self, window: &PlaylistWindow) -> String {
+        let target_duration = window
+            .segments
+            .iter()
+            .map(|(_, duration)| duration.as_secs_f64().ceil() as u64)
+            .fold(window.target_duration, u64::max);
+        let mut result = vec![
+            "#EXTM3U".to_string(),
+            "#EXT-X-VERSION:3".to_string(),
+            format!("{}:{}", TARGET_DURATION, target_duration),
+            format!("{}:{}", MEDIA_SEQUENCE, window.media_sequence),
+        ];
+        if self.removed_discontinuities > 0 {
+            result.push(format!(
+                "{}:{}",
+                DISCONTINUITY_SEQUENCE, self.removed_discontinuities
+            ));
+        }
+        for (name, duration) in window.segments.iter() {
+            if self.discontinuities.contains(name) {
+                result.push(DISCONTINUITY.to_string());
+            }
+            if let Some(pdt) = self.program_date_times.get(name) {
+                result.push(format!(
+                    "{}:{}",
+                    PROGRAM_DATE_TIME,
+                    pdt.to_rfc3339_opts(SecondsFormat::Millis, false)
+                ));
+            }
+            result.push(format!("{}:{:.3},", EXTINF, duration.as_secs_f64()));
+            result.push(name.clone());
+        }
+        let mut playlist = result.join("\n");
+        playlist.push('\n');
+        playlist
+    }
+}
+
+fn segment_name(location: &str) -> String {
+    Path::new(location)
+        .file_name()
+        .map(|f| f.to_string_lossy().to_string())
+        .unwrap_or(location.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_annotate() {
+        let playlist = "#EXTM3U\n#EXT-X-VERSION:3\n#EXTINF:6,\n20241211-083017-chunk_00000.ts\n#EXTINF:6,\n20241211-083017-chunk_00001.ts\n";
+        let mut annotator = PlaylistAnnotator::new();
+        let pdt = Local.with_ymd_and_hms(2024, 12, 11, 8, 30, 17).unwrap();
+        annotator.add_segment(
+            "/tmp/20241211-083017-chunk_00000.ts",
+            Duration::from_secs(6),
+            pdt,
+        );
+        annotator.add_discontinuity("/tmp/20241211-083017-chunk_00001.ts");
+        let annotated = annotator.annotate(playlist);
+        let expected = format!(
+            "#EXTM3U\n#EXT-X-VERSION:3\n{}:{}\n#EXTINF:6,\n20241211-083017-chunk_00000.ts\n{}\n#EXTINF:6,\n20241211-083017-chunk_00001.ts\n",
+            PROGRAM_DATE_TIME,
+            pdt.to_rfc3339_opts(SecondsFormat::Millis, false),
+            DISCONTINUITY
+        );
+        assert_eq!(annotated, expected);
+        assert_eq!(annotator.annotate(&annotated), expected);
+    }
+
+    #[test]
+    fn test_remove_segment() {
+        let playlist = "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-MEDIA-SEQUENCE:0\n#EXTINF:6,\n20241211-083017-chunk_00000.ts\n#EXTINF:6,\n20241211-083017-chunk_00001.ts\n#EXTINF:6,\n20241211-083017-chunk_00002.ts\n";
+        let mut annotator = PlaylistAnnotator::new();
+        annotator.add_discontinuity("20241211-083017-chunk_00001.ts");
+        annotator.remove_segment("/tmp/20241211-083017-chunk_00000.ts");
+        annotator.remove_segment("/tmp/20241211-083017-chunk_00001.ts");
+        assert_eq!(
+            annotator.annotate(playlist),
+            "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-MEDIA-SEQUENCE:2\n#EXT-X-DISCONTINUITY-SEQUENCE:1\n#EXTINF:6,\n20241211-083017-chunk_00002.ts\n"
+        );
+    }
+
+    #[test]
+    fn test_window() {
+        let mut annotator = PlaylistAnnotator::new();
+        annotator.reset_window("/tmp/window-test-playlist.m3u8", 6);
+        let pdt = Local.with_ymd_and_hms(2024, 12, 11, 8, 30, 17).unwrap();
+        for i in 0..3 {
+            let location = format!("/tmp/20241211-083017-chunk_{:05}.ts", i);
+            if i == 1 {
+                annotator.add_discontinuity(&location);
+            }
+            let start = pdt + chrono::Duration::seconds(6 * i);
+            annotator.add_segment(&location, Duration::from_secs(6), start);
+        }
+        annotator.remove_segment("/tmp/20241211-083017-chunk_00000.ts");
+        annotator.remove_segment("/tmp/20241211-083017-chunk_00001.ts");
+        let window = annotator.window.as_ref().unwrap();
+        let start = pdt + chrono::Duration::seconds(12);
+        assert_eq!(
+            annotator.render_window(window),
+            format!(
+                "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:6\n#EXT-X-MEDIA-SEQUENCE:2\n#EXT-X-DISCONTINUITY-SEQUENCE:1\n{}:{}\n#EXTINF:6.000,\n20241211-083017-chunk_00002.ts\n",
+                PROGRAM_DATE_TIME,
+                start.to_rfc3339_opts(SecondsFormat::Millis, false)
+            )
+        );
+        // only the segments of the window are tracked
+        assert_eq!(annotator.program_date_times.len(), 1);
+        assert!(annotator.removed.is_empty());
+    }
+}
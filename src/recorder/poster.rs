@@ -0,0 +1,238 @@
+use crate::dtos::messages::Artifact;
+use crate::recorder::analyzer::{AnalyzerOutput, AnalyzerSession, Frame, FrameAnalyzer};
+use chrono::Local;
+use gst::prelude::*;
+use gstreamer_app::{gst, AppSrc};
+use log::{debug, error};
+use opencv::core::{Mat, Size, Vector};
+use opencv::prelude::*;
+use opencv::{imgcodecs, imgproc};
+
+// the frames kept for the poster and the preview, every other frame is dropped once reached
+const MAX_FRAMES: usize = 64;
+const PREVIEW_WIDTH: i32 = 320;
+const PREVIEW_FPS: u64 = 4;
+// how long the preview encoding may take
+const ENCODE_TIMEOUT: gst::ClockTime = gst::ClockTime::from_seconds(10);
+
+#[derive(Clone, Debug)]
+pub struct PosterConfig {
+    // the position of the poster frame in the session (0.0 - 1.0)
+    pub position: f64,
+    // the frames of the animated preview
+    pub preview_frames: usize,
+}
+
+impl Default for PosterConfig {
+    fn default() -> Self {
+        PosterConfig {
+            position: 0.1,
+            preview_frames: 20,
+        }
+    }
+}
+
+// Writes {prefix}-poster.jpg (a frame at the configured position of the session) and
+// {prefix}-preview.gif (frames evenly spread over the session) when a session ends.
+// The frames are kept JPEG-encoded and thinned out evenly, so long sessions use little memory.
+pub struct PosterAnalyzer {
+    config: PosterConfig,
+    session: AnalyzerSession,
+    frames: Vec<Vector<u8>>,
+    // every step-th frame is kept
+    step: usize,
+    seen: usize,
+}
+
+impl PosterAnalyzer {
+    pub fn new(config: PosterConfig) -> PosterAnalyzer {
+        PosterAnalyzer {
+            config,
+            session: AnalyzerSession::default(),
+            frames: Vec::new(),
+            step: 1,
+            seen: 0,
+        }
+    }
+
+    fn file(&self, suffix: &str) -> String {
+        format!("{}-{}", self.session.prefix, suffix)
+    }
+
+    fn location(&self, file: &str) -> String {
+        format!("{}/{}", self.session.output_dir, file)
+    }
+
+    fn write_poster(&self) -> Result<String, String> {
+        let index = ((self.frames.len() - 1) as f64 * self.config.position.clamp(0.0, 1.0)).round();
+        let file = self.file("poster.jpg");
+        std::fs::write(self.location(&file), self.frames[index as usize].as_slice())
+            .map_err(|e| e.to_string())?;
+        Ok(file)
+    }
+
+    fn write_preview(&self) -> Result<String, String> {
+        let count = self.config.preview_frames.clamp(1, self.frames.len());
+        let mut images = Vec::new();
+        for i in 0..count {
+            let jpeg = &self.frames[i * self.frames.len() / count];
+            let image =
+                imgcodecs::imdecode(jpeg, imgcodecs::IMREAD_COLOR).map_err(|e| e.to_string())?;
+            let height = (image.rows() * PREVIEW_WIDTH / image.cols().max(1)).max(2) & !1;
+            let mut resized = Mat::default();
+            imgproc::resize(
+                &image,
+                &mut resized,
+                Size::new(PREVIEW_WIDTH, height),
+                0.0,
+                0.0,
+                imgproc::INTER_AREA,
+            )
+            .map_err(|e| e.to_string())?;
+            images.push(resized);
+        }
+        let file = self.file("preview.gif");
+        encode_gif(&images, &self.location(&file))?;
+        Ok(file)
+    }
+}
+
+impl FrameAnalyzer for PosterAnalyzer {
+    fn name(&self) -> String {
+        "poster".to_string()
+    }
+
+    fn start(&mut self, session: &AnalyzerSession) {
+        self.session = session.clone();
+        self.frames.clear();
+        self.step = 1;
+        self.seen = 0;
+    }
+
+    fn analyze(&mut self, frame: &Frame, _output: &mut AnalyzerOutput) -> Result<(), String> {
+        self.seen += 1;
+        if (self.seen - 1) % self.step != 0 {
+            return Ok(());
+        }
+        let mut jpeg = Vector::<u8>::new();
+        imgcodecs::imencode(".jpg", frame.image, &mut jpeg, &Vector::new())
+            .map_err(|e| e.to_string())?;
+        self.frames.push(jpeg);
+        if self.frames.len() >= MAX_FRAMES {
+            self.frames = std::mem::take(&mut self.frames)
+                .into_iter()
+                .step_by(2)
+                .collect();
+            self.step *= 2;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self, output: &mut AnalyzerOutput) -> Result<(), String> {
+        if self.frames.is_empty() {
+            return Ok(());
+        }
+        let poster = self.write_poster()?;
+        output.add_artifact(Artifact {
+            analyzer: self.name(),
+            kind: "poster".to_string(),
+            file: poster,
+            timestamp: Local::now(),
+        });
+        match self.write_preview() {
+            Ok(preview) => output.add_artifact(Artifact {
+                analyzer: self.name(),
+                kind: "preview".to_string(),
+                file: preview,
+                timestamp: Local::now(),
+            }),
+            Err(e) => error!("Unable to write the preview: {e}"),
+        }
+        self.frames.clear();
+        Ok(())
+    }
+}
+
+// Encodes BGR frames of the same size into an animated gif
+fn encode_gif(images: &[Mat], location: &str) -> Result<(), String> {
+    let (width, height) = (images[0].cols(), images[0].rows());
+    let pipeline = gst::parse::launch(&format!(
+        "appsrc name=src format=time \
+            caps=video/x-raw,format=BGR,width={width},height={height},framerate={PREVIEW_FPS}/1 \
+            ! videoconvert ! gifenc ! filesink name=sink"
+    ))
+    .map_err(|e| e.to_string())?
+    .downcast::<gst::Pipeline>()
+    .map_err(|_| "Not a pipeline".to_string())?;
+    pipeline
+        .by_name("sink")
+        .ok_or("Missing sink")?
+        .set_property("location", location);
+    let src = pipeline
+        .by_name("src")
+        .and_then(|s| s.downcast::<AppSrc>().ok())
+        .ok_or("Missing appsrc")?;
+    pipeline
+        .set_state(gst::State::Playing)
+        .map_err(|e| e.to_string())?;
+    let frame_duration = gst::ClockTime::SECOND / PREVIEW_FPS;
+    for (i, image) in images.iter().enumerate() {
+        let data = image.data_bytes().map_err(|e| e.to_string())?.to_vec();
+        let mut buffer = gst::Buffer::from_mut_slice(data);
+        {
+            let buffer = buffer.get_mut().unwrap();
+            buffer.set_pts(frame_duration * i as u64);
+            buffer.set_duration(frame_duration);
+        }
+        if src.push_buffer(buffer).is_err() {
+            break;
+        }
+    }
+    let _ = src.end_of_stream();
+    let bus = pipeline.bus().ok_or("Pipeline without bus")?;
+    let result = match bus.timed_pop_filtered(
+        Some(ENCODE_TIMEOUT),
+        &[gst::MessageType::Eos, gst::MessageType::Error],
+    ) {
+        Some(msg) => match msg.view() {
+            gst::MessageView::Error(err) => Err(err.error().to_string()),
+            _ => Ok(()),
+        },
+        None => Err("Timeout".to_string()),
+    };
+    debug!("Preview {}: {:?}", location, result);
+    let _ = pipeline.set_state(gst::State::Null);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recorder::analyzer::tests::{frame_at, solid, TestSession};
+    use opencv::core::Scalar;
+    use std::path::Path;
+
+    #[test]
+    fn test_poster() {
+        gst::init().unwrap();
+        let test = TestSession::new("poster-test");
+        let mut analyzer = PosterAnalyzer::new(PosterConfig::default());
+        analyzer.start(&test.session);
+        let mut output = AnalyzerOutput::default();
+        for i in 0..200 {
+            let image = solid(64, 48, Scalar::all(i as f64));
+            analyzer
+                .analyze(&frame_at(&image, None, Local::now()), &mut output)
+                .unwrap();
+        }
+        assert!(analyzer.frames.len() < MAX_FRAMES);
+        analyzer.finish(&mut output).unwrap();
+        let artifacts = output.take_artifacts();
+        assert_eq!(artifacts[0].kind, "poster");
+        assert!(Path::new(&test.path("poster-test-poster.jpg")).exists());
+        // gifenc (gst-plugins-rs) might not be installed
+        if artifacts.len() > 1 {
+            assert!(Path::new(&test.path("poster-test-preview.gif")).exists());
+        }
+    }
+}
@@ -0,0 +1,368 @@
+use crate::recorder;
+use gst::prelude::*;
+use gstreamer_app::{gst, AppSink, AppSrc};
+use log::{debug, error, info};
+use recorder::common::PipelineError;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+const VIDEO_SOURCE: &str = "video-source";
+const PREROLL_SINK: &str = "preroll-sink";
+
+#[allow(dead_code)]
+pub trait Preroll: Sync + Send {
+    // Starts buffering the encoded source
+    // clock: the clock shared with the recording pipeline
+    fn start(&self, clock: &gst::Clock) -> Result<(), PipelineError>;
+    fn stop(&self) -> Result<(), PipelineError>;
+    // Feeds the buffered pre-roll followed by the live stream into the appsrc (video-source)
+    // of a recording pipeline, the pipeline gets the clock and base time of the pre-roll pipeline
+    fn attach(&self, recording_pipeline: &Option<gst::Pipeline>) -> Result<(), PipelineError>;
+    fn detach(&self);
+    // The recording pipeline consuming the encoded stream
+    fn get_pipeline(&self) -> String;
+}
+
+#[derive(Default)]
+struct PrerollState {
+    buffers: VecDeque<gst::Buffer>,
+    // the number of buffers received, the sequence number of the next buffer
+    received: u64,
+    caps: Option<gst::Caps>,
+    target: Option<AppSrc>,
+    // the target has not caught up with the pre-roll yet, live buffers are only buffered
+    pending: bool,
+}
+
+impl PrerollState {
+    // Keeps at least duration of buffers, the buffer always starts with a keyframe
+    fn trim(&mut self, duration: gst::ClockTime) {
+        let Some(newest) = self.buffers.back().and_then(|b| b.pts()) else {
+            return;
+        };
+        let keep_from = newest.saturating_sub(duration);
+        let cut = self
+            .buffers
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| !b.flags().contains(gst::BufferFlags::DELTA_UNIT))
+            .filter(|(_, b)| b.pts().map(|pts| pts <= keep_from).unwrap_or(false))
+            .map(|(idx, _)| idx)
+            .last();
+        if let Some(cut) = cut {
+            self.buffers.drain(..cut);
+        }
+    }
+}
+
+pub struct PrerollImpl {
+    socket_path: String,
+    pipeline_str: String,
+    recording_pipeline_str: String,
+    duration: gst::ClockTime,
+    gst_pipeline: Mutex<Option<gst::Pipeline>>,
+    state: Arc<Mutex<PrerollState>>,
+}
+
+impl Preroll for PrerollImpl {
+    fn start(&self, clock: &gst::Clock) -> Result<(), PipelineError> {
+        info!("Starting pre-roll buffer of {}", self.duration);
+        let mut gst_pipeline = self.gst_pipeline.lock().unwrap();
+        if gst_pipeline.is_some() {
+            return Err(PipelineError::AlreadyStarted);
+        }
+        let pipeline = gst::parse::launch(self.pipeline_str.as_str())
+            .map_err(|e| {
+                error!("{e}");
+                PipelineError::ParseError
+            })?
+            .downcast::<gst::Pipeline>()
+            .unwrap();
+        let source = pipeline
+            .by_name(VIDEO_SOURCE)
+            .expect("Source bin not found");
+        if source.has_property("socket-path", None) {
+            source.set_property("socket-path", &self.socket_path);
+        }
+        let sink = pipeline
+            .by_name(PREROLL_SINK)
+            .expect("Pre-roll sink not found")
+            .downcast::<AppSink>()
+            .expect("Pre-roll sink is expected to be an appsink!");
+        sink.set_callbacks(
+            gstreamer_app::AppSinkCallbacks::builder()
+                .new_sample(preroll_callback(self.state.clone(), self.duration))
+                .build(),
+        );
+        pipeline.use_clock(Some(clock));
+        pipeline.set_state(gst::State::Playing).map_err(|e| {
+            error!("{e}");
+            PipelineError::EncodingError
+        })?;
+        if log::log_enabled!(log::Level::Debug) {
+            pipeline.debug_to_dot_file(gst::DebugGraphDetails::MEDIA_TYPE, "preroll");
+        }
+        *gst_pipeline = Some(pipeline);
+        Ok(())
+    }
+
+    fn stop(&self) -> Result<(), PipelineError> {
+        self.detach();
+        let Some(pipeline) = self.gst_pipeline.lock().unwrap().take() else {
+            return Err(PipelineError::NotRunning);
+        };
+        let mut state = self.state.lock().unwrap();
+        state.buffers.clear();
+        state.caps = None;
+        pipeline.set_state(gst::State::Null).map_err(|e| {
+            error!("{e}");
+            PipelineError::EncodingError
+        })?;
+        Ok(())
+    }
+
+    fn attach(&self, recording_pipeline: &Option<gst::Pipeline>) -> Result<(), PipelineError> {
+        let gst_pipeline = self.gst_pipeline.lock().unwrap();
+        let Some(preroll_pipeline) = gst_pipeline.as_ref() else {
+            return Err(PipelineError::NotRunning);
+        };
+        let recording_pipeline = recording_pipeline.as_ref().expect("Pipeline mangled");
+        let appsrc = recording_pipeline
+            .by_name(VIDEO_SOURCE)
+            .expect("Source bin not found")
+            .downcast::<AppSrc>()
+            .map_err(|_| {
+                error!("The video source of a pre-roll recording has to be an appsrc");
+                PipelineError::ParseError
+            })?;
+        // the buffers keep the running time of the pre-roll pipeline, so both pipelines
+        // share clock and base time (segments are tagged with the time of capture)
+        if let Some(clock) = preroll_pipeline.clock() {
+            recording_pipeline.use_clock(Some(&clock));
+        }
+        recording_pipeline.set_start_time(gst::ClockTime::NONE);
+        if let Some(base_time) = preroll_pipeline.base_time() {
+            recording_pipeline.set_base_time(base_time);
+        }
+        appsrc.set_format(gst::Format::Time);
+        appsrc.set_max_bytes(0);
+        // the pre-roll is pushed once the recording pipeline asks for data (it is running),
+        // a stopped appsrc refuses buffers
+        let state = self.state.clone();
+        appsrc.set_callbacks(
+            gstreamer_app::AppSrcCallbacks::builder()
+                .need_data(move |appsrc, _| {
+                    if !state.lock().unwrap().pending {
+                        return;
+                    }
+                    match push_preroll(&state, appsrc) {
+                        Ok(pushed) => info!("Pre-roll of {} buffers pushed", pushed),
+                        Err(e) => {
+                            error!("Unable to push the pre-roll: {:?}", e);
+                            let mut state = state.lock().unwrap();
+                            state.pending = false;
+                            state.target = None;
+                        }
+                    }
+                })
+                .build(),
+        );
+
+        let mut state = self.state.lock().unwrap();
+        appsrc.set_caps(state.caps.as_ref());
+        state.target = Some(appsrc);
+        state.pending = true;
+        Ok(())
+    }
+
+    fn detach(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.pending = false;
+        if let Some(appsrc) = state.target.take() {
+            let _ = appsrc.end_of_stream();
+        }
+    }
+
+    fn get_pipeline(&self) -> String {
+        self.recording_pipeline_str.clone()
+    }
+}
+
+fn preroll_callback(
+    state: Arc<Mutex<PrerollState>>,
+    duration: gst::ClockTime,
+) -> impl Fn(&AppSink) -> Result<gst::FlowSuccess, gst::FlowError> {
+    move |app_sink: &AppSink| {
+        let sample = app_sink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
+        let buffer = sample.buffer_owned().ok_or(gst::FlowError::Error)?;
+        let mut state = state.lock().unwrap();
+        if state.caps.is_none() {
+            state.caps = sample.caps_owned();
+        }
+        if !state.pending {
+            if let Some(appsrc) = state.target.as_ref() {
+                if let Err(e) = appsrc.push_buffer(buffer.clone()) {
+                    // the recording pipeline has been stopped
+                    debug!("Detaching pre-roll: {:?}", e);
+                    state.target = None;
+                }
+            }
+        }
+        state.buffers.push_back(buffer);
+        state.received += 1;
+        state.trim(duration);
+        Ok(gst::FlowSuccess::Ok)
+    }
+}
+
+// Pushes the buffered pre-roll (starting with a keyframe) into the appsrc. The buffers are
+// pushed without holding the lock, buffers received meanwhile are pushed in the next round.
+// Once the pre-roll has been caught up with, the live buffers are pushed by the pre-roll sink.
+// returns: the number of buffers pushed
+fn push_preroll(state: &Mutex<PrerollState>, appsrc: &AppSrc) -> Result<usize, gst::FlowError> {
+    let mut next: Option<u64> = None;
+    let mut pushed = 0;
+    loop {
+        let batch = {
+            let mut state = state.lock().unwrap();
+            let first = state.received - state.buffers.len() as u64;
+            let start = match next {
+                Some(next) => next.saturating_sub(first) as usize,
+                None => state
+                    .buffers
+                    .iter()
+                    .position(|b| !b.flags().contains(gst::BufferFlags::DELTA_UNIT))
+                    .unwrap_or(state.buffers.len()),
+            };
+            let batch = state
+                .buffers
+                .iter()
+                .skip(start)
+                .cloned()
+                .collect::<Vec<gst::Buffer>>();
+            next = Some(state.received);
+            if batch.is_empty() {
+                state.pending = false;
+            }
+            batch
+        };
+        if batch.is_empty() {
+            return Ok(pushed);
+        }
+        for buffer in batch {
+            appsrc.push_buffer(buffer)?;
+            pushed += 1;
+        }
+    }
+}
+
+pub struct PrerollBuilder {
+    socket_path: String,
+    pipeline_str: String,
+    recording_pipeline_str: String,
+    duration_sec: u32,
+}
+
+impl PrerollBuilder {
+    pub fn new() -> PrerollBuilder {
+        PrerollBuilder {
+            socket_path: "/tmp/video0.sock".to_string(),
+            pipeline_str: "unixfdsrc name=video-source ! queue ! videoconvert ! x264enc key-int-max=10 tune=zerolatency ! h264parse config-interval=-1 ! video/x-h264, stream-format=byte-stream, alignment=au ! appsink name=preroll-sink sync=false".to_string(),
+            recording_pipeline_str: "appsrc name=video-source ! h264parse ! hlssink3 name=video-sink".to_string(),
+            duration_sec: 10,
+        }
+    }
+
+    pub fn with_socket_path(mut self, socket_path: &str) -> PrerollBuilder {
+        self.socket_path = socket_path.to_string();
+        self
+    }
+
+    pub fn with_pipeline_str(mut self, pipeline_str: &str) -> PrerollBuilder {
+        self.pipeline_str = pipeline_str.to_string();
+        self
+    }
+
+    pub fn with_recording_pipeline_str(mut self, pipeline_str: &str) -> PrerollBuilder {
+        self.recording_pipeline_str = pipeline_str.to_string();
+        self
+    }
+
+    pub fn with_duration_sec(mut self, duration_sec: u32) -> PrerollBuilder {
+        self.duration_sec = duration_sec;
+        self
+    }
+
+    pub fn build(&self) -> PrerollImpl {
+        PrerollImpl {
+            socket_path: self.socket_path.clone(),
+            pipeline_str: self.pipeline_str.clone(),
+            recording_pipeline_str: self.recording_pipeline_str.clone(),
+            duration: gst::ClockTime::from_seconds(self.duration_sec as u64),
+            gst_pipeline: Mutex::new(None),
+            state: Arc::new(Mutex::new(PrerollState::default())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer(sec: u64, keyframe: bool) -> gst::Buffer {
+        let mut buffer = gst::Buffer::new();
+        {
+            let buffer = buffer.get_mut().unwrap();
+            buffer.set_pts(gst::ClockTime::from_seconds(sec));
+            if !keyframe {
+                buffer.set_flags(gst::BufferFlags::DELTA_UNIT);
+            }
+        }
+        buffer
+    }
+
+    #[test]
+    fn test_trim() {
+        let _ = gst::init();
+        let mut state = PrerollState::default();
+        for sec in 0..10 {
+            state.buffers.push_back(buffer(sec, sec % 4 == 0));
+        }
+        state.trim(gst::ClockTime::from_seconds(3));
+        // the newest buffer is at 9s, the last keyframe at or before 6s is at 4s
+        assert_eq!(
+            state.buffers.front().unwrap().pts(),
+            Some(gst::ClockTime::from_seconds(4))
+        );
+    }
+
+    #[test]
+    fn test_push_preroll() {
+        let _ = gst::init();
+        let pipeline = gst::parse::launch("appsrc name=video-source ! fakesink")
+            .unwrap()
+            .downcast::<gst::Pipeline>()
+            .unwrap();
+        let appsrc = pipeline
+            .by_name(VIDEO_SOURCE)
+            .unwrap()
+            .downcast::<AppSrc>()
+            .unwrap();
+        appsrc.set_max_bytes(0);
+        // a stopped appsrc refuses buffers
+        pipeline.set_state(gst::State::Playing).unwrap();
+        let state = Mutex::new(PrerollState::default());
+        {
+            let mut state = state.lock().unwrap();
+            for sec in 0..6 {
+                state.buffers.push_back(buffer(sec, sec % 4 == 1));
+                state.received += 1;
+            }
+            state.pending = true;
+        }
+        // the pre-roll starts with the keyframe at 1s
+        assert_eq!(push_preroll(&state, &appsrc), Ok(5));
+        assert!(!state.lock().unwrap().pending);
+        pipeline.set_state(gst::State::Null).unwrap();
+    }
+}
@@ -0,0 +1,456 @@
+use crate::recorder::framehandler::FrameFormat;
+use crate::utils::config::{MaskMode, PrivacyRegion};
+use gst::prelude::*;
+use gstreamer_app::gst;
+use gstreamer_video::VideoFrameRef;
+use log::{error, info};
+use opencv::core::{Mat, Point, Rect, Scalar, Size, Vector, CV_8UC1, CV_8UC3, CV_8UC4};
+use opencv::imgproc;
+use opencv::objdetect::CascadeClassifier;
+use opencv::prelude::*;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+// the element of the source pipeline the masks are burnt into (raw GRAY8, BGR or RGB frames)
+pub const PRIVACY_ELEMENT: &str = "privacy";
+// faces are searched in every n-th frame, the masks stay in place in between
+const FACE_INTERVAL: u32 = 5;
+// faces are searched in a copy of the frame scaled down to this width
+const FACE_SEARCH_WIDTH: f64 = 320.0;
+// the face boxes are grown by this share of their size on every side
+const FACE_PADDING: f64 = 0.25;
+// the boxes of a search grow by this share of their size per frame after the searched one,
+// covering the movement of the faces until the next search
+const FACE_MOTION: f64 = 0.05;
+// the frames a search covers, older searches (e.g. a busy classifier) mask the whole frame
+const FACE_MAX_AGE: u32 = 2 * FACE_INTERVAL;
+// the size of the blocks a blurred region is reduced to (pixels)
+const BLUR_BLOCK: i32 = 16;
+
+// Finds faces with a cascade classifier (e.g. haarcascade_frontalface_default.xml).
+// The search runs on a thread of its own, so the streaming thread never waits for the
+// classifier. The frames are masked with the grown boxes of a recent search, without one
+// (e.g. the first frames of a stream) the whole frame is masked.
+struct FaceDetector {
+    sender: Mutex<SyncSender<(u32, Mat)>>,
+    // the number of the searched frame and its face boxes
+    faces: Arc<Mutex<Option<(u32, Vec<Rect>)>>>,
+    frames: AtomicU32,
+}
+
+impl FaceDetector {
+    fn new(mut classifier: CascadeClassifier) -> FaceDetector {
+        // a single frame waits for the search, newer frames are skipped while it is busy
+        let (sender, receiver) = mpsc::sync_channel::<(u32, Mat)>(1);
+        let faces = Arc::new(Mutex::new(None));
+        let found = faces.clone();
+        thread::spawn(move || {
+            for (number, frame) in receiver {
+                match search(&mut classifier, &frame) {
+                    Ok(boxes) => *found.lock().unwrap() = Some((number, boxes)),
+                    Err(e) => error!("Face search failed: {e}"),
+                }
+            }
+        });
+        FaceDetector {
+            sender: Mutex::new(sender),
+            faces,
+            frames: AtomicU32::new(0),
+        }
+    }
+
+    // Queues every n-th frame for the search
+    // returns: the face boxes of the latest search grown by the frames since, None without a
+    // search of the last FACE_MAX_AGE frames
+    fn detect(&self, frame: &Mat) -> opencv::Result<Option<Vec<Rect>>> {
+        let number = self.frames.fetch_add(1, Ordering::SeqCst);
+        if number % FACE_INTERVAL == 0 {
+            if let Err(TrySendError::Disconnected(_)) = self
+                .sender
+                .lock()
+                .unwrap()
+                .try_send((number, frame.try_clone()?))
+            {
+                error!("Face search thread stopped");
+            }
+        }
+        let faces = self.faces.lock().unwrap();
+        Ok(faces.as_ref().and_then(|(searched, boxes)| {
+            let age = number.wrapping_sub(*searched);
+            (age <= FACE_MAX_AGE).then(|| {
+                boxes
+                    .iter()
+                    .map(|face| grow(face, FACE_MOTION * age as f64))
+                    .collect()
+            })
+        }))
+    }
+}
+
+// Grows the box by share of its size on every side
+fn grow(face: &Rect, share: f64) -> Rect {
+    let pad_x = (face.width as f64 * share).ceil() as i32;
+    let pad_y = (face.height as f64 * share).ceil() as i32;
+    Rect::new(
+        face.x - pad_x,
+        face.y - pad_y,
+        face.width + 2 * pad_x,
+        face.height + 2 * pad_y,
+    )
+}
+
+// The corners of the box
+fn corners(face: &Rect) -> Vector<Point> {
+    Vector::from_iter([
+        Point::new(face.x, face.y),
+        Point::new(face.x + face.width, face.y),
+        Point::new(face.x + face.width, face.y + face.height),
+        Point::new(face.x, face.y + face.height),
+    ])
+}
+
+// returns: the (padded) face boxes of the frame
+fn search(classifier: &mut CascadeClassifier, frame: &Mat) -> opencv::Result<Vec<Rect>> {
+    let mut gray = Mat::default();
+    match frame.channels() {
+        3 => imgproc::cvt_color_def(frame, &mut gray, imgproc::COLOR_BGR2GRAY)?,
+        4 => imgproc::cvt_color_def(frame, &mut gray, imgproc::COLOR_BGRA2GRAY)?,
+        _ => gray = frame.try_clone()?,
+    }
+    let scale = (FACE_SEARCH_WIDTH / frame.cols() as f64).min(1.0);
+    let mut small = Mat::default();
+    imgproc::resize(
+        &gray,
+        &mut small,
+        Size::default(),
+        scale,
+        scale,
+        imgproc::INTER_AREA,
+    )?;
+    let mut equalized = Mat::default();
+    imgproc::equalize_hist(&small, &mut equalized)?;
+    let mut found = Vector::<Rect>::new();
+    classifier.detect_multi_scale(
+        &equalized,
+        &mut found,
+        1.1,
+        3,
+        0,
+        Size::new(16, 16),
+        Size::default(),
+    )?;
+    Ok(found
+        .iter()
+        .map(|face| {
+            let pad_x = face.width as f64 * FACE_PADDING;
+            let pad_y = face.height as f64 * FACE_PADDING;
+            Rect::new(
+                ((face.x as f64 - pad_x) / scale).floor() as i32,
+                ((face.y as f64 - pad_y) / scale).floor() as i32,
+                ((face.width as f64 + 2.0 * pad_x) / scale).ceil() as i32,
+                ((face.height as f64 + 2.0 * pad_y) / scale).ceil() as i32,
+            )
+        })
+        .collect())
+}
+
+// Burns privacy masks into the frames of the source before they are encoded.
+// The static regions are configured per device, faces are blurred wherever they are found.
+// Frames that can not be masked are dropped, so unmasked pixels never reach a recording.
+pub struct PrivacyMask {
+    regions: HashMap<String, Vec<PrivacyRegion>>,
+    active: Mutex<Vec<PrivacyRegion>>,
+    faces: Option<FaceDetector>,
+}
+
+impl PrivacyMask {
+    // regions: the masked regions per device (e.g. video0)
+    // face_model: the cascade classifier used to blur faces (no face blurring without)
+    pub fn new(
+        regions: HashMap<String, Vec<PrivacyRegion>>,
+        face_model: Option<&str>,
+    ) -> Result<PrivacyMask, String> {
+        let faces = match face_model {
+            Some(model) => {
+                let classifier = CascadeClassifier::new(model).map_err(|e| e.to_string())?;
+                if classifier.empty().map_err(|e| e.to_string())? {
+                    return Err(format!("Unable to load the face model {model}"));
+                }
+                info!("Blurring faces found with {}", model);
+                Some(FaceDetector::new(classifier))
+            }
+            None => None,
+        };
+        Ok(PrivacyMask {
+            regions,
+            active: Mutex::new(Vec::new()),
+            faces,
+        })
+    }
+
+    // Selects the regions of the started device
+    // returns: true if the frames of the device are masked
+    pub fn select(&self, device: &str) -> bool {
+        let regions = self.regions.get(device).cloned().unwrap_or_default();
+        info!("{} privacy regions for {}", regions.len(), device);
+        *self.active.lock().unwrap() = regions;
+        self.is_enabled()
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.faces.is_some() || !self.active.lock().unwrap().is_empty()
+    }
+
+    // Masks the regions and faces of the frame in place
+    pub fn apply(&self, frame: &mut Mat) -> opencv::Result<()> {
+        let faces = match self.faces.as_ref() {
+            Some(detector) => detector.detect(frame)?,
+            None => Some(Vec::new()),
+        };
+        let Some(faces) = faces else {
+            // no recent face search, nothing of the frame is shown
+            let all = Rect::new(0, 0, frame.cols(), frame.rows());
+            return mask_polygon(frame, corners(&all), MaskMode::Black);
+        };
+        for region in self.active.lock().unwrap().iter() {
+            let polygon = region
+                .points
+                .iter()
+                .map(|(x, y)| {
+                    Point::new(
+                        (x.clamp(0.0, 1.0) * frame.cols() as f64).round() as i32,
+                        (y.clamp(0.0, 1.0) * frame.rows() as f64).round() as i32,
+                    )
+                })
+                .collect();
+            mask_polygon(frame, polygon, region.mode)?;
+        }
+        for face in faces {
+            mask_polygon(frame, corners(&face), MaskMode::Blur)?;
+        }
+        Ok(())
+    }
+
+    // Masks the buffers leaving the privacy element of the pipeline
+    // returns: false if the pipeline has no privacy element
+    pub fn attach(self: &Arc<Self>, pipeline: &gst::Bin) -> bool {
+        let Some(pad) = pipeline
+            .by_name(PRIVACY_ELEMENT)
+            .and_then(|element| element.static_pad("src"))
+        else {
+            return false;
+        };
+        let mask = self.clone();
+        pad.add_probe(gst::PadProbeType::BUFFER, move |pad, info| {
+            if !mask.is_enabled() {
+                return gst::PadProbeReturn::Ok;
+            }
+            match mask.apply_buffer(pad, info) {
+                Ok(_) => gst::PadProbeReturn::Ok,
+                Err(e) => {
+                    error!("Dropping a frame that can not be masked: {e}");
+                    gst::PadProbeReturn::Drop
+                }
+            }
+        });
+        true
+    }
+
+    fn apply_buffer(&self, pad: &gst::Pad, info: &mut gst::PadProbeInfo) -> Result<(), String> {
+        let caps = pad.current_caps().ok_or("No caps negotiated")?;
+        let format = FrameFormat::from_caps(&caps)?;
+        let Some(gst::PadProbeData::Buffer(buffer)) = info.data.as_mut() else {
+            return Ok(());
+        };
+        // the strides and offsets of a VideoMeta of the buffer take precedence over the caps
+        let mut video_frame =
+            VideoFrameRef::from_buffer_ref_writable(buffer.make_mut(), &format.info)
+                .map_err(|e| e.to_string())?;
+        let stride = video_frame.plane_stride()[0] as usize;
+        let data = video_frame.plane_data_mut(0).map_err(|e| e.to_string())?;
+        let mut frame = wrap(data, &format, stride)?;
+        self.apply(&mut frame).map_err(|e| e.to_string())
+    }
+}
+
+// Wraps the frame data in a Mat, the masks are drawn into the data
+// stride: the bytes of a row including the padding
+fn wrap(data: &mut [u8], format: &FrameFormat, stride: usize) -> Result<Mat, String> {
+    let (typ, channels) = match format.format.as_str() {
+        "GRAY8" => (CV_8UC1, 1),
+        "BGR" | "RGB" => (CV_8UC3, 3),
+        "BGRx" | "BGRA" | "RGBx" | "RGBA" => (CV_8UC4, 4),
+        // yuv frames are refused, masking their luma would leave the colors
+        _ => return Err(format!("Unsupported frame format: {}", format.format)),
+    };
+    // the last row is not padded
+    let size = stride * (format.height as usize - 1) + format.width as usize * channels;
+    if data.len() < size {
+        return Err(format!("Unexpected frame size: {} bytes", data.len()));
+    }
+    unsafe {
+        Mat::new_rows_cols_with_data_unsafe(
+            format.height,
+            format.width,
+            typ,
+            data.as_mut_ptr().cast(),
+            stride,
+        )
+    }
+    .map_err(|e| e.to_string())
+}
+
+// Blurs or blacks out a polygon (in pixels) of the frame
+fn mask_polygon(frame: &mut Mat, polygon: Vector<Point>, mode: MaskMode) -> opencv::Result<()> {
+    let bounds = imgproc::bounding_rect(&polygon)?;
+    let left = bounds.x.clamp(0, frame.cols());
+    let top = bounds.y.clamp(0, frame.rows());
+    let right = (bounds.x + bounds.width).clamp(0, frame.cols());
+    let bottom = (bounds.y + bounds.height).clamp(0, frame.rows());
+    if right <= left || bottom <= top {
+        return Ok(());
+    }
+    let roi = Rect::new(left, top, right - left, bottom - top);
+    let mut mask =
+        Mat::new_rows_cols_with_default(roi.height, roi.width, CV_8UC1, Scalar::all(0.0))?;
+    imgproc::fill_poly(
+        &mut mask,
+        &Vector::<Vector<Point>>::from_iter([polygon]),
+        Scalar::all(255.0),
+        imgproc::LINE_8,
+        0,
+        Point::new(-roi.x, -roi.y),
+    )?;
+    let mut area = frame.roi_mut(roi)?;
+    let hidden = match mode {
+        MaskMode::Black => {
+            Mat::new_rows_cols_with_default(roi.height, roi.width, area.typ(), Scalar::all(0.0))?
+        }
+        MaskMode::Blur => {
+            // the region is reduced to blocks, nothing recognizable is left
+            let blocks = Size::new(
+                (roi.width / BLUR_BLOCK).max(1),
+                (roi.height / BLUR_BLOCK).max(1),
+            );
+            let mut small = Mat::default();
+            imgproc::resize(&*area, &mut small, blocks, 0.0, 0.0, imgproc::INTER_AREA)?;
+            let mut blurred = Mat::default();
+            let size = roi.size();
+            imgproc::resize(&small, &mut blurred, size, 0.0, 0.0, imgproc::INTER_LINEAR)?;
+            blurred
+        }
+    };
+    hidden.copy_to_masked(&mut *area, &mask)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opencv::core::Vec3b;
+
+    // a frame with a fine checkerboard, blurring it leaves gray
+    fn checkerboard() -> Mat {
+        let mut frame = Mat::new_rows_cols_with_default(64, 64, CV_8UC3, Scalar::all(0.0)).unwrap();
+        for row in 0..64 {
+            for col in 0..64 {
+                let value = if (row + col) % 2 == 0 { 255 } else { 0 };
+                *frame.at_2d_mut::<Vec3b>(row, col).unwrap() = Vec3b::all(value);
+            }
+        }
+        frame
+    }
+
+    fn pixel(frame: &Mat, row: i32, col: i32) -> u8 {
+        frame.at_2d::<Vec3b>(row, col).unwrap()[0]
+    }
+
+    fn regions(mode: MaskMode) -> HashMap<String, Vec<PrivacyRegion>> {
+        HashMap::from([(
+            "video0".to_string(),
+            vec![PrivacyRegion {
+                points: vec![(0.0, 0.0), (0.5, 0.0), (0.5, 0.5), (0.0, 0.5)],
+                mode,
+            }],
+        )])
+    }
+
+    #[test]
+    fn test_select() {
+        let mask = PrivacyMask::new(regions(MaskMode::Black), None).unwrap();
+        assert!(!mask.is_enabled());
+        assert!(!mask.select("video1"));
+        assert!(mask.select("video0"));
+        assert!(PrivacyMask::new(HashMap::new(), Some("missing.xml")).is_err());
+    }
+
+    #[test]
+    fn test_black() {
+        let mask = PrivacyMask::new(regions(MaskMode::Black), None).unwrap();
+        mask.select("video0");
+        let mut frame = checkerboard();
+        mask.apply(&mut frame).unwrap();
+        assert_eq!(pixel(&frame, 0, 0), 0);
+        assert_eq!(pixel(&frame, 30, 30), 0);
+        assert_eq!(pixel(&frame, 40, 40), 255);
+        assert_eq!(pixel(&frame, 0, 40), 255);
+    }
+
+    #[test]
+    fn test_blur() {
+        let mask = PrivacyMask::new(regions(MaskMode::Blur), None).unwrap();
+        mask.select("video0");
+        let mut frame = checkerboard();
+        mask.apply(&mut frame).unwrap();
+        let blurred = pixel(&frame, 10, 10);
+        assert!(blurred > 100 && blurred < 155, "{blurred}");
+        assert_eq!(pixel(&frame, 40, 40), 255);
+        assert_eq!(pixel(&frame, 40, 41), 0);
+    }
+
+    #[test]
+    fn test_faces() {
+        // the empty classifier never finds a result, the frames are masked entirely
+        let mask = PrivacyMask {
+            regions: HashMap::new(),
+            active: Mutex::new(Vec::new()),
+            faces: Some(FaceDetector::new(CascadeClassifier::default().unwrap())),
+        };
+        for _ in 0..3 {
+            let mut frame = checkerboard();
+            mask.apply(&mut frame).unwrap();
+            assert_eq!(pixel(&frame, 40, 40), 0);
+            assert_eq!(pixel(&frame, 63, 63), 0);
+        }
+        let face = Rect::new(10, 10, 20, 10);
+        assert_eq!(grow(&face, 0.0), face);
+        assert_eq!(grow(&face, 0.25), Rect::new(5, 7, 30, 16));
+    }
+
+    #[test]
+    fn test_padded_rows() {
+        let caps = gst::Caps::builder("video/x-raw")
+            .field("format", "RGB")
+            .field("width", 5)
+            .field("height", 2)
+            .build();
+        let format = FrameFormat::from_caps(&caps).unwrap();
+        // e.g. the stride of a VideoMeta, wider than the default of the caps
+        let stride = 32;
+        let mut data = vec![255u8; stride * 2];
+        assert!(wrap(&mut data[..40], &format, stride).is_err());
+        let mut frame = wrap(&mut data, &format, stride).unwrap();
+        let polygon = [
+            Point::new(0, 1),
+            Point::new(5, 1),
+            Point::new(5, 2),
+            Point::new(0, 2),
+        ];
+        mask_polygon(&mut frame, Vector::from_iter(polygon), MaskMode::Black).unwrap();
+        // the second row starts after the padding of the first one
+        assert_eq!(data[..stride], vec![255u8; stride][..]);
+        assert_eq!(data[stride..stride + 15], [0u8; 15]);
+    }
+}
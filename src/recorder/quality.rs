@@ -0,0 +1,359 @@
+use crate::dtos::messages::{Event, EventKind, QualityIssue, QualityIssueKind};
+use crate::recorder::analyzer::{AnalyzerOutput, AnalyzerSession, Frame, FrameAnalyzer};
+use chrono::{DateTime, Duration, Local};
+use opencv::core::{self, Mat, Size};
+use opencv::imgproc;
+use opencv::prelude::*;
+
+// frames with a lower mean brightness (0 - 255) are black
+const BLACK_THRESHOLD: f64 = 16.0;
+// pixels at or above this brightness are clipped
+const CLIPPED_LEVEL: f64 = 250.0;
+// frames with a higher share of clipped pixels are overexposed
+const OVEREXPOSED_RATIO: f64 = 0.5;
+// the frames are compared at this size
+const COMPARE_SIZE: (i32, i32) = (160, 120);
+
+// How long a condition has to last before an alarm is raised
+#[derive(Clone, Debug)]
+pub struct QualityConfig {
+    // black and overexposed frames
+    pub alarm_sec: u32,
+    // frames without change
+    pub frozen_sec: u32,
+    // frames with a lower mean difference (0 - 255) to the previous frame are frozen,
+    // the sensor noise of a static scene in good light may stay below 1.0
+    pub frozen_threshold: f64,
+}
+
+impl Default for QualityConfig {
+    fn default() -> Self {
+        QualityConfig {
+            alarm_sec: 3,
+            frozen_sec: 10,
+            frozen_threshold: 1.0,
+        }
+    }
+}
+
+// A condition of the signal and the alarm raised for it
+struct Alarm {
+    kind: QualityIssueKind,
+    // the name of the analyzer raising the alarm
+    source: String,
+    min_duration: Duration,
+    // the first frame of the condition
+    since: Option<DateTime<Local>>,
+    active: bool,
+}
+
+impl Alarm {
+    fn new(kind: QualityIssueKind, source: &str, min_sec: u32) -> Alarm {
+        Alarm {
+            kind,
+            source: source.to_string(),
+            min_duration: Duration::seconds(min_sec as i64),
+            since: None,
+            active: false,
+        }
+    }
+
+    // Raises the alarm once the condition lasts long enough, ends it with the condition
+    // score: the measured value reported with the events
+    fn update(
+        &mut self,
+        condition: bool,
+        score: f64,
+        timestamp: DateTime<Local>,
+        output: &mut AnalyzerOutput,
+    ) {
+        if !condition {
+            self.end(timestamp, score, output);
+            return;
+        }
+        let since = *self.since.get_or_insert(timestamp);
+        if !self.active && timestamp - since >= self.min_duration {
+            self.active = true;
+            output.emit(self.event(EventKind::QualityAlarmStarted, since, score));
+        }
+    }
+
+    fn end(&mut self, timestamp: DateTime<Local>, score: f64, output: &mut AnalyzerOutput) {
+        let Some(since) = self.since.take() else {
+            return;
+        };
+        if !self.active {
+            return;
+        }
+        self.active = false;
+        output.emit(self.event(EventKind::QualityAlarmEnded, timestamp, score));
+        output.add_quality_issue(QualityIssue {
+            kind: self.kind.clone(),
+            from: since,
+            until: timestamp,
+        });
+    }
+
+    fn event(&self, kind: EventKind, timestamp: DateTime<Local>, score: f64) -> Event {
+        Event {
+            timestamp,
+            kind,
+            score: Some(score),
+            prefix: None,
+            source: Some(self.source.clone()),
+            detail: Some(self.kind.to_string()),
+        }
+    }
+}
+
+// Monitors the signal of a recording: raises an alarm for black, frozen (no change) and
+// overexposed (clipped) frames and adds the affected time ranges to the session
+pub struct QualityAnalyzer {
+    name: String,
+    alarms: Vec<Alarm>,
+    frozen_threshold: f64,
+    previous: Option<Mat>,
+    last_timestamp: Option<DateTime<Local>>,
+}
+
+impl QualityAnalyzer {
+    pub fn new(config: QualityConfig) -> QualityAnalyzer {
+        let name = "quality";
+        QualityAnalyzer {
+            name: name.to_string(),
+            alarms: vec![
+                Alarm::new(QualityIssueKind::Black, name, config.alarm_sec),
+                Alarm::new(QualityIssueKind::Frozen, name, config.frozen_sec),
+                Alarm::new(QualityIssueKind::Overexposed, name, config.alarm_sec),
+            ],
+            frozen_threshold: config.frozen_threshold,
+            previous: None,
+            last_timestamp: None,
+        }
+    }
+
+    // Only the black alarm, named black_frame (the former black frame analyzer)
+    pub fn black_frame(config: QualityConfig) -> QualityAnalyzer {
+        let name = "black_frame";
+        QualityAnalyzer {
+            name: name.to_string(),
+            alarms: vec![Alarm::new(QualityIssueKind::Black, name, config.alarm_sec)],
+            ..QualityAnalyzer::new(config)
+        }
+    }
+}
+
+impl FrameAnalyzer for QualityAnalyzer {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn start(&mut self, _session: &AnalyzerSession) {
+        for alarm in self.alarms.iter_mut() {
+            alarm.since = None;
+            alarm.active = false;
+        }
+        self.previous = None;
+        self.last_timestamp = None;
+    }
+
+    fn analyze(&mut self, frame: &Frame, output: &mut AnalyzerOutput) -> Result<(), String> {
+        let measurement =
+            Measurement::new(frame.image, self.previous.as_ref()).map_err(|e| e.to_string())?;
+        let timestamp = frame.timestamp;
+        for alarm in self.alarms.iter_mut() {
+            let (condition, score) = match alarm.kind {
+                QualityIssueKind::Black => (
+                    measurement.brightness < BLACK_THRESHOLD,
+                    measurement.brightness,
+                ),
+                QualityIssueKind::Frozen => match measurement.difference {
+                    Some(difference) => (difference < self.frozen_threshold, difference),
+                    None => continue,
+                },
+                QualityIssueKind::Overexposed => {
+                    (measurement.clipped > OVEREXPOSED_RATIO, measurement.clipped)
+                }
+            };
+            alarm.update(condition, score, timestamp, output);
+        }
+        self.previous = Some(measurement.small);
+        self.last_timestamp = Some(timestamp);
+        Ok(())
+    }
+
+    fn finish(&mut self, output: &mut AnalyzerOutput) -> Result<(), String> {
+        let timestamp = self.last_timestamp.unwrap_or_else(Local::now);
+        for alarm in self.alarms.iter_mut() {
+            alarm.end(timestamp, 0.0, output);
+        }
+        Ok(())
+    }
+}
+
+struct Measurement {
+    // the mean brightness (0 - 255)
+    brightness: f64,
+    // the share of clipped pixels
+    clipped: f64,
+    // the mean difference to the previous frame (0 - 255)
+    difference: Option<f64>,
+    // the downscaled grayscale image compared with the next frame
+    small: Mat,
+}
+
+impl Measurement {
+    // Measures a BGR frame
+    // previous: the downscaled grayscale image of the previous frame
+    fn new(image: &Mat, previous: Option<&Mat>) -> opencv::Result<Measurement> {
+        let mut gray = Mat::default();
+        imgproc::cvt_color_def(image, &mut gray, imgproc::COLOR_BGR2GRAY)?;
+        let mut small = Mat::default();
+        imgproc::resize(
+            &gray,
+            &mut small,
+            Size::new(COMPARE_SIZE.0, COMPARE_SIZE.1),
+            0.0,
+            0.0,
+            imgproc::INTER_AREA,
+        )?;
+        let brightness = core::mean(&gray, &core::no_array())?[0];
+        let mut clipped = Mat::default();
+        imgproc::threshold(
+            &gray,
+            &mut clipped,
+            CLIPPED_LEVEL - 1.0,
+            255.0,
+            imgproc::THRESH_BINARY,
+        )?;
+        let pixels = (gray.rows() * gray.cols()).max(1) as f64;
+        let clipped = core::count_non_zero(&clipped)? as f64 / pixels;
+        let difference = match previous {
+            Some(previous) => {
+                let mut diff = Mat::default();
+                core::absdiff(previous, &small, &mut diff)?;
+                Some(core::mean(&diff, &core::no_array())?[0])
+            }
+            None => None,
+        };
+        Ok(Measurement {
+            brightness,
+            clipped,
+            difference,
+            small,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recorder::analyzer::tests::{frame_at, solid};
+    use opencv::core::Scalar;
+
+    #[test]
+    fn test_quality_alarms() {
+        let mut analyzer = QualityAnalyzer::new(QualityConfig {
+            alarm_sec: 3,
+            frozen_sec: 10,
+            frozen_threshold: 1.0,
+        });
+        analyzer.start(&AnalyzerSession::default());
+        let mut output = AnalyzerOutput::default();
+        let start = Local::now();
+        let black = solid(64, 48, Scalar::all(0.0));
+        let gray = solid(64, 48, Scalar::all(128.0));
+        let white = solid(64, 48, Scalar::all(255.0));
+        // 5s black, 2s gray, 2s white (too short for an alarm)
+        let images = [&black; 5]
+            .into_iter()
+            .chain([&gray; 2])
+            .chain([&white; 2])
+            .chain([&gray]);
+        for (i, image) in images.enumerate() {
+            let frame = frame_at(image, None, start + Duration::seconds(i as i64));
+            analyzer.analyze(&frame, &mut output).unwrap();
+        }
+        analyzer.finish(&mut output).unwrap();
+        let kinds: Vec<EventKind> = output.take_events().into_iter().map(|e| e.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![EventKind::QualityAlarmStarted, EventKind::QualityAlarmEnded]
+        );
+        let issues = output.take_quality_issues();
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].kind, QualityIssueKind::Black);
+        assert_eq!(issues[0].until - issues[0].from, Duration::seconds(5));
+    }
+
+    #[test]
+    fn test_frozen() {
+        let mut analyzer = QualityAnalyzer::new(QualityConfig {
+            alarm_sec: 3,
+            frozen_sec: 2,
+            frozen_threshold: 1.0,
+        });
+        let mut output = AnalyzerOutput::default();
+        let start = Local::now();
+        let gray = solid(64, 48, Scalar::all(128.0));
+        for i in 0..4 {
+            let frame = frame_at(&gray, None, start + Duration::seconds(i));
+            analyzer.analyze(&frame, &mut output).unwrap();
+        }
+        let events = output.take_events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].detail, Some("frozen".to_string()));
+    }
+
+    #[test]
+    fn test_static_scene() {
+        // a static scene with little noise: half of the pixels change by one level
+        let still = solid(160, 120, Scalar::all(128.0));
+        let mut noisy = still.clone();
+        noisy
+            .roi_mut(opencv::core::Rect::new(0, 0, 160, 60))
+            .unwrap()
+            .set_to_def(&Scalar::all(129.0))
+            .unwrap();
+        let frozen_events = |frozen_threshold| {
+            let mut analyzer = QualityAnalyzer::new(QualityConfig {
+                alarm_sec: 3,
+                frozen_sec: 2,
+                frozen_threshold,
+            });
+            let mut output = AnalyzerOutput::default();
+            let start = Local::now();
+            for i in 0..4 {
+                let image = if i % 2 == 0 { &still } else { &noisy };
+                let frame = frame_at(image, None, start + Duration::seconds(i));
+                analyzer.analyze(&frame, &mut output).unwrap();
+            }
+            output.take_events().len()
+        };
+        // the noise (a mean difference of 0.5) stays below the default threshold
+        assert_eq!(frozen_events(1.0), 1);
+        assert_eq!(frozen_events(0.25), 0);
+    }
+
+    #[test]
+    fn test_black_frame() {
+        let mut analyzer = QualityAnalyzer::black_frame(QualityConfig {
+            alarm_sec: 1,
+            frozen_sec: 1,
+            frozen_threshold: 1.0,
+        });
+        assert_eq!(analyzer.name(), "black_frame");
+        let mut output = AnalyzerOutput::default();
+        let start = Local::now();
+        let black = solid(64, 48, Scalar::all(0.0));
+        for i in 0..3 {
+            let frame = frame_at(&black, None, start + Duration::seconds(i));
+            analyzer.analyze(&frame, &mut output).unwrap();
+        }
+        // the frozen black frames only raise the black alarm
+        let events = output.take_events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].detail, Some("black".to_string()));
+        assert_eq!(events[0].source, Some("black_frame".to_string()));
+    }
+}
@@ -0,0 +1,625 @@
+use crate::dtos::messages::{IncidentInfo, TIMESTAMP_FORMAT};
+use crate::recorder::common::PipelineError;
+use crate::recorder::incident::Incident;
+use crate::recorder::playlist::{DISCONTINUITY, EXTINF, MEDIA_SEQUENCE, PROGRAM_DATE_TIME};
+use chrono::{DateTime, Local};
+use log::{debug, error, info};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::Path;
+use std::time::Duration;
+
+// without a retention policy the segments are only tracked for protect requests
+const TRACKING_WINDOW: Duration = Duration::from_secs(600);
+
+// How much of a loop recording is kept on disk
+#[derive(Clone, Debug, Default)]
+pub struct RetentionPolicy {
+    pub max_age: Option<Duration>,
+    pub max_bytes: Option<u64>,
+}
+
+struct RetainedSegment {
+    // the session of the segment
+    prefix: String,
+    index: u32,
+    location: String,
+    bytes: u64,
+    duration: Duration,
+    program_date_time: DateTime<Local>,
+    protected: bool,
+}
+
+// Deletes the oldest segments of a loop recording together with their sprites and tooltips.
+// Segments around an incident are protected: they are copied into an incident playlist and never deleted.
+// The session layout is {prefix}-{chunk_prefix}_{index}.ts, {prefix}-sprite_{index}.jpg,
+// {prefix}-tooltips_{index}.jpg and {prefix}-thumbnails.vtt in the output directory.
+// The retention window spans the sessions: the segments of earlier sessions (also the ones
+// of the loop sessions found in the output directory at the first start) are deleted once they
+// leave the window, the playlists of earlier sessions are pruned (removed with the session
+// manifest once all their segments are gone).
+#[derive(Default)]
+pub struct RetentionManager {
+    policy: Option<RetentionPolicy>,
+    output_dir: String,
+    prefix: String,
+    segments: VecDeque<RetainedSegment>,
+    total_bytes: u64,
+    incidents: Vec<Incident>,
+    // the incidents of the session
+    incident_count: u32,
+}
+
+impl RetentionManager {
+    pub fn new(policy: Option<RetentionPolicy>) -> RetentionManager {
+        RetentionManager {
+            policy,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.policy.is_some()
+    }
+
+    // Starts a new session
+    // output_dir: the directory holding the session files
+    // prefix: the timestamp prefix of the session files
+    pub fn reset(&mut self, output_dir: &str, prefix: &str) {
+        self.finish_incidents();
+        if self.policy.is_none() {
+            self.segments.clear();
+            self.total_bytes = 0;
+        } else if self.output_dir.is_empty() {
+            self.load_earlier(output_dir);
+        }
+        self.output_dir = output_dir.to_string();
+        self.prefix = prefix.to_string();
+        self.incident_count = 0;
+    }
+
+    // Picks up the segments of the loop sessions of earlier runs (see their session manifests),
+    // so they are deleted once they leave the window. The segments of other recordings are kept.
+    // Their start is approximated by the time they have been written.
+    fn load_earlier(&mut self, output_dir: &str) {
+        let Ok(entries) = std::fs::read_dir(output_dir) else {
+            return;
+        };
+        let paths = entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .collect::<Vec<_>>();
+        let loop_sessions = paths
+            .iter()
+            .filter(|path| path.to_string_lossy().ends_with("-session.json"))
+            .filter_map(|path| {
+                let json = std::fs::read_to_string(path).ok()?;
+                let manifest = serde_json::from_str::<serde_json::Value>(&json).ok()?;
+                if manifest["loop_recording"] != true {
+                    return None;
+                }
+                manifest["prefix"].as_str().map(str::to_string)
+            })
+            .collect::<HashSet<String>>();
+        let mut segments = paths
+            .iter()
+            .filter_map(|path| {
+                if path.extension().and_then(|e| e.to_str()) != Some("ts") {
+                    return None;
+                }
+                let location = path.to_str()?.to_string();
+                let (prefix, _) = path.file_stem()?.to_str()?.rsplit_once('-')?;
+                if !loop_sessions.contains(prefix) {
+                    return None;
+                }
+                let index = segment_index(&location)?;
+                let metadata = path.metadata().ok()?;
+                Some(RetainedSegment {
+                    prefix: prefix.to_string(),
+                    index,
+                    location,
+                    bytes: metadata.len(),
+                    duration: Duration::ZERO,
+                    program_date_time: DateTime::<Local>::from(metadata.modified().ok()?),
+                    protected: false,
+                })
+            })
+            .collect::<Vec<RetainedSegment>>();
+        if segments.is_empty() {
+            return;
+        }
+        segments.sort_by_key(|s| s.program_date_time);
+        info!(
+            "Retention: found {} segments of earlier recordings",
+            segments.len()
+        );
+        self.total_bytes += segments.iter().map(|s| s.bytes).sum::<u64>();
+        self.segments.extend(segments);
+    }
+
+    // Adds a finished segment and deletes the segments outside the retention window
+    // location: the segment file
+    // duration: the duration of the segment
+    // program_date_time: the wall-clock time of the start of the segment
+    // returns: the locations of the segments removed from the window
+    pub fn add_segment(
+        &mut self,
+        location: &str,
+        duration: Duration,
+        program_date_time: DateTime<Local>,
+    ) -> Vec<String> {
+        let Some(index) = segment_index(location) else {
+            error!("Unable to get the segment index of {}", location);
+            return Vec::new();
+        };
+        let bytes = std::fs::metadata(location)
+            .map(|m| m.len())
+            .unwrap_or_default();
+        let mut protected = false;
+        for incident in self.incidents.iter_mut() {
+            if incident.overlaps(&program_date_time, duration) {
+                incident.add_segment(location, index, duration, program_date_time);
+                protected = true;
+            }
+        }
+        self.segments.push_back(RetainedSegment {
+            prefix: self.prefix.clone(),
+            index,
+            location: location.to_string(),
+            bytes,
+            duration,
+            program_date_time,
+            protected,
+        });
+        self.total_bytes += bytes;
+        let (finished, pending): (Vec<Incident>, Vec<Incident>) = self
+            .incidents
+            .drain(..)
+            .partition(|i| i.ends_before(&program_date_time, duration));
+        self.incidents = pending;
+        for incident in finished {
+            incident.finish(|index| self.thumbnail_files(index));
+        }
+
+        let Some(policy) = self.policy.clone() else {
+            while self.age() > TRACKING_WINDOW {
+                let segment = self.segments.pop_front().unwrap();
+                self.total_bytes -= segment.bytes;
+            }
+            return Vec::new();
+        };
+        let mut evicted = Vec::new();
+        // the newest segment is always kept
+        while self.segments.len() > 1 && self.exceeds(&policy) {
+            let segment = self.segments.pop_front().unwrap();
+            self.total_bytes -= segment.bytes;
+            if segment.protected {
+                debug!("Retention: keeping protected segment {}", segment.location);
+            } else {
+                self.delete(&segment);
+            }
+            evicted.push(segment);
+        }
+        if !evicted.is_empty() {
+            self.prune_thumbnails(&evicted);
+            self.prune_playlists(&evicted);
+            info!(
+                "Retention: removed {} segments, keeping {} segments ({} bytes, {}s)",
+                evicted.len(),
+                self.segments.len(),
+                self.total_bytes,
+                self.age().as_secs()
+            );
+        }
+        evicted.into_iter().map(|s| s.location).collect()
+    }
+
+    // Protects the footage around now from deletion and copies it into an incident playlist.
+    // Segments are added to the incident until the protected window has passed.
+    // now: the time of the incident
+    // before, after: the protected window around now
+    // name: an optional description of the incident
+    pub fn protect(
+        &mut self,
+        now: DateTime<Local>,
+        before: Duration,
+        after: Duration,
+        name: Option<String>,
+    ) -> Result<IncidentInfo, PipelineError> {
+        if self.prefix.is_empty() {
+            return Err(PipelineError::NotRunning);
+        }
+        let from = now - chrono::Duration::from_std(before).unwrap_or(chrono::Duration::zero());
+        let until = now + chrono::Duration::from_std(after).unwrap_or(chrono::Duration::zero());
+        // the counter keeps the ids of incidents within the same second unique
+        self.incident_count += 1;
+        let id = format!("{}-{}", now.format(TIMESTAMP_FORMAT), self.incident_count);
+        let mut incident = Incident::new(&self.output_dir, &self.prefix, &id, name, from, until)
+            .map_err(|e| {
+                error!("Unable to create incident {}: {e}", id);
+                PipelineError::StorageError
+            })?;
+        // only the current session is protected, the segment indices start over in every session
+        for segment in self.segments.iter_mut().filter(|s| s.prefix == self.prefix) {
+            if incident.overlaps(&segment.program_date_time, segment.duration) {
+                segment.protected = true;
+                incident.add_segment(
+                    &segment.location,
+                    segment.index,
+                    segment.duration,
+                    segment.program_date_time,
+                );
+            }
+        }
+        let info = incident.info().clone();
+        self.incidents.push(incident);
+        Ok(info)
+    }
+
+    // Ends the pending incidents (e.g. when the recording stops)
+    pub fn finish_incidents(&mut self) {
+        let incidents = self.incidents.drain(..).collect::<Vec<Incident>>();
+        for incident in incidents {
+            incident.finish(|index| self.thumbnail_files(index));
+        }
+    }
+
+    // The time between the start of the oldest and the end of the newest segment
+    fn age(&self) -> Duration {
+        match (self.segments.front(), self.segments.back()) {
+            (Some(oldest), Some(newest)) => {
+                (newest.program_date_time - oldest.program_date_time)
+                    .to_std()
+                    .unwrap_or_default()
+                    + newest.duration
+            }
+            _ => Duration::ZERO,
+        }
+    }
+
+    fn exceeds(&self, policy: &RetentionPolicy) -> bool {
+        policy.max_age.map(|max| self.age() > max).unwrap_or(false)
+            || policy
+                .max_bytes
+                .map(|max| self.total_bytes > max)
+                .unwrap_or(false)
+    }
+
+    fn thumbnail_files(&self, index: u32) -> Vec<String> {
+        session_thumbnail_files(&self.output_dir, &self.prefix, index)
+    }
+
+    fn delete(&self, segment: &RetainedSegment) {
+        let mut files = vec![segment.location.clone()];
+        files.extend(session_thumbnail_files(
+            &self.output_dir,
+            &segment.prefix,
+            segment.index,
+        ));
+        for file in files {
+            match std::fs::remove_file(&file) {
+                Ok(_) => debug!("Retention: deleted {}", file),
+                Err(e) => debug!("Retention: unable to delete {}: {e}", file),
+            }
+        }
+    }
+
+    // Removes the cues pointing to deleted tooltips from the thumbnail vtt of their session
+    fn prune_thumbnails(&self, evicted: &[RetainedSegment]) {
+        let mut tooltips = HashMap::<&str, HashSet<String>>::new();
+        for segment in evicted {
+            tooltips.entry(&segment.prefix).or_default().insert(format!(
+                "{}-tooltips_{:05}.jpg",
+                segment.prefix, segment.index
+            ));
+        }
+        for (prefix, tooltips) in tooltips {
+            let vtt_location = format!("{}/{}-thumbnails.vtt", self.output_dir, prefix);
+            let Ok(vtt) = std::fs::read_to_string(&vtt_location) else {
+                continue;
+            };
+            let pruned = prune_vtt(&vtt, &tooltips);
+            let tmp = format!("{}.tmp", vtt_location);
+            if let Err(e) =
+                std::fs::write(&tmp, pruned).and_then(|_| std::fs::rename(&tmp, &vtt_location))
+            {
+                error!("Unable to prune {}: {e}", vtt_location);
+            }
+        }
+    }
+
+    // Removes the deleted segments from the playlists of earlier sessions (the playlist of the
+    // current session is a window kept by the annotator). A session without segments left is
+    // removed: its playlist, its thumbnails vtt and its manifest.
+    fn prune_playlists(&self, evicted: &[RetainedSegment]) {
+        let mut deleted = HashMap::<&str, HashSet<String>>::new();
+        for segment in evicted
+            .iter()
+            .filter(|s| !s.protected && s.prefix != self.prefix)
+        {
+            if let Some(name) = Path::new(&segment.location).file_name() {
+                deleted
+                    .entry(&segment.prefix)
+                    .or_default()
+                    .insert(name.to_string_lossy().to_string());
+            }
+        }
+        for (prefix, deleted) in deleted {
+            let location = format!("{}/{}-playlist.m3u8", self.output_dir, prefix);
+            let Ok(playlist) = std::fs::read_to_string(&location) else {
+                continue;
+            };
+            let (pruned, remaining) = prune_playlist(&playlist, &deleted);
+            if remaining == 0 {
+                info!(
+                    "Retention: removing session {}, its segments are gone",
+                    prefix
+                );
+                for file in ["playlist.m3u8", "thumbnails.vtt", "session.json"] {
+                    let file = format!("{}/{}-{}", self.output_dir, prefix, file);
+                    if let Err(e) = std::fs::remove_file(&file) {
+                        debug!("Retention: unable to delete {}: {e}", file);
+                    }
+                }
+                continue;
+            }
+            let tmp = format!("{}.tmp", location);
+            if let Err(e) =
+                std::fs::write(&tmp, pruned).and_then(|_| std::fs::rename(&tmp, &location))
+            {
+                error!("Unable to prune {}: {e}", location);
+            }
+        }
+    }
+}
+
+fn session_thumbnail_files(output_dir: &str, prefix: &str, index: u32) -> Vec<String> {
+    vec![
+        format!("{}/{}-sprite_{:05}.jpg", output_dir, prefix, index),
+        format!("{}/{}-tooltips_{:05}.jpg", output_dir, prefix, index),
+    ]
+}
+
+// Parses the index of a segment from its location ({prefix}-{chunk_prefix}_{index}.ts)
+pub fn segment_index(location: &str) -> Option<u32> {
+    Path::new(location)
+        .file_stem()?
+        .to_str()?
+        .rsplit('_')
+        .next()?
+        .parse::<u32>()
+        .ok()
+}
+
+// Drops the given segments and their tags from a playlist, the media sequence is advanced by
+// the dropped segments (the oldest ones)
+// returns: the playlist and the number of segments left
+fn prune_playlist(playlist: &str, segments: &HashSet<String>) -> (String, usize) {
+    let mut lines = Vec::new();
+    // the tags of the next segment
+    let mut tags = Vec::new();
+    let mut remaining = 0;
+    for line in playlist.lines() {
+        if line.starts_with(EXTINF) || line.starts_with(PROGRAM_DATE_TIME) || line == DISCONTINUITY
+        {
+            tags.push(line);
+        } else if line.is_empty() || line.starts_with('#') {
+            lines.push(line.to_string());
+        } else if segments.contains(line) {
+            tags.clear();
+        } else {
+            lines.extend(tags.drain(..).map(str::to_string));
+            lines.push(line.to_string());
+            remaining += 1;
+        }
+    }
+    lines.extend(tags.drain(..).map(str::to_string));
+    let dropped = playlist.lines().filter(|l| segments.contains(*l)).count() as u64;
+    let mut pruned = lines
+        .into_iter()
+        .map(
+            |line| match line.strip_prefix(&format!("{}:", MEDIA_SEQUENCE)) {
+                Some(sequence) => {
+                    let sequence = sequence.parse::<u64>().unwrap_or_default() + dropped;
+                    format!("{}:{}", MEDIA_SEQUENCE, sequence)
+                }
+                None => line,
+            },
+        )
+        .collect::<Vec<String>>()
+        .join("\n");
+    pruned.push('\n');
+    (pruned, remaining)
+}
+
+// Drops the cues (blocks separated by empty lines) referencing one of the given images
+fn prune_vtt(vtt: &str, images: &HashSet<String>) -> String {
+    let mut pruned = vtt
+        .split("\n\n")
+        .filter(|cue| {
+            !cue.lines().any(|line| {
+                line.split('#')
+                    .next()
+                    .map(|image| images.contains(image))
+                    .unwrap_or(false)
+            })
+        })
+        .collect::<Vec<&str>>()
+        .join("\n\n");
+    if vtt.ends_with("\n\n") && !pruned.ends_with("\n\n") {
+        pruned.push_str("\n\n");
+    }
+    pruned
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retention() {
+        let dir = "/tmp/retention-test";
+        let _ = std::fs::create_dir_all(dir);
+        let mut manager = RetentionManager::new(Some(RetentionPolicy {
+            max_age: Some(Duration::from_secs(12)),
+            max_bytes: None,
+        }));
+        manager.reset(dir, "20241211-083017");
+        let mut evicted = Vec::new();
+        let start = Local::now();
+        for i in 0..4 {
+            let location = format!("{}/20241211-083017-chunk_{:05}.ts", dir, i);
+            std::fs::write(&location, "segment").unwrap();
+            let pdt = start + chrono::Duration::seconds(6 * i);
+            evicted.extend(manager.add_segment(&location, Duration::from_secs(6), pdt));
+        }
+        assert_eq!(
+            evicted,
+            vec![
+                format!("{}/20241211-083017-chunk_00000.ts", dir),
+                format!("{}/20241211-083017-chunk_00001.ts", dir)
+            ]
+        );
+        assert!(!Path::new(&evicted[0]).exists());
+        assert!(Path::new(&format!("{}/20241211-083017-chunk_00003.ts", dir)).exists());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_earlier_sessions() {
+        let dir = "/tmp/retention-sessions-test";
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir_all(dir).unwrap();
+        // two segments, a sprite, the playlist and the manifest left by an earlier loop session
+        let earlier = |i| format!("{}/20241210-083017-chunk_{:05}.ts", dir, i);
+        std::fs::write(earlier(0), "segment").unwrap();
+        std::fs::write(earlier(1), "segment").unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(earlier(0))
+            .unwrap()
+            .set_modified(std::time::SystemTime::now() - Duration::from_secs(60))
+            .unwrap();
+        let sprite = format!("{}/20241210-083017-sprite_00000.jpg", dir);
+        std::fs::write(&sprite, "sprite").unwrap();
+        let playlist = format!("{}/20241210-083017-playlist.m3u8", dir);
+        std::fs::write(
+            &playlist,
+            "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:6\n#EXT-X-MEDIA-SEQUENCE:0\n\
+            #EXT-X-PROGRAM-DATE-TIME:2024-12-10T08:30:17.000+01:00\n#EXTINF:6.000,\n\
+            20241210-083017-chunk_00000.ts\n#EXTINF:6.000,\n20241210-083017-chunk_00001.ts\n",
+        )
+        .unwrap();
+        let manifest = format!("{}/20241210-083017-session.json", dir);
+        std::fs::write(
+            &manifest,
+            r#"{"prefix": "20241210-083017", "loop_recording": true}"#,
+        )
+        .unwrap();
+        // a normal recording is never deleted
+        let recording = format!("{}/20241209-083017-chunk_00000.ts", dir);
+        std::fs::write(&recording, "segment").unwrap();
+        std::fs::write(
+            format!("{}/20241209-083017-session.json", dir),
+            r#"{"prefix": "20241209-083017", "loop_recording": false}"#,
+        )
+        .unwrap();
+        let mut manager = RetentionManager::new(Some(RetentionPolicy {
+            max_age: None,
+            max_bytes: Some(14),
+        }));
+        manager.reset(dir, "20241211-083017");
+        let start = Local::now();
+        let first = format!("{}/20241211-083017-chunk_00000.ts", dir);
+        std::fs::write(&first, "segment").unwrap();
+        let evicted = manager.add_segment(&first, Duration::from_secs(6), start);
+        assert_eq!(evicted, vec![earlier(0)]);
+        assert!(!Path::new(&earlier(0)).exists());
+        assert!(!Path::new(&sprite).exists());
+        let pruned = std::fs::read_to_string(&playlist).unwrap();
+        assert_eq!(
+            pruned,
+            "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:6\n#EXT-X-MEDIA-SEQUENCE:1\n\
+            #EXTINF:6.000,\n20241210-083017-chunk_00001.ts\n"
+        );
+        // the next session keeps counting the segments of the first one
+        manager.reset(dir, "20241211-090000");
+        let second = format!("{}/20241211-090000-chunk_00000.ts", dir);
+        std::fs::write(&second, "segment").unwrap();
+        let pdt = start + chrono::Duration::seconds(6);
+        let evicted = manager.add_segment(&second, Duration::from_secs(6), pdt);
+        assert_eq!(evicted, vec![earlier(1)]);
+        // the earlier session is gone with its last segment
+        assert!(!Path::new(&playlist).exists());
+        assert!(!Path::new(&manifest).exists());
+        let third = format!("{}/20241211-090000-chunk_00001.ts", dir);
+        std::fs::write(&third, "segment").unwrap();
+        let pdt = start + chrono::Duration::seconds(12);
+        let evicted = manager.add_segment(&third, Duration::from_secs(6), pdt);
+        assert_eq!(evicted, vec![first.clone()]);
+        assert!(!Path::new(&first).exists());
+        assert!(Path::new(&second).exists());
+        assert!(Path::new(&recording).exists());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_protect() {
+        let dir = "/tmp/protect-test";
+        let _ = std::fs::remove_dir_all(dir);
+        let _ = std::fs::create_dir_all(dir);
+        let mut manager = RetentionManager::new(Some(RetentionPolicy {
+            max_age: Some(Duration::from_secs(6)),
+            max_bytes: None,
+        }));
+        manager.reset(dir, "20241211-083017");
+        let start = Local::now();
+        let location = |i: i64| format!("{}/20241211-083017-chunk_{:05}.ts", dir, i);
+        std::fs::write(location(0), "segment").unwrap();
+        manager.add_segment(&location(0), Duration::from_secs(6), start);
+        let info = manager
+            .protect(
+                start + chrono::Duration::seconds(5),
+                Duration::from_secs(2),
+                Duration::from_secs(2),
+                Some("test".to_string()),
+            )
+            .unwrap();
+        // a second incident at the same time gets its own playlist
+        let second = manager
+            .protect(
+                start + chrono::Duration::seconds(5),
+                Duration::from_secs(0),
+                Duration::from_secs(0),
+                None,
+            )
+            .unwrap();
+        assert_ne!(second.id, info.id);
+        assert_ne!(second.playlist, info.playlist);
+        for i in 1..4 {
+            std::fs::write(location(i), "segment").unwrap();
+            let pdt = start + chrono::Duration::seconds(6 * i);
+            manager.add_segment(&location(i), Duration::from_secs(6), pdt);
+        }
+        // protected segments survive the retention window, the others are deleted
+        assert!(Path::new(&location(0)).exists());
+        assert!(Path::new(&location(1)).exists());
+        assert!(!Path::new(&location(2)).exists());
+        assert!(Path::new(&location(3)).exists());
+        let playlist = std::fs::read_to_string(&info.playlist).unwrap();
+        assert!(playlist.contains("20241211-083017-chunk_00000.ts"));
+        assert!(playlist.contains("20241211-083017-chunk_00001.ts"));
+        assert!(playlist.contains("#EXT-X-ENDLIST"));
+        assert!(playlist.contains("\n#EXT-X-TARGETDURATION:6\n"));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_prune_vtt() {
+        let vtt = "WEBVTT\n\n1\n00:00:00.000 --> 00:00:01.000\nx-tooltips_00000.jpg#xywh=0,0,720,480\n\n2\n00:00:06.000 --> 00:00:07.000\nx-tooltips_00001.jpg#xywh=0,0,720,480\n\n";
+        let images = HashSet::from(["x-tooltips_00000.jpg".to_string()]);
+        assert_eq!(
+            prune_vtt(vtt, &images),
+            "WEBVTT\n\n2\n00:00:06.000 --> 00:00:07.000\nx-tooltips_00001.jpg#xywh=0,0,720,480\n\n"
+        );
+    }
+}
@@ -0,0 +1,244 @@
+use crate::dtos::messages::{
+    Artifact, IncidentInfo, MotionInterval, QualityIssue, RecordingRequest, SessionManifest,
+    StopReason,
+};
+use chrono::{DateTime, Local};
+use log::{error, info};
+use std::time::Duration;
+
+// Book-keeping of a recording session, persisted as {timestamp}-session.json
+#[derive(Default)]
+pub struct Session {
+    manifest: SessionManifest,
+    manifest_location: String,
+    // the sessions started so far, tells the message loops of earlier sessions apart
+    generation: u64,
+}
+
+impl Session {
+    pub fn new() -> Session {
+        Session::default()
+    }
+
+    // Starts a new session
+    // output_dir: the directory holding the recording
+    // prefix: the timestamp prefix of the session files
+    // started_at: the start of the recording
+    // request: name, profile and the conditions that end the session automatically
+    // loop_recording: the retention manager deletes the segments of the session
+    pub fn start(
+        &mut self,
+        output_dir: &str,
+        prefix: &str,
+        started_at: &DateTime<Local>,
+        request: RecordingRequest,
+        loop_recording: bool,
+    ) {
+        self.manifest = SessionManifest {
+            prefix: prefix.to_string(),
+            name: request.name,
+            profile: request.profile,
+            playlist: format!("{}-playlist.m3u8", prefix),
+            started_at: *started_at,
+            limits: request.limits,
+            loop_recording,
+            ..Default::default()
+        };
+        self.manifest_location = format!("{}/{}-session.json", output_dir, prefix);
+        self.generation += 1;
+        self.write();
+    }
+
+    // The number of the running (or last) session
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    // Accounts a finished segment
+    // location: the segment file
+    // duration: the duration of the segment
+    pub fn add_segment(&mut self, location: &str, duration: Duration) {
+        self.manifest.segments += 1;
+        self.manifest.bytes += std::fs::metadata(location)
+            .map(|m| m.len())
+            .unwrap_or_default();
+        self.manifest.duration_sec += duration.as_secs_f64();
+        self.write();
+    }
+
+    // Records an incident (protected footage) of the session
+    pub fn add_incident(&mut self, incident: IncidentInfo) {
+        self.manifest.incidents.push(incident);
+        self.write();
+    }
+
+    // Records a period with motion
+    pub fn add_motion_interval(&mut self, interval: MotionInterval) {
+        self.manifest.motion_intervals.push(interval);
+        self.write();
+    }
+
+    // Records a file written by a frame analyzer
+    // the poster and the preview are the cover of the session
+    pub fn add_artifact(&mut self, artifact: Artifact) {
+        match artifact.kind.as_str() {
+            "poster" => self.manifest.poster = Some(artifact.file.clone()),
+            "preview" => self.manifest.preview = Some(artifact.file.clone()),
+            _ => (),
+        }
+        self.manifest.artifacts.push(artifact);
+        self.write();
+    }
+
+    // Records a period with a bad signal
+    pub fn add_quality_issue(&mut self, issue: QualityIssue) {
+        self.manifest.quality_issues.push(issue);
+        self.write();
+    }
+
+    // True between start and finish
+    pub fn is_active(&self) -> bool {
+        !self.manifest_location.is_empty() && self.manifest.ended_at.is_none()
+    }
+
+    // Checks the limits of the session
+    // now: the wall-clock time of the last segment
+    // returns: the reason to stop the recording if a limit has been reached
+    pub fn check_limits(&self, now: &DateTime<Local>) -> Option<StopReason> {
+        let limits = &self.manifest.limits;
+        if let Some(max) = limits.max_duration_sec {
+            if self.manifest.duration_sec >= max as f64 {
+                return Some(StopReason::MaxDuration);
+            }
+        }
+        if let Some(max) = limits.max_bytes {
+            if self.manifest.bytes >= max {
+                return Some(StopReason::MaxBytes);
+            }
+        }
+        if let Some(stop_at) = limits.stop_at {
+            if *now >= stop_at {
+                return Some(StopReason::StopAt);
+            }
+        }
+        None
+    }
+
+    // The wall-clock time the session ends at even if no segment arrives (paused or stalled)
+    // returns: the earliest of stop_at and the start plus max_duration_sec
+    pub fn deadline(&self) -> Option<(DateTime<Local>, StopReason)> {
+        let limits = &self.manifest.limits;
+        let max_duration = limits.max_duration_sec.map(|max| {
+            let at = self.manifest.started_at + chrono::Duration::seconds(max as i64);
+            (at, StopReason::MaxDuration)
+        });
+        let stop_at = limits.stop_at.map(|at| (at, StopReason::StopAt));
+        max_duration
+            .into_iter()
+            .chain(stop_at)
+            .min_by_key(|(at, _)| *at)
+    }
+
+    // True if the session is ending (a stop reason has been recorded) or has ended
+    pub fn is_stopping(&self) -> bool {
+        self.manifest.stop_reason.is_some()
+    }
+
+    // Records the reason the session is going to end (the first one wins)
+    pub fn set_stop_reason(&mut self, reason: StopReason) {
+        if self.manifest.stop_reason.is_none() {
+            self.manifest.stop_reason = Some(reason);
+        }
+    }
+
+    // Ends the session and writes the final manifest
+    // reason: used if no other reason has been recorded before
+    pub fn finish(&mut self, reason: StopReason) {
+        if self.manifest_location.is_empty() || self.manifest.ended_at.is_some() {
+            return;
+        }
+        self.set_stop_reason(reason);
+        self.manifest.ended_at = Some(Local::now());
+        info!(
+            "Session {} ended: {:?}",
+            self.manifest.prefix, self.manifest.stop_reason
+        );
+        self.write();
+    }
+
+    pub fn manifest(&self) -> &SessionManifest {
+        &self.manifest
+    }
+
+    fn write(&self) {
+        if self.manifest_location.is_empty() {
+            return;
+        }
+        match serde_json::to_string_pretty(&self.manifest) {
+            Ok(json) => {
+                if let Err(e) = std::fs::write(&self.manifest_location, json) {
+                    error!("Unable to write {}: {e}", self.manifest_location);
+                }
+            }
+            Err(e) => error!("Unable to serialize session manifest: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dtos::messages::RecordingLimits;
+    use std::fs::remove_file;
+
+    #[test]
+    fn test_limits() {
+        let mut session = Session::new();
+        let now = Local::now();
+        session.start(
+            "/tmp",
+            "session-test",
+            &now,
+            RecordingRequest {
+                limits: RecordingLimits {
+                    max_duration_sec: Some(12),
+                    max_bytes: None,
+                    stop_at: Some(now + chrono::Duration::hours(1)),
+                },
+                ..Default::default()
+            },
+            false,
+        );
+        assert_eq!(
+            session.deadline(),
+            Some((now + chrono::Duration::seconds(12), StopReason::MaxDuration))
+        );
+        session.add_segment("/tmp/does-not-exist.ts", Duration::from_secs(6));
+        assert_eq!(session.check_limits(&now), None);
+        session.add_segment("/tmp/does-not-exist.ts", Duration::from_secs(6));
+        assert_eq!(session.check_limits(&now), Some(StopReason::MaxDuration));
+        assert!(!session.is_stopping());
+        session.set_stop_reason(StopReason::MaxDuration);
+        assert!(session.is_stopping());
+        assert_eq!(session.is_active(), true);
+        session.finish(StopReason::Manual);
+        assert_eq!(session.is_active(), false);
+        assert_eq!(
+            session.manifest().stop_reason,
+            Some(StopReason::MaxDuration)
+        );
+        assert_eq!(session.manifest().segments, 2);
+        let generation = session.generation();
+        session.start(
+            "/tmp",
+            "session-test",
+            &now,
+            RecordingRequest::default(),
+            true,
+        );
+        assert!(session.manifest().loop_recording);
+        assert_eq!(session.generation(), generation + 1);
+        assert!(!session.is_stopping());
+        remove_file("/tmp/session-test-session.json").unwrap();
+    }
+}
@@ -1,17 +1,78 @@
-use crate::dtos::messages::StillInfo;
+use crate::dtos::messages::{StillEncoding, StillFormat, StillInfo};
 use crate::recorder;
+use crate::recorder::branch::DynamicBranch;
+use crate::recorder::exif::{self, StillMetadata};
+use crate::recorder::framehandler::{to_bgr, FrameFormat};
+use futures::channel::mpsc::UnboundedReceiver;
+use futures::channel::oneshot;
+use futures::future::{self, Either};
+use futures::StreamExt;
 use gst::prelude::*;
-use gstreamer_app::gst;
+use gstreamer_app::{gst, AppSink};
 use log::{debug, error, info};
+use opencv::core::{Mat, Rect, Size, Vector};
+use opencv::prelude::*;
+use opencv::{imgcodecs, imgproc};
 use recorder::common::PipelineError;
+use std::sync::{Arc, Condvar, Mutex};
 use std::{thread, time};
 
 const VIDEO_SOURCE: &str = "video-source";
 const VIDEO_SINK: &str = "video-sink";
+const STILL_SINK: &str = "still-sink";
+// how long a live still waits for a frame newer than the previous still
+const FRAME_TIMEOUT: time::Duration = time::Duration::from_secs(1);
+// how long a still branch may take to encode its frame
+const BRANCH_TIMEOUT: time::Duration = time::Duration::from_secs(5);
 
 #[allow(dead_code)]
 pub trait StillRecorder: Sync + Send {
-    fn take_still(&self, name: &str) -> Result<StillInfo, PipelineError>;
+    // The stills are encoded as requested (JPEG stills have the metadata in Exif), stills of
+    // the still pipeline (JPEG) are decoded and encoded again if the encoding is not the default
+    fn take_still(
+        &self,
+        name: &str,
+        encoding: &StillEncoding,
+        metadata: &StillMetadata,
+    ) -> Result<StillInfo, PipelineError>;
+    // Takes the still from a branch attached to the tee of the running source pipeline,
+    // messages are the messages of the source pipeline
+    fn take_still_from(
+        &self,
+        pipeline: &gst::Pipeline,
+        tee: &str,
+        messages: UnboundedReceiver<gst::Message>,
+        name: &str,
+        encoding: &StillEncoding,
+        metadata: &StillMetadata,
+    ) -> Result<StillInfo, PipelineError>;
+    // Keeps the last frame of the running source in an appsink (still-sink), so a still is
+    // encoded from it without launching a pipeline. In branch mode (source: the source
+    // pipeline and its tee) the appsink is a branch of the source, else a pipeline reading
+    // the source socket.
+    fn start_live(&self, source: Option<(&gst::Pipeline, &str)>) -> Result<(), PipelineError>;
+    fn stop_live(&self);
+    // Encodes the last frame of the live source
+    fn take_live_still(
+        &self,
+        name: &str,
+        encoding: &StillEncoding,
+        metadata: &StillMetadata,
+    ) -> Result<StillInfo, PipelineError>;
+    // Encodes the last frame of the live source in memory, nothing is written to disk
+    fn encode_live_still(
+        &self,
+        encoding: &StillEncoding,
+        metadata: &StillMetadata,
+    ) -> Result<Vec<u8>, PipelineError>;
+}
+
+// What keeps the last frame of the source
+#[derive(Default)]
+struct LiveSource {
+    pipeline: Option<gst::Pipeline>,
+    // the branch stays attached to the source pipeline, also while the source is stopped
+    branch: Option<DynamicBranch>,
 }
 
 pub struct StillRecorderImpl {
@@ -20,12 +81,25 @@ pub struct StillRecorderImpl {
     socket_path: String,
     output_dir: String,
     pipeline_str: String,
+    branch_str: String,
+    live_pipeline_str: String,
+    live_branch_str: String,
+    live: Mutex<LiveSource>,
+    // the last sample of the still-sink, notified for every sample
+    last_sample: Arc<(Mutex<Option<gst::Sample>>, Condvar)>,
+    // the timestamp of the frame of the last live still
+    last_still_pts: Mutex<Option<gst::ClockTime>>,
 }
 
 impl StillRecorder for StillRecorderImpl {
-    fn take_still(&self, name: &str) -> Result<StillInfo, PipelineError> {
+    fn take_still(
+        &self,
+        name: &str,
+        encoding: &StillEncoding,
+        metadata: &StillMetadata,
+    ) -> Result<StillInfo, PipelineError> {
         debug!("Taking still");
-        let still_file = format!("{}/{}-{}.jpg", self.output_dir, name, self.postfix);
+        let still_file = self.still_file(name, StillFormat::Jpeg);
         let gst_pipeline = match gst::parse::launch(self.pipeline_str.as_str()) {
             Ok(pipeline) => {
                 info!("Pipeline created...");
@@ -77,6 +151,8 @@ impl StillRecorder for StillRecorderImpl {
         if log::log_enabled!(log::Level::Debug) {
             gst_pipeline.debug_to_dot_file(gst::DebugGraphDetails::MEDIA_TYPE, "still");
         }
+        // the caps are kept till the pipeline is stopped
+        let caps = sink_caps(&sink_element);
         gst_pipeline
             .set_state(gst::State::Null)
             .expect("Unable to set the pipeline to the `Playing` state");
@@ -86,21 +162,387 @@ impl StillRecorder for StillRecorderImpl {
             debug!("Pipeline state: {:?}, {:?}, {:?}", state, before, after);
         }
 
-        Ok(StillInfo {
-            device: self.device.clone(),
-            width: 0,
-            height: 0,
-            format: "".to_string(),
+        let (still_file, caps) = self.transcode(name, still_file, caps, encoding)?;
+        Ok(self.still_info(still_file, caps, metadata))
+    }
+
+    fn take_still_from(
+        &self,
+        pipeline: &gst::Pipeline,
+        tee: &str,
+        mut messages: UnboundedReceiver<gst::Message>,
+        name: &str,
+        encoding: &StillEncoding,
+        metadata: &StillMetadata,
+    ) -> Result<StillInfo, PipelineError> {
+        debug!("Taking still from branch");
+        let still_file = self.still_file(name, StillFormat::Jpeg);
+        let branch = DynamicBranch::attach(pipeline, tee, self.branch_str.as_str())?;
+        let sink_element = branch.bin().by_name(VIDEO_SINK);
+        if let Some(sink_element) = sink_element.as_ref() {
+            if sink_element.has_property("location", None) {
+                sink_element.set_property("location", &still_file);
+            }
+        }
+        branch.start()?;
+
+        // the encoder ends the branch after the first frame (snapshot)
+        if wait_for_eos(&branch, &mut messages, BRANCH_TIMEOUT) {
+            info!("End of stream");
+        }
+        let caps = sink_element.as_ref().and_then(sink_caps);
+        branch.release();
+
+        let (still_file, caps) = self.transcode(name, still_file, caps, encoding)?;
+        Ok(self.still_info(still_file, caps, metadata))
+    }
+
+    fn start_live(&self, source: Option<(&gst::Pipeline, &str)>) -> Result<(), PipelineError> {
+        let mut live = self.live.lock().unwrap();
+        match source {
+            Some((pipeline, tee)) => {
+                if self.live_branch_str.is_empty() || live.branch.is_some() {
+                    return Ok(());
+                }
+                let branch = DynamicBranch::attach(pipeline, tee, self.live_branch_str.as_str())?;
+                self.connect_sink(branch.bin())?;
+                branch.start()?;
+                live.branch = Some(branch);
+            }
+            None => {
+                if self.live_pipeline_str.is_empty() {
+                    return Ok(());
+                }
+                if live.pipeline.is_some() {
+                    return Err(PipelineError::AlreadyStarted);
+                }
+                let pipeline = gst::parse::launch(self.live_pipeline_str.as_str())
+                    .map_err(|e| {
+                        error!("{e}");
+                        PipelineError::ParseError
+                    })?
+                    .downcast::<gst::Pipeline>()
+                    .map_err(|_| PipelineError::ParseError)?;
+                if let Some(source) = pipeline.by_name(VIDEO_SOURCE) {
+                    if source.has_property("socket-path", None) {
+                        source.set_property("socket-path", &self.socket_path);
+                    }
+                }
+                self.connect_sink(pipeline.upcast_ref())?;
+                pipeline.set_state(gst::State::Playing).map_err(|e| {
+                    error!("{e}");
+                    PipelineError::EncodingError
+                })?;
+                live.pipeline = Some(pipeline);
+            }
+        }
+        info!("Live stills started");
+        Ok(())
+    }
+
+    fn stop_live(&self) {
+        *self.last_sample.0.lock().unwrap() = None;
+        *self.last_still_pts.lock().unwrap() = None;
+        if let Some(pipeline) = self.live.lock().unwrap().pipeline.take() {
+            if let Err(e) = pipeline.set_state(gst::State::Null) {
+                error!("{e}");
+            }
+        }
+    }
+
+    fn take_live_still(
+        &self,
+        name: &str,
+        encoding: &StillEncoding,
+        metadata: &StillMetadata,
+    ) -> Result<StillInfo, PipelineError> {
+        let sample = self.next_sample()?;
+        let still_file = self.still_file(name, encoding.format);
+        let (width, height) = sample_to_bgr(&sample)
+            .and_then(|(image, _)| encode(&image, encoding, &still_file))
+            .map_err(|e| {
+                error!("Unable to write {}: {e}", still_file);
+                PipelineError::EncodingError
+            })?;
+        debug!("Live still {}", still_file);
+        let caps = (width, height, encoding.format.name().to_string());
+        Ok(self.still_info(still_file, Some(caps), metadata))
+    }
+
+    fn encode_live_still(
+        &self,
+        encoding: &StillEncoding,
+        metadata: &StillMetadata,
+    ) -> Result<Vec<u8>, PipelineError> {
+        let sample = self.next_sample()?;
+        let mut still = sample_to_bgr(&sample)
+            .and_then(|(image, _)| encode_to_memory(&image, encoding))
+            .map_err(|e| {
+                error!("Unable to encode still: {e}");
+                PipelineError::EncodingError
+            })?;
+        if !exif::insert(&mut still, self.device(metadata), metadata) {
+            debug!("No Exif, the still is no JPEG");
+        }
+        Ok(still)
+    }
+}
+
+impl StillRecorderImpl {
+    fn still_file(&self, name: &str, format: StillFormat) -> String {
+        format!(
+            "{}/{}-{}.{}",
+            self.output_dir,
+            name,
+            self.postfix,
+            format.extension()
+        )
+    }
+
+    // Encodes the JPEG of the still pipeline as requested, the JPEG is replaced
+    // returns: the still and its width, height and format
+    fn transcode(
+        &self,
+        name: &str,
+        jpeg_file: String,
+        caps: Option<(u32, u32, String)>,
+        encoding: &StillEncoding,
+    ) -> Result<(String, Option<(u32, u32, String)>), PipelineError> {
+        if *encoding == StillEncoding::default() {
+            return Ok((jpeg_file, caps));
+        }
+        let still_file = self.still_file(name, encoding.format);
+        let (width, height) = imgcodecs::imread(&jpeg_file, imgcodecs::IMREAD_COLOR)
+            .map_err(|e| e.to_string())
+            .and_then(|image| match image.empty() {
+                true => Err(format!("Unable to read {jpeg_file}")),
+                false => encode(&image, encoding, &still_file),
+            })
+            .map_err(|e| {
+                error!("Unable to write {}: {e}", still_file);
+                PipelineError::EncodingError
+            })?;
+        if still_file != jpeg_file {
+            if let Err(e) = std::fs::remove_file(&jpeg_file) {
+                error!("Unable to remove {}: {e}", jpeg_file);
+            }
+        }
+        let caps = (width, height, encoding.format.name().to_string());
+        Ok((still_file, Some(caps)))
+    }
+
+    // The device of the still, the one of the metadata if set
+    fn device<'a>(&'a self, metadata: &'a StillMetadata) -> &'a str {
+        metadata.device.as_deref().unwrap_or(&self.device)
+    }
+
+    // Embeds the metadata in the still, caps: the width, height and format of the still
+    fn still_info(
+        &self,
+        still_file: String,
+        caps: Option<(u32, u32, String)>,
+        metadata: &StillMetadata,
+    ) -> StillInfo {
+        match exif::embed(&still_file, self.device(metadata), metadata) {
+            Ok(true) => (),
+            Ok(false) => debug!("No Exif for {}, not a JPEG", still_file),
+            Err(e) => error!("Unable to add Exif to {}: {e}", still_file),
+        }
+        let (width, height, format) = caps.unwrap_or_default();
+        StillInfo {
+            device: self.device(metadata).to_string(),
+            width,
+            height,
+            format,
             still_file,
-        })
+        }
+    }
+
+    // The last sample of the live source, consecutive stills (e.g. a burst) get different
+    // frames; if the source stalls the frame of the previous still is not handed out again
+    fn next_sample(&self) -> Result<gst::Sample, PipelineError> {
+        let (last_sample, new_sample) = &*self.last_sample;
+        let mut last_still_pts = self.last_still_pts.lock().unwrap();
+        let pts = |sample: &Option<gst::Sample>| {
+            sample
+                .as_ref()
+                .and_then(|s| s.buffer().and_then(|b| b.pts()))
+        };
+        let stale = |sample: &mut Option<gst::Sample>| {
+            sample.is_some() && last_still_pts.is_some() && pts(sample) == *last_still_pts
+        };
+        let guard = last_sample.lock().unwrap();
+        let (mut guard, _) = new_sample
+            .wait_timeout_while(guard, FRAME_TIMEOUT, stale)
+            .unwrap();
+        if stale(&mut guard) {
+            error!("No new frame of the live source within {:?}", FRAME_TIMEOUT);
+            return Err(PipelineError::StaleFrame);
+        }
+        let sample = guard.clone().ok_or(PipelineError::NotRunning)?;
+        *last_still_pts = pts(&guard);
+        Ok(sample)
+    }
+
+    // Keeps the samples of the still-sink of the live pipeline or branch
+    fn connect_sink(&self, bin: &gst::Bin) -> Result<(), PipelineError> {
+        let sink = bin
+            .by_name(STILL_SINK)
+            .and_then(|sink| sink.downcast::<AppSink>().ok())
+            .ok_or_else(|| {
+                error!("Live stills need an appsink named {}", STILL_SINK);
+                PipelineError::ParseError
+            })?;
+        let last_sample = self.last_sample.clone();
+        sink.set_callbacks(
+            gstreamer_app::AppSinkCallbacks::builder()
+                .new_sample(move |app_sink| {
+                    let sample = app_sink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
+                    let (sample_lock, new_sample) = &*last_sample;
+                    *sample_lock.lock().unwrap() = Some(sample);
+                    new_sample.notify_all();
+                    Ok(gst::FlowSuccess::Ok)
+                })
+                .build(),
+        );
+        Ok(())
     }
 }
 
+// Blocks until the branch has ended (EOS), the source pipeline stopped or the timeout expired
+// returns: true if the branch has ended
+fn wait_for_eos(
+    branch: &DynamicBranch,
+    messages: &mut UnboundedReceiver<gst::Message>,
+    timeout: time::Duration,
+) -> bool {
+    let (expire, mut expired) = oneshot::channel::<()>();
+    thread::spawn(move || {
+        thread::sleep(timeout);
+        let _ = expire.send(());
+    });
+    futures::executor::block_on(async {
+        loop {
+            match future::select(messages.next(), &mut expired).await {
+                Either::Left((Some(msg), _)) if branch.is_eos(&msg) => return true,
+                Either::Left((Some(_), _)) => (),
+                Either::Left((None, _)) => {
+                    error!("Source pipeline stopped");
+                    return false;
+                }
+                Either::Right(_) => {
+                    error!("No still within {:?}", timeout);
+                    return false;
+                }
+            }
+        }
+    })
+}
+
+// The frame of a raw sample (see FrameFormat) in BGR
+pub(crate) fn sample_to_bgr(sample: &gst::Sample) -> Result<(Mat, FrameFormat), String> {
+    let caps = sample.caps().ok_or("Sample without caps")?;
+    let format = FrameFormat::from_caps(caps)?;
+    let buffer = sample.buffer().ok_or("Sample without buffer")?;
+    let mut data = format.read(buffer)?;
+    Ok((to_bgr(&mut data, &format)?, format))
+}
+
+// Crops and scales the image as requested
+// returns: the image and the encoder parameters of the format
+fn prepare(image: &Mat, encoding: &StillEncoding) -> Result<(Mat, Vector<i32>), String> {
+    let mut image = image.try_clone().map_err(|e| e.to_string())?;
+    if let Some(crop) = encoding.crop.as_ref() {
+        let (cols, rows) = (image.cols() as f64, image.rows() as f64);
+        let x = ((crop.x * cols).round() as i32).min(image.cols() - 1);
+        let y = ((crop.y * rows).round() as i32).min(image.rows() - 1);
+        let width = ((crop.width * cols).round() as i32).clamp(1, image.cols() - x);
+        let height = ((crop.height * rows).round() as i32).clamp(1, image.rows() - y);
+        image = Mat::roi(&image, Rect::new(x, y, width, height))
+            .and_then(|roi| roi.try_clone())
+            .map_err(|e| e.to_string())?;
+    }
+    let aspect = image.cols() as f64 / image.rows().max(1) as f64;
+    let size = match (encoding.width, encoding.height) {
+        (Some(width), Some(height)) => Some((width as i32, height as i32)),
+        (Some(width), None) => Some((width as i32, (width as f64 / aspect).round() as i32)),
+        (None, Some(height)) => Some(((height as f64 * aspect).round() as i32, height as i32)),
+        (None, None) => None,
+    };
+    if let Some((width, height)) = size {
+        let mut scaled = Mat::default();
+        let size = Size::new(width.max(1), height.max(1));
+        imgproc::resize(&image, &mut scaled, size, 0.0, 0.0, imgproc::INTER_AREA)
+            .map_err(|e| e.to_string())?;
+        image = scaled;
+    }
+    let mut params = Vector::<i32>::new();
+    match encoding.format {
+        StillFormat::Jpeg => {
+            params.push(imgcodecs::IMWRITE_JPEG_QUALITY);
+            params.push(encoding.quality.unwrap_or(95) as i32);
+        }
+        StillFormat::Webp => {
+            params.push(imgcodecs::IMWRITE_WEBP_QUALITY);
+            params.push(encoding.quality.unwrap_or(90) as i32);
+        }
+        StillFormat::Png => (),
+        StillFormat::Tiff => {
+            // LZW
+            params.push(imgcodecs::IMWRITE_TIFF_COMPRESSION);
+            params.push(5);
+        }
+    }
+    Ok((image, params))
+}
+
+// Crops, scales and writes the image as requested
+// returns: the width and height of the still
+fn encode(image: &Mat, encoding: &StillEncoding, file: &str) -> Result<(u32, u32), String> {
+    let (image, params) = prepare(image, encoding)?;
+    match imgcodecs::imwrite(file, &image, &params) {
+        Ok(true) => Ok((image.cols() as u32, image.rows() as u32)),
+        Ok(false) => Err("Unsupported image format".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// Crops, scales and encodes the image as requested
+// returns: the encoded still
+fn encode_to_memory(image: &Mat, encoding: &StillEncoding) -> Result<Vec<u8>, String> {
+    let (image, params) = prepare(image, encoding)?;
+    let mut still = Vector::<u8>::new();
+    let extension = format!(".{}", encoding.format.extension());
+    match imgcodecs::imencode(&extension, &image, &mut still, &params) {
+        Ok(true) => Ok(still.to_vec()),
+        Ok(false) => Err("Unsupported image format".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// The width, height and format (JPEG, PNG or the raw format) negotiated by the sink
+fn sink_caps(sink: &gst::Element) -> Option<(u32, u32, String)> {
+    let caps = sink.static_pad("sink")?.current_caps()?;
+    let structure = caps.structure(0)?;
+    let width = structure.get::<i32>("width").ok()?;
+    let height = structure.get::<i32>("height").ok()?;
+    let format = match structure.name().as_str() {
+        "image/jpeg" => "JPEG".to_string(),
+        "image/png" => "PNG".to_string(),
+        "video/x-raw" => structure.get::<String>("format").unwrap_or_default(),
+        name => name.to_string(),
+    };
+    Some((width as u32, height as u32, format))
+}
+
 pub struct StillRecorderBuilder {
     device: String,
     postfix: String,
     socket_path: String,
     pipeline_str: String,
+    branch_str: String,
+    live_pipeline_str: String,
+    live_branch_str: String,
     output_dir: String,
 }
 impl StillRecorderBuilder {
@@ -110,6 +552,14 @@ impl StillRecorderBuilder {
             postfix: "still".to_string(),
             socket_path: "/tmp/video0.sock".to_string(),
             pipeline_str: "unixfdsrc name=video-source ! queue ! videoconvert ! jpegenc snapshot=true ! queue ! filesink name=video-sink".to_string(),
+            branch_str: "queue ! videoconvert ! jpegenc snapshot=true ! filesink name=video-sink".to_string(),
+            live_pipeline_str: "unixfdsrc name=video-source \
+                ! queue leaky=2 max-size-buffers=1 \
+                ! appsink name=still-sink sync=false max-buffers=1 drop=true"
+                .to_string(),
+            live_branch_str: "queue leaky=2 max-size-buffers=1 \
+                ! appsink name=still-sink sync=false max-buffers=1 drop=true"
+                .to_string(),
             output_dir: "./".to_string(),
         }
     }
@@ -137,6 +587,24 @@ impl StillRecorderBuilder {
         self
     }
 
+    // The still branch attached to the source pipeline in branch mode
+    pub fn with_branch_str(mut self, branch_str: &str) -> StillRecorderBuilder {
+        self.branch_str = branch_str.to_string();
+        self
+    }
+
+    // The pipeline keeping the last frame of the source (empty: a pipeline per still)
+    pub fn with_live_pipeline_str(mut self, pipeline_str: &str) -> StillRecorderBuilder {
+        self.live_pipeline_str = pipeline_str.to_string();
+        self
+    }
+
+    // The branch keeping the last frame of the source in branch mode
+    pub fn with_live_branch_str(mut self, branch_str: &str) -> StillRecorderBuilder {
+        self.live_branch_str = branch_str.to_string();
+        self
+    }
+
     pub fn with_output_dir(mut self, output_dir: &str) -> StillRecorderBuilder {
         self.output_dir = output_dir.to_string();
         self
@@ -149,6 +617,12 @@ impl StillRecorderBuilder {
             socket_path: self.socket_path.clone(),
             output_dir: self.output_dir.clone(),
             pipeline_str: self.pipeline_str.clone(),
+            branch_str: self.branch_str.clone(),
+            live_pipeline_str: self.live_pipeline_str.clone(),
+            live_branch_str: self.live_branch_str.clone(),
+            live: Mutex::new(LiveSource::default()),
+            last_sample: Arc::new((Mutex::new(None), Condvar::new())),
+            last_still_pts: Mutex::new(None),
         }
     }
 }
@@ -167,10 +641,145 @@ mod tests {
             .with_socket_path("/tmp/video.sock")
             .with_output_dir("/tmp")
             .build();
-        let still_info = still_recorder.take_still("dummy").unwrap();
+        let still_info = still_recorder
+            .take_still("dummy", &StillEncoding::default(), &StillMetadata::new())
+            .unwrap();
         thread::sleep(time::Duration::from_secs(1));
         assert_eq!(still_info.device, "video0");
+        // the default size of videotestsrc
+        assert_eq!((still_info.width, still_info.height), (320, 240));
+        assert_eq!(still_info.format, "JPEG");
+        let still = std::fs::read("/tmp/dummy-still.jpg").unwrap();
+        assert!(still.windows(6).any(|w| w == b"Exif\0\0"));
         assert_eq!(still_info.still_file, "/tmp/dummy-still.jpg");
         remove_file("/tmp/dummy-still.jpg").unwrap();
+
+        // the JPEG of the pipeline is encoded again
+        let encoding = StillEncoding {
+            format: StillFormat::Png,
+            width: Some(160),
+            ..StillEncoding::default()
+        };
+        let still_info = still_recorder
+            .take_still("dummy", &encoding, &StillMetadata::new())
+            .unwrap();
+        assert_eq!(still_info.still_file, "/tmp/dummy-still.png");
+        assert_eq!((still_info.width, still_info.height), (160, 120));
+        assert_eq!(still_info.format, "PNG");
+        assert!(!std::path::Path::new("/tmp/dummy-still.jpg").exists());
+        remove_file("/tmp/dummy-still.png").unwrap();
+    }
+
+    #[test]
+    fn test_encode() {
+        let image = Mat::new_rows_cols_with_default(
+            240,
+            320,
+            opencv::core::CV_8UC3,
+            opencv::core::Scalar::all(128.0),
+        )
+        .unwrap();
+        let crop = crate::dtos::messages::CropRect {
+            x: 0.5,
+            y: 0.5,
+            width: 0.5,
+            height: 0.5,
+        };
+        for (format, width, height, size) in [
+            (StillFormat::Jpeg, Some(80), None, (80, 60)),
+            (StillFormat::Png, None, Some(30), (40, 30)),
+            (StillFormat::Webp, None, None, (160, 120)),
+            (StillFormat::Tiff, Some(100), Some(50), (100, 50)),
+        ] {
+            let encoding = StillEncoding {
+                format,
+                quality: Some(80),
+                width,
+                height,
+                crop: Some(crop.clone()),
+            };
+            assert_eq!(encoding.validate(), Ok(()));
+            let file = format!("/tmp/encode-test.{}", format.extension());
+            assert_eq!(encode(&image, &encoding, &file).unwrap(), size);
+            let read = imgcodecs::imread(&file, imgcodecs::IMREAD_COLOR).unwrap();
+            assert_eq!((read.cols() as u32, read.rows() as u32), size);
+            remove_file(&file).unwrap();
+        }
+        let outside = StillEncoding {
+            crop: Some(crate::dtos::messages::CropRect { width: 0.6, ..crop }),
+            ..StillEncoding::default()
+        };
+        assert!(outside.validate().is_err());
+    }
+
+    #[test]
+    fn test_live_still() {
+        let _ = gst::init();
+        let still_recorder = StillRecorderBuilder::new()
+            .with_device("video0")
+            .with_live_pipeline_str(
+                "videotestsrc is-live=true name=video-source \
+                ! video/x-raw, format=YUY2, width=320, height=240 \
+                ! appsink name=still-sink sync=false max-buffers=1 drop=true",
+            )
+            .with_output_dir("/tmp")
+            .build();
+        let metadata = StillMetadata::new();
+        let encoding = StillEncoding::default();
+        assert_eq!(
+            still_recorder
+                .take_live_still("live", &encoding, &metadata)
+                .err(),
+            Some(PipelineError::NotRunning)
+        );
+        still_recorder.start_live(None).unwrap();
+        thread::sleep(time::Duration::from_millis(500));
+        let started = time::Instant::now();
+        for _ in 0..5 {
+            let still_info = still_recorder
+                .take_live_still("live", &encoding, &metadata)
+                .unwrap();
+            assert_eq!(still_info.still_file, "/tmp/live-still.jpg");
+            assert_eq!((still_info.width, still_info.height), (320, 240));
+        }
+        assert!(started.elapsed() < time::Duration::from_secs(1));
+        remove_file("/tmp/live-still.jpg").unwrap();
+        let metadata = StillMetadata {
+            device: Some("video7".to_string()),
+            ..StillMetadata::new()
+        };
+        let still = still_recorder
+            .encode_live_still(&encoding, &metadata)
+            .unwrap();
+        assert!(still.starts_with(&[0xFF, 0xD8]));
+        assert!(still.windows(6).any(|w| w == b"video7"));
+        // nothing is written
+        assert!(!std::path::Path::new("/tmp/live-still.jpg").exists());
+        still_recorder.stop_live();
+        assert_eq!(
+            still_recorder
+                .take_live_still("live", &encoding, &metadata)
+                .err(),
+            Some(PipelineError::NotRunning)
+        );
+    }
+
+    #[test]
+    fn test_stale_frame() {
+        let _ = gst::init();
+        let still_recorder = StillRecorderBuilder::new().build();
+        let mut buffer = gst::Buffer::new();
+        buffer
+            .get_mut()
+            .unwrap()
+            .set_pts(gst::ClockTime::from_seconds(1));
+        let sample = gst::Sample::builder().buffer(&buffer).build();
+        *still_recorder.last_sample.0.lock().unwrap() = Some(sample);
+        assert!(still_recorder.next_sample().is_ok());
+        // the source stalled, the frame of the previous still is not returned again
+        assert_eq!(
+            still_recorder.next_sample().err(),
+            Some(PipelineError::StaleFrame)
+        );
     }
 }
@@ -0,0 +1,305 @@
+use crate::dtos::messages::{Artifact, SceneInfo};
+use crate::recorder::analyzer::{AnalyzerOutput, AnalyzerSession, Frame, FrameAnalyzer, Segment};
+use crate::recorder::thumbnailer::format_time;
+use chrono::{DateTime, Local};
+use gstreamer_app::gst;
+use opencv::core::{self, Mat, Size, Vector};
+use opencv::prelude::*;
+use opencv::{imgcodecs, imgproc};
+
+// the histogram distance (0.0 - 1.0) starting a new scene
+pub const DEFAULT_SCENE_THRESHOLD: f64 = 0.4;
+// the width of the scene thumbnails, the height keeps the aspect ratio
+const THUMBNAIL_WIDTH: i32 = 320;
+// shorter scenes (flashes, camera shake) are merged into the previous scene
+const MIN_SCENE: gst::ClockTime = gst::ClockTime::from_seconds(2);
+
+// A scene waiting for the segment it starts in
+struct PendingScene {
+    pts: Option<gst::ClockTime>,
+    timestamp: DateTime<Local>,
+    score: f64,
+    image: String,
+}
+
+// Detects scene changes with the difference of the color histograms (HSV) and writes a
+// thumbnail per scene, {prefix}-storyboard.json and {prefix}-storyboard.vtt
+// (one cue per scene) so a player can jump between the scenes
+pub struct StoryboardAnalyzer {
+    threshold: f64,
+    session: AnalyzerSession,
+    // the histogram of the first frame of the current scene
+    reference: Option<Mat>,
+    scene_started: Option<gst::ClockTime>,
+    pending: Vec<PendingScene>,
+    scenes: Vec<SceneInfo>,
+    // the playlist time of the next segment
+    offset: gst::ClockTime,
+    // the running time of the end of the last segment
+    last_end: gst::ClockTime,
+}
+
+impl StoryboardAnalyzer {
+    // threshold: the histogram distance (0.0 - 1.0) starting a new scene
+    pub fn new(threshold: f64) -> StoryboardAnalyzer {
+        StoryboardAnalyzer {
+            threshold,
+            session: AnalyzerSession::default(),
+            reference: None,
+            scene_started: None,
+            pending: Vec::new(),
+            scenes: Vec::new(),
+            offset: gst::ClockTime::ZERO,
+            last_end: gst::ClockTime::ZERO,
+        }
+    }
+
+    fn file(&self, suffix: &str) -> String {
+        format!("{}-{}", self.session.prefix, suffix)
+    }
+
+    fn save_thumbnail(&self, image: &Mat, file: &str) -> opencv::Result<()> {
+        let height = image.rows() * THUMBNAIL_WIDTH / image.cols().max(1);
+        let mut thumbnail = Mat::default();
+        imgproc::resize(
+            image,
+            &mut thumbnail,
+            Size::new(THUMBNAIL_WIDTH, height.max(1)),
+            0.0,
+            0.0,
+            imgproc::INTER_AREA,
+        )?;
+        let location = format!("{}/{}", self.session.output_dir, file);
+        imgcodecs::imwrite(&location, &thumbnail, &Vector::new())?;
+        Ok(())
+    }
+
+    // Moves the pending scenes into the storyboard
+    // start: the running time the playlist times are relative to
+    // until: the pending scenes starting before are moved
+    fn place_scenes(&mut self, start: gst::ClockTime, until: Option<gst::ClockTime>) {
+        let split = self
+            .pending
+            .iter()
+            .position(|s| matches!((s.pts, until), (Some(pts), Some(until)) if pts >= until))
+            .unwrap_or(self.pending.len());
+        for scene in self.pending.drain(..split) {
+            let relative = scene
+                .pts
+                .map_or(gst::ClockTime::ZERO, |p| p.saturating_sub(start));
+            let time = self.offset + relative;
+            self.scenes.push(SceneInfo {
+                scene: self.scenes.len() + 1,
+                start_sec: time.mseconds() as f64 / 1000.0,
+                end_sec: time.mseconds() as f64 / 1000.0,
+                timestamp: scene.timestamp,
+                score: scene.score,
+                image: scene.image,
+            });
+        }
+    }
+
+    // Rewrites the storyboard files, a scene ends with the next one (or the end of the playlist)
+    fn write(&mut self) -> Result<(), String> {
+        let end_sec = self.offset.mseconds() as f64 / 1000.0;
+        let starts: Vec<f64> = self.scenes.iter().skip(1).map(|s| s.start_sec).collect();
+        for (i, scene) in self.scenes.iter_mut().enumerate() {
+            scene.end_sec = starts
+                .get(i)
+                .copied()
+                .unwrap_or(end_sec)
+                .max(scene.start_sec);
+        }
+        let json = serde_json::to_string_pretty(&self.scenes).map_err(|e| e.to_string())?;
+        std::fs::write(
+            format!(
+                "{}/{}",
+                self.session.output_dir,
+                self.file("storyboard.json")
+            ),
+            json,
+        )
+        .map_err(|e| e.to_string())?;
+        let mut vtt = "WEBVTT\n\n".to_string();
+        for scene in self.scenes.iter() {
+            vtt.push_str(&format!(
+                "{}\n{} --> {}\n{}\n\n",
+                scene.scene,
+                format_time(gst::ClockTime::from_mseconds(
+                    (scene.start_sec * 1000.0) as u64
+                )),
+                format_time(gst::ClockTime::from_mseconds(
+                    (scene.end_sec * 1000.0) as u64
+                )),
+                scene.image
+            ));
+        }
+        std::fs::write(
+            format!(
+                "{}/{}",
+                self.session.output_dir,
+                self.file("storyboard.vtt")
+            ),
+            vtt,
+        )
+        .map_err(|e| e.to_string())
+    }
+}
+
+impl FrameAnalyzer for StoryboardAnalyzer {
+    fn name(&self) -> String {
+        "storyboard".to_string()
+    }
+
+    fn start(&mut self, session: &AnalyzerSession) {
+        *self = StoryboardAnalyzer::new(self.threshold);
+        self.session = session.clone();
+    }
+
+    fn analyze(&mut self, frame: &Frame, _output: &mut AnalyzerOutput) -> Result<(), String> {
+        let histogram = histogram(frame.image).map_err(|e| e.to_string())?;
+        let score = match self.reference.as_ref() {
+            Some(reference) => {
+                imgproc::compare_hist(reference, &histogram, imgproc::HISTCMP_BHATTACHARYYA)
+                    .map_err(|e| e.to_string())?
+            }
+            None => 0.0,
+        };
+        if self.reference.is_some() {
+            if score < self.threshold {
+                return Ok(());
+            }
+            if let (Some(started), Some(pts)) = (self.scene_started, frame.pts) {
+                if pts.saturating_sub(started) < MIN_SCENE {
+                    return Ok(());
+                }
+            }
+        }
+        let image = self.file(&format!(
+            "scene_{:05}.jpg",
+            self.scenes.len() + self.pending.len() + 1
+        ));
+        self.save_thumbnail(frame.image, &image)
+            .map_err(|e| e.to_string())?;
+        self.reference = Some(histogram);
+        self.scene_started = frame.pts;
+        self.pending.push(PendingScene {
+            pts: frame.pts,
+            timestamp: frame.timestamp,
+            score,
+            image,
+        });
+        Ok(())
+    }
+
+    fn segment_finished(
+        &mut self,
+        segment: &Segment,
+        _output: &mut AnalyzerOutput,
+    ) -> Result<(), String> {
+        let end = segment.start + segment.duration;
+        self.place_scenes(segment.start, Some(end));
+        self.offset += segment.duration;
+        self.last_end = end;
+        if self.scenes.is_empty() {
+            return Ok(());
+        }
+        self.write()
+    }
+
+    fn finish(&mut self, output: &mut AnalyzerOutput) -> Result<(), String> {
+        // the scenes after the last reported segment
+        self.place_scenes(self.last_end, None);
+        if self.scenes.is_empty() {
+            return Ok(());
+        }
+        self.write()?;
+        for kind in ["storyboard.json", "storyboard.vtt"] {
+            output.add_artifact(Artifact {
+                analyzer: self.name(),
+                kind: kind.rsplit('.').next().unwrap().to_string(),
+                file: self.file(kind),
+                timestamp: Local::now(),
+            });
+        }
+        Ok(())
+    }
+}
+
+// The normalized hue/saturation histogram of a BGR frame
+fn histogram(image: &Mat) -> opencv::Result<Mat> {
+    let mut hsv = Mat::default();
+    imgproc::cvt_color_def(image, &mut hsv, imgproc::COLOR_BGR2HSV)?;
+    let mut images = Vector::<Mat>::new();
+    images.push(hsv);
+    let mut histogram = Mat::default();
+    imgproc::calc_hist(
+        &images,
+        &Vector::from_slice(&[0, 1]),
+        &core::no_array(),
+        &mut histogram,
+        &Vector::from_slice(&[50, 60]),
+        &Vector::from_slice(&[0.0f32, 180.0, 0.0, 256.0]),
+        false,
+    )?;
+    let mut normalized = Mat::default();
+    core::normalize(
+        &histogram,
+        &mut normalized,
+        0.0,
+        1.0,
+        core::NORM_MINMAX,
+        -1,
+        &core::no_array(),
+    )?;
+    Ok(normalized)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recorder::analyzer::tests::{frame_at, solid, TestSession};
+    use opencv::core::Scalar;
+    use std::path::Path;
+
+    #[test]
+    fn test_storyboard() {
+        let test = TestSession::new("storyboard-test");
+        let mut analyzer = StoryboardAnalyzer::new(DEFAULT_SCENE_THRESHOLD);
+        analyzer.start(&test.session);
+        let gray = solid(64, 48, Scalar::all(128.0));
+        let red = solid(64, 48, Scalar::new(0.0, 0.0, 255.0, 0.0));
+        let blue = solid(64, 48, Scalar::new(255.0, 0.0, 0.0, 0.0));
+        let mut output = AnalyzerOutput::default();
+        // the blue flash is shorter than a scene
+        for (image, ms) in [
+            (&gray, 0),
+            (&gray, 1000),
+            (&red, 3000),
+            (&blue, 4000),
+            (&red, 4500),
+        ] {
+            let frame = frame_at(image, Some(ms), Local::now());
+            analyzer.analyze(&frame, &mut output).unwrap();
+        }
+        let segment = Segment {
+            index: 0,
+            start: gst::ClockTime::ZERO,
+            duration: gst::ClockTime::from_seconds(6),
+        };
+        analyzer.segment_finished(&segment, &mut output).unwrap();
+        analyzer.finish(&mut output).unwrap();
+        assert_eq!(analyzer.scenes.len(), 2);
+        assert_eq!(analyzer.scenes[1].start_sec, 3.0);
+        assert_eq!(analyzer.scenes[1].end_sec, 6.0);
+        assert_eq!(output.take_artifacts().len(), 2);
+        for file in [
+            "storyboard-test-scene_00001.jpg",
+            "storyboard-test-scene_00002.jpg",
+            "storyboard-test-storyboard.json",
+            "storyboard-test-storyboard.vtt",
+        ] {
+            assert!(Path::new(&test.path(file)).exists(), "{file}");
+        }
+    }
+}
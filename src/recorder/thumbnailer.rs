@@ -0,0 +1,351 @@
+use crate::dtos::messages::Artifact;
+use crate::recorder::analyzer::{AnalyzerOutput, AnalyzerSession, Frame, FrameAnalyzer, Segment};
+use chrono::{Local, NaiveTime};
+use gstreamer_app::gst;
+use opencv::boxed_ref::BoxedRef;
+use opencv::prelude::*;
+use opencv::{
+    core::{self, hconcat, Mat, Size, Vector},
+    imgcodecs, imgproc,
+};
+use std::fs::OpenOptions;
+use std::io::Write;
+
+// The sizes of the thumbnails
+#[derive(Clone, Debug)]
+pub struct ThumbnailConfig {
+    // the size of a tooltip thumbnail
+    pub width: i32,
+    pub height: i32,
+    // the strip cut from the center of a frame for the sprite
+    pub sprite_width: i32,
+    pub sprite_height: i32,
+    // the thumbnails of a tile, a shorter segment repeats its last frame
+    pub frames_per_tile: usize,
+}
+
+impl Default for ThumbnailConfig {
+    fn default() -> Self {
+        ThumbnailConfig {
+            width: 720,
+            height: 480,
+            sprite_width: 4,
+            sprite_height: 54,
+            frames_per_tile: 6,
+        }
+    }
+}
+
+// A thumbnail and the running time of its frame
+struct Thumbnail {
+    image: Mat,
+    pts: Option<gst::ClockTime>,
+}
+
+// Writes a sprite and a tooltip image per segment and the thumbnails VTT of the session.
+// The frames of a segment are the frames with a running time before the end of the segment,
+// the cues start at the frame time relative to the playlist (the sum of the previous segments).
+pub struct Thumbnailer {
+    config: ThumbnailConfig,
+    frames: Vec<Thumbnail>,
+    session: AnalyzerSession,
+    // the playlist time of the next segment
+    offset: gst::ClockTime,
+    next_index: u32,
+    cues: usize,
+}
+
+impl Thumbnailer {
+    pub fn new(config: ThumbnailConfig) -> Thumbnailer {
+        Thumbnailer {
+            config,
+            frames: Vec::new(),
+            session: AnalyzerSession::default(),
+            offset: gst::ClockTime::ZERO,
+            next_index: 0,
+            cues: 0,
+        }
+    }
+
+    fn vtt_file(&self) -> String {
+        format!("{}-thumbnails.vtt", self.session.prefix)
+    }
+
+    // Writes the tile of a segment and appends its cues to the vtt
+    // start: the running time of the start of the segment
+    fn write_tile(
+        &mut self,
+        index: u32,
+        start: gst::ClockTime,
+        duration: gst::ClockTime,
+        frames: Vec<Thumbnail>,
+    ) -> Result<(), String> {
+        if frames.is_empty() {
+            return Ok(());
+        }
+        let output_path = &self.session.output_dir;
+        let timestamp = &self.session.prefix;
+        let count = self.config.frames_per_tile;
+        let images: Vec<Mat> = frames.iter().map(|f| f.image.clone()).collect();
+        let sprites = create_sprites(&images, &self.config)?;
+        let sprite = concat_sprites(&sprites, count)?;
+        imgcodecs::imwrite(
+            format!("{}/{}-sprite_{:05}.jpg", output_path, timestamp, index).as_str(),
+            &sprite,
+            &Vector::new(),
+        )
+        .map_err(|e| e.to_string())?;
+        let tooltips = concat_sprites(&images, count)?;
+        imgcodecs::imwrite(
+            format!("{}/{}-tooltips_{:05}.jpg", output_path, timestamp, index).as_str(),
+            &tooltips,
+            &Vector::new(),
+        )
+        .map_err(|e| e.to_string())?;
+
+        // vtt file creation
+        // the file is reopened for every chunk, so it can be pruned in between (loop recording)
+        let vtt_location = format!("{}/{}", output_path, self.vtt_file());
+        let mut vtt_file = if self.cues == 0 {
+            let mut file = OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(&vtt_location)
+                .map_err(|e| e.to_string())?;
+            writeln!(file, "WEBVTT\n").map_err(|e| e.to_string())?;
+            file
+        } else {
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&vtt_location)
+                .map_err(|e| e.to_string())?
+        };
+        // the time of a frame within the segment, frames without timestamp follow the previous one
+        let mut times = Vec::new();
+        for frame in frames.iter() {
+            let previous = times.last().copied().unwrap_or(gst::ClockTime::ZERO);
+            let time = frame.pts.map_or(previous, |pts| pts.saturating_sub(start));
+            times.push(time.min(duration).max(previous));
+        }
+        for (i, from) in times.iter().enumerate() {
+            let to = times.get(i + 1).copied().unwrap_or(duration);
+            self.cues += 1;
+            let x = i * self.config.width as usize;
+            let y = 0;
+            let w = self.config.width as usize;
+            let h = self.config.height as usize;
+            writeln!(
+                vtt_file,
+                "{}\n{} --> {}\n{}-tooltips_{:05}.jpg#xywh={},{},{},{}\n",
+                self.cues,
+                format_time(self.offset + *from),
+                format_time(self.offset + to),
+                timestamp,
+                index,
+                x,
+                y,
+                w,
+                h
+            )
+            .map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+}
+
+impl FrameAnalyzer for Thumbnailer {
+    fn name(&self) -> String {
+        "thumbnails".to_string()
+    }
+
+    fn start(&mut self, session: &AnalyzerSession) {
+        self.session = session.clone();
+        self.frames.clear();
+        self.offset = gst::ClockTime::ZERO;
+        self.next_index = 0;
+        self.cues = 0;
+    }
+
+    fn analyze(&mut self, frame: &Frame, _output: &mut AnalyzerOutput) -> Result<(), String> {
+        let size = Size::new(self.config.width, self.config.height);
+        let image = if frame.image.size().map_err(|e| e.to_string())? == size {
+            frame.image.clone()
+        } else {
+            let mut thumbnail = Mat::default();
+            imgproc::resize(
+                frame.image,
+                &mut thumbnail,
+                size,
+                0.0,
+                0.0,
+                imgproc::INTER_AREA,
+            )
+            .map_err(|e| e.to_string())?;
+            thumbnail
+        };
+        self.frames.push(Thumbnail {
+            image,
+            pts: frame.pts,
+        });
+        Ok(())
+    }
+
+    fn segment_finished(
+        &mut self,
+        segment: &Segment,
+        _output: &mut AnalyzerOutput,
+    ) -> Result<(), String> {
+        let end = segment.start + segment.duration;
+        let split = self
+            .frames
+            .iter()
+            .position(|f| f.pts.is_some_and(|pts| pts >= end))
+            .unwrap_or(self.frames.len());
+        let frames: Vec<Thumbnail> = self.frames.drain(..split).collect();
+        let result = self.write_tile(segment.index, segment.start, segment.duration, frames);
+        self.offset += segment.duration;
+        self.next_index = segment.index + 1;
+        result
+    }
+
+    fn finish(&mut self, output: &mut AnalyzerOutput) -> Result<(), String> {
+        // the frames after the last reported segment (e.g. a recording stopped manually)
+        let frames = std::mem::take(&mut self.frames);
+        if let Some(first) = frames.first() {
+            let start = first.pts.unwrap_or(gst::ClockTime::ZERO);
+            let last = frames.last().and_then(|f| f.pts).unwrap_or(start);
+            let duration = last.saturating_sub(start) + gst::ClockTime::SECOND;
+            self.write_tile(self.next_index, start, duration, frames)?;
+        }
+        if self.cues > 0 {
+            output.add_artifact(Artifact {
+                analyzer: self.name(),
+                kind: "vtt".to_string(),
+                file: self.vtt_file(),
+                timestamp: Local::now(),
+            });
+        }
+        Ok(())
+    }
+}
+
+// Formats a playlist time as vtt timestamp
+pub fn format_time(time: gst::ClockTime) -> String {
+    let time = NaiveTime::from_num_seconds_from_midnight_opt(
+        (time.seconds() % 86400) as u32,
+        (time.nseconds() % 1_000_000_000) as u32,
+    )
+    .unwrap();
+    format!("{}", time.format("%H:%M:%S%.3f"))
+}
+fn create_sprite(input: &Mat, config: &ThumbnailConfig) -> Result<Mat, String> {
+    let new_height = config.sprite_height;
+    let scale_factor = new_height as f64 / input.rows() as f64;
+    let new_width = (input.cols() as f64 * scale_factor).round() as i32;
+    let mut img = Mat::default();
+    imgproc::resize(
+        &input,
+        &mut img,
+        Size::new(new_width, new_height),
+        0.0,
+        0.0,
+        imgproc::INTER_LINEAR,
+    )
+    .map_err(|e| e.to_string())?;
+    let width = config.sprite_width.min(img.cols());
+    let roi = img
+        .roi(core::Rect::new(
+            (img.cols() - width) / 2,
+            0,
+            width,
+            img.rows(),
+        ))
+        .map_err(|e| e.to_string())?;
+    Ok(roi.clone_pointee())
+}
+
+fn create_sprites(input: &Vec<Mat>, config: &ThumbnailConfig) -> Result<Vec<Mat>, String> {
+    let mut mat_vec: Vec<Mat> = Vec::new();
+    for frame in input.iter() {
+        let sprite = create_sprite(frame, config)?;
+        mat_vec.push(sprite);
+    }
+    Ok(mat_vec)
+}
+macro_rules! bref_from_mat {
+    ($val:expr) => {
+        $val.roi(core::Rect::new(0, 0, $val.cols(), $val.rows()))
+    };
+}
+// count: the minimum number of images, the last image is repeated
+fn concat_sprites(input: &Vec<Mat>, count: usize) -> Result<Mat, String> {
+    let mut roi_vec = Vector::<BoxedRef<Mat>>::new();
+    for mat in input.iter() {
+        let ref_mat = bref_from_mat!(mat).map_err(|e| e.to_string())?;
+        roi_vec.push(ref_mat);
+    }
+    let last = input.last().ok_or("No images to concat")?;
+    let diff = count.checked_sub(input.len()).unwrap_or(0);
+    for _ in 0..diff {
+        let ref_mat = bref_from_mat!(last).map_err(|e| e.to_string())?;
+        roi_vec.push(ref_mat);
+    }
+    let mut result = core::Mat::default();
+    hconcat(&roi_vec, &mut result).map_err(|e| e.to_string())?;
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recorder::analyzer::tests::{frame_at, solid, TestSession};
+    use opencv::core::Scalar;
+
+    #[test]
+    fn test_thumbnailer() {
+        let test = TestSession::new("thumbnailer-test");
+        let config = ThumbnailConfig {
+            width: 160,
+            height: 120,
+            frames_per_tile: 4,
+            ..Default::default()
+        };
+        let mut thumbnailer = Thumbnailer::new(config);
+        thumbnailer.start(&test.session);
+        let image = solid(640, 480, Scalar::all(128.0));
+        let mut output = AnalyzerOutput::default();
+        for pts in [0, 1000, 2500] {
+            let frame = frame_at(&image, Some(pts), Local::now());
+            thumbnailer.analyze(&frame, &mut output).unwrap();
+        }
+        for index in 0..2 {
+            let segment = Segment {
+                index,
+                start: gst::ClockTime::from_seconds(2 * index as u64),
+                duration: gst::ClockTime::from_seconds(2),
+            };
+            thumbnailer.segment_finished(&segment, &mut output).unwrap();
+        }
+        thumbnailer.finish(&mut output).unwrap();
+        let artifacts = output.take_artifacts();
+        assert_eq!(artifacts.len(), 1);
+        assert_eq!(artifacts[0].file, "thumbnailer-test-thumbnails.vtt");
+        let vtt = std::fs::read_to_string(test.path("thumbnailer-test-thumbnails.vtt")).unwrap();
+        assert!(vtt.contains("00:00:01.000 --> 00:00:02.000\nthumbnailer-test-tooltips_00000.jpg"));
+        assert!(vtt.contains("00:00:02.500 --> 00:00:04.000\nthumbnailer-test-tooltips_00001.jpg"));
+        let tooltips = imgcodecs::imread(
+            &test.path("thumbnailer-test-tooltips_00000.jpg"),
+            imgcodecs::IMREAD_COLOR,
+        )
+        .unwrap();
+        assert_eq!((tooltips.cols(), tooltips.rows()), (640, 120));
+        let sprite = imgcodecs::imread(
+            &test.path("thumbnailer-test-sprite_00000.jpg"),
+            imgcodecs::IMREAD_COLOR,
+        )
+        .unwrap();
+        assert_eq!((sprite.cols(), sprite.rows()), (16, 54));
+    }
+}
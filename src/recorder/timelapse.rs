@@ -0,0 +1,581 @@
+use crate::dtos::messages::{TimelapseInfo, TimelapseRequest, TIMESTAMP_FORMAT};
+use crate::recorder::branch::DynamicBranch;
+use crate::recorder::common::PipelineError;
+use crate::recorder::stillrecorder::sample_to_bgr;
+use chrono::Local;
+use gst::prelude::*;
+use gstreamer_app::{gst, AppSink, AppSrc};
+use log::{debug, error, info, warn};
+use opencv::core::{Mat, Size, Vector};
+use opencv::prelude::*;
+use opencv::{imgcodecs, imgproc};
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const VIDEO_SOURCE: &str = "video-source";
+const VIDEO_SINK: &str = "video-sink";
+const TIMELAPSE_SINK: &str = "timelapse-sink";
+const TIMELAPSE_SOURCE: &str = "timelapse-source";
+const TICK: Duration = Duration::from_millis(100);
+const THUMBNAIL_WIDTH: i32 = 320;
+const EOS_TIMEOUT: gst::ClockTime = gst::ClockTime::from_seconds(10);
+
+// Samples the running source every interval into a video played at a normal frame rate
+pub trait Timelapse: Sync + Send {
+    // Starts sampling, source: the source pipeline and its tee in branch mode (the sampler is
+    // a branch of the source), else the sampler pipeline reads the source socket
+    fn start(
+        &self,
+        source: Option<(&gst::Pipeline, &str)>,
+        request: TimelapseRequest,
+    ) -> Result<TimelapseInfo, PipelineError>;
+    // Finishes the video and writes the timelapse manifest, also after an encoder error
+    fn stop(&self) -> Result<TimelapseInfo, PipelineError>;
+    fn is_running(&self) -> bool;
+}
+
+// Keeps the last frame of the source
+enum Sampler {
+    Pipeline(gst::Pipeline),
+    Branch(DynamicBranch),
+}
+
+impl Sampler {
+    fn stop(&self) {
+        match self {
+            Sampler::Pipeline(pipeline) => {
+                if let Err(e) = pipeline.set_state(gst::State::Null) {
+                    error!("{e}");
+                }
+            }
+            Sampler::Branch(branch) => branch.release(),
+        }
+    }
+}
+
+struct Running {
+    sampler: Sampler,
+    encoder: gst::Pipeline,
+    appsrc: AppSrc,
+    stop: Arc<AtomicBool>,
+    worker: JoinHandle<()>,
+    info: Arc<Mutex<TimelapseInfo>>,
+}
+
+pub struct TimelapseImpl {
+    socket_path: String,
+    pipeline_str: String,
+    branch_str: String,
+    encoding_pipeline_str: String,
+    output_dir: String,
+    interval_sec: f64,
+    fps: u32,
+    thumbnail_every: u32,
+    running: Mutex<Option<Running>>,
+}
+
+impl Timelapse for TimelapseImpl {
+    fn start(
+        &self,
+        source: Option<(&gst::Pipeline, &str)>,
+        request: TimelapseRequest,
+    ) -> Result<TimelapseInfo, PipelineError> {
+        let mut running = self.running.lock().unwrap();
+        // the worker ends only when stopped or on an encoder error
+        if running.as_ref().is_some_and(|r| r.worker.is_finished()) {
+            self.finish(running.take().unwrap());
+        }
+        if running.is_some() {
+            return Err(PipelineError::AlreadyStarted);
+        }
+        let interval_sec = request.interval_sec.unwrap_or(self.interval_sec);
+        let fps = request.fps.unwrap_or(self.fps);
+        if interval_sec <= 0.0 || fps == 0 {
+            error!(
+                "Invalid timelapse interval {} or frame rate {}",
+                interval_sec, fps
+            );
+            return Err(PipelineError::ParseError);
+        }
+        let started_at = Local::now();
+        let prefix = started_at.format(TIMESTAMP_FORMAT).to_string();
+        let video_file = format!("{}/{}-timelapse.mp4", self.output_dir, prefix);
+
+        let encoder = launch(&self.encoding_pipeline_str)?;
+        if let Some(sink) = encoder.by_name(VIDEO_SINK) {
+            if sink.has_property("location", None) {
+                sink.set_property("location", &video_file);
+            }
+        }
+        let appsrc = encoder
+            .by_name(TIMELAPSE_SOURCE)
+            .and_then(|src| src.downcast::<AppSrc>().ok())
+            .ok_or_else(|| {
+                error!(
+                    "The timelapse pipeline needs an appsrc named {}",
+                    TIMELAPSE_SOURCE
+                );
+                PipelineError::ParseError
+            })?;
+        appsrc.set_format(gst::Format::Time);
+        let bus = encoder.bus().ok_or(PipelineError::EncodingError)?;
+
+        let last_sample = Arc::new(Mutex::new(None));
+        let sampler = match source {
+            Some((pipeline, tee)) => {
+                let branch = DynamicBranch::attach(pipeline, tee, self.branch_str.as_str())?;
+                if let Err(e) =
+                    connect_sink(branch.bin(), last_sample.clone()).and_then(|_| branch.start())
+                {
+                    branch.release();
+                    return Err(e);
+                }
+                Sampler::Branch(branch)
+            }
+            None => {
+                let pipeline = launch(&self.pipeline_str)?;
+                if let Some(source) = pipeline.by_name(VIDEO_SOURCE) {
+                    if source.has_property("socket-path", None) {
+                        source.set_property("socket-path", &self.socket_path);
+                    }
+                }
+                connect_sink(pipeline.upcast_ref(), last_sample.clone())?;
+                if let Err(e) = pipeline.set_state(gst::State::Playing) {
+                    error!("{e}");
+                    let _ = pipeline.set_state(gst::State::Null);
+                    return Err(PipelineError::EncodingError);
+                }
+                Sampler::Pipeline(pipeline)
+            }
+        };
+        if let Err(e) = encoder.set_state(gst::State::Playing) {
+            error!("{e}");
+            sampler.stop();
+            return Err(PipelineError::EncodingError);
+        }
+
+        let info = Arc::new(Mutex::new(TimelapseInfo {
+            prefix: prefix.clone(),
+            name: request.name,
+            video_file,
+            interval_sec,
+            fps,
+            started_at,
+            finished_at: None,
+            frames: 0,
+            thumbnails: Vec::new(),
+            error: None,
+        }));
+        let stop = Arc::new(AtomicBool::new(false));
+        let worker = {
+            let worker = Worker {
+                appsrc: appsrc.clone(),
+                bus,
+                last_sample,
+                info: info.clone(),
+                stop: stop.clone(),
+                interval: Duration::from_secs_f64(interval_sec),
+                thumbnail_every: self.thumbnail_every,
+                output_dir: self.output_dir.clone(),
+            };
+            thread::spawn(move || worker.run())
+        };
+        info!(
+            "Timelapse {} started, a frame every {}s at {} fps",
+            prefix, interval_sec, fps
+        );
+        let started = info.lock().unwrap().clone();
+        *running = Some(Running {
+            sampler,
+            encoder,
+            appsrc,
+            stop,
+            worker,
+            info,
+        });
+        Ok(started)
+    }
+
+    fn stop(&self) -> Result<TimelapseInfo, PipelineError> {
+        let running = self
+            .running
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or(PipelineError::NotRunning)?;
+        Ok(self.finish(running))
+    }
+
+    fn is_running(&self) -> bool {
+        self.running.lock().unwrap().is_some()
+    }
+}
+
+impl TimelapseImpl {
+    // Stops sampling, finishes the video and writes the manifest
+    fn finish(&self, running: Running) -> TimelapseInfo {
+        running.stop.store(true, Ordering::SeqCst);
+        if running.worker.join().is_err() {
+            error!("Timelapse sampling failed");
+        }
+        running.sampler.stop();
+
+        // the muxer finishes the file on eos, a failed encoder does not
+        let failed = running.info.lock().unwrap().error.is_some();
+        let _ = running.appsrc.end_of_stream();
+        if let Some(bus) = running.encoder.bus().filter(|_| !failed) {
+            match bus.timed_pop_filtered(
+                Some(EOS_TIMEOUT),
+                &[gst::MessageType::Eos, gst::MessageType::Error],
+            ) {
+                Some(msg) => match msg.view() {
+                    gst::MessageView::Error(err) => error!("Timelapse: {}", err.error()),
+                    _ => debug!("Timelapse encoded"),
+                },
+                None => error!("Timeout finishing the timelapse"),
+            }
+        }
+        if let Err(e) = running.encoder.set_state(gst::State::Null) {
+            error!("{e}");
+        }
+
+        let mut info = running.info.lock().unwrap().clone();
+        info.finished_at = Some(Local::now());
+        if info.frames == 0 {
+            warn!("Timelapse {} without frames", info.prefix);
+        }
+        let manifest = format!("{}/{}-timelapse.json", self.output_dir, info.prefix);
+        let res = serde_json::to_string_pretty(&info)
+            .map_err(|e| e.to_string())
+            .and_then(|json| fs::write(&manifest, json).map_err(|e| e.to_string()));
+        if let Err(e) = res {
+            error!("Unable to write {}: {}", manifest, e);
+        }
+        info!("Timelapse {} stopped, {} frames", info.prefix, info.frames);
+        info
+    }
+}
+
+// Pushes the last frame of the source into the encoder every interval
+struct Worker {
+    appsrc: AppSrc,
+    // the bus of the encoder, sampling ends on its errors
+    bus: gst::Bus,
+    last_sample: Arc<Mutex<Option<gst::Sample>>>,
+    info: Arc<Mutex<TimelapseInfo>>,
+    stop: Arc<AtomicBool>,
+    interval: Duration,
+    thumbnail_every: u32,
+    output_dir: String,
+}
+
+impl Worker {
+    fn run(self) {
+        let (prefix, fps) = {
+            let info = self.info.lock().unwrap();
+            (info.prefix.clone(), info.fps)
+        };
+        let frame_duration = gst::ClockTime::SECOND / fps as u64;
+        // the caps of the first sample, the encoder does not follow changes of the source
+        let mut caps: Option<gst::Caps> = None;
+        let mut next = Instant::now();
+        let mut frames: u32 = 0;
+        while !self.stop.load(Ordering::SeqCst) {
+            if let Some(msg) = self.bus.pop_filtered(&[gst::MessageType::Error]) {
+                if let gst::MessageView::Error(err) = msg.view() {
+                    error!("Timelapse {} failed: {}", prefix, err.error());
+                    self.info.lock().unwrap().error = Some(err.error().to_string());
+                }
+                break;
+            }
+            let now = Instant::now();
+            if now < next {
+                thread::sleep(TICK.min(next - now));
+                continue;
+            }
+            next += self.interval;
+            // a late worker skips the missed frames instead of pushing the same sample again
+            if next <= now {
+                next = now + self.interval;
+            }
+            let Some(sample) = self.last_sample.lock().unwrap().clone() else {
+                debug!("Timelapse {}: no frame yet", prefix);
+                continue;
+            };
+            if let Err(e) = self.push(&sample, &mut caps, frame_duration * frames as u64, fps) {
+                error!("Timelapse {}: {}", prefix, e);
+                continue;
+            }
+            let mut thumbnail = None;
+            if self.thumbnail_every > 0 && frames % self.thumbnail_every == 0 {
+                let file = format!("{}-timelapse-thumb-{:05}.jpg", prefix, frames);
+                let location = format!("{}/{}", self.output_dir, file);
+                match write_thumbnail(&sample, &location) {
+                    Ok(_) => thumbnail = Some(file),
+                    Err(e) => error!("Unable to write {}: {}", location, e),
+                }
+            }
+            frames += 1;
+            let mut info = self.info.lock().unwrap();
+            info.frames = frames;
+            info.thumbnails.extend(thumbnail);
+        }
+    }
+
+    // Pushes the sample as the frame at pts of the video
+    fn push(
+        &self,
+        sample: &gst::Sample,
+        caps: &mut Option<gst::Caps>,
+        pts: gst::ClockTime,
+        fps: u32,
+    ) -> Result<(), String> {
+        let sample_caps = sample.caps().ok_or("Sample without caps")?;
+        match caps.as_ref() {
+            None => {
+                let mut video_caps = sample_caps.to_owned();
+                video_caps
+                    .make_mut()
+                    .set("framerate", gst::Fraction::new(fps as i32, 1));
+                self.appsrc.set_caps(Some(&video_caps));
+                *caps = Some(sample_caps.to_owned());
+            }
+            Some(caps) if **caps != *sample_caps => {
+                return Err(format!("Source caps changed to {sample_caps}"));
+            }
+            Some(_) => (),
+        }
+        let mut buffer = sample.buffer_owned().ok_or("Sample without buffer")?;
+        {
+            let buffer = buffer.make_mut();
+            buffer.set_pts(pts);
+            buffer.set_dts(gst::ClockTime::NONE);
+            buffer.set_duration(gst::ClockTime::SECOND / fps as u64);
+        }
+        self.appsrc.push_buffer(buffer).map_err(|e| e.to_string())?;
+        Ok(())
+    }
+}
+
+fn launch(pipeline_str: &str) -> Result<gst::Pipeline, PipelineError> {
+    gst::parse::launch(pipeline_str)
+        .map_err(|e| {
+            error!("{e}");
+            PipelineError::ParseError
+        })?
+        .downcast::<gst::Pipeline>()
+        .map_err(|_| PipelineError::ParseError)
+}
+
+// Keeps the last sample of the timelapse-sink of the sampler
+fn connect_sink(
+    bin: &gst::Bin,
+    last_sample: Arc<Mutex<Option<gst::Sample>>>,
+) -> Result<(), PipelineError> {
+    let sink = bin
+        .by_name(TIMELAPSE_SINK)
+        .and_then(|sink| sink.downcast::<AppSink>().ok())
+        .ok_or_else(|| {
+            error!(
+                "The timelapse sampler needs an appsink named {}",
+                TIMELAPSE_SINK
+            );
+            PipelineError::ParseError
+        })?;
+    sink.set_callbacks(
+        gstreamer_app::AppSinkCallbacks::builder()
+            .new_sample(move |app_sink| {
+                let sample = app_sink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
+                *last_sample.lock().unwrap() = Some(sample);
+                Ok(gst::FlowSuccess::Ok)
+            })
+            .build(),
+    );
+    Ok(())
+}
+
+fn write_thumbnail(sample: &gst::Sample, location: &str) -> Result<(), String> {
+    let (image, _) = sample_to_bgr(sample)?;
+    let height = (image.rows() * THUMBNAIL_WIDTH / image.cols().max(1)).max(1);
+    let mut thumbnail = Mat::default();
+    let size = Size::new(THUMBNAIL_WIDTH, height);
+    imgproc::resize(&image, &mut thumbnail, size, 0.0, 0.0, imgproc::INTER_AREA)
+        .map_err(|e| e.to_string())?;
+    match imgcodecs::imwrite(location, &thumbnail, &Vector::new()) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err("Unable to encode the thumbnail".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+pub struct TimelapseBuilder {
+    socket_path: String,
+    pipeline_str: String,
+    branch_str: String,
+    encoding_pipeline_str: String,
+    output_dir: String,
+    interval_sec: f64,
+    fps: u32,
+    thumbnail_every: u32,
+}
+
+impl TimelapseBuilder {
+    pub fn new() -> TimelapseBuilder {
+        TimelapseBuilder {
+            socket_path: "/tmp/video0.sock".to_string(),
+            pipeline_str: "unixfdsrc name=video-source ! queue leaky=2 max-size-buffers=1 \
+                ! appsink name=timelapse-sink sync=false max-buffers=1 drop=true"
+                .to_string(),
+            branch_str: "queue leaky=2 max-size-buffers=1 \
+                ! appsink name=timelapse-sink sync=false max-buffers=1 drop=true"
+                .to_string(),
+            encoding_pipeline_str: "appsrc name=timelapse-source format=time \
+                ! videoconvert ! x264enc ! h264parse ! mp4mux ! filesink name=video-sink"
+                .to_string(),
+            output_dir: "./".to_string(),
+            interval_sec: 10.0,
+            fps: 30,
+            thumbnail_every: 30,
+        }
+    }
+
+    pub fn with_socket_path(mut self, socket_path: &str) -> TimelapseBuilder {
+        self.socket_path = socket_path.to_string();
+        self
+    }
+
+    // The sampler reading the source socket, it has an appsink named timelapse-sink
+    pub fn with_pipeline_str(mut self, pipeline_str: &str) -> TimelapseBuilder {
+        self.pipeline_str = pipeline_str.to_string();
+        self
+    }
+
+    // The sampler attached to the source pipeline in branch mode
+    pub fn with_branch_str(mut self, branch_str: &str) -> TimelapseBuilder {
+        self.branch_str = branch_str.to_string();
+        self
+    }
+
+    // The pipeline encoding the samples pushed into its appsrc named timelapse-source
+    pub fn with_encoding_pipeline_str(mut self, pipeline_str: &str) -> TimelapseBuilder {
+        self.encoding_pipeline_str = pipeline_str.to_string();
+        self
+    }
+
+    pub fn with_output_dir(mut self, output_dir: &str) -> TimelapseBuilder {
+        self.output_dir = output_dir.to_string();
+        self
+    }
+
+    pub fn with_interval_sec(mut self, interval_sec: f64) -> TimelapseBuilder {
+        self.interval_sec = interval_sec;
+        self
+    }
+
+    pub fn with_fps(mut self, fps: u32) -> TimelapseBuilder {
+        self.fps = fps;
+        self
+    }
+
+    // A thumbnail every n frames of the video (0: no thumbnails)
+    pub fn with_thumbnail_every(mut self, thumbnail_every: u32) -> TimelapseBuilder {
+        self.thumbnail_every = thumbnail_every;
+        self
+    }
+
+    pub fn build(&self) -> TimelapseImpl {
+        TimelapseImpl {
+            socket_path: self.socket_path.clone(),
+            pipeline_str: self.pipeline_str.clone(),
+            branch_str: self.branch_str.clone(),
+            encoding_pipeline_str: self.encoding_pipeline_str.clone(),
+            output_dir: self.output_dir.clone(),
+            interval_sec: self.interval_sec,
+            fps: self.fps,
+            thumbnail_every: self.thumbnail_every,
+            running: Mutex::new(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::remove_file;
+
+    #[test]
+    fn test_timelapse() {
+        let _ = gst::init();
+        let timelapse = TimelapseBuilder::new()
+            .with_pipeline_str(
+                "videotestsrc is-live=true name=video-source \
+                ! video/x-raw, format=I420, width=320, height=240 \
+                ! appsink name=timelapse-sink sync=false max-buffers=1 drop=true",
+            )
+            .with_output_dir("/tmp")
+            .with_thumbnail_every(2)
+            .build();
+        assert_eq!(timelapse.stop().err(), Some(PipelineError::NotRunning));
+        let request = TimelapseRequest {
+            interval_sec: Some(0.1),
+            fps: Some(10),
+            ..TimelapseRequest::default()
+        };
+        let started = timelapse.start(None, request.clone()).unwrap();
+        assert!(timelapse.is_running());
+        assert_eq!(
+            timelapse.start(None, request).err(),
+            Some(PipelineError::AlreadyStarted)
+        );
+        thread::sleep(Duration::from_millis(1500));
+        let info = timelapse.stop().unwrap();
+        assert!(!timelapse.is_running());
+        assert_eq!(info.prefix, started.prefix);
+        assert!(info.frames >= 10, "{} frames", info.frames);
+        assert_eq!(info.thumbnails.len() as u32, info.frames.div_ceil(2));
+        assert!(fs::metadata(&info.video_file).unwrap().len() > 0);
+        let manifest = format!("/tmp/{}-timelapse.json", info.prefix);
+        assert!(fs::read_to_string(&manifest)
+            .unwrap()
+            .contains("timelapse.mp4"));
+        remove_file(&info.video_file).unwrap();
+        remove_file(&manifest).unwrap();
+        for thumbnail in info.thumbnails {
+            remove_file(format!("/tmp/{}", thumbnail)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_encoder_error() {
+        let _ = gst::init();
+        // the encoder refuses the caps of the samples
+        let timelapse = TimelapseBuilder::new()
+            .with_pipeline_str(
+                "videotestsrc is-live=true name=video-source \
+                ! video/x-raw, format=I420, width=320, height=240 \
+                ! appsink name=timelapse-sink sync=false max-buffers=1 drop=true",
+            )
+            .with_encoding_pipeline_str(
+                "appsrc name=timelapse-source format=time ! audio/x-raw ! fakesink",
+            )
+            .with_output_dir("/tmp")
+            .with_thumbnail_every(0)
+            .build();
+        let request = TimelapseRequest {
+            interval_sec: Some(0.1),
+            fps: Some(10),
+            ..TimelapseRequest::default()
+        };
+        let started = timelapse.start(None, request).unwrap();
+        assert!(started.error.is_none());
+        thread::sleep(Duration::from_millis(1000));
+        // the worker ended on the error, stop finishes the timelapse without waiting for eos
+        let info = timelapse.stop().unwrap();
+        assert!(info.error.is_some());
+        assert!(!timelapse.is_running());
+        remove_file(format!("/tmp/{}-timelapse.json", info.prefix)).unwrap();
+    }
+}
@@ -1,14 +1,25 @@
-use crate::dtos::messages::{RecordingInfo, StillInfo};
+use crate::dtos::messages::{
+    FrameMetrics, IncidentInfo, MotionInterval, ProtectRequest, RecordingInfo, RecordingRequest,
+    StillInfo, StillRequest, TimelapseInfo, TimelapseRequest, STILL_TIMESTAMP_FORMAT,
+};
+use crate::recorder::branch::DynamicBranch;
+use crate::recorder::exif::StillMetadata;
+use crate::recorder::preroll::Preroll;
 use crate::recorder::preview::Preview;
 use crate::recorder::stillrecorder::StillRecorder;
+use crate::recorder::timelapse::Timelapse;
 use crate::{dtos, recorder};
-use chrono::Local;
+use chrono::{DateTime, Local};
 use dtos::messages::VideoSourceInfo;
-use gstreamer::Pipeline;
-use log::error;
+use gstreamer::prelude::*;
+use gstreamer::{self as gst, Pipeline};
+use log::{debug, error};
 use recorder::common::PipelineError;
 use recorder::videorecorder::Recorder;
 use recorder::videosource::Source;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Arc, Mutex};
 use std::{thread, time};
 
 #[allow(dead_code)]
@@ -27,13 +38,60 @@ pub trait VideoController: Sync + Send {
     fn stop(&self, device: &str) -> Result<(), PipelineError>;
 
     // Start recording
-    fn start_recording(&mut self) -> Result<RecordingInfo, PipelineError>;
+    // request: the profile and the conditions that stop the recording automatically
+    fn start_recording(
+        &mut self,
+        request: RecordingRequest,
+    ) -> Result<RecordingInfo, PipelineError>;
 
     // Stop recording
     fn stop_recording(&self) -> Result<(), PipelineError>;
 
-    // Take still
-    fn take_still(&self, device: &str, still_file: &str) -> Result<StillInfo, PipelineError>;
+    // Pause recording (the recording pipeline keeps running)
+    fn pause_recording(&self) -> Result<(), PipelineError>;
+
+    // Resume a paused recording
+    fn resume_recording(&self) -> Result<(), PipelineError>;
+
+    // Protect the footage around now from deletion and copy it into an incident playlist
+    fn protect_recording(&self, request: ProtectRequest) -> Result<IncidentInfo, PipelineError>;
+
+    // True while a recording is running
+    fn is_recording(&self) -> bool;
+
+    // The prefix of the running recording
+    fn session_prefix(&self) -> Option<String>;
+
+    // Adds a period with motion to the running recording
+    fn add_motion_interval(&self, interval: MotionInterval) -> Result<(), PipelineError>;
+
+    // Attaches a branch to the tee of the running source, None without branches
+    fn attach_branch(&self, description: &str) -> Result<Option<DynamicBranch>, PipelineError>;
+
+    // The started device, None while the source is stopped
+    fn device(&self) -> Option<String>;
+
+    // Take still, the capture time, the running session and the note are embedded in it
+    // device: the started device, NotRunning for other devices
+    fn take_still(
+        &self,
+        device: &str,
+        still_file: &str,
+        request: &StillRequest,
+    ) -> Result<StillInfo, PipelineError>;
+
+    // Encodes a still of the live source in memory, nothing is written to the output dir
+    // device: the started device, NotRunning for other devices
+    fn encode_still(&self, device: &str, request: &StillRequest) -> Result<Vec<u8>, PipelineError>;
+
+    // Start a timelapse video of the running source
+    fn start_timelapse(&self, request: TimelapseRequest) -> Result<TimelapseInfo, PipelineError>;
+
+    // Stop the timelapse, the video is finished
+    fn stop_timelapse(&self) -> Result<TimelapseInfo, PipelineError>;
+
+    // The state of the frame analyzer queue of the recordings
+    fn frame_metrics(&self) -> FrameMetrics;
 }
 
 pub struct VideoControllerImpl {
@@ -43,6 +101,14 @@ pub struct VideoControllerImpl {
     preview: Box<dyn Preview>,
     recording_pipeline: Option<Pipeline>,
     preview_pipeline: Option<Pipeline>,
+    profiles: HashMap<String, String>,
+    preroll: Option<Box<dyn Preroll>>,
+    timelapse: Option<Box<dyn Timelapse>>,
+    source_tee: Option<String>,
+    recording_branch_str: String,
+    recording_branch: Option<Arc<DynamicBranch>>,
+    // the started device
+    device: Mutex<Option<String>>,
 }
 
 impl VideoController for VideoControllerImpl {
@@ -51,7 +117,29 @@ impl VideoController for VideoControllerImpl {
     }
 
     fn start(&mut self, device: &str) -> Result<VideoSourceInfo, PipelineError> {
+        if self.source_tee.is_some() {
+            // branches write wall-clock timestamps derived from the source pipeline clock
+            if let Some(pipeline) = self.source.pipeline() {
+                pipeline.use_clock(Some(&self.recorder.clock()));
+            }
+        }
         let res = self.source.start(device);
+        if res.is_ok() {
+            *self.device.lock().unwrap() = Some(device.to_string());
+            let source = self.source.pipeline();
+            let live = match (self.source_tee.as_ref(), source.as_ref()) {
+                (Some(tee), Some(pipeline)) => self.still.start_live(Some((pipeline, tee))),
+                _ => self.still.start_live(None),
+            };
+            if let Err(e) = live {
+                error!("Error starting live stills: {:?}", e);
+            }
+        }
+        if let Some(preroll) = self.preroll.as_ref() {
+            if let Err(e) = preroll.start(&self.recorder.clock()) {
+                error!("Error starting pre-roll buffer: {:?}", e);
+            }
+        }
         let preview_pipeline = self
             .preview
             .prepare_pipeline(self.preview.get_pipeline().as_str())
@@ -75,31 +163,186 @@ impl VideoController for VideoControllerImpl {
         if let Err(e) = self.preview.stop(&self.preview_pipeline) {
             error!("Error stopping preview pipeline: {:?}", e);
         }
+        if let Some(preroll) = self.preroll.as_ref() {
+            if let Err(e) = preroll.stop() {
+                error!("Error stopping pre-roll buffer: {:?}", e);
+            }
+        }
+        self.still.stop_live();
+        if let Some(timelapse) = self.timelapse.as_ref().filter(|t| t.is_running()) {
+            if let Err(e) = timelapse.stop() {
+                error!("Error stopping timelapse: {:?}", e);
+            }
+        }
         thread::sleep(time::Duration::from_secs(1));
+        *self.device.lock().unwrap() = None;
         self.source.stop(device)
     }
 
-    fn start_recording(&mut self) -> Result<RecordingInfo, PipelineError> {
+    fn start_recording(
+        &mut self,
+        request: RecordingRequest,
+    ) -> Result<RecordingInfo, PipelineError> {
         let timestamp = Local::now();
+        // without profile the recording is a branch of the running source (branch mode)
+        if let (Some(tee), None) = (self.source_tee.clone(), request.profile.as_ref()) {
+            return self.start_recording_branch(&tee, &timestamp, request);
+        }
+        let pipeline_str = match request.profile.as_ref() {
+            Some(profile) => self.profiles.get(profile).cloned().ok_or_else(|| {
+                error!("Unknown recording profile: {}", profile);
+                PipelineError::ParseError
+            })?,
+            None => match self.preroll.as_ref() {
+                Some(preroll) => preroll.get_pipeline(),
+                None => self.recorder.get_pipeline(),
+            },
+        };
+        // a recording without profile starts with the buffered pre-roll
+        let preroll = match request.profile {
+            Some(_) => None,
+            None => self.preroll.as_ref(),
+        };
         let recording_pipeline = self
             .recorder
-            .prepare_pipeline(self.recorder.get_pipeline().as_str())
+            .prepare_pipeline(pipeline_str.as_str())
             .map_or_else(|_| Err(PipelineError::ParseError), |pipeline| Ok(pipeline));
         match recording_pipeline {
             Ok(pipeline) => {
                 self.recording_pipeline = pipeline;
-                self.recorder.start(&self.recording_pipeline, &timestamp)
+                self.recording_branch = None;
+                if let Some(preroll) = preroll {
+                    preroll.attach(&self.recording_pipeline)?;
+                }
+                self.recorder
+                    .start(&self.recording_pipeline, &timestamp, request)
             }
             Err(e) => Err(e),
         }
     }
 
     fn stop_recording(&self) -> Result<(), PipelineError> {
+        if let Some(branch) = self.recording_branch.as_ref() {
+            return self.recorder.stop_branch(branch);
+        }
+        if let Some(preroll) = self.preroll.as_ref() {
+            preroll.detach();
+        }
         self.recorder.stop(&self.recording_pipeline)
     }
 
-    fn take_still(&self, _: &str, image_name: &str) -> Result<StillInfo, PipelineError> {
-        self.still.take_still(image_name)
+    fn pause_recording(&self) -> Result<(), PipelineError> {
+        self.recorder.pause(&self.recording_pipeline)
+    }
+
+    fn resume_recording(&self) -> Result<(), PipelineError> {
+        self.recorder.resume(&self.recording_pipeline)
+    }
+
+    fn protect_recording(&self, request: ProtectRequest) -> Result<IncidentInfo, PipelineError> {
+        self.recorder.protect(&self.recording_pipeline, request)
+    }
+
+    fn is_recording(&self) -> bool {
+        self.recorder.is_recording()
+    }
+
+    fn session_prefix(&self) -> Option<String> {
+        self.recorder.session_prefix()
+    }
+
+    fn add_motion_interval(&self, interval: MotionInterval) -> Result<(), PipelineError> {
+        self.recorder.add_motion_interval(interval)
+    }
+
+    fn attach_branch(&self, description: &str) -> Result<Option<DynamicBranch>, PipelineError> {
+        let Some(tee) = self.source_tee.as_ref() else {
+            return Ok(None);
+        };
+        let pipeline = self
+            .source
+            .pipeline()
+            .filter(|p| p.current_state() == gst::State::Playing)
+            .ok_or(PipelineError::NotRunning)?;
+        DynamicBranch::attach(&pipeline, tee, description).map(Some)
+    }
+
+    fn device(&self) -> Option<String> {
+        self.device.lock().unwrap().clone()
+    }
+
+    fn take_still(
+        &self,
+        device: &str,
+        image_name: &str,
+        request: &StillRequest,
+    ) -> Result<StillInfo, PipelineError> {
+        let metadata = self.still_metadata(device, request)?;
+        let encoding = &request.encoding;
+        // the last frame of the running source, without starting a pipeline
+        match self.still.take_live_still(image_name, encoding, &metadata) {
+            Err(PipelineError::NotRunning) => debug!("No live stills, taking a still"),
+            result => return result,
+        }
+        if let (Some(tee), Some(pipeline)) = (self.source_tee.as_ref(), self.source.pipeline()) {
+            if pipeline.current_state() == gst::State::Playing {
+                return self.still.take_still_from(
+                    &pipeline,
+                    tee,
+                    self.source.subscribe(),
+                    image_name,
+                    encoding,
+                    &metadata,
+                );
+            }
+        }
+        self.still.take_still(image_name, encoding, &metadata)
+    }
+
+    fn encode_still(&self, device: &str, request: &StillRequest) -> Result<Vec<u8>, PipelineError> {
+        let metadata = self.still_metadata(device, request)?;
+        match self.still.encode_live_still(&request.encoding, &metadata) {
+            Err(PipelineError::NotRunning) => debug!("No live stills, taking a still"),
+            result => return result,
+        }
+        // without live stills the still is written, read and removed again
+        let name = format!(
+            "{}-unpersisted",
+            Local::now().format(STILL_TIMESTAMP_FORMAT)
+        );
+        let info = self.take_still(device, &name, request)?;
+        let image = fs::read(&info.still_file).map_err(|e| {
+            error!("Unable to read {}: {}", info.still_file, e);
+            PipelineError::StorageError
+        });
+        if let Err(e) = fs::remove_file(&info.still_file) {
+            error!("Unable to remove {}: {}", info.still_file, e);
+        }
+        image
+    }
+
+    fn start_timelapse(&self, request: TimelapseRequest) -> Result<TimelapseInfo, PipelineError> {
+        let timelapse = self.timelapse.as_ref().ok_or(PipelineError::ParseError)?;
+        let pipeline = self
+            .source
+            .pipeline()
+            .filter(|p| p.current_state() == gst::State::Playing)
+            .ok_or(PipelineError::NotRunning)?;
+        match self.source_tee.as_ref() {
+            Some(tee) => timelapse.start(Some((&pipeline, tee)), request),
+            None => timelapse.start(None, request),
+        }
+    }
+
+    fn stop_timelapse(&self) -> Result<TimelapseInfo, PipelineError> {
+        self.timelapse
+            .as_ref()
+            .ok_or(PipelineError::NotRunning)?
+            .stop()
+    }
+
+    fn frame_metrics(&self) -> FrameMetrics {
+        self.recorder.frame_metrics()
     }
 }
 
@@ -117,8 +360,96 @@ impl VideoControllerImpl {
             preview: Box::new(preview),
             recording_pipeline: None,
             preview_pipeline: None,
+            profiles: HashMap::new(),
+            preroll: None,
+            timelapse: None,
+            source_tee: None,
+            recording_branch_str: String::new(),
+            recording_branch: None,
+            device: Mutex::new(None),
         }
     }
+
+    // The metadata of a still of the started device
+    fn still_metadata(
+        &self,
+        device: &str,
+        request: &StillRequest,
+    ) -> Result<StillMetadata, PipelineError> {
+        if self.device().as_deref() != Some(device) {
+            debug!("No still of {}, the device is not started", device);
+            return Err(PipelineError::NotRunning);
+        }
+        Ok(StillMetadata {
+            session: self.recorder.session_prefix(),
+            note: request.note.clone(),
+            device: Some(device.to_string()),
+            ..StillMetadata::new()
+        })
+    }
+
+    // Recordings and stills are branches attached to the tee of the running source pipeline
+    // instead of separate pipelines reading from the unixfd socket
+    pub fn with_branches(
+        mut self,
+        source_tee: &str,
+        recording_branch: &str,
+    ) -> VideoControllerImpl {
+        self.source_tee = Some(source_tee.to_string());
+        self.recording_branch_str = recording_branch.to_string();
+        self
+    }
+
+    fn start_recording_branch(
+        &mut self,
+        tee: &str,
+        timestamp: &DateTime<Local>,
+        request: RecordingRequest,
+    ) -> Result<RecordingInfo, PipelineError> {
+        let pipeline = self.source.pipeline().ok_or(PipelineError::NotRunning)?;
+        if pipeline.current_state() != gst::State::Playing {
+            return Err(PipelineError::NotRunning);
+        }
+        if let Some(branch) = self.recording_branch.as_ref() {
+            if !branch.is_ended() {
+                return Err(PipelineError::AlreadyStarted);
+            }
+        }
+        let branch = Arc::new(DynamicBranch::attach(
+            &pipeline,
+            tee,
+            self.recording_branch_str.as_str(),
+        )?);
+        let messages = self.source.subscribe();
+        let res = self
+            .recorder
+            .start_branch(&branch, messages, timestamp, request);
+        if res.is_err() {
+            branch.release();
+        }
+        // pause, resume and protect check the state of the source pipeline
+        self.recording_pipeline = Some(pipeline);
+        self.recording_branch = Some(branch);
+        res
+    }
+
+    // Records start with the last seconds before the start request
+    pub fn with_preroll(mut self, preroll: impl Preroll + 'static) -> VideoControllerImpl {
+        self.preroll = Some(Box::new(preroll));
+        self
+    }
+
+    // Timelapse videos of the source, sampled by a pipeline or a branch of the source
+    pub fn with_timelapse(mut self, timelapse: impl Timelapse + 'static) -> VideoControllerImpl {
+        self.timelapse = Some(Box::new(timelapse));
+        self
+    }
+
+    // Named recording pipelines that can be selected when starting a recording
+    pub fn with_profiles(mut self, profiles: HashMap<String, String>) -> VideoControllerImpl {
+        self.profiles = profiles;
+        self
+    }
 }
 
 #[cfg(test)]
@@ -166,9 +497,15 @@ mod test {
         let mut controller = VideoControllerImpl::new(source, recorder, still, preview);
         let res = controller.start("video0");
         assert_eq!(res.is_ok(), true);
-        let res = controller.start_recording();
+        let res = controller.start_recording(RecordingRequest::default());
         assert_eq!(res.is_ok(), true);
-        let res = controller.take_still("video0", "test");
+        let res = controller.take_still("video0", "test", &StillRequest::default());
+        assert_eq!(res.is_ok(), true);
+        let res = controller.pause_recording();
+        assert_eq!(res.is_ok(), true);
+        let res = controller.pause_recording();
+        assert_eq!(res.err(), Some(PipelineError::AlreadyPaused));
+        let res = controller.resume_recording();
         assert_eq!(res.is_ok(), true);
         let res = controller.stop_recording();
         assert_eq!(res.is_ok(), true);
@@ -176,4 +513,64 @@ mod test {
         assert_eq!(res.is_ok(), true);
         let _ = remove_file("test-still.jpg");
     }
+
+    #[test]
+    fn test_branch_recording() {
+        let _ = remove_file("/tmp/video1.sock");
+        let source = VideoSourceBuilder::new()
+            .with_fd_dir("/tmp")
+            .with_pipeline(
+                "videotestsrc is-live=true name=video-source \
+                ! tee name=source-tee allow-not-linked=true \
+                ! queue ! unixfdsink name=video-sink",
+            )
+            .build();
+        let recorder = VideoRecorderBuilder::new().build();
+        // without live stills
+        let still = StillRecorderBuilder::new()
+            .with_device("video1")
+            .with_output_dir("/tmp")
+            .with_live_branch_str("")
+            .build();
+        let preview = PreviewBuilder::new()
+            .with_device("video1")
+            .with_pipeline_str(
+                "videotestsrc name=video-source ! videoconvert ! fakesink name=video-sink",
+            )
+            .build();
+        let mut controller = VideoControllerImpl::new(source, recorder, still, preview)
+            .with_branches(
+                "source-tee",
+                "queue ! tee name=t \
+                t. ! queue ! fakesink name=video-sink \
+                t. ! queue ! videoconvert ! appsink name=frame-sink",
+            );
+        let res = controller.start("video1");
+        assert_eq!(res.is_ok(), true);
+        let res = controller.start_recording(RecordingRequest::default());
+        assert_eq!(res.is_ok(), true);
+        let res = controller.start_recording(RecordingRequest::default());
+        assert_eq!(res.err(), Some(PipelineError::AlreadyStarted));
+        let res = controller.take_still("video1", "branch", &StillRequest::default());
+        assert_eq!(res.map(|info| info.device), Ok("video1".to_string()));
+        let res = controller.take_still("video2", "branch", &StillRequest::default());
+        assert_eq!(res.err(), Some(PipelineError::NotRunning));
+        // the still is taken from the source and removed after reading it
+        let image = controller
+            .encode_still("video1", &StillRequest::default())
+            .unwrap();
+        assert_eq!(image[..2], [0xff, 0xd8]);
+        let unpersisted = std::fs::read_dir("/tmp")
+            .unwrap()
+            .filter_map(|entry| entry.ok())
+            .any(|entry| entry.file_name().to_string_lossy().contains("-unpersisted"));
+        assert!(!unpersisted);
+        let res = controller.stop_recording();
+        assert_eq!(res.is_ok(), true);
+        let res = controller.stop_recording();
+        assert_eq!(res.err(), Some(PipelineError::NotRunning));
+        let res = controller.stop("video1");
+        assert_eq!(res.is_ok(), true);
+        let _ = remove_file("/tmp/branch-still.jpg");
+    }
 }
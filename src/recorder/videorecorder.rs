@@ -1,16 +1,34 @@
-use crate::dtos::messages::{ChunkInfo, RecordingInfo};
-use crate::recorder::framehandler::{FrameHandler, FrameHandlerImpl};
+use crate::dtos::messages::{
+    ChunkInfo, FrameMetrics, IncidentInfo, MotionInterval, ProtectRequest, RecordingInfo,
+    RecordingRequest, StopReason,
+};
+use crate::recorder::analyzer::{AnalyzerFactory, AnalyzerSession, FrameAnalyzer, Segment};
+use crate::recorder::branch::DynamicBranch;
+use crate::recorder::clock::WallClock;
+use crate::recorder::events::EventLog;
+use crate::recorder::framehandler::{FrameFormat, FrameHandler, FrameHandlerImpl};
+use crate::recorder::playlist::PlaylistAnnotator;
+use crate::recorder::poster::{PosterAnalyzer, PosterConfig};
+use crate::recorder::retention::{segment_index, RetentionManager, RetentionPolicy};
+use crate::recorder::session::Session;
+use crate::recorder::thumbnailer::{ThumbnailConfig, Thumbnailer};
+use crate::utils::config::ClockSource;
 use crate::{dtos, recorder};
-use chrono::{DateTime, Local};
-use futures::StreamExt;
+use chrono::{DateTime, Local, TimeZone};
+use futures::channel::mpsc::UnboundedReceiver;
+use futures::{Stream, StreamExt};
 use gio::prelude::*;
-use gio::{glib, Cancellable, File, FileCreateFlags, FileOutputStream};
+use gio::{
+    glib, Cancellable, File, FileCreateFlags, FileOutputStream, MemoryOutputStream, OutputStream,
+};
 use gst::prelude::*;
 use gstreamer::element_error;
 use gstreamer_app::{gst, AppSink};
 use log::{debug, error, info};
 use recorder::common::PipelineError;
-use std::sync::{mpsc, Mutex};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use tokio::runtime::Runtime;
 use tokio::time::*;
 
@@ -24,11 +42,74 @@ pub trait Recorder: Sync + Send {
         &self,
         pipeline: &Option<gst::Pipeline>,
         start_timestamp: &DateTime<Local>,
+        request: RecordingRequest,
     ) -> Result<RecordingInfo, PipelineError>;
     fn stop(&self, pipeline: &Option<gst::Pipeline>) -> Result<(), PipelineError>;
+    // Starts recording into a branch attached to the running source pipeline,
+    // messages are the messages of the source pipeline
+    fn start_branch(
+        &self,
+        branch: &Arc<DynamicBranch>,
+        messages: UnboundedReceiver<gst::Message>,
+        start_timestamp: &DateTime<Local>,
+        request: RecordingRequest,
+    ) -> Result<RecordingInfo, PipelineError>;
+    // Ends the recording branch, the source pipeline keeps running
+    fn stop_branch(&self, branch: &Arc<DynamicBranch>) -> Result<(), PipelineError>;
+    // Stops writing segments and thumbnails while the pipeline keeps running
+    fn pause(&self, pipeline: &Option<gst::Pipeline>) -> Result<(), PipelineError>;
+    // Continues writing segments, starting with a keyframe after a discontinuity
+    fn resume(&self, pipeline: &Option<gst::Pipeline>) -> Result<(), PipelineError>;
+    // Keeps the footage around now (exempt from retention) and copies it into an incident playlist
+    fn protect(
+        &self,
+        pipeline: &Option<gst::Pipeline>,
+        request: ProtectRequest,
+    ) -> Result<IncidentInfo, PipelineError>;
+    // True while a recording session is running
+    fn is_recording(&self) -> bool;
+    // The prefix of the running session
+    fn session_prefix(&self) -> Option<String>;
+    // Adds a period with motion to the running session
+    fn add_motion_interval(&self, interval: MotionInterval) -> Result<(), PipelineError>;
     fn prepare_pipeline(&self, cmd: &str) -> Result<Option<gst::Pipeline>, PipelineError>;
 
     fn get_pipeline(&self) -> String;
+
+    // The pipeline clock (wall-clock) of the recordings
+    fn clock(&self) -> gst::Clock;
+
+    // The state of the frame analyzer queue
+    fn frame_metrics(&self) -> FrameMetrics;
+}
+
+// Shared between the recorder, the pad probes and the message loop
+#[derive(Default)]
+struct PauseState {
+    paused: AtomicBool,
+    waiting_for_keyframe: AtomicBool,
+    discontinuity_pending: AtomicBool,
+    resume_running_time: AtomicU64,
+}
+
+impl PauseState {
+    fn reset(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+        self.waiting_for_keyframe.store(false, Ordering::SeqCst);
+        self.discontinuity_pending.store(false, Ordering::SeqCst);
+        self.resume_running_time.store(0, Ordering::SeqCst);
+    }
+
+    // Returns true if the segment starting at running_time is the first one after a resume
+    fn take_discontinuity(&self, running_time: u64) -> bool {
+        if self.discontinuity_pending.load(Ordering::SeqCst)
+            && running_time >= self.resume_running_time.load(Ordering::SeqCst)
+        {
+            self.discontinuity_pending.store(false, Ordering::SeqCst);
+            return true;
+        }
+        false
+    }
 }
 
 pub struct VideoRecorder {
@@ -39,8 +120,14 @@ pub struct VideoRecorder {
     chunk_prefix: String,
     runtime: Runtime,
     socket_path: String,
-    fh: std::sync::Arc<Mutex<FrameHandlerImpl>>,
-    sender: mpsc::SyncSender<String>,
+    fh: std::sync::Arc<FrameHandlerImpl>,
+    clock: WallClock,
+    annotator: std::sync::Arc<Mutex<PlaylistAnnotator>>,
+    pause_state: std::sync::Arc<PauseState>,
+    session: std::sync::Arc<Mutex<Session>>,
+    retention: std::sync::Arc<Mutex<RetentionManager>>,
+    sink: Mutex<Option<gst::Element>>,
+    analyzer_factories: HashMap<String, AnalyzerFactory>,
 }
 
 impl Recorder for VideoRecorder {
@@ -48,6 +135,7 @@ impl Recorder for VideoRecorder {
         &self,
         gst_pipeline: &Option<gst::Pipeline>,
         start_timestamp: &DateTime<Local>,
+        request: RecordingRequest,
     ) -> Result<RecordingInfo, PipelineError> {
         info!("Starting recording pipeline: {}", self.pipeline);
         if gst_pipeline.as_ref().unwrap().current_state() == gst::State::Playing {
@@ -60,51 +148,14 @@ impl Recorder for VideoRecorder {
             source_binding.set_property("socket-path", &self.socket_path);
         }
         debug!("using socket path: {}", self.socket_path);
-        let sink_binding = pipeline_bin.by_name(VIDEO_SINK).unwrap();
-        let timestamp = start_timestamp
-            .format(dtos::messages::TIMESTAMP_FORMAT)
-            .to_string();
-        let output_location = format!(
-            "{}/{}-{}_%05d.ts",
-            &self.output_dir, &timestamp, &self.chunk_prefix
-        );
-        let ols = output_location.as_str();
-        info!("Output location: {}", ols);
-        if sink_binding.has_property("location", None) {
-            sink_binding.set_property("location", output_location);
-            sink_binding.set_property("target-duration", &self.chunk_sec);
-            sink_binding.set_property(
-                "playlist-location",
-                format!("{}/{}-playlist.m3u8", &self.output_dir, &timestamp),
-            );
-            sink_binding.set_property("message-forward", true);
-            let sender = self.sender.clone();
-            sink_binding.connect_closure(
-                "get-fragment-stream",
-                false,
-                glib::closure!(
-                    move |_elem: &gst::Element, filename: &str| -> FileOutputStream {
-                        info!("stream_id: {}", filename);
-                        let file = File::for_path(filename);
-                        sender
-                            .try_send(filename.to_string())
-                            .expect("Send fragment event");
-                        file.replace(None, false, FileCreateFlags::NONE, Cancellable::NONE)
-                            .unwrap()
-                    }
-                ),
-            );
-        }
-        let frame_sink_binding = pipeline_bin.by_name(FRAME_SINK).unwrap();
-        let dummy = frame_sink_binding.downcast_ref::<AppSink>();
-        let frame_sink = dummy.expect("Frame sink is expected to be an appsink!");
-        self.fh.lock().unwrap().reset();
-        frame_sink.set_callbacks(
-            gstreamer_app::AppSinkCallbacks::builder()
-                .new_sample(sample_callback(self.fh.clone()))
-                .build(),
-        );
+        let timestamp = self.configure(
+            pipeline_bin.upcast_ref::<gst::Bin>(),
+            start_timestamp,
+            request,
+        )?;
 
+        // segments are tagged with the wall-clock time derived from the pipeline clock
+        pipeline_bin.use_clock(Some(&self.clock.clock));
         let bus = gst_pipeline
             .as_ref()
             .expect("unable to get pipeline for bus")
@@ -117,19 +168,14 @@ impl Recorder for VideoRecorder {
             .set_state(gst::State::Playing)
             .or_else(|e| {
                 error!("{e}");
+                self.session.lock().unwrap().finish(StopReason::Error);
                 Err(PipelineError::EncodingError)
             })?;
 
-        let callback = self.on_chunk.clone();
-        let frame_handler = self.fh.clone();
-        self.fh
-            .as_ref()
-            .lock()
-            .unwrap()
-            .set_start_timestamp(start_timestamp.clone());
-        self.runtime.spawn(async {
-            message_loop(bus, callback, frame_handler).await;
-        });
+        self.spawn_message_loop(
+            RecordingTarget::Pipeline(pipeline_bin.clone()),
+            bus.stream(),
+        );
         info!("Pipeline started");
         if log::log_enabled!(log::Level::Debug) {
             gst_pipeline
@@ -139,6 +185,36 @@ impl Recorder for VideoRecorder {
         }
         Ok(RecordingInfo { prefix: timestamp })
     }
+
+    fn start_branch(
+        &self,
+        branch: &Arc<DynamicBranch>,
+        messages: UnboundedReceiver<gst::Message>,
+        start_timestamp: &DateTime<Local>,
+        request: RecordingRequest,
+    ) -> Result<RecordingInfo, PipelineError> {
+        info!("Starting recording branch: {}", branch.bin().name());
+        let timestamp = self.configure(branch.bin(), start_timestamp, request)?;
+        if let Err(e) = branch.start() {
+            self.session.lock().unwrap().finish(StopReason::Error);
+            return Err(e);
+        }
+        self.spawn_message_loop(RecordingTarget::Branch(branch.clone()), messages);
+        Ok(RecordingInfo { prefix: timestamp })
+    }
+
+    fn stop_branch(&self, branch: &Arc<DynamicBranch>) -> Result<(), PipelineError> {
+        info!("Stopping recording branch: {}", branch.bin().name());
+        if branch.is_ended() {
+            return Err(PipelineError::NotRunning);
+        }
+        self.fh.finish();
+        self.session.lock().unwrap().finish(StopReason::Manual);
+        self.retention.lock().unwrap().finish_incidents();
+        // the branch is released by the message loop once the last segment is written
+        branch.end()
+    }
+
     fn stop(&self, gst_pipeline: &Option<gst::Pipeline>) -> Result<(), PipelineError> {
         info!("Stopping pipeline: {}", self.pipeline);
         if let None = gst_pipeline.as_ref() {
@@ -147,19 +223,115 @@ impl Recorder for VideoRecorder {
         if gst_pipeline.as_ref().unwrap().current_state() == gst::State::Null {
             return Err(PipelineError::NotRunning);
         }
-        let res = gst_pipeline
-            .as_ref()
-            .unwrap()
-            .set_state(gst::State::Null)
-            .map_or_else(
-                |e| {
-                    error!("{e}");
-                    Err(PipelineError::EncodingError)
-                },
-                |_| Ok(()),
-            );
+        self.fh.finish();
+        self.session.lock().unwrap().finish(StopReason::Manual);
+        self.retention.lock().unwrap().finish_incidents();
+        // wakes the message loop, it ends with the stopped session
+        let stopped = gst::Structure::new_empty("recording-stopped");
+        let pipeline = gst_pipeline.as_ref().unwrap();
+        if pipeline
+            .post_message(gst::message::Application::new(stopped))
+            .is_err()
+        {
+            debug!("Unable to wake the message loop");
+        }
+        let res = pipeline.set_state(gst::State::Null).map_or_else(
+            |e| {
+                error!("{e}");
+                Err(PipelineError::EncodingError)
+            },
+            |_| Ok(()),
+        );
         res
     }
+    fn pause(&self, gst_pipeline: &Option<gst::Pipeline>) -> Result<(), PipelineError> {
+        info!("Pausing recording");
+        match gst_pipeline.as_ref() {
+            Some(pipeline) if pipeline.current_state() == gst::State::Playing => (),
+            _ => return Err(PipelineError::NotRunning),
+        }
+        if self.pause_state.paused.swap(true, Ordering::SeqCst) {
+            return Err(PipelineError::AlreadyPaused);
+        }
+        Ok(())
+    }
+
+    fn resume(&self, gst_pipeline: &Option<gst::Pipeline>) -> Result<(), PipelineError> {
+        info!("Resuming recording");
+        let pipeline = match gst_pipeline.as_ref() {
+            Some(pipeline) if pipeline.current_state() == gst::State::Playing => pipeline,
+            _ => return Err(PipelineError::NotRunning),
+        };
+        if !self.pause_state.paused.load(Ordering::SeqCst) {
+            return Err(PipelineError::NotPaused);
+        }
+        let running_time = pipeline
+            .current_running_time()
+            .map(|t| t.nseconds())
+            .unwrap_or_default();
+        self.pause_state
+            .resume_running_time
+            .store(running_time, Ordering::SeqCst);
+        self.pause_state
+            .discontinuity_pending
+            .store(true, Ordering::SeqCst);
+        self.pause_state
+            .waiting_for_keyframe
+            .store(true, Ordering::SeqCst);
+        self.pause_state.paused.store(false, Ordering::SeqCst);
+        // ask the encoder for a keyframe, so the next segment starts right away
+        let sink = self.sink.lock().unwrap().clone();
+        if let Some(pad) = sink.and_then(|s| s.sink_pads().into_iter().next()) {
+            let force_key_unit = gst::Structure::builder("GstForceKeyUnit")
+                .field("all-headers", true)
+                .build();
+            if !pad.send_event(gst::event::CustomUpstream::new(force_key_unit)) {
+                debug!("Keyframe request not handled");
+            }
+        }
+        Ok(())
+    }
+
+    fn protect(
+        &self,
+        gst_pipeline: &Option<gst::Pipeline>,
+        request: ProtectRequest,
+    ) -> Result<IncidentInfo, PipelineError> {
+        info!("Protecting recording: {:?}", request);
+        match gst_pipeline.as_ref() {
+            Some(pipeline) if pipeline.current_state() == gst::State::Playing => (),
+            _ => return Err(PipelineError::NotRunning),
+        }
+        let incident = self.retention.lock().unwrap().protect(
+            Local::now(),
+            Duration::from_secs(request.before_sec),
+            Duration::from_secs(request.after_sec),
+            request.name,
+        )?;
+        self.session.lock().unwrap().add_incident(incident.clone());
+        Ok(incident)
+    }
+
+    fn is_recording(&self) -> bool {
+        self.session.lock().unwrap().is_active()
+    }
+
+    fn session_prefix(&self) -> Option<String> {
+        let session = self.session.lock().unwrap();
+        session
+            .is_active()
+            .then(|| session.manifest().prefix.clone())
+    }
+
+    fn add_motion_interval(&self, interval: MotionInterval) -> Result<(), PipelineError> {
+        let mut session = self.session.lock().unwrap();
+        if !session.is_active() {
+            return Err(PipelineError::NotRunning);
+        }
+        session.add_motion_interval(interval);
+        Ok(())
+    }
+
     fn prepare_pipeline(&self, cmd: &str) -> Result<Option<gst::Pipeline>, PipelineError> {
         match gst::parse::launch(cmd) {
             Ok(pipeline) => {
@@ -176,10 +348,230 @@ impl Recorder for VideoRecorder {
     fn get_pipeline(&self) -> String {
         self.pipeline.clone()
     }
+
+    fn clock(&self) -> gst::Clock {
+        self.clock.clock.clone()
+    }
+
+    fn frame_metrics(&self) -> FrameMetrics {
+        self.fh.metrics()
+    }
+}
+
+impl VideoRecorder {
+    // Sets up the sinks and the session of a recording (pipeline or branch)
+    // returns: the session prefix
+    fn configure(
+        &self,
+        pipeline_bin: &gst::Bin,
+        start_timestamp: &DateTime<Local>,
+        request: RecordingRequest,
+    ) -> Result<String, PipelineError> {
+        let analyzers = self.create_analyzers(&request.analyzers)?;
+        let sink_binding = pipeline_bin.by_name(VIDEO_SINK).unwrap();
+        let timestamp = start_timestamp
+            .format(dtos::messages::TIMESTAMP_FORMAT)
+            .to_string();
+        let output_location = format!(
+            "{}/{}-{}_%05d.ts",
+            &self.output_dir, &timestamp, &self.chunk_prefix
+        );
+        let ols = output_location.as_str();
+        info!("Output location: {}", ols);
+        let playlist_location = format!("{}/{}-playlist.m3u8", &self.output_dir, &timestamp);
+        self.retention
+            .lock()
+            .unwrap()
+            .reset(&self.output_dir, &timestamp);
+        let loop_recording = self.retention.lock().unwrap().is_enabled();
+        if loop_recording {
+            self.annotator
+                .lock()
+                .unwrap()
+                .reset_window(&playlist_location, self.chunk_sec);
+        } else {
+            self.annotator.lock().unwrap().reset(&playlist_location);
+        }
+        if sink_binding.has_property("location", None) {
+            sink_binding.set_property("location", output_location);
+            sink_binding.set_property("target-duration", &self.chunk_sec);
+            sink_binding.set_property("playlist-location", &playlist_location);
+            sink_binding.set_property("message-forward", true);
+            if loop_recording {
+                // loop recording: the retention manager deletes the old segments (protected
+                // ones are kept, so hlssink3 must not delete any) and the annotator writes the
+                // playlist as a sliding window, the playlist of hlssink3 is kept in memory
+                sink_binding.set_property_from_str("playlist-type", "unspecified");
+                sink_binding.set_property("playlist-length", 1u32);
+                sink_binding.set_property("max-files", 0u32);
+                sink_binding.set_property("enable-endlist", false);
+                sink_binding.connect_closure(
+                    "get-playlist-stream",
+                    false,
+                    glib::closure!(
+                        move |_elem: &gst::Element, _location: &str| -> OutputStream {
+                            MemoryOutputStream::new_resizable().upcast()
+                        }
+                    ),
+                );
+            }
+            sink_binding.connect_closure(
+                "get-fragment-stream",
+                false,
+                glib::closure!(
+                    move |_elem: &gst::Element, filename: &str| -> FileOutputStream {
+                        info!("stream_id: {}", filename);
+                        let file = File::for_path(filename);
+                        file.replace(None, false, FileCreateFlags::NONE, Cancellable::NONE)
+                            .unwrap()
+                    }
+                ),
+            );
+        }
+        self.pause_state.reset();
+        add_pause_probe(&sink_binding, self.pause_state.clone(), true);
+        *self.sink.lock().unwrap() = Some(sink_binding);
+        let frame_sink_binding = pipeline_bin.by_name(FRAME_SINK).unwrap();
+        add_pause_probe(&frame_sink_binding, self.pause_state.clone(), false);
+        let dummy = frame_sink_binding.downcast_ref::<AppSink>();
+        let frame_sink = dummy.expect("Frame sink is expected to be an appsink!");
+        self.session.lock().unwrap().start(
+            &self.output_dir,
+            &timestamp,
+            start_timestamp,
+            request,
+            loop_recording,
+        );
+        self.fh.start(
+            AnalyzerSession {
+                output_dir: self.output_dir.clone(),
+                prefix: timestamp.clone(),
+                started_at: *start_timestamp,
+            },
+            analyzers,
+        );
+        frame_sink.set_callbacks(
+            gstreamer_app::AppSinkCallbacks::builder()
+                .new_sample(sample_callback(self.fh.clone(), self.clock.clone()))
+                .build(),
+        );
+        Ok(timestamp)
+    }
+
+    // Creates the analyzers requested for a single recording
+    fn create_analyzers(
+        &self,
+        names: &[String],
+    ) -> Result<Vec<Box<dyn FrameAnalyzer>>, PipelineError> {
+        names
+            .iter()
+            .map(|name| match self.analyzer_factories.get(name) {
+                Some(factory) => Ok(factory()),
+                None => {
+                    error!("Unknown analyzer: {}", name);
+                    Err(PipelineError::ParseError)
+                }
+            })
+            .collect()
+    }
+
+    // Starts the message loop of a started recording
+    fn spawn_message_loop(
+        &self,
+        target: RecordingTarget,
+        messages: impl Stream<Item = gst::Message> + Unpin + Send + 'static,
+    ) {
+        let callback = self.on_chunk.clone();
+        let frame_handler = self.fh.clone();
+        let context = MessageContext {
+            target,
+            generation: self.session.lock().unwrap().generation(),
+            annotator: self.annotator.clone(),
+            clock: self.clock.clone(),
+            pause_state: self.pause_state.clone(),
+            session: self.session.clone(),
+            retention: self.retention.clone(),
+        };
+        self.runtime.spawn(async {
+            message_loop(messages, callback, frame_handler, context).await;
+        });
+    }
+}
+
+// What a recording writes into: an own pipeline or a branch of the source pipeline
+enum RecordingTarget {
+    Pipeline(gst::Pipeline),
+    Branch(Arc<DynamicBranch>),
+}
+
+impl RecordingTarget {
+    // Ends the recording with an EOS, so the last segment and the thumbnails are finalized
+    fn end(&self) {
+        match self {
+            RecordingTarget::Pipeline(pipeline) => {
+                if !pipeline.send_event(gst::event::Eos::new()) {
+                    error!("Failed to send eos event");
+                }
+            }
+            RecordingTarget::Branch(branch) => {
+                if let Err(e) = branch.end() {
+                    debug!("Branch already ended: {:?}", e);
+                }
+            }
+        }
+    }
+
+    fn is_eos(&self, msg: &gst::Message) -> bool {
+        match self {
+            RecordingTarget::Pipeline(_) => matches!(msg.view(), gst::MessageView::Eos(..)),
+            RecordingTarget::Branch(branch) => branch.is_eos(msg),
+        }
+    }
+
+    fn release(&self) {
+        match self {
+            RecordingTarget::Pipeline(pipeline) => {
+                if let Err(e) = pipeline.set_state(gst::State::Null) {
+                    error!("{e}");
+                }
+            }
+            RecordingTarget::Branch(branch) => branch.release(),
+        }
+    }
+}
+
+// Drops the buffers reaching the sink while the recording is paused.
+// For encoded streams (wait_for_keyframe) buffers are dropped after a resume until the next keyframe.
+fn add_pause_probe(
+    sink: &gst::Element,
+    pause_state: std::sync::Arc<PauseState>,
+    wait_for_keyframe: bool,
+) {
+    let Some(pad) = sink.sink_pads().into_iter().next() else {
+        error!("No sink pad found on {}", sink.name());
+        return;
+    };
+    pad.add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+        if pause_state.paused.load(Ordering::SeqCst) {
+            return gst::PadProbeReturn::Drop;
+        }
+        if wait_for_keyframe && pause_state.waiting_for_keyframe.load(Ordering::SeqCst) {
+            if let Some(buffer) = info.buffer() {
+                if buffer.flags().contains(gst::BufferFlags::DELTA_UNIT) {
+                    return gst::PadProbeReturn::Drop;
+                }
+                pause_state
+                    .waiting_for_keyframe
+                    .store(false, Ordering::SeqCst);
+            }
+        }
+        gst::PadProbeReturn::Ok
+    });
 }
 
 fn sample_callback(
-    fh: std::sync::Arc<Mutex<FrameHandlerImpl>>,
+    fh: std::sync::Arc<FrameHandlerImpl>,
+    clock: WallClock,
 ) -> impl Fn(&AppSink) -> Result<gst::FlowSuccess, gst::FlowError> {
     move |app_sink: &AppSink| {
         let sample = app_sink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
@@ -192,7 +584,22 @@ fn sample_callback(
             );
             gst::FlowError::Error
         })?;
-        fh.lock().unwrap().handle_frame(&buffer).unwrap();
+        let base_time = app_sink
+            .base_time()
+            .map(|t| t.nseconds())
+            .unwrap_or_default();
+        let timestamp = buffer.pts().map_or_else(Local::now, |pts| {
+            Local.timestamp_nanos(clock.to_unix_nanos(base_time, pts.nseconds()) as i64)
+        });
+        let format = sample
+            .caps()
+            .ok_or("Sample without caps".to_string())
+            .and_then(FrameFormat::from_caps)
+            .map_err(|e| {
+                element_error!(app_sink, gst::ResourceError::Failed, ("{}", e));
+                gst::FlowError::NotNegotiated
+            })?;
+        fh.handle_frame(&buffer, &format, timestamp)?;
         let _ = buffer.map_readable().map_err(|_| {
             element_error!(
                 app_sink,
@@ -206,24 +613,92 @@ fn sample_callback(
     }
 }
 
+// The session state the message loop works on
+struct MessageContext {
+    target: RecordingTarget,
+    // the session of the loop, a later session is left alone
+    generation: u64,
+    annotator: std::sync::Arc<Mutex<PlaylistAnnotator>>,
+    clock: WallClock,
+    pause_state: std::sync::Arc<PauseState>,
+    session: std::sync::Arc<Mutex<Session>>,
+    retention: std::sync::Arc<Mutex<RetentionManager>>,
+}
+
+impl MessageContext {
+    // True while the session of the loop is the latest one
+    fn is_current(&self) -> bool {
+        self.session.lock().unwrap().generation() == self.generation
+    }
+
+    // True once a pipeline has nothing left to report: its session was stopped or replaced.
+    // A branch is stopped with an eos, its loop waits for it to release the branch.
+    fn is_done(&self) -> bool {
+        let session = self.session.lock().unwrap();
+        match self.target {
+            RecordingTarget::Pipeline(_) => {
+                session.generation() != self.generation || !session.is_active()
+            }
+            RecordingTarget::Branch(_) => false,
+        }
+    }
+}
+
 async fn message_loop(
-    bus: gst::Bus,
+    mut messages: impl Stream<Item = gst::Message> + Unpin,
     on_chunk: std::sync::Arc<Mutex<Option<fn(&ChunkInfo) -> ()>>>,
-    fh: std::sync::Arc<Mutex<FrameHandlerImpl>>,
+    fh: std::sync::Arc<FrameHandlerImpl>,
+    context: MessageContext,
 ) {
-    let mut messages = bus.stream();
-
-    while let Some(msg) = messages.next().await {
+    // the time limits are checked by a timer, a paused or stalled recording has no segments
+    let mut deadline = context.session.lock().unwrap().deadline();
+    let timer = sleep(deadline.as_ref().map_or(Duration::ZERO, |(at, _)| {
+        (*at - Local::now()).to_std().unwrap_or_default()
+    }));
+    tokio::pin!(timer);
+    loop {
+        let msg = tokio::select! {
+            msg = messages.next() => msg,
+            _ = &mut timer, if deadline.is_some() => {
+                if context.is_done() {
+                    break;
+                }
+                if let Some((_, reason)) = deadline.take() {
+                    end_recording(&context, reason);
+                }
+                continue;
+            }
+        };
+        let Some(msg) = msg else {
+            break;
+        };
+        if context.is_done() {
+            break;
+        }
         use gst::MessageView;
 
         // Determine whether we want to quit: on EOS or error message
         // we quit, otherwise simply continue.
-        match msg.view() {
-            MessageView::Eos(..) => {
-                info!("EOS");
-                fh.lock().unwrap().collect_frames().unwrap();
-                break;
+        let current = context.is_current();
+        if context.target.is_eos(&msg) {
+            info!("EOS");
+            if current {
+                fh.finish();
+                context
+                    .session
+                    .lock()
+                    .unwrap()
+                    .finish(StopReason::EndOfStream);
+                context.retention.lock().unwrap().finish_incidents();
             }
+            context.target.release();
+            break;
+        }
+        // a stopped branch only waits for its eos
+        if !current {
+            continue;
+        }
+        match msg.view() {
             MessageView::Error(err) => {
                 println!(
                     "Error from {:?}: {} ({:?})",
@@ -231,6 +706,7 @@ async fn message_loop(
                     err.error(),
                     err.debug()
                 );
+                context.session.lock().unwrap().finish(StopReason::Error);
                 break;
             }
             MessageView::Element(_) => {
@@ -251,21 +727,59 @@ async fn message_loop(
                                     "duration: {}",
                                     msg_struct.get::<u64>("duration").unwrap().to_string()
                                 );
+                                let location =
+                                    msg_struct.get::<&str>("location").unwrap().to_string();
+                                let running_time = msg_struct.get::<u64>("running-time").unwrap();
+                                let base_time = msg
+                                    .src()
+                                    .and_then(|s| s.downcast_ref::<gst::Element>())
+                                    .and_then(|e| e.base_time())
+                                    .map(|t| t.nseconds())
+                                    .unwrap_or_default();
+                                let duration = Duration::from_nanos(
+                                    msg_struct.get::<u64>("duration").unwrap(),
+                                );
+                                let program_date_time = Local.timestamp_nanos(
+                                    context.clock.to_unix_nanos(base_time, running_time) as i64,
+                                );
+                                if let Some(index) = segment_index(&location) {
+                                    let _ = fh.segment_finished(Segment {
+                                        index,
+                                        start: gst::ClockTime::from_nseconds(running_time),
+                                        duration: gst::ClockTime::from_nseconds(
+                                            duration.as_nanos() as u64,
+                                        ),
+                                    });
+                                }
+                                let evicted = context.retention.lock().unwrap().add_segment(
+                                    &location,
+                                    duration,
+                                    program_date_time,
+                                );
+                                if let Ok(mut a) = context.annotator.lock() {
+                                    if context.pause_state.take_discontinuity(running_time) {
+                                        a.add_discontinuity(&location);
+                                    }
+                                    a.add_segment(&location, duration, program_date_time);
+                                    for segment in evicted.iter() {
+                                        a.remove_segment(segment);
+                                    }
+                                    if let Err(e) = a.apply() {
+                                        error!("Unable to annotate playlist: {e}");
+                                    }
+                                }
                                 if let Ok(f) = on_chunk.lock() {
                                     if let Some(ff) = f.as_ref() {
                                         let chunk = ChunkInfo::new(
-                                            msg_struct.get::<&str>("location").unwrap().to_string(),
-                                            msg_struct
-                                                .get::<u64>("running-time")
-                                                .unwrap()
-                                                .to_string(),
-                                            Duration::from_secs(
-                                                msg_struct.get::<u64>("duration").unwrap(),
-                                            ),
+                                            location.clone(),
+                                            running_time.to_string(),
+                                            duration,
+                                            program_date_time,
                                         );
                                         ff(&chunk);
                                     }
                                 }
+                                check_limits(&context, &location, duration, &program_date_time);
                             }
                         }
                         _ => (),
@@ -276,6 +790,39 @@ async fn message_loop(
         };
     }
 }
+// Accounts the segment in the session and ends the recording once a limit has been reached.
+// An EOS is sent, so the last segment and the thumbnails are finalized before the pipeline stops.
+fn check_limits(
+    context: &MessageContext,
+    location: &str,
+    duration: Duration,
+    program_date_time: &DateTime<Local>,
+) {
+    let reason = {
+        let mut session = context.session.lock().unwrap();
+        if session.generation() != context.generation {
+            return;
+        }
+        session.add_segment(location, duration);
+        session.check_limits(program_date_time)
+    };
+    if let Some(reason) = reason {
+        end_recording(context, reason);
+    }
+}
+
+// Ends the recording once a limit has been reached, unless it is already ending or a later
+// session has started
+fn end_recording(context: &MessageContext, reason: StopReason) {
+    let mut session = context.session.lock().unwrap();
+    if session.generation() != context.generation || !session.is_active() || session.is_stopping() {
+        return;
+    }
+    info!("Recording limit reached: {:?}", reason);
+    session.set_stop_reason(reason);
+    context.target.end();
+}
+
 #[derive(Default)]
 pub struct VideoRecorderBuilder {
     pipeline: String,
@@ -284,6 +831,14 @@ pub struct VideoRecorderBuilder {
     output_dir: String,
     chunk_prefix: String,
     socket_path: String,
+    clock: Option<WallClock>,
+    retention: Option<RetentionPolicy>,
+    analyzers: Vec<Box<dyn FrameAnalyzer>>,
+    analyzer_factories: HashMap<String, AnalyzerFactory>,
+    events: Option<std::sync::Arc<Mutex<EventLog>>>,
+    frame_queue_size: usize,
+    thumbnails: ThumbnailConfig,
+    poster: PosterConfig,
 }
 impl VideoRecorderBuilder {
     pub fn new() -> VideoRecorderBuilder {
@@ -295,6 +850,14 @@ impl VideoRecorderBuilder {
             output_dir: ".".to_string(),
             chunk_prefix: "chunk".to_string(),
             socket_path: "/tmp/video.sock".to_string(),
+            clock: None,
+            retention: None,
+            analyzers: Vec::new(),
+            analyzer_factories: HashMap::new(),
+            events: None,
+            frame_queue_size: 8,
+            thumbnails: ThumbnailConfig::default(),
+            poster: PosterConfig::default(),
         }
     }
 
@@ -326,8 +889,66 @@ impl VideoRecorderBuilder {
         self
     }
 
+    // The wall clock of the recordings, the system clock if not set
+    pub fn with_clock(mut self, clock: WallClock) -> VideoRecorderBuilder {
+        self.clock = Some(clock);
+        self
+    }
+
+    // Enables loop recording: only the segments within the retention window are kept
+    pub fn with_retention(mut self, retention: Option<RetentionPolicy>) -> VideoRecorderBuilder {
+        self.retention = retention;
+        self
+    }
+
+    // Runs the analyzer on every recording (in addition to the thumbnails)
+    pub fn with_analyzer(mut self, analyzer: impl FrameAnalyzer + 'static) -> VideoRecorderBuilder {
+        self.analyzers.push(Box::new(analyzer));
+        self
+    }
+
+    // Registers an analyzer that is selected by name in a recording request
+    pub fn with_analyzer_factory(
+        mut self,
+        name: &str,
+        factory: AnalyzerFactory,
+    ) -> VideoRecorderBuilder {
+        self.analyzer_factories.insert(name.to_string(), factory);
+        self
+    }
+
+    // The event log the events of the analyzers are published in
+    pub fn with_events(mut self, events: std::sync::Arc<Mutex<EventLog>>) -> VideoRecorderBuilder {
+        self.events = Some(events);
+        self
+    }
+
+    // The number of frames waiting for the analyzers before new frames are dropped
+    pub fn with_frame_queue_size(mut self, frame_queue_size: usize) -> VideoRecorderBuilder {
+        self.frame_queue_size = frame_queue_size;
+        self
+    }
+
+    pub fn with_thumbnails(mut self, thumbnails: ThumbnailConfig) -> VideoRecorderBuilder {
+        self.thumbnails = thumbnails;
+        self
+    }
+
+    pub fn with_poster(mut self, poster: PosterConfig) -> VideoRecorderBuilder {
+        self.poster = poster;
+        self
+    }
+
     pub fn build(self) -> VideoRecorder {
-        let (sender, receiver) = mpsc::sync_channel::<String>(1);
+        let session = std::sync::Arc::new(Mutex::new(Session::new()));
+        let mut analyzers: Vec<Box<dyn FrameAnalyzer>> = vec![
+            Box::new(Thumbnailer::new(self.thumbnails)),
+            Box::new(PosterAnalyzer::new(self.poster)),
+        ];
+        analyzers.extend(self.analyzers);
+        let clock = self
+            .clock
+            .unwrap_or_else(|| WallClock::new(&ClockSource::System, "", 0).unwrap());
         VideoRecorder {
             pipeline: self.pipeline.clone(),
             on_chunk: std::sync::Arc::new(Mutex::new(self.on_chunk)),
@@ -336,8 +957,19 @@ impl VideoRecorderBuilder {
             chunk_prefix: self.chunk_prefix,
             socket_path: self.socket_path,
             runtime: Runtime::new().unwrap(),
-            fh: std::sync::Arc::new(Mutex::new(FrameHandlerImpl::new(self.output_dir, receiver))),
-            sender,
+            fh: std::sync::Arc::new(FrameHandlerImpl::new(
+                self.frame_queue_size,
+                analyzers,
+                session.clone(),
+                self.events,
+            )),
+            clock,
+            annotator: std::sync::Arc::new(Mutex::new(PlaylistAnnotator::new())),
+            pause_state: std::sync::Arc::new(PauseState::default()),
+            session,
+            retention: std::sync::Arc::new(Mutex::new(RetentionManager::new(self.retention))),
+            sink: Mutex::new(None),
+            analyzer_factories: self.analyzer_factories,
         }
     }
 }
@@ -1,11 +1,14 @@
+use crate::recorder::privacy::{PrivacyMask, PRIVACY_ELEMENT};
 use crate::{dtos, recorder};
 use dtos::messages::VideoSourceInfo;
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
 use futures::StreamExt;
 use gst::prelude::*;
 use gstreamer::{Caps, Element};
 use gstreamer_app::gst;
 use log::{debug, error, info};
 use recorder::common::PipelineError;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio::runtime::Runtime;
 
@@ -25,6 +28,12 @@ pub trait Source: Sync + Send {
     // Stop the video source
     // device: the device to start
     fn stop(&self, device: &str) -> Result<(), PipelineError>;
+
+    // The source pipeline, branches can be attached to its tee while it is running
+    fn pipeline(&self) -> Option<gst::Pipeline>;
+
+    // Receives the messages of the source pipeline bus (e.g. of attached branches)
+    fn subscribe(&self) -> UnboundedReceiver<gst::Message>;
 }
 
 pub struct VideoSource {
@@ -33,6 +42,8 @@ pub struct VideoSource {
     gst_pipeline: Option<Element>,
     runtime: Runtime,
     device: String,
+    subscribers: Arc<Mutex<Vec<UnboundedSender<gst::Message>>>>,
+    privacy: Option<Arc<PrivacyMask>>,
 }
 
 impl Source for VideoSource {
@@ -70,6 +81,13 @@ impl Source for VideoSource {
         if source.has_property("device", None) {
             source.set_property("device", format!("/dev/{}", &device));
         }
+        // the source is not started if the masks can not be applied
+        if let Some(privacy) = self.privacy.as_ref() {
+            if privacy.select(&device) && pipeline_bin.by_name(PRIVACY_ELEMENT).is_none() {
+                error!("Privacy masks need a {PRIVACY_ELEMENT} element in the source pipeline");
+                return Err(PipelineError::ParseError);
+            }
+        }
 
         let bus = self
             .gst_pipeline
@@ -98,8 +116,9 @@ impl Source for VideoSource {
                 .unwrap()
                 .debug_to_dot_file(gst::DebugGraphDetails::MEDIA_TYPE, "source");
         }
+        let subscribers = self.subscribers.clone();
         self.runtime.spawn(async {
-            message_loop(bus).await;
+            message_loop(bus, subscribers).await;
         });
         info!("Pipeline started");
         // query the video source for info (width, height, framerate, format)
@@ -140,6 +159,18 @@ impl Source for VideoSource {
 
         Ok(())
     }
+
+    fn pipeline(&self) -> Option<gst::Pipeline> {
+        self.gst_pipeline
+            .as_ref()
+            .and_then(|p| p.clone().downcast::<gst::Pipeline>().ok())
+    }
+
+    fn subscribe(&self) -> UnboundedReceiver<gst::Message> {
+        let (sender, receiver) = unbounded();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
 }
 
 impl VideoSource {
@@ -157,12 +188,18 @@ impl VideoSource {
     }
 }
 
-async fn message_loop(bus: gst::Bus) {
+async fn message_loop(bus: gst::Bus, subscribers: Arc<Mutex<Vec<UnboundedSender<gst::Message>>>>) {
     let mut messages = bus.stream();
 
     while let Some(msg) = messages.next().await {
         use gst::MessageView;
 
+        // closed subscriptions are dropped
+        subscribers
+            .lock()
+            .unwrap()
+            .retain(|s| s.unbounded_send(msg.clone()).is_ok());
+
         // Determine whether we want to quit: on EOS or error message
         // we quit, otherwise simply continue.
         match msg.view() {
@@ -187,6 +224,7 @@ async fn message_loop(bus: gst::Bus) {
 pub struct VideoSourceBuilder {
     fd_dir: String,
     pipeline_str: String,
+    privacy: Option<Arc<PrivacyMask>>,
 }
 
 impl VideoSourceBuilder {
@@ -197,6 +235,7 @@ impl VideoSourceBuilder {
             pipeline_str:
                 "v4l2src name=video-source device=/dev/video0 ! unixfdsink name=video-sink"
                     .to_string(),
+            privacy: None,
         }
     }
     pub fn with_fd_dir(mut self, fd_dir: &str) -> VideoSourceBuilder {
@@ -208,19 +247,34 @@ impl VideoSourceBuilder {
         self.pipeline_str = pipeline.to_string();
         self
     }
+
+    // Masks the frames of the source before they reach recordings, stills and the preview
+    pub fn with_privacy(mut self, privacy: Arc<PrivacyMask>) -> VideoSourceBuilder {
+        self.privacy = Some(privacy);
+        self
+    }
+
     pub fn build(&self) -> VideoSource {
+        let gst_pipeline = match gst::parse::launch(self.pipeline_str.clone().as_str()) {
+            Ok(pipeline) => Some(pipeline),
+            Err(e) => {
+                error!("{e}");
+                None
+            }
+        };
+        if let (Some(privacy), Some(pipeline)) = (self.privacy.as_ref(), gst_pipeline.as_ref()) {
+            if let Some(bin) = pipeline.downcast_ref::<gst::Bin>() {
+                privacy.attach(bin);
+            }
+        }
         VideoSource {
             fd_dir: self.fd_dir.to_string(),
             pipeline_str: self.pipeline_str.to_string(),
-            gst_pipeline: match gst::parse::launch(self.pipeline_str.clone().as_str()) {
-                Ok(pipeline) => Some(pipeline),
-                Err(e) => {
-                    error!("{e}");
-                    None
-                }
-            },
+            gst_pipeline,
             runtime: Runtime::new().unwrap(),
             device: String::new(),
+            subscribers: Arc::new(Mutex::new(Vec::new())),
+            privacy: self.privacy.clone(),
         }
     }
 }
@@ -0,0 +1,230 @@
+use chrono::{DateTime, Duration, Local};
+use cron::Schedule as CronSchedule;
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::str::FromStr;
+
+// A recording that is started and stopped automatically.
+// Either start_at (one-off) or cron (recurring, "sec min hour day-of-month month day-of-week [year]") is set.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Schedule {
+    #[serde(default)]
+    pub id: String,
+    pub name: String,
+    #[serde(default = "default_device")]
+    pub device: String,
+    #[serde(default)]
+    pub start_at: Option<DateTime<Local>>,
+    #[serde(default)]
+    pub cron: Option<String>,
+    pub duration_sec: u64,
+    #[serde(default)]
+    pub profile: Option<String>,
+    // the last occurrence that has been handled (started or missed)
+    #[serde(default)]
+    pub last_occurrence: Option<DateTime<Local>>,
+    #[serde(default)]
+    pub missed: Vec<DateTime<Local>>,
+    #[serde(default)]
+    pub created_at: Option<DateTime<Local>>,
+}
+
+fn default_device() -> String {
+    "video10".to_string()
+}
+
+impl Schedule {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.duration_sec == 0 {
+            return Err("duration_sec must be greater than 0".to_string());
+        }
+        match (self.start_at.as_ref(), self.cron.as_ref()) {
+            (Some(_), None) => Ok(()),
+            (None, Some(cron)) => CronSchedule::from_str(cron)
+                .map(|_| ())
+                .map_err(|e| format!("invalid cron expression: {e}")),
+            _ => Err("either start_at or cron has to be set".to_string()),
+        }
+    }
+
+    pub fn duration(&self) -> Duration {
+        Duration::seconds(self.duration_sec as i64)
+    }
+
+    // The occurrences that have not been handled yet, oldest first
+    fn occurrences(&self) -> Box<dyn Iterator<Item = DateTime<Local>>> {
+        let after = self
+            .last_occurrence
+            .or(self.created_at.map(|c| c - self.duration()));
+        match (self.start_at, self.cron.as_ref()) {
+            (Some(start_at), _) => match after {
+                Some(after) if after >= start_at => Box::new(std::iter::empty()),
+                _ => Box::new(std::iter::once(start_at)),
+            },
+            (None, Some(cron)) => match CronSchedule::from_str(cron) {
+                Ok(schedule) => Box::new(schedule.after_owned(after.unwrap_or(Local::now()))),
+                Err(e) => {
+                    error!("Invalid cron expression {}: {e}", cron);
+                    Box::new(std::iter::empty())
+                }
+            },
+            _ => Box::new(std::iter::empty()),
+        }
+    }
+
+    // The latest occurrence up to now that has not been handled yet, together with the
+    // earlier unhandled occurrences it supersedes (e.g. after a downtime)
+    // keep: the number of superseded occurrences returned (the most recent ones)
+    // returns: None if no occurrence is due
+    pub fn due_occurrence(
+        &self,
+        now: &DateTime<Local>,
+        keep: usize,
+    ) -> Option<(DateTime<Local>, Vec<DateTime<Local>>)> {
+        let mut superseded = VecDeque::new();
+        let mut latest = None;
+        for occurrence in self.occurrences().take_while(|o| o <= now) {
+            if let Some(previous) = latest.replace(occurrence) {
+                superseded.push_back(previous);
+                if superseded.len() > keep {
+                    superseded.pop_front();
+                }
+            }
+        }
+        latest.map(|latest| (latest, superseded.into()))
+    }
+}
+
+// The persistent list of schedules (a json file)
+pub struct ScheduleStore {
+    path: String,
+    schedules: Vec<Schedule>,
+}
+
+impl ScheduleStore {
+    // Loads the schedules, a missing file results in an empty store
+    pub fn load(path: &str) -> ScheduleStore {
+        let schedules = std::fs::read_to_string(path)
+            .ok()
+            .and_then(|content| {
+                serde_json::from_str::<Vec<Schedule>>(&content)
+                    .map_err(|e| error!("Unable to read schedules from {}: {e}", path))
+                    .ok()
+            })
+            .unwrap_or_default();
+        ScheduleStore {
+            path: path.to_string(),
+            schedules,
+        }
+    }
+
+    pub fn list(&self) -> Vec<Schedule> {
+        self.schedules.clone()
+    }
+
+    pub fn add(&mut self, mut schedule: Schedule) -> Result<Schedule, String> {
+        schedule.validate()?;
+        let now = Local::now();
+        // the counter keeps the ids of schedules created within the same second unique
+        let base = now.format("%Y%m%d%H%M%S").to_string();
+        schedule.id = (0..)
+            .map(|n| format!("{}-{:04}", base, n))
+            .find(|id| self.schedules.iter().all(|s| s.id != *id))
+            .unwrap();
+        schedule.created_at = Some(now);
+        schedule.last_occurrence = None;
+        schedule.missed.clear();
+        self.schedules.push(schedule.clone());
+        self.save();
+        Ok(schedule)
+    }
+
+    pub fn remove(&mut self, id: &str) -> Option<Schedule> {
+        let idx = self.schedules.iter().position(|s| s.id == id)?;
+        let schedule = self.schedules.remove(idx);
+        self.save();
+        Some(schedule)
+    }
+
+    pub fn get_mut(&mut self, id: &str) -> Option<&mut Schedule> {
+        self.schedules.iter_mut().find(|s| s.id == id)
+    }
+
+    pub fn save(&self) {
+        match serde_json::to_string_pretty(&self.schedules) {
+            Ok(json) => {
+                if let Err(e) = std::fs::write(&self.path, json) {
+                    error!("Unable to write {}: {e}", self.path);
+                }
+            }
+            Err(e) => error!("Unable to serialize schedules: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Timelike;
+
+    #[test]
+    fn test_due_occurrence() {
+        let now = Local::now();
+        let one_off = Schedule {
+            name: "one-off".to_string(),
+            start_at: Some(now + Duration::minutes(5)),
+            duration_sec: 60,
+            created_at: Some(now),
+            ..Default::default()
+        };
+        assert!(one_off.validate().is_ok());
+        assert_eq!(one_off.due_occurrence(&now, 10), None);
+        let start_at = one_off.start_at.unwrap();
+        assert_eq!(
+            one_off.due_occurrence(&start_at, 10),
+            Some((start_at, Vec::new()))
+        );
+        let handled = Schedule {
+            last_occurrence: one_off.start_at,
+            ..one_off.clone()
+        };
+        assert_eq!(
+            handled.due_occurrence(&(start_at + Duration::hours(1)), 10),
+            None
+        );
+
+        // a day of hourly occurrences has been missed
+        let hour = now.with_minute(0).unwrap().with_second(0).unwrap();
+        let hour = hour.with_nanosecond(0).unwrap() - Duration::days(1);
+        let recurring = Schedule {
+            name: "recurring".to_string(),
+            cron: Some("0 0 * * * *".to_string()),
+            duration_sec: 60,
+            created_at: Some(hour - Duration::days(1)),
+            last_occurrence: Some(hour),
+            ..Default::default()
+        };
+        assert!(recurring.validate().is_ok());
+        let now = hour + Duration::days(1) + Duration::minutes(30);
+        let (latest, superseded) = recurring.due_occurrence(&now, 100).unwrap();
+        assert_eq!(latest, hour + Duration::days(1));
+        assert_eq!(superseded.len(), 23);
+        assert_eq!(superseded[0], hour + Duration::hours(1));
+        let (_, superseded) = recurring.due_occurrence(&now, 5).unwrap();
+        assert_eq!(superseded.len(), 5);
+        assert_eq!(superseded[4], hour + Duration::hours(23));
+        assert_eq!(
+            recurring.due_occurrence(&(hour + Duration::minutes(30)), 5),
+            None
+        );
+
+        let invalid = Schedule {
+            start_at: Some(now),
+            cron: Some("0 0 * * * *".to_string()),
+            duration_sec: 60,
+            ..Default::default()
+        };
+        assert!(invalid.validate().is_err());
+    }
+}
@@ -0,0 +1,191 @@
+use crate::dtos::messages::{RecordingLimits, RecordingRequest};
+use crate::recorder::videocontroller::VideoController;
+use crate::scheduler::schedule::{Schedule, ScheduleStore};
+use crate::utils::config::MissedSchedulePolicy;
+use chrono::{DateTime, Duration, Local};
+use log::{error, info, warn};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+const TICK: std::time::Duration = std::time::Duration::from_secs(1);
+// occurrences found later than this are treated as missed (e.g. after a restart)
+const GRACE_SEC: i64 = 30;
+const MAX_MISSED: usize = 100;
+
+struct ActiveRecording {
+    schedule_id: String,
+    ends_at: DateTime<Local>,
+}
+
+// Starts and stops recordings according to the stored schedules
+pub struct Scheduler {
+    store: ScheduleStore,
+    controller: Arc<Mutex<dyn VideoController>>,
+    policy: MissedSchedulePolicy,
+    active: Option<ActiveRecording>,
+}
+
+impl Scheduler {
+    // path: the file the schedules are persisted in
+    // controller: the controller used to start and stop the recordings
+    // policy: how schedules missed while the recorder was down are handled
+    pub fn new(
+        path: &str,
+        controller: Arc<Mutex<dyn VideoController>>,
+        policy: MissedSchedulePolicy,
+    ) -> Scheduler {
+        let store = ScheduleStore::load(path);
+        info!("Loaded {} schedules from {}", store.list().len(), path);
+        Scheduler {
+            store,
+            controller,
+            policy,
+            active: None,
+        }
+    }
+
+    // Runs the scheduler in a background thread
+    pub fn run(scheduler: Arc<Mutex<Scheduler>>) {
+        thread::spawn(move || loop {
+            scheduler.lock().unwrap().tick(Local::now());
+            thread::sleep(TICK);
+        });
+    }
+
+    pub fn list(&self) -> Vec<Schedule> {
+        self.store.list()
+    }
+
+    pub fn add(&mut self, schedule: Schedule) -> Result<Schedule, String> {
+        let schedule = self.store.add(schedule)?;
+        info!("Schedule {} ({}) created", schedule.id, schedule.name);
+        Ok(schedule)
+    }
+
+    // Removes a schedule, a recording started by it is stopped
+    pub fn remove(&mut self, id: &str) -> Option<Schedule> {
+        let schedule = self.store.remove(id)?;
+        if self.active.as_ref().map(|a| a.schedule_id == id) == Some(true) {
+            self.stop_recording();
+        }
+        info!("Schedule {} ({}) deleted", schedule.id, schedule.name);
+        Some(schedule)
+    }
+
+    // Starts due recordings and stops finished ones
+    // now: the current time
+    pub fn tick(&mut self, now: DateTime<Local>) {
+        if let Some(active) = self.active.as_ref() {
+            if now >= active.ends_at {
+                self.stop_recording();
+            }
+        }
+        // only the latest occurrence of a schedule is started, the ones before have been missed
+        let mut due = self
+            .store
+            .list()
+            .into_iter()
+            .filter_map(|s| {
+                let (occurrence, superseded) = s.due_occurrence(&now, MAX_MISSED)?;
+                Some((s, occurrence, superseded))
+            })
+            .collect::<Vec<(Schedule, DateTime<Local>, Vec<DateTime<Local>>)>>();
+        if due.is_empty() {
+            return;
+        }
+        due.sort_by_key(|(_, occurrence, _)| *occurrence);
+        for (schedule, occurrence, superseded) in due {
+            if let Some(last) = superseded.last() {
+                warn!("Schedule {} missed up to {}", schedule.name, last);
+            }
+            let ends_at = occurrence + schedule.duration();
+            let late = now - occurrence > Duration::seconds(GRACE_SEC);
+            let missed = if ends_at <= now {
+                warn!("Schedule {} missed at {}", schedule.name, occurrence);
+                true
+            } else if late && self.policy == MissedSchedulePolicy::Skip {
+                warn!("Schedule {} skipped (started late)", schedule.name);
+                true
+            } else if self.active.is_some() {
+                warn!("Schedule {} skipped (recorder busy)", schedule.name);
+                true
+            } else {
+                !self.start_recording(&schedule, ends_at, now)
+            };
+            if let Some(s) = self.store.get_mut(&schedule.id) {
+                s.last_occurrence = Some(occurrence);
+                s.missed.extend(superseded);
+                if missed {
+                    s.missed.push(occurrence);
+                }
+                let excess = s.missed.len().saturating_sub(MAX_MISSED);
+                s.missed.drain(..excess);
+            }
+        }
+        self.store.save();
+    }
+
+    fn start_recording(
+        &mut self,
+        schedule: &Schedule,
+        ends_at: DateTime<Local>,
+        now: DateTime<Local>,
+    ) -> bool {
+        info!(
+            "Schedule {} starts recording until {}",
+            schedule.name, ends_at
+        );
+        let mut controller = self.controller.lock().unwrap();
+        // a running source is kept (with its preview and pre-roll), another device is not taken
+        match controller.device() {
+            Some(device) if device == schedule.device => (),
+            Some(device) => {
+                error!(
+                    "Schedule {} needs {}, {} is running",
+                    schedule.name, schedule.device, device
+                );
+                return false;
+            }
+            None => {
+                if let Err(e) = controller.start(&schedule.device) {
+                    error!("Unable to start {}: {:?}", schedule.device, e);
+                    return false;
+                }
+            }
+        }
+        let request = RecordingRequest {
+            name: Some(schedule.name.clone()),
+            profile: schedule.profile.clone(),
+            limits: RecordingLimits {
+                max_duration_sec: Some((ends_at - now).num_seconds().max(1) as u64),
+                stop_at: Some(ends_at),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        match controller.start_recording(request) {
+            Ok(info) => {
+                info!("Schedule {} recording {}", schedule.name, info.prefix);
+                self.active = Some(ActiveRecording {
+                    schedule_id: schedule.id.clone(),
+                    ends_at,
+                });
+                true
+            }
+            Err(e) => {
+                error!("Unable to start recording for {}: {:?}", schedule.name, e);
+                false
+            }
+        }
+    }
+
+    fn stop_recording(&mut self) {
+        if let Some(active) = self.active.take() {
+            info!("Stopping recording of schedule {}", active.schedule_id);
+            // the recording might have ended already because of its limits
+            if let Err(e) = self.controller.lock().unwrap().stop_recording() {
+                info!("Recording already stopped: {:?}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,393 @@
+use crate::dtos::messages::{
+    JobStill, StillJob, StillJobKind, StillJobRequest, StillJobState, STILL_TIMESTAMP_FORMAT,
+};
+use crate::recorder::common::PipelineError;
+use crate::recorder::videocontroller::VideoController;
+use chrono::Local;
+use log::{error, info};
+use serde::Serialize;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const TICK: Duration = Duration::from_millis(100);
+const MAX_BURST: u32 = 1000;
+const MIN_INTERVAL_SEC: f64 = 0.1;
+// the ended jobs kept for the job list, older ones are evicted
+const MAX_ENDED_JOBS: usize = 16;
+
+struct RunningJob {
+    job: Arc<Mutex<StillJob>>,
+    stop: Arc<AtomicBool>,
+}
+
+// Takes sequences of stills: a burst of stills (one per frame) or a still every interval,
+// each job runs in its own thread and appends its stills to a manifest (JSON lines), only
+// the counters of a job are kept in memory
+pub struct StillJobs {
+    controller: Arc<Mutex<dyn VideoController>>,
+    output_dir: String,
+    jobs: Vec<RunningJob>,
+    // the jobs started so far, keeps the job ids unique
+    started: usize,
+}
+
+impl StillJobs {
+    // controller: the controller taking the stills
+    // output_dir: the directory of the stills and the manifests
+    pub fn new(controller: Arc<Mutex<dyn VideoController>>, output_dir: &str) -> StillJobs {
+        StillJobs {
+            controller,
+            output_dir: output_dir.to_string(),
+            jobs: Vec::new(),
+            started: 0,
+        }
+    }
+
+    pub fn start(&mut self, request: StillJobRequest) -> Result<StillJob, String> {
+        validate(&request)?;
+        self.evict();
+        let started_at = Local::now();
+        let id = format!(
+            "{}-{}",
+            started_at.format(STILL_TIMESTAMP_FORMAT),
+            self.started
+        );
+        self.started += 1;
+        let job = StillJob {
+            id: id.clone(),
+            name: request.name.clone(),
+            kind: request.kind,
+            state: StillJobState::Running,
+            started_at,
+            finished_at: None,
+            count: request.count,
+            interval_sec: match request.kind {
+                StillJobKind::Burst => 0.0,
+                StillJobKind::Interval => request.interval_sec,
+            },
+            duration_sec: request.duration_sec,
+            manifest: format!("{}/{}-stills.jsonl", self.output_dir, id),
+            captured: 0,
+            failed: 0,
+        };
+        info!("Still job {} ({:?}) started", id, request.kind);
+        append(&job.manifest, &job);
+        let running = RunningJob {
+            job: Arc::new(Mutex::new(job.clone())),
+            stop: Arc::new(AtomicBool::new(false)),
+        };
+        let controller = self.controller.clone();
+        let (shared, stop) = (running.job.clone(), running.stop.clone());
+        thread::spawn(move || run(controller, request, shared, stop));
+        self.jobs.push(running);
+        Ok(job)
+    }
+
+    // Stops a running job, the stills taken so far are kept
+    pub fn stop(&mut self, id: &str) -> Option<StillJob> {
+        let running = self.jobs.iter().find(|r| r.job.lock().unwrap().id == id)?;
+        running.stop.store(true, Ordering::SeqCst);
+        info!("Still job {} stopping", id);
+        let job = running.job.lock().unwrap().clone();
+        Some(job)
+    }
+
+    // The running and the recently ended jobs, their stills are in the manifests
+    pub fn list(&mut self) -> Vec<StillJob> {
+        self.evict();
+        self.jobs
+            .iter()
+            .map(|r| r.job.lock().unwrap().clone())
+            .collect()
+    }
+
+    // Drops the oldest ended jobs beyond MAX_ENDED_JOBS
+    fn evict(&mut self) {
+        let ended = self
+            .jobs
+            .iter()
+            .filter(|r| r.job.lock().unwrap().state != StillJobState::Running)
+            .count();
+        let mut evicted = ended.saturating_sub(MAX_ENDED_JOBS);
+        self.jobs.retain(|r| {
+            let ended = r.job.lock().unwrap().state != StillJobState::Running;
+            if ended && evicted > 0 {
+                evicted -= 1;
+                return false;
+            }
+            true
+        });
+    }
+}
+
+pub fn validate(request: &StillJobRequest) -> Result<(), String> {
+    request.still.encoding.validate()?;
+    if request.count == Some(0) {
+        return Err("count must be greater than 0".to_string());
+    }
+    match request.kind {
+        StillJobKind::Burst => match request.count {
+            Some(count) if count <= MAX_BURST => Ok(()),
+            Some(_) => Err(format!("A burst has at most {MAX_BURST} stills")),
+            None => Err("A burst needs a count".to_string()),
+        },
+        StillJobKind::Interval if request.interval_sec < MIN_INTERVAL_SEC => {
+            Err(format!("interval_sec must be at least {MIN_INTERVAL_SEC}"))
+        }
+        StillJobKind::Interval => Ok(()),
+    }
+}
+
+// Appends a line to the manifest of a job
+fn append(manifest: &str, line: &impl Serialize) {
+    let res = serde_json::to_string(line)
+        .map_err(|e| e.to_string())
+        .and_then(|json| {
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(manifest)
+                .and_then(|mut file| writeln!(file, "{json}"))
+                .map_err(|e| e.to_string())
+        });
+    if let Err(e) = res {
+        error!("Unable to write {}: {}", manifest, e);
+    }
+}
+
+fn run(
+    controller: Arc<Mutex<dyn VideoController>>,
+    request: StillJobRequest,
+    job: Arc<Mutex<StillJob>>,
+    stop: Arc<AtomicBool>,
+) {
+    let (id, manifest) = {
+        let job = job.lock().unwrap();
+        (job.id.clone(), job.manifest.clone())
+    };
+    let interval = Duration::from_secs_f64(job.lock().unwrap().interval_sec);
+    let duration = request.duration_sec.map(Duration::from_secs);
+    let started = Instant::now();
+    let mut index: u32 = 0;
+    let state = loop {
+        if stop.load(Ordering::SeqCst) {
+            break StillJobState::Stopped;
+        }
+        if request.count.is_some_and(|count| index >= count)
+            || duration.is_some_and(|duration| started.elapsed() >= duration)
+        {
+            break StillJobState::Finished;
+        }
+        // the next still is due relative to the start, slow stills do not add up
+        let due = started + interval * index;
+        let now = Instant::now();
+        if now < due {
+            thread::sleep(TICK.min(due - now));
+            continue;
+        }
+        // the index keeps the names of the stills unique
+        let name = format!("{}-{:05}", id, index);
+        let res = {
+            let controller = controller.lock().unwrap();
+            match controller.device() {
+                Some(device) => controller.take_still(&device, &name, &request.still),
+                None => Err(PipelineError::NotRunning),
+            }
+        };
+        match res {
+            Ok(info) => {
+                let still = JobStill {
+                    index,
+                    captured_at: Local::now(),
+                    info,
+                };
+                append(&manifest, &still);
+                job.lock().unwrap().captured += 1;
+            }
+            Err(e) => {
+                error!("Still {} of job {} failed: {:?}", index, id, e);
+                job.lock().unwrap().failed += 1;
+            }
+        }
+        index += 1;
+    };
+    let mut job = job.lock().unwrap();
+    job.state = state;
+    job.finished_at = Some(Local::now());
+    append(&manifest, &*job);
+    info!(
+        "Still job {} {:?}: {} stills, {} failed",
+        id, state, job.captured, job.failed
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dtos::messages::{
+        FrameMetrics, IncidentInfo, MotionInterval, ProtectRequest, RecordingInfo,
+        RecordingRequest, StillInfo, StillRequest, TimelapseInfo, TimelapseRequest,
+        VideoSourceInfo,
+    };
+    use crate::recorder::branch::DynamicBranch;
+    use serde_json::Value;
+    use std::collections::HashSet;
+    use std::fs;
+
+    // A started source taking stills without a pipeline
+    struct StubController;
+
+    impl VideoController for StubController {
+        fn scan(&self) -> Result<Vec<String>, PipelineError> {
+            Ok(vec!["video0".to_string()])
+        }
+        fn start(&mut self, _device: &str) -> Result<VideoSourceInfo, PipelineError> {
+            Err(PipelineError::AlreadyStarted)
+        }
+        fn stop(&self, _device: &str) -> Result<(), PipelineError> {
+            Ok(())
+        }
+        fn start_recording(&mut self, _: RecordingRequest) -> Result<RecordingInfo, PipelineError> {
+            Err(PipelineError::NotRunning)
+        }
+        fn stop_recording(&self) -> Result<(), PipelineError> {
+            Err(PipelineError::NotRunning)
+        }
+        fn pause_recording(&self) -> Result<(), PipelineError> {
+            Err(PipelineError::NotRunning)
+        }
+        fn resume_recording(&self) -> Result<(), PipelineError> {
+            Err(PipelineError::NotRunning)
+        }
+        fn protect_recording(&self, _: ProtectRequest) -> Result<IncidentInfo, PipelineError> {
+            Err(PipelineError::NotRunning)
+        }
+        fn is_recording(&self) -> bool {
+            false
+        }
+        fn session_prefix(&self) -> Option<String> {
+            None
+        }
+        fn add_motion_interval(&self, _: MotionInterval) -> Result<(), PipelineError> {
+            Err(PipelineError::NotRunning)
+        }
+        fn attach_branch(&self, _: &str) -> Result<Option<DynamicBranch>, PipelineError> {
+            Ok(None)
+        }
+        fn device(&self) -> Option<String> {
+            Some("video0".to_string())
+        }
+        fn take_still(
+            &self,
+            device: &str,
+            still_file: &str,
+            _request: &StillRequest,
+        ) -> Result<StillInfo, PipelineError> {
+            Ok(StillInfo {
+                device: device.to_string(),
+                width: 640,
+                height: 480,
+                format: "jpeg".to_string(),
+                still_file: format!("{still_file}.jpg"),
+            })
+        }
+        fn encode_still(&self, _: &str, _: &StillRequest) -> Result<Vec<u8>, PipelineError> {
+            Ok(Vec::new())
+        }
+        fn start_timelapse(&self, _: TimelapseRequest) -> Result<TimelapseInfo, PipelineError> {
+            Err(PipelineError::NotRunning)
+        }
+        fn stop_timelapse(&self) -> Result<TimelapseInfo, PipelineError> {
+            Err(PipelineError::NotRunning)
+        }
+        fn frame_metrics(&self) -> FrameMetrics {
+            FrameMetrics::default()
+        }
+    }
+
+    #[test]
+    fn test_burst() {
+        let dir = std::env::temp_dir().join(format!("stilljobs-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut jobs = StillJobs::new(Arc::new(Mutex::new(StubController)), dir.to_str().unwrap());
+        let request = StillJobRequest {
+            count: Some(5),
+            ..StillJobRequest::default()
+        };
+        let first = jobs.start(request.clone()).unwrap();
+        let second = jobs.start(request).unwrap();
+        assert_ne!(first.id, second.id);
+        let started = Instant::now();
+        while jobs
+            .list()
+            .iter()
+            .any(|j| j.state == StillJobState::Running)
+        {
+            assert!(started.elapsed() < Duration::from_secs(10));
+            thread::sleep(TICK);
+        }
+        let mut names = HashSet::new();
+        for job in jobs.list() {
+            assert_eq!(
+                (job.state, job.captured, job.failed),
+                (StillJobState::Finished, 5, 0)
+            );
+            let manifest = fs::read_to_string(&job.manifest).unwrap();
+            let lines: Vec<Value> = manifest
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect();
+            // the job, a line per still, the ended job
+            assert_eq!(lines.len(), 7);
+            assert_eq!(lines[0]["state"], "running");
+            assert_eq!(lines[6]["state"], "finished");
+            assert_eq!(lines[6]["captured"], 5);
+            for (index, still) in lines[1..6].iter().enumerate() {
+                assert_eq!(still["index"], index);
+                assert!(names.insert(still["still_file"].as_str().unwrap().to_string()));
+            }
+        }
+        assert_eq!(names.len(), 10);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_validate() {
+        let burst = StillJobRequest {
+            count: Some(10),
+            ..StillJobRequest::default()
+        };
+        assert_eq!(validate(&burst), Ok(()));
+        let endless_burst = StillJobRequest {
+            count: None,
+            ..burst.clone()
+        };
+        assert!(validate(&endless_burst).is_err());
+        let huge_burst = StillJobRequest {
+            count: Some(MAX_BURST + 1),
+            ..burst.clone()
+        };
+        assert!(validate(&huge_burst).is_err());
+        let interval = StillJobRequest {
+            kind: StillJobKind::Interval,
+            interval_sec: 10.0,
+            duration_sec: Some(3600),
+            ..StillJobRequest::default()
+        };
+        assert_eq!(validate(&interval), Ok(()));
+        let too_fast = StillJobRequest {
+            interval_sec: 0.0,
+            ..interval.clone()
+        };
+        assert!(validate(&too_fast).is_err());
+        let no_stills = StillJobRequest {
+            count: Some(0),
+            ..interval.clone()
+        };
+        assert!(validate(&no_stills).is_err());
+    }
+}
@@ -1,6 +1,57 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use toml;
 
+#[derive(Deserialize, Serialize, Default, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ClockSource {
+    // realtime system clock
+    #[default]
+    System,
+    // pipeline clock synchronized to an ntp server
+    Ntp,
+}
+
+#[derive(Deserialize, Serialize, Default, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MissedSchedulePolicy {
+    // start a missed recording if its time window has not passed yet (with the remaining duration)
+    #[default]
+    CatchUp,
+    // never start a recording after its scheduled start
+    Skip,
+}
+
+// A rectangle of the frame in relative coordinates (0.0 - 1.0)
+#[derive(Deserialize, Serialize, Default, Debug, Clone, PartialEq)]
+pub struct MotionRegion {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+    // motion inside the region is ignored
+    #[serde(default)]
+    pub exclude: bool,
+}
+
+// How a privacy region is hidden
+#[derive(Deserialize, Serialize, Default, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MaskMode {
+    #[default]
+    Blur,
+    Black,
+}
+
+// A polygon of the frame that never reaches the recordings
+#[derive(Deserialize, Serialize, Default, Debug, Clone, PartialEq)]
+pub struct PrivacyRegion {
+    // the corners in relative coordinates (0.0 - 1.0)
+    pub points: Vec<(f64, f64)>,
+    #[serde(default)]
+    pub mode: MaskMode,
+}
+
 #[derive(Deserialize, Serialize, Default, Debug)]
 pub struct RecordingConfig {
     pub source_pipeline: String,
@@ -11,6 +62,253 @@ pub struct RecordingConfig {
     pub chunk_size: u32,
     pub output_dir: String,
     pub chunk_prefix: String,
+    #[serde(default)]
+    pub clock_source: ClockSource,
+    #[serde(default = "default_ntp_server")]
+    pub ntp_server: String,
+    #[serde(default = "default_ntp_port")]
+    pub ntp_port: i32,
+    #[serde(default)]
+    pub profiles: HashMap<String, String>,
+    #[serde(default = "default_schedule_file")]
+    pub schedule_file: String,
+    #[serde(default)]
+    pub missed_schedule_policy: MissedSchedulePolicy,
+    #[serde(default)]
+    pub loop_recording: bool,
+    #[serde(default)]
+    pub retention_hours: Option<f64>,
+    #[serde(default)]
+    pub retention_gb: Option<f64>,
+    #[serde(default)]
+    pub preroll_sec: u32,
+    #[serde(default)]
+    pub preroll_pipeline: String,
+    #[serde(default)]
+    pub preroll_recording_pipeline: String,
+    #[serde(default)]
+    pub branch_mode: bool,
+    #[serde(default = "default_source_tee")]
+    pub source_tee: String,
+    #[serde(default)]
+    pub source_branch_pipeline: String,
+    #[serde(default)]
+    pub recording_branch: String,
+    #[serde(default)]
+    pub still_branch: String,
+    #[serde(default = "default_still_live_pipeline")]
+    pub still_live_pipeline: String,
+    #[serde(default = "default_still_live_branch")]
+    pub still_live_branch: String,
+    #[serde(default = "default_timelapse_pipeline")]
+    pub timelapse_pipeline: String,
+    #[serde(default = "default_timelapse_branch")]
+    pub timelapse_branch: String,
+    #[serde(default = "default_timelapse_encoding_pipeline")]
+    pub timelapse_encoding_pipeline: String,
+    #[serde(default = "default_timelapse_interval_sec")]
+    pub timelapse_interval_sec: f64,
+    #[serde(default = "default_timelapse_fps")]
+    pub timelapse_fps: u32,
+    #[serde(default = "default_timelapse_thumbnail_every")]
+    pub timelapse_thumbnail_every: u32,
+    #[serde(default)]
+    pub motion_detection: bool,
+    #[serde(default)]
+    pub motion_pipeline: String,
+    #[serde(default = "default_motion_branch")]
+    pub motion_branch: String,
+    #[serde(default = "default_motion_sensitivity")]
+    pub motion_sensitivity: f64,
+    #[serde(default = "default_motion_cooldown_sec")]
+    pub motion_cooldown_sec: u32,
+    #[serde(default)]
+    pub motion_regions: Vec<MotionRegion>,
+    #[serde(default = "default_frame_queue_size")]
+    pub frame_queue_size: usize,
+    #[serde(default = "default_thumbnail_width")]
+    pub thumbnail_width: i32,
+    #[serde(default = "default_thumbnail_height")]
+    pub thumbnail_height: i32,
+    #[serde(default = "default_sprite_width")]
+    pub sprite_width: i32,
+    #[serde(default = "default_sprite_height")]
+    pub sprite_height: i32,
+    #[serde(default = "default_thumbnails_per_tile")]
+    pub thumbnails_per_tile: usize,
+    #[serde(default)]
+    pub storyboard: bool,
+    #[serde(default = "default_scene_threshold")]
+    pub scene_threshold: f64,
+    #[serde(default = "default_poster_position")]
+    pub poster_position: f64,
+    #[serde(default = "default_preview_frames")]
+    pub preview_frames: usize,
+    #[serde(default)]
+    pub quality_alarms: bool,
+    #[serde(default = "default_quality_alarm_sec")]
+    pub quality_alarm_sec: u32,
+    #[serde(default = "default_frozen_sec")]
+    pub frozen_sec: u32,
+    #[serde(default = "default_frozen_threshold")]
+    pub frozen_threshold: f64,
+    #[serde(default)]
+    pub heatmap: bool,
+    // the onnx model of the object detection (no detection without)
+    #[serde(default)]
+    pub detection_model: String,
+    #[serde(default)]
+    pub detection_labels: String,
+    #[serde(default = "default_detection_input_size")]
+    pub detection_input_size: i32,
+    #[serde(default = "default_detection_confidence")]
+    pub detection_confidence: f32,
+    #[serde(default = "default_detection_fps")]
+    pub detection_fps: f64,
+    #[serde(default)]
+    pub privacy_faces: bool,
+    #[serde(default = "default_privacy_face_model")]
+    pub privacy_face_model: String,
+    // the masked regions per device (e.g. video0)
+    #[serde(default)]
+    pub privacy_regions: HashMap<String, Vec<PrivacyRegion>>,
+}
+
+fn default_ntp_server() -> String {
+    "pool.ntp.org".to_string()
+}
+
+fn default_ntp_port() -> i32 {
+    123
+}
+
+fn default_schedule_file() -> String {
+    "schedules.json".to_string()
+}
+
+fn default_source_tee() -> String {
+    "source-tee".to_string()
+}
+
+fn default_still_live_pipeline() -> String {
+    "unixfdsrc name=video-source ! queue leaky=2 max-size-buffers=1 \
+    ! appsink name=still-sink sync=false max-buffers=1 drop=true"
+        .to_string()
+}
+
+fn default_still_live_branch() -> String {
+    "queue leaky=2 max-size-buffers=1 ! appsink name=still-sink sync=false max-buffers=1 drop=true"
+        .to_string()
+}
+
+fn default_timelapse_pipeline() -> String {
+    "unixfdsrc name=video-source ! queue leaky=2 max-size-buffers=1 \
+    ! appsink name=timelapse-sink sync=false max-buffers=1 drop=true"
+        .to_string()
+}
+
+fn default_timelapse_branch() -> String {
+    "queue leaky=2 max-size-buffers=1 \
+    ! appsink name=timelapse-sink sync=false max-buffers=1 drop=true"
+        .to_string()
+}
+
+fn default_timelapse_encoding_pipeline() -> String {
+    "appsrc name=timelapse-source format=time \
+    ! videoconvert ! x264enc ! h264parse ! mp4mux ! filesink name=video-sink"
+        .to_string()
+}
+
+fn default_timelapse_interval_sec() -> f64 {
+    10.0
+}
+
+fn default_timelapse_fps() -> u32 {
+    30
+}
+
+fn default_timelapse_thumbnail_every() -> u32 {
+    30
+}
+
+fn default_motion_branch() -> String {
+    "queue leaky=2 max-size-buffers=1 \
+    ! videoconvert ! videorate ! videoscale \
+    ! video/x-raw, format=GRAY8, width=320, height=240, framerate=5/1 \
+    ! appsink name=frame-sink sync=false max-buffers=1 drop=true"
+        .to_string()
+}
+
+fn default_motion_sensitivity() -> f64 {
+    0.5
+}
+
+fn default_motion_cooldown_sec() -> u32 {
+    10
+}
+
+fn default_frame_queue_size() -> usize {
+    8
+}
+
+fn default_thumbnail_width() -> i32 {
+    720
+}
+
+fn default_thumbnail_height() -> i32 {
+    480
+}
+
+fn default_sprite_width() -> i32 {
+    4
+}
+
+fn default_sprite_height() -> i32 {
+    54
+}
+
+fn default_thumbnails_per_tile() -> usize {
+    6
+}
+
+fn default_scene_threshold() -> f64 {
+    0.4
+}
+
+fn default_poster_position() -> f64 {
+    0.1
+}
+
+fn default_preview_frames() -> usize {
+    20
+}
+
+fn default_quality_alarm_sec() -> u32 {
+    3
+}
+
+fn default_frozen_sec() -> u32 {
+    10
+}
+
+fn default_frozen_threshold() -> f64 {
+    1.0
+}
+
+fn default_detection_input_size() -> i32 {
+    640
+}
+
+fn default_detection_confidence() -> f32 {
+    0.5
+}
+
+fn default_detection_fps() -> f64 {
+    1.0
+}
+
+fn default_privacy_face_model() -> String {
+    "/usr/share/opencv4/haarcascades/haarcascade_frontalface_default.xml".to_string()
 }
 
 pub struct Config {}
@@ -43,6 +341,72 @@ mod tests {
             chunk_size: 1024,
             output_dir: "/tmp".to_string(),
             chunk_prefix: "chunk".to_string(),
+            clock_source: ClockSource::Ntp,
+            ntp_server: "pool.ntp.org".to_string(),
+            ntp_port: 123,
+            profiles: HashMap::from([("test".to_string(), "test".to_string())]),
+            schedule_file: "schedules.json".to_string(),
+            missed_schedule_policy: MissedSchedulePolicy::Skip,
+            loop_recording: true,
+            retention_hours: Some(24.0),
+            retention_gb: None,
+            preroll_sec: 5,
+            preroll_pipeline: "test".to_string(),
+            preroll_recording_pipeline: "test".to_string(),
+            branch_mode: true,
+            source_tee: "source-tee".to_string(),
+            source_branch_pipeline: "test".to_string(),
+            recording_branch: "test".to_string(),
+            still_branch: "test".to_string(),
+            still_live_pipeline: "test".to_string(),
+            still_live_branch: "test".to_string(),
+            timelapse_pipeline: "test".to_string(),
+            timelapse_branch: "test".to_string(),
+            timelapse_encoding_pipeline: "test".to_string(),
+            timelapse_interval_sec: 10.0,
+            timelapse_fps: 30,
+            timelapse_thumbnail_every: 30,
+            motion_detection: true,
+            motion_pipeline: "test".to_string(),
+            motion_branch: "test".to_string(),
+            motion_sensitivity: 0.5,
+            motion_cooldown_sec: 10,
+            motion_regions: vec![MotionRegion {
+                x: 0.0,
+                y: 0.0,
+                width: 0.5,
+                height: 1.0,
+                exclude: true,
+            }],
+            frame_queue_size: 8,
+            thumbnail_width: 720,
+            thumbnail_height: 480,
+            sprite_width: 4,
+            sprite_height: 54,
+            thumbnails_per_tile: 6,
+            storyboard: true,
+            scene_threshold: 0.4,
+            poster_position: 0.1,
+            preview_frames: 20,
+            quality_alarms: true,
+            quality_alarm_sec: 3,
+            frozen_sec: 10,
+            frozen_threshold: 1.0,
+            heatmap: true,
+            detection_model: "yolov8n.onnx".to_string(),
+            detection_labels: "coco.names".to_string(),
+            detection_input_size: 640,
+            detection_confidence: 0.5,
+            detection_fps: 1.0,
+            privacy_faces: true,
+            privacy_face_model: "haarcascade_frontalface_default.xml".to_string(),
+            privacy_regions: HashMap::from([(
+                "video0".to_string(),
+                vec![PrivacyRegion {
+                    points: vec![(0.0, 0.0), (0.5, 0.0), (0.5, 0.5)],
+                    mode: MaskMode::Black,
+                }],
+            )]),
         };
         let config_str = toml::to_string(&config).unwrap();
         std::fs::write("test.toml", config_str).unwrap();